@@ -0,0 +1,31 @@
+-- Conformance behavior of the script hosts
+-- (see src/app_sys/script/conformance.rs)
+-- Keep it in sync with behavior.wat.
+
+local entity
+local ticks = 0
+
+function init()
+  engine.log("info", "init")
+  entity = engine.entities.spawn {
+    x = 0, y = 2, w = 8, h = 8, texture = "conformance",
+  }
+  engine.events.emit("ready", "")
+end
+
+function tick(dt)
+  ticks = ticks + 1
+  engine.entities.set_position(entity, ticks, 2)
+end
+
+function on_event(name, payload)
+  if name == "ping" then
+    engine.tiles.set("conformance", 1, 0, ticks)
+    engine.events.emit("pong", payload)
+  end
+end
+
+function shutdown()
+  engine.entities.despawn(entity)
+  engine.log("info", "shutdown")
+end
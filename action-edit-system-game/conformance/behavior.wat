@@ -0,0 +1,66 @@
+;; Conformance behavior of the script hosts (host ABI v1)
+;; (see src/app_sys/script/conformance.rs)
+;; Keep it in sync with behavior.lua.
+(module
+  (import "aes_v1" "log" (func $log (param i32 i32 i32)))
+  (import "aes_v1" "entity_spawn"
+    (func $entity_spawn (param f32 f32 f32 f32 i32 i32) (result i32)))
+  (import "aes_v1" "entity_despawn"
+    (func $entity_despawn (param i32) (result i32)))
+  (import "aes_v1" "entity_set_pos"
+    (func $entity_set_pos (param i32 f32 f32) (result i32)))
+  (import "aes_v1" "tile_set"
+    (func $tile_set (param i32 i32 i32 i32 i32) (result i32)))
+  (import "aes_v1" "event_emit"
+    (func $event_emit (param i32 i32 i32 i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "init")
+  (data (i32.const 16) "shutdown")
+  (data (i32.const 32) "conformance")
+  (data (i32.const 48) "ready")
+  (data (i32.const 64) "pong")
+  (global $entity (mut i32) (i32.const -1))
+  (global $ticks (mut i32) (i32.const 0))
+
+  (func (export "aes_abi_version") (result i32)
+    i32.const 1)
+
+  (func (export "init") (result i32)
+    (call $log (i32.const 3) (i32.const 0) (i32.const 4))
+    (global.set $entity
+      (call $entity_spawn
+        (f32.const 0) (f32.const 2) (f32.const 8) (f32.const 8)
+        (i32.const 32) (i32.const 11)))
+    (call $event_emit (i32.const 48) (i32.const 5) (i32.const 0) (i32.const 0))
+    i32.const 0)
+
+  (func (export "tick") (param $dt f32)
+    (global.set $ticks (i32.add (global.get $ticks) (i32.const 1)))
+    (drop
+      (call $entity_set_pos
+        (global.get $entity)
+        (f32.convert_i32_s (global.get $ticks))
+        (f32.const 2))))
+
+  ;; The event strings are written to a fixed buffer.
+  (func (export "aes_alloc") (param $len i32) (result i32)
+    i32.const 1024)
+
+  (func (export "on_event")
+    (param $name i32) (param $name_len i32) (param $ptr i32) (param $len i32)
+    ;; name == "ping"
+    (if (i32.and
+          (i32.eq (local.get $name_len) (i32.const 4))
+          (i32.eq (i32.load (local.get $name)) (i32.const 0x676e6970)))
+      (then
+        (drop
+          (call $tile_set
+            (i32.const 32) (i32.const 11)
+            (i32.const 1) (i32.const 0) (global.get $ticks)))
+        (call $event_emit
+          (i32.const 64) (i32.const 4) (local.get $ptr) (local.get $len)))))
+
+  (func (export "shutdown")
+    (drop (call $entity_despawn (global.get $entity)))
+    (call $log (i32.const 3) (i32.const 16) (i32.const 8)))
+)
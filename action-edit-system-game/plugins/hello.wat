@@ -0,0 +1,45 @@
+;; Minimal plugin of the host ABI v1 (see src/app_sys/wasm/abi.rs)
+(module
+  (import "aes_v1" "log" (func $log (param i32 i32 i32)))
+  (import "aes_v1" "entity_spawn"
+    (func $entity_spawn (param f32 f32 f32 f32 i32 i32) (result i32)))
+  (import "aes_v1" "entity_set_pos"
+    (func $entity_set_pos (param i32 f32 f32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "hello from wasm plugin")
+  (data (i32.const 32) "ferris")
+  (global $entity (mut i32) (i32.const -1))
+  (global $time (mut f32) (f32.const 0))
+
+  (func (export "aes_abi_version") (result i32)
+    i32.const 1)
+
+  (func (export "init") (result i32)
+    (call $log (i32.const 3) (i32.const 0) (i32.const 22))
+    (global.set $entity
+      (call $entity_spawn
+        (f32.const 200) (f32.const 0) (f32.const 32) (f32.const 32)
+        (i32.const 32) (i32.const 6)))
+    i32.const 0)
+
+  ;; Move the entity back and forth
+  (func (export "tick") (param $dt f32)
+    (global.set $time (f32.add (global.get $time) (local.get $dt)))
+    (drop
+      (call $entity_set_pos
+        (global.get $entity)
+        (f32.const 200)
+        (f32.mul
+          (f32.const 100)
+          (f32.sub
+            (f32.abs
+              (f32.sub
+                (f32.sub
+                  (global.get $time)
+                  (f32.mul
+                    (f32.const 2)
+                    (f32.floor
+                      (f32.div (global.get $time) (f32.const 2)))))
+                (f32.const 1)))
+            (f32.const 0.5))))))
+)
@@ -0,0 +1,24 @@
+-- Scripts in this directory are run on startup, and reloaded when
+-- the file is saved. Globals of a script stay in its own environment.
+
+state = state or {}
+
+if not state.ferris then
+  state.ferris = scene.spawn_sprite {
+    x = 0, y = 0, w = 64, h = 64, texture = "ferris",
+  }
+end
+
+function on_reload()
+  log.info("main.lua reloaded. ferris is", state.ferris)
+end
+
+-- Tasks are coroutines resumed by the game tick.
+if not state.blink then
+  state.blink = task.spawn(function()
+    while true do
+      wait_seconds(0.5)
+      state.ferris.visible = not state.ferris.visible
+    end
+  end, "ferris blink")
+end
@@ -0,0 +1,1326 @@
+//! Action editor
+//! アクションアセットのエディタ
+//!
+//! - Frame strip: the frames with the sprite thumbnails. Add,
+//!   duplicate, remove and reorder the frames.
+//! - Canvas: the sprite of the frame with the boxes. Drag a box to
+//!   move it, drag the corner handle to resize it, and drag on the
+//!   empty area to draw a new box of the tool kind. Delete removes
+//!   the selected box. Onion skin shows the neighboring frames.
+//! - Inspector: the frame data, the selected box and the cancel
+//!   windows.
+//! - Timeline: the keyframed tracks of the action, with the
+//!   playhead shared with the preview.
+//! - Preview: plays the action in the scene through the game camera,
+//!   one tick per simulation step. The character moves by the root
+//!   motion, and goes back at the loop. The boxes are drawn as
+//!   translucent sprites, and the sprite is scaled and tinted by the
+//!   timeline. The camera shake cues shake the preview, and the
+//!   sound cues are shown in the transport.
+//!
+//! Every change of an action can be undone (Ctrl+Z) and redone
+//! (Ctrl+Shift+Z). The actions are saved to `ACTION_ROOT` as TOML.
+
+use super::{
+  format::{self, ActionLibrary},
+  timeline::{Cue, Shake},
+  timeline_editor::TimelineEditor,
+  ActionAsset, BoxRect, CancelWindow, Frame, HitRule,
+  Hitbox, Hurtbox, SpriteRef, ACTION_ROOT,
+};
+use crate::app_sys::{
+  gfx::util::{TextureID, TextureStorage},
+  history::{Edit, History},
+  scene::{Scene2D, Sprite, SpriteID},
+};
+use egui::{Color32, Pos2, Rect, Sense, Stroke, Vec2};
+use hashbrown::{HashMap, HashSet};
+use nalgebra::Vector2;
+use std::{any::Any, path::Path};
+
+/// Plain texture for the box sprites of the preview
+pub const WHITE_TEXTURE: &str = "white";
+/// Layer of the preview sprites
+const PREVIEW_LAYER: i32 = 1000;
+const HIT_COLOR: Color32 = Color32::from_rgb(230, 60, 60);
+const HURT_COLOR: Color32 = Color32::from_rgb(60, 140, 230);
+/// Handle size of the box corner (point)
+const HANDLE: f32 = 6.;
+const CANVAS_SIZE: Vec2 = Vec2::new(420., 320.);
+const THUMBNAIL: f32 = 48.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BoxKind {
+  Hit,
+  Hurt,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Drag {
+  Move {
+    kind: BoxKind,
+    index: usize,
+    /// Box position at the start
+    from: [f32; 2],
+    /// Pointer at the start (action coordinates)
+    grab: [f32; 2],
+  },
+  Resize {
+    kind: BoxKind,
+    index: usize,
+    /// Fixed corner (action coordinates)
+    anchor: [f32; 2],
+  },
+}
+
+/// Canvas mapping between the action coordinates (y up, origin at
+/// the character) and the screen
+struct View {
+  origin: Pos2,
+  zoom: f32,
+}
+impl View {
+  fn to_screen(&self, p: [f32; 2]) -> Pos2 {
+    self.origin + Vec2::new(p[0], -p[1]) * self.zoom
+  }
+
+  fn to_action(&self, p: Pos2) -> [f32; 2] {
+    let v = (p - self.origin) / self.zoom;
+    [v.x.round(), -v.y.round()]
+  }
+
+  fn rect(&self, r: &BoxRect) -> Rect {
+    Rect::from_center_size(
+      self.to_screen(r.pos),
+      Vec2::new(r.size[0].abs(), r.size[1].abs())
+        * self.zoom,
+    )
+  }
+}
+
+fn rect_between(a: [f32; 2], b: [f32; 2]) -> BoxRect {
+  BoxRect {
+    pos: [(a[0] + b[0]) * 0.5, (a[1] + b[1]) * 0.5],
+    size: [(a[0] - b[0]).abs(), (a[1] - b[1]).abs()],
+  }
+}
+
+#[derive(Default)]
+struct Preview {
+  playing: bool,
+  /// Steps requested while paused
+  steps: u32,
+  tick: u32,
+  /// Sprite of the frame, then the boxes
+  sprites: Vec<SpriteID>,
+  /// Camera shake cue and the ticks since it fired
+  shake: Option<(Shake, u32)>,
+  /// Last sound cue
+  sound: Option<String>,
+}
+
+/// Actions under editing
+#[derive(Default)]
+struct Document {
+  library: ActionLibrary,
+  /// Actions changed since saved
+  modified: HashSet<String>,
+  /// Action changed by the last undo or redo
+  touched: Option<String>,
+}
+
+/// Change of an action (None before the creation)
+struct ActionChange {
+  before: Option<ActionAsset>,
+  after: ActionAsset,
+}
+impl ActionChange {
+  fn touch(&self, doc: &mut Document) {
+    doc.modified.insert(self.after.name.clone());
+    doc.touched = Some(self.after.name.clone());
+  }
+}
+impl Edit<Document> for ActionChange {
+  fn label(&self) -> String {
+    match self.before {
+      Some(_) => format!("edit {}", self.after.name),
+      None => format!("new {}", self.after.name),
+    }
+  }
+
+  fn apply(&mut self, doc: &mut Document) {
+    doc.library.insert(self.after.clone());
+    self.touch(doc);
+  }
+
+  fn revert(&mut self, doc: &mut Document) {
+    match &self.before {
+      Some(before) => {
+        doc.library.insert(before.clone());
+      }
+      None => {
+        doc.library.remove(&self.after.name);
+      }
+    }
+    self.touch(doc);
+  }
+
+  fn merge(&mut self, next: &mut dyn Any) -> bool {
+    match next.downcast_mut::<Self>() {
+      Some(next)
+        if self.before.is_some()
+          && next.before.is_some()
+          && next.after.name == self.after.name =>
+      {
+        std::mem::swap(&mut self.after, &mut next.after);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+pub struct ActionEditor {
+  doc: Document,
+  history: History<Document>,
+  current: Option<String>,
+  frame: usize,
+  selected: Option<(BoxKind, usize)>,
+  /// Kind of the box drawn on the empty area
+  tool: BoxKind,
+  drag: Option<Drag>,
+  onion: bool,
+  zoom: f32,
+  new_name: String,
+  status: Option<String>,
+  preview: Preview,
+  timeline: TimelineEditor,
+  /// Window is expanded (the preview is shown)
+  visible: bool,
+}
+impl ActionEditor {
+  /// Load the actions under `ACTION_ROOT`, and register the plain
+  /// texture of the preview.
+  pub fn new(textures: &mut TextureStorage) -> Self {
+    if textures.get_id(WHITE_TEXTURE).is_none() {
+      textures.register(
+        WHITE_TEXTURE,
+        image::RgbaImage::from_pixel(
+          1,
+          1,
+          image::Rgba([255; 4]),
+        ),
+      );
+    }
+    let mut editor = Self {
+      doc: Document::default(),
+      history: History::default(),
+      current: None,
+      frame: 0,
+      selected: None,
+      tool: BoxKind::Hit,
+      drag: None,
+      onion: true,
+      zoom: 2.,
+      new_name: String::new(),
+      status: None,
+      preview: Preview::default(),
+      timeline: TimelineEditor::new(),
+      visible: false,
+    };
+    editor.reload();
+    editor
+  }
+
+  pub fn library(&self) -> &ActionLibrary {
+    &self.doc.library
+  }
+
+  /// Reload every action from the disk. Unsaved changes are lost.
+  pub fn reload(&mut self) {
+    self.doc.library = ActionLibrary::new();
+    self.history.clear();
+    let errors = self.doc.library.load_dir(ACTION_ROOT);
+    for e in errors.iter() {
+      log::warn!("Action load error: {e}");
+    }
+    self.status = errors.into_iter().next();
+    self.doc.modified.clear();
+    self.timeline.clear_selection();
+    if self
+      .current
+      .as_ref()
+      .is_none_or(|c| self.doc.library.get(c).is_none())
+    {
+      self.current =
+        self.doc.library.names().next().map(str::to_string);
+    }
+    self.select_frame(0);
+  }
+
+  fn action(&self) -> Option<&ActionAsset> {
+    self.doc.library.get(self.current.as_deref()?)
+  }
+
+  /// Current action, marked as modified
+  fn action_mut(&mut self) -> Option<&mut ActionAsset> {
+    let name = self.current.clone()?;
+    self.doc.modified.insert(name.clone());
+    self.doc.library.get_mut(&name)
+  }
+
+  fn select_frame(&mut self, index: usize) {
+    let count = self.action().map_or(0, |a| a.frames.len());
+    self.frame = index.min(count.saturating_sub(1));
+    self.selected = None;
+    self.drag = None;
+    self.preview.tick = self
+      .action()
+      .map_or(0, |a| a.frame_start(self.frame));
+  }
+
+  fn save(&mut self) {
+    let Some(action) = self.action() else {
+      return;
+    };
+    let path = Path::new(ACTION_ROOT)
+      .join(&action.name)
+      .with_extension("toml");
+    let result = action
+      .validate()
+      .map_err(Into::into)
+      .and_then(|_| format::save(&path, action));
+    let name = action.name.clone();
+    match result {
+      Ok(_) => {
+        self.status =
+          Some(format!("saved {}", path.display()));
+        self.doc.modified.remove(&name);
+      }
+      Err(e) => self.status = Some(e.to_string()),
+    }
+  }
+
+  /// Advance the preview, and reflect it to the scene. Called once
+  /// per simulation step.
+  pub fn preview_step(
+    &mut self,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+  ) {
+    let Some(action) =
+      self.action().filter(|_| self.visible).cloned()
+    else {
+      for id in self.preview.sprites.drain(..) {
+        scene.remove_sprite(id);
+      }
+      return;
+    };
+    let total = action.total_ticks().max(1);
+    if self.preview.playing || 0 < self.preview.steps {
+      self.preview.steps =
+        self.preview.steps.saturating_sub(1);
+      self.preview.tick = (self.preview.tick + 1) % total;
+      self.fire_cues(&action);
+      if let Some((i, _)) =
+        action.frame_at(self.preview.tick)
+      {
+        if i != self.frame {
+          self.frame = i;
+          self.selected = None;
+        }
+      }
+    }
+    let Some((_, frame)) =
+      action.frame_at(self.preview.tick)
+    else {
+      return;
+    };
+    let origin = scene.active_camera().pos
+      + self.shake_offset()
+      + root_offset(&action, self.preview.tick);
+    let white = textures.get_id(WHITE_TEXTURE);
+    let sample =
+      action.timeline.sample(self.preview.tick as f32);
+    let scale = sample.scale.unwrap_or([1., 1.]);
+    let mut sprites = Vec::new();
+    if let Some(s) = &frame.sprite {
+      if let Some((texture, uv)) = s.resolve(textures) {
+        sprites.push(Sprite {
+          pos: origin
+            + Vector2::new(s.offset[0], s.offset[1]),
+          size: Vector2::new(
+            s.size[0] * scale[0],
+            s.size[1] * scale[1],
+          ),
+          filter: sample.color.unwrap_or([1.; 4]),
+          texture: Some(texture),
+          uv,
+          layer: PREVIEW_LAYER,
+          ..Default::default()
+        });
+      }
+    }
+    let boxes = frame
+      .hurtboxes
+      .iter()
+      .map(|b| (&b.rect, HURT_COLOR))
+      .chain(
+        frame.hitboxes.iter().map(|b| (&b.rect, HIT_COLOR)),
+      );
+    for (rect, color) in boxes {
+      let aabb = rect.aabb(origin, true);
+      let [r, g, b, _] = color.to_normalized_gamma_f32();
+      sprites.push(Sprite {
+        pos: aabb.center(),
+        size: aabb.half_size() * 2.,
+        filter: [r, g, b, 0.4],
+        texture: white,
+        layer: PREVIEW_LAYER + 1,
+        ..Default::default()
+      });
+    }
+    while sprites.len() < self.preview.sprites.len() {
+      if let Some(id) = self.preview.sprites.pop() {
+        scene.remove_sprite(id);
+      }
+    }
+    for (i, sprite) in sprites.into_iter().enumerate() {
+      match self
+        .preview
+        .sprites
+        .get(i)
+        .and_then(|id| scene.sprite_mut(*id))
+      {
+        Some(s) => *s = sprite,
+        None => {
+          let id = scene.spawn_sprite(sprite);
+          match self.preview.sprites.get_mut(i) {
+            Some(old) => *old = id,
+            None => self.preview.sprites.push(id),
+          }
+        }
+      }
+    }
+  }
+
+  /// Draw the editor window.
+  pub fn show(
+    &mut self,
+    ctx: &egui::Context,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    let shown = egui::Window::new("Action editor")
+      .default_open(false)
+      .resizable(true)
+      .show(ctx, |ui| self.ui(ui, textures, egui_textures))
+      .and_then(|r| r.inner)
+      .is_some();
+    self.visible = shown;
+  }
+
+  fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    if self.history.shortcuts(ui, &mut self.doc) {
+      self.follow_history();
+    }
+    let current = self.current.clone();
+    let before = self.action().cloned();
+    self.edit_ui(ui, textures, egui_textures);
+    // Every change of the action in the frame is an edit. The
+    // changes while dragging or typing are merged.
+    let after =
+      self.action().filter(|_| self.current == current);
+    if let Some((before, after)) = before.zip(after) {
+      if before != *after {
+        let after = after.clone();
+        self.history.record(ActionChange {
+          before: Some(before),
+          after,
+        });
+      }
+    }
+    let busy = ui.input(|i| i.pointer.any_down())
+      || ui.ctx().wants_keyboard_input();
+    if !busy {
+      self.history.seal();
+    }
+  }
+
+  /// Show the action changed by the undo or redo.
+  fn follow_history(&mut self) {
+    if let Some(name) = self.doc.touched.take() {
+      self.current = Some(name);
+    }
+    if self
+      .current
+      .as_ref()
+      .is_none_or(|c| self.doc.library.get(c).is_none())
+    {
+      self.current =
+        self.doc.library.names().next().map(str::to_string);
+    }
+    self.timeline.clear_selection();
+    self.select_frame(self.frame);
+  }
+
+  fn edit_ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    self.toolbar(ui);
+    if let Some(status) = &self.status {
+      ui.label(status);
+    }
+    if self.action().is_none() {
+      return;
+    }
+    ui.separator();
+    self.frame_strip(ui, textures, egui_textures);
+    ui.separator();
+    ui.horizontal_top(|ui| {
+      self.canvas(ui, textures, egui_textures);
+      ui.vertical(|ui| {
+        egui::ScrollArea::vertical()
+          .max_height(CANVAS_SIZE.y)
+          .show(ui, |ui| self.inspector(ui, textures));
+      });
+    });
+    egui::CollapsingHeader::new("Timeline")
+      .default_open(true)
+      .show(ui, |ui| self.timeline_ui(ui));
+  }
+
+  fn timeline_ui(&mut self, ui: &mut egui::Ui) {
+    let Some(action) = self.action() else {
+      return;
+    };
+    let mut timeline = action.timeline.clone();
+    let length = action.total_ticks();
+    let mut tick = self.preview.tick;
+    if self.timeline.ui(
+      ui,
+      &mut timeline,
+      &mut tick,
+      length,
+    ) {
+      if let Some(action) = self.action_mut() {
+        action.timeline = timeline;
+      }
+    }
+    if tick != self.preview.tick {
+      let frame = self
+        .action()
+        .and_then(|a| a.frame_at(tick))
+        .map(|(i, _)| i);
+      if let Some(i) = frame.filter(|i| *i != self.frame) {
+        self.frame = i;
+        self.selected = None;
+        self.drag = None;
+      }
+      self.preview.tick = tick;
+    }
+  }
+
+  fn toolbar(&mut self, ui: &mut egui::Ui) {
+    ui.horizontal(|ui| {
+      let label = |name: &str| {
+        if self.doc.modified.contains(name) {
+          format!("{name} *")
+        } else {
+          name.to_string()
+        }
+      };
+      let mut select = None;
+      egui::ComboBox::from_id_salt("action editor current")
+        .selected_text(
+          self
+            .current
+            .as_deref()
+            .map(label)
+            .unwrap_or_default(),
+        )
+        .show_ui(ui, |ui| {
+          for name in self.doc.library.names() {
+            let current =
+              self.current.as_deref() == Some(name);
+            if ui
+              .selectable_label(current, label(name))
+              .clicked()
+            {
+              select = Some(name.to_string());
+            }
+          }
+        });
+      if let Some(name) = select {
+        self.current = Some(name);
+        self.timeline.clear_selection();
+        self.select_frame(0);
+      }
+      ui.add(
+        egui::TextEdit::singleline(&mut self.new_name)
+          .desired_width(80.)
+          .hint_text("name"),
+      );
+      let name = self.new_name.trim().to_string();
+      if ui
+        .add_enabled(
+          !name.is_empty()
+            && self.doc.library.get(&name).is_none(),
+          egui::Button::new("New"),
+        )
+        .clicked()
+      {
+        self.history.apply(
+          &mut self.doc,
+          ActionChange {
+            before: None,
+            after: ActionAsset::new(&name),
+          },
+        );
+        self.current = Some(name);
+        self.timeline.clear_selection();
+        self.new_name.clear();
+        self.select_frame(0);
+      }
+      if ui.button("Save").clicked() {
+        self.save();
+      }
+      if self.history.ui(ui, &mut self.doc) {
+        self.follow_history();
+      }
+      if ui.button("Reload").clicked() {
+        self.reload();
+      }
+    });
+    ui.horizontal(|ui| {
+      ui.label("Draw:");
+      ui.radio_value(
+        &mut self.tool,
+        BoxKind::Hit,
+        "Hitbox",
+      );
+      ui.radio_value(
+        &mut self.tool,
+        BoxKind::Hurt,
+        "Hurtbox",
+      );
+      ui.checkbox(&mut self.onion, "Onion skin");
+      ui.add(
+        egui::Slider::new(&mut self.zoom, 0.5..=8.)
+          .text("zoom"),
+      );
+    });
+    ui.horizontal(|ui| {
+      let label = if self.preview.playing {
+        "Pause"
+      } else {
+        "Play"
+      };
+      if ui.button(label).clicked() {
+        self.preview.playing = !self.preview.playing;
+      }
+      if ui
+        .add_enabled(
+          !self.preview.playing,
+          egui::Button::new("Step"),
+        )
+        .clicked()
+      {
+        self.preview.steps += 1;
+      }
+      let total =
+        self.action().map_or(0, |a| a.total_ticks());
+      ui.label(format!(
+        "tick {} / {total}",
+        self.preview.tick
+      ));
+      if let Some(sound) = &self.preview.sound {
+        ui.label(format!("sound: {sound}"));
+      }
+    });
+  }
+
+  /// Fire the cues of the preview tick. The sounds are only shown,
+  /// as there is no audio output.
+  fn fire_cues(&mut self, action: &ActionAsset) {
+    let p = &mut self.preview;
+    p.shake = p
+      .shake
+      .map(|(s, age)| (s, age + 1))
+      .filter(|(s, age)| *age < s.duration);
+    for cue in action.timeline.cues(p.tick, p.tick + 1) {
+      match cue {
+        Cue::CameraShake(s) => p.shake = Some((s, 0)),
+        Cue::Sound(name) => {
+          log::info!("Sound cue \"{name}\"");
+          p.sound = Some(name);
+        }
+      }
+    }
+  }
+
+  /// Offset of the preview by the camera shake, which alternates
+  /// and fades out
+  fn shake_offset(&self) -> Vector2<f32> {
+    let Some((s, age)) = self.preview.shake else {
+      return Vector2::zeros();
+    };
+    let fade = 1. - age as f32 / s.duration.max(1) as f32;
+    let sign = if age % 2 == 0 { 1. } else { -1. };
+    Vector2::new(sign, -0.5 * sign) * s.amplitude * fade
+  }
+
+  fn frame_strip(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    let Some(action) = self.action() else {
+      return;
+    };
+    let mut select = None;
+    egui::ScrollArea::horizontal()
+      .id_salt("action editor frames")
+      .show(ui, |ui| {
+        ui.horizontal(|ui| {
+          for (i, frame) in action.frames.iter().enumerate()
+          {
+            ui.vertical(|ui| {
+              let thumbnail =
+                frame.sprite.as_ref().and_then(|s| {
+                  let (id, uv) = s.resolve(textures)?;
+                  Some((*egui_textures.get(&id)?, uv))
+                });
+              let selected = i == self.frame;
+              let clicked = match thumbnail {
+                Some((id, [o, e])) => {
+                  let image = egui::Image::new(
+                    egui::load::SizedTexture::new(
+                      id,
+                      Vec2::splat(THUMBNAIL),
+                    ),
+                  )
+                  .uv(
+                    Rect::from_min_size(
+                      Pos2::new(o[0], o[1]),
+                      Vec2::new(e[0], e[1]),
+                    ),
+                  );
+                  ui.add(
+                    egui::ImageButton::new(image)
+                      .selected(selected),
+                  )
+                  .clicked()
+                }
+                None => ui
+                  .add_sized(
+                    Vec2::splat(THUMBNAIL),
+                    egui::SelectableLabel::new(
+                      selected,
+                      format!("#{i}"),
+                    ),
+                  )
+                  .clicked(),
+              };
+              if clicked {
+                select = Some(i);
+              }
+              ui.label(format!("{}f", frame.duration));
+            });
+          }
+        });
+      });
+    if let Some(i) = select {
+      self.select_frame(i);
+    }
+    ui.horizontal(|ui| {
+      let i = self.frame;
+      let count =
+        self.action().map_or(0, |a| a.frames.len());
+      if ui.button("Add").clicked() {
+        if let Some(a) = self.action_mut() {
+          a.frames.insert(i + 1, Frame::new(1));
+        }
+        self.select_frame(i + 1);
+      }
+      if ui.button("Duplicate").clicked() {
+        if let Some(a) = self.action_mut() {
+          let frame = a.frames[i].clone();
+          a.frames.insert(i + 1, frame);
+        }
+        self.select_frame(i + 1);
+      }
+      if ui
+        .add_enabled(1 < count, egui::Button::new("Remove"))
+        .clicked()
+      {
+        if let Some(a) = self.action_mut() {
+          a.frames.remove(i);
+        }
+        self.select_frame(i.saturating_sub(1));
+      }
+      if ui
+        .add_enabled(0 < i, egui::Button::new("<"))
+        .clicked()
+      {
+        if let Some(a) = self.action_mut() {
+          a.frames.swap(i, i - 1);
+        }
+        self.select_frame(i - 1);
+      }
+      if ui
+        .add_enabled(i + 1 < count, egui::Button::new(">"))
+        .clicked()
+      {
+        if let Some(a) = self.action_mut() {
+          a.frames.swap(i, i + 1);
+        }
+        self.select_frame(i + 1);
+      }
+    });
+  }
+
+  fn canvas(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    let (response, painter) = ui.allocate_painter(
+      CANVAS_SIZE,
+      Sense::click_and_drag(),
+    );
+    let area = response.rect;
+    painter.rect_filled(area, 0., Color32::from_gray(32));
+    let view = View {
+      origin: Pos2::new(
+        area.center().x,
+        area.bottom() - 48.,
+      ),
+      zoom: self.zoom,
+    };
+    // Ground and the origin
+    let grid = Stroke::new(1., Color32::from_gray(80));
+    painter.hline(area.x_range(), view.origin.y, grid);
+    painter.vline(view.origin.x, area.y_range(), grid);
+
+    let Some(action) = self.action() else {
+      return;
+    };
+    let draw_sprite = |frame: &Frame, tint: Color32| {
+      let Some(s) = &frame.sprite else {
+        return;
+      };
+      let Some((id, [o, e])) = s.resolve(textures) else {
+        return;
+      };
+      let Some(egui_id) = egui_textures.get(&id) else {
+        return;
+      };
+      painter.image(
+        *egui_id,
+        view.rect(&BoxRect {
+          pos: s.offset,
+          size: s.size,
+        }),
+        Rect::from_min_size(
+          Pos2::new(o[0], o[1]),
+          Vec2::new(e[0], e[1]),
+        ),
+        tint,
+      );
+    };
+    if self.onion {
+      let onion = Color32::from_white_alpha(60);
+      for i in [self.frame.wrapping_sub(1), self.frame + 1]
+      {
+        if let Some(frame) = action.frames.get(i) {
+          draw_sprite(frame, onion);
+        }
+      }
+    }
+    let frame = &action.frames[self.frame];
+    draw_sprite(frame, Color32::WHITE);
+    let boxes = frame
+      .hurtboxes
+      .iter()
+      .enumerate()
+      .map(|(i, b)| (BoxKind::Hurt, i, b.rect, HURT_COLOR))
+      .chain(
+        frame.hitboxes.iter().enumerate().map(|(i, b)| {
+          (BoxKind::Hit, i, b.rect, HIT_COLOR)
+        }),
+      )
+      .collect::<Vec<_>>();
+    for (kind, i, rect, color) in boxes.iter() {
+      let r = view.rect(rect);
+      let selected = self.selected == Some((*kind, *i));
+      painter.rect(
+        r,
+        0.,
+        color.gamma_multiply(0.3),
+        Stroke::new(if selected { 2. } else { 1. }, *color),
+      );
+      if selected {
+        painter.rect_filled(
+          Rect::from_center_size(
+            r.right_bottom(),
+            Vec2::splat(HANDLE),
+          ),
+          0.,
+          Color32::WHITE,
+        );
+      }
+    }
+
+    let pointer = response.interact_pointer_pos();
+    if response.drag_started() {
+      if let Some(p) = pointer {
+        self.drag = self.start_drag(&view, &boxes, p);
+      }
+    } else if response.clicked() {
+      self.selected = pointer.and_then(|p| {
+        boxes
+          .iter()
+          .rev()
+          .find(|b| view.rect(&b.2).contains(p))
+          .map(|b| (b.0, b.1))
+      });
+    }
+    if let (Some(drag), Some(p)) = (self.drag, pointer) {
+      if response.dragged() {
+        self.apply_drag(drag, view.to_action(p));
+      }
+    }
+    if response.drag_stopped() {
+      self.drag = None;
+      // A click without the size does not make a box.
+      if let Some((kind, i)) = self.selected {
+        if self
+          .box_rect(kind, i)
+          .is_some_and(|r| r.size[0] < 1. || r.size[1] < 1.)
+        {
+          self.remove_box(kind, i);
+        }
+      }
+    }
+    if response.hovered()
+      && ui.input(|i| i.key_pressed(egui::Key::Delete))
+    {
+      if let Some((kind, i)) = self.selected {
+        self.remove_box(kind, i);
+      }
+    }
+  }
+
+  fn start_drag(
+    &mut self,
+    view: &View,
+    boxes: &[(BoxKind, usize, BoxRect, Color32)],
+    p: Pos2,
+  ) -> Option<Drag> {
+    let at = view.to_action(p);
+    // Handle of the selected box
+    if let Some((kind, index)) = self.selected {
+      if let Some(r) = self.box_rect(kind, index) {
+        let screen = view.rect(&r);
+        if Rect::from_center_size(
+          screen.right_bottom(),
+          Vec2::splat(HANDLE * 2.),
+        )
+        .contains(p)
+        {
+          return Some(Drag::Resize {
+            kind,
+            index,
+            anchor: view.to_action(screen.left_top()),
+          });
+        }
+      }
+    }
+    // The box on the top (hitboxes are drawn over hurtboxes)
+    if let Some((kind, index, rect, _)) = boxes
+      .iter()
+      .rev()
+      .find(|b| view.rect(&b.2).contains(p))
+    {
+      self.selected = Some((*kind, *index));
+      return Some(Drag::Move {
+        kind: *kind,
+        index: *index,
+        from: rect.pos,
+        grab: at,
+      });
+    }
+    // New box
+    let rect = BoxRect {
+      pos: at,
+      size: [0.; 2],
+    };
+    let kind = self.tool;
+    let frame = self.frame;
+    let a = self.action_mut()?;
+    let frame = &mut a.frames[frame];
+    let index = match kind {
+      BoxKind::Hit => {
+        frame.hitboxes.push(Hitbox {
+          rect,
+          damage: 10,
+          knockback: [60., 0.],
+          hitstun: 10,
+          hitstop: 4,
+          group: 0,
+        });
+        frame.hitboxes.len() - 1
+      }
+      BoxKind::Hurt => {
+        frame.hurtboxes.push(Hurtbox {
+          rect,
+          invincible: false,
+        });
+        frame.hurtboxes.len() - 1
+      }
+    };
+    self.selected = Some((kind, index));
+    Some(Drag::Resize {
+      kind,
+      index,
+      anchor: at,
+    })
+  }
+
+  fn apply_drag(&mut self, drag: Drag, at: [f32; 2]) {
+    let (kind, index, rect) = match drag {
+      Drag::Move {
+        kind,
+        index,
+        from,
+        grab,
+      } => {
+        let Some(mut rect) = self.box_rect(kind, index)
+        else {
+          return;
+        };
+        rect.pos = [
+          from[0] + at[0] - grab[0],
+          from[1] + at[1] - grab[1],
+        ];
+        (kind, index, rect)
+      }
+      Drag::Resize {
+        kind,
+        index,
+        anchor,
+      } => (kind, index, rect_between(anchor, at)),
+    };
+    if let Some(r) = self.box_rect_mut(kind, index) {
+      *r = rect;
+    }
+  }
+
+  fn box_rect(
+    &self,
+    kind: BoxKind,
+    i: usize,
+  ) -> Option<BoxRect> {
+    let frame = self.action()?.frames.get(self.frame)?;
+    match kind {
+      BoxKind::Hit => frame.hitboxes.get(i).map(|b| b.rect),
+      BoxKind::Hurt => {
+        frame.hurtboxes.get(i).map(|b| b.rect)
+      }
+    }
+  }
+
+  fn box_rect_mut(
+    &mut self,
+    kind: BoxKind,
+    i: usize,
+  ) -> Option<&mut BoxRect> {
+    let frame = self.frame;
+    let frame = self.action_mut()?.frames.get_mut(frame)?;
+    match kind {
+      BoxKind::Hit => {
+        frame.hitboxes.get_mut(i).map(|b| &mut b.rect)
+      }
+      BoxKind::Hurt => {
+        frame.hurtboxes.get_mut(i).map(|b| &mut b.rect)
+      }
+    }
+  }
+
+  fn remove_box(&mut self, kind: BoxKind, i: usize) {
+    let frame = self.frame;
+    if let Some(frame) = self
+      .action_mut()
+      .and_then(|a| a.frames.get_mut(frame))
+    {
+      match kind {
+        BoxKind::Hit if i < frame.hitboxes.len() => {
+          frame.hitboxes.remove(i);
+        }
+        BoxKind::Hurt if i < frame.hurtboxes.len() => {
+          frame.hurtboxes.remove(i);
+        }
+        _ => {}
+      }
+    }
+    self.selected = None;
+  }
+
+  fn inspector(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+  ) {
+    let Some(mut action) = self.action().cloned() else {
+      return;
+    };
+    let frame_index = self.frame;
+    let selected = self.selected;
+    let mut texture_names =
+      textures.iter().map(|(n, _)| n).collect::<Vec<_>>();
+    texture_names.sort();
+
+    ui.strong(format!("Frame #{frame_index}"));
+    let frame = &mut action.frames[frame_index];
+    egui::Grid::new("action editor frame").show(ui, |ui| {
+      ui.label("duration");
+      ui.add(
+        egui::DragValue::new(&mut frame.duration)
+          .range(1..=600),
+      );
+      ui.end_row();
+      ui.label("root motion");
+      vec2_ui(ui, &mut frame.root_motion);
+      ui.end_row();
+      let mut has_sprite = frame.sprite.is_some();
+      ui.label("sprite");
+      if ui.checkbox(&mut has_sprite, "").changed() {
+        frame.sprite = has_sprite.then(|| SpriteRef {
+          texture: texture_names
+            .first()
+            .map(|n| n.to_string())
+            .unwrap_or_default(),
+          section: None,
+          offset: [0., 16.],
+          size: [32., 32.],
+        });
+      }
+      ui.end_row();
+      if let Some(s) = &mut frame.sprite {
+        ui.label("texture");
+        egui::ComboBox::from_id_salt(
+          "action editor texture",
+        )
+        .selected_text(s.texture.as_str())
+        .show_ui(ui, |ui| {
+          for name in texture_names.iter() {
+            ui.selectable_value(
+              &mut s.texture,
+              name.to_string(),
+              *name,
+            );
+          }
+        });
+        ui.end_row();
+        ui.label("section");
+        let mut section =
+          s.section.clone().unwrap_or_default();
+        if ui
+          .add(
+            egui::TextEdit::singleline(&mut section)
+              .desired_width(100.)
+              .hint_text("whole"),
+          )
+          .changed()
+        {
+          s.section =
+            (!section.is_empty()).then_some(section);
+        }
+        ui.end_row();
+        ui.label("offset");
+        vec2_ui(ui, &mut s.offset);
+        ui.end_row();
+        ui.label("size");
+        vec2_ui(ui, &mut s.size);
+        ui.end_row();
+      }
+    });
+
+    match selected {
+      Some((BoxKind::Hit, i))
+        if i < frame.hitboxes.len() =>
+      {
+        ui.separator();
+        ui.strong(format!("Hitbox #{i}"));
+        let b = &mut frame.hitboxes[i];
+        egui::Grid::new("action editor box").show(
+          ui,
+          |ui| {
+            rect_ui(ui, &mut b.rect);
+            ui.label("damage");
+            ui.add(egui::DragValue::new(&mut b.damage));
+            ui.end_row();
+            ui.label("knockback");
+            vec2_ui(ui, &mut b.knockback);
+            ui.end_row();
+            ui.label("hitstun");
+            ui.add(egui::DragValue::new(&mut b.hitstun));
+            ui.end_row();
+            ui.label("hitstop");
+            ui.add(egui::DragValue::new(&mut b.hitstop));
+            ui.end_row();
+            ui.label("group");
+            ui.add(egui::DragValue::new(&mut b.group));
+            ui.end_row();
+          },
+        );
+      }
+      Some((BoxKind::Hurt, i))
+        if i < frame.hurtboxes.len() =>
+      {
+        ui.separator();
+        ui.strong(format!("Hurtbox #{i}"));
+        let b = &mut frame.hurtboxes[i];
+        egui::Grid::new("action editor box").show(
+          ui,
+          |ui| {
+            rect_ui(ui, &mut b.rect);
+            ui.label("invincible");
+            ui.checkbox(&mut b.invincible, "");
+            ui.end_row();
+          },
+        );
+      }
+      _ => {}
+    }
+
+    ui.separator();
+    ui.strong("Action");
+    ui.checkbox(&mut action.looping, "looping");
+    ui.horizontal(|ui| {
+      ui.label("hit rule");
+      ui.radio_value(
+        &mut action.hit_rule,
+        HitRule::Once,
+        "once a group",
+      );
+      ui.radio_value(
+        &mut action.hit_rule,
+        HitRule::Single,
+        "single",
+      );
+      let mut interval = match action.hit_rule {
+        HitRule::Interval(t) => Some(t),
+        _ => None,
+      };
+      if ui.radio(interval.is_some(), "interval").clicked()
+        && interval.is_none()
+      {
+        interval = Some(8);
+      }
+      if let Some(t) = &mut interval {
+        ui.add(
+          egui::DragValue::new(t)
+            .range(1..=u32::MAX)
+            .suffix(" ticks"),
+        );
+        action.hit_rule = HitRule::Interval(*t);
+      }
+    });
+    ui.label("Cancel windows");
+    let mut remove = None;
+    egui::Grid::new("action editor cancels").show(
+      ui,
+      |ui| {
+        for (i, c) in action.cancels.iter_mut().enumerate()
+        {
+          ui.add(
+            egui::DragValue::new(&mut c.start)
+              .prefix("from "),
+          );
+          ui.add(
+            egui::DragValue::new(&mut c.end).prefix("to "),
+          );
+          let mut into = c.into.join(", ");
+          if ui
+            .add(
+              egui::TextEdit::singleline(&mut into)
+                .desired_width(80.)
+                .hint_text("any"),
+            )
+            .changed()
+          {
+            c.into = into
+              .split(',')
+              .map(|s| s.trim().to_string())
+              .filter(|s| !s.is_empty())
+              .collect();
+          }
+          ui.checkbox(&mut c.on_hit, "on hit");
+          if ui.small_button("x").clicked() {
+            remove = Some(i);
+          }
+          ui.end_row();
+        }
+      },
+    );
+    if let Some(i) = remove {
+      action.cancels.remove(i);
+    }
+    if ui.button("Add cancel window").clicked() {
+      let total = action.total_ticks();
+      action.cancels.push(CancelWindow {
+        start: 0,
+        end: total,
+        into: Vec::new(),
+        on_hit: false,
+      });
+    }
+
+    if self.action() != Some(&action) {
+      if let Some(a) = self.action_mut() {
+        *a = action;
+      }
+    }
+  }
+}
+
+fn vec2_ui(ui: &mut egui::Ui, v: &mut [f32; 2]) {
+  ui.horizontal(|ui| {
+    ui.add(egui::DragValue::new(&mut v[0]).prefix("x "));
+    ui.add(egui::DragValue::new(&mut v[1]).prefix("y "));
+  });
+}
+
+fn rect_ui(ui: &mut egui::Ui, r: &mut BoxRect) {
+  ui.label("pos");
+  vec2_ui(ui, &mut r.pos);
+  ui.end_row();
+  ui.label("size");
+  vec2_ui(ui, &mut r.size);
+  ui.end_row();
+}
+
+/// Movement by the root motion of the ticks before the tick, as the
+/// combat step moves a fighter
+fn root_offset(
+  action: &ActionAsset,
+  tick: u32,
+) -> Vector2<f32> {
+  (0..tick)
+    .map(|t| action.root_motion_at(t))
+    .fold(Vector2::zeros(), |sum, [x, y]| {
+      sum + Vector2::new(x, y)
+    })
+}
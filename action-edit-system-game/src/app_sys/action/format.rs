@@ -0,0 +1,157 @@
+//! File formats of the action assets
+//!
+//! The format is chosen by the extension:
+//! - `.toml`, `.json`: for editing
+//! - `.msgpack`: for shipping (compact, fast to read)
+//!
+//! The state machines (`machine`) use the same formats.
+
+use super::ActionAsset;
+use crate::StdError;
+use serde::{de::DeserializeOwned, Serialize};
+use std::{collections::BTreeMap, path::Path};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ActionFormat {
+  Toml,
+  Json,
+  MessagePack,
+}
+impl ActionFormat {
+  pub fn from_path(path: &Path) -> Option<Self> {
+    match path.extension()?.to_str()? {
+      "toml" => Some(Self::Toml),
+      "json" => Some(Self::Json),
+      "msgpack" => Some(Self::MessagePack),
+      _ => None,
+    }
+  }
+
+  pub fn encode<T: Serialize>(
+    self,
+    asset: &T,
+  ) -> Result<Vec<u8>, StdError> {
+    Ok(match self {
+      Self::Toml => {
+        toml::to_string_pretty(asset)?.into_bytes()
+      }
+      Self::Json => serde_json::to_vec_pretty(asset)?,
+      Self::MessagePack => rmp_serde::to_vec_named(asset)?,
+    })
+  }
+
+  pub fn decode<T: DeserializeOwned>(
+    self,
+    bytes: &[u8],
+  ) -> Result<T, StdError> {
+    Ok(match self {
+      Self::Toml => {
+        toml::from_str(std::str::from_utf8(bytes)?)?
+      }
+      Self::Json => serde_json::from_slice(bytes)?,
+      Self::MessagePack => rmp_serde::from_slice(bytes)?,
+    })
+  }
+}
+
+pub(crate) fn format_of(
+  path: &Path,
+) -> Result<ActionFormat, StdError> {
+  ActionFormat::from_path(path).ok_or_else(|| {
+    format!("unknown action format: {}", path.display())
+      .into()
+  })
+}
+
+/// Load and validate the action.
+pub fn load(
+  path: impl AsRef<Path>,
+) -> Result<ActionAsset, StdError> {
+  let path = path.as_ref();
+  let action: ActionAsset =
+    format_of(path)?.decode(&std::fs::read(path)?)?;
+  action.validate()?;
+  Ok(action)
+}
+
+pub fn save(
+  path: impl AsRef<Path>,
+  action: &ActionAsset,
+) -> Result<(), StdError> {
+  let path = path.as_ref();
+  std::fs::write(path, format_of(path)?.encode(action)?)?;
+  Ok(())
+}
+
+/// Actions by the name
+#[derive(Debug, Clone, Default)]
+pub struct ActionLibrary {
+  actions: BTreeMap<String, ActionAsset>,
+}
+impl ActionLibrary {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Load the actions in the directory. Returns the errors of the
+  /// files that are not loaded.
+  pub fn load_dir(
+    &mut self,
+    dir: impl AsRef<Path>,
+  ) -> Vec<String> {
+    let dir = dir.as_ref();
+    let entries = match std::fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(e) => {
+        return vec![format!("{}: {e}", dir.display())]
+      }
+    };
+    let mut paths = entries
+      .filter_map(|e| e.ok().map(|e| e.path()))
+      .filter(|p| ActionFormat::from_path(p).is_some())
+      .collect::<Vec<_>>();
+    paths.sort();
+    let mut errors = Vec::new();
+    for path in paths {
+      match load(&path) {
+        Ok(action) => {
+          self.insert(action);
+        }
+        Err(e) => {
+          errors.push(format!("{}: {e}", path.display()))
+        }
+      }
+    }
+    errors
+  }
+
+  /// Add the action. Returns the one of the same name.
+  pub fn insert(
+    &mut self,
+    action: ActionAsset,
+  ) -> Option<ActionAsset> {
+    self.actions.insert(action.name.clone(), action)
+  }
+
+  pub fn remove(
+    &mut self,
+    name: &str,
+  ) -> Option<ActionAsset> {
+    self.actions.remove(name)
+  }
+
+  pub fn get(&self, name: &str) -> Option<&ActionAsset> {
+    self.actions.get(name)
+  }
+
+  pub fn get_mut(
+    &mut self,
+    name: &str,
+  ) -> Option<&mut ActionAsset> {
+    self.actions.get_mut(name)
+  }
+
+  pub fn names(&self) -> impl Iterator<Item = &str> {
+    self.actions.keys().map(String::as_str)
+  }
+}
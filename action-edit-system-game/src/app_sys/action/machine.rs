@@ -0,0 +1,769 @@
+//! Action state machines
+//! アクションを選ぶステートマシン
+//!
+//! A state plays an action. Every tick, the transitions of the
+//! current state, then the `any` transitions of the machine, are
+//! checked in order at the tick played last, and the first one
+//! whose conditions all hold fires. The new state plays the tick 0
+//! of its action in the same step, so a `finished` transition shows
+//! no gap.
+//!
+//! The conditions read the input buffer, the ground state, the ticks
+//! in the state and the cancel windows of the action:
+//!
+//! ```toml
+//! name = "player"
+//! initial = "idle"
+//!
+//! [[states]]
+//! name = "jab"
+//! action = "jab"
+//! transitions = [
+//!   { to = "jab", when = ["cancel", { pressed = "attack" }] },
+//!   { to = "idle", when = ["finished"] },
+//! ]
+//! ```
+
+use super::{
+  format::{format_of, ActionLibrary},
+  ActionAsset,
+};
+use crate::{
+  app_sys::{
+    combat::Fighter,
+    ecs::World,
+    input::buffer::{InputBuffer, PlayerInputs},
+    physics::body::Body,
+  },
+  StdError,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::{BTreeMap, VecDeque},
+  fmt,
+  path::Path,
+};
+
+/// Directory of the state machine assets
+pub const MACHINE_ROOT: &str = "./machines";
+/// Fired transitions kept for the debugger
+const HISTORY: usize = 32;
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Condition {
+  /// The input action is buffered. The press is consumed when the
+  /// transition fires.
+  Pressed(String),
+  /// The input action is held
+  Held(String),
+  /// The command of the input buffer is entered. Its action press
+  /// is consumed when the transition fires.
+  Command(String),
+  /// The direction is one of the numpad directions
+  Direction(Vec<u8>),
+  Grounded,
+  Airborne,
+  /// The action is over (never for a looping action)
+  Finished,
+  /// The ticks in the state are at least the value
+  After(u32),
+  /// The ticks in the state are less than the value
+  Before(u32),
+  /// A cancel window of the action allows the action of the target
+  /// state
+  Cancel,
+  /// The action hit something in the state
+  Hit,
+  Not(Box<Condition>),
+}
+impl fmt::Display for Condition {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Self::Pressed(a) => write!(f, "pressed {a}"),
+      Self::Held(a) => write!(f, "held {a}"),
+      Self::Command(c) => write!(f, "command {c}"),
+      Self::Direction(d) => write!(f, "direction {d:?}"),
+      Self::Grounded => write!(f, "grounded"),
+      Self::Airborne => write!(f, "airborne"),
+      Self::Finished => write!(f, "finished"),
+      Self::After(t) => write!(f, "after {t} ticks"),
+      Self::Before(t) => write!(f, "before {t} ticks"),
+      Self::Cancel => write!(f, "cancel window"),
+      Self::Hit => write!(f, "hit"),
+      Self::Not(c) => write!(f, "not {c}"),
+    }
+  }
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Transition {
+  pub to: String,
+  /// All of them hold (always if empty)
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub when: Vec<Condition>,
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct State {
+  pub name: String,
+  /// Name of the action asset
+  pub action: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub transitions: Vec<Transition>,
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct StateMachineAsset {
+  pub name: String,
+  pub initial: String,
+  pub states: Vec<State>,
+  /// Transitions from every state, checked after the ones of the
+  /// state. The ones into the current state are skipped.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub any: Vec<Transition>,
+}
+impl StateMachineAsset {
+  pub fn state_index(&self, name: &str) -> Option<usize> {
+    self.states.iter().position(|s| s.name == name)
+  }
+
+  pub fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty() {
+      return Err(
+        "the state machine has no name".to_string(),
+      );
+    }
+    for (i, s) in self.states.iter().enumerate() {
+      if self.states[..i].iter().any(|o| o.name == s.name) {
+        return Err(format!(
+          "{}: duplicate state \"{}\"",
+          self.name, s.name
+        ));
+      }
+    }
+    if self.state_index(&self.initial).is_none() {
+      return Err(format!(
+        "{}: no initial state \"{}\"",
+        self.name, self.initial
+      ));
+    }
+    let transitions = self
+      .states
+      .iter()
+      .flat_map(|s| s.transitions.iter())
+      .chain(self.any.iter());
+    for t in transitions {
+      if self.state_index(&t.to).is_none() {
+        return Err(format!(
+          "{}: transition into unknown state \"{}\"",
+          self.name, t.to
+        ));
+      }
+    }
+    Ok(())
+  }
+
+  /// Actions of the states missing in the library
+  pub fn missing_actions(
+    &self,
+    actions: &ActionLibrary,
+  ) -> Vec<String> {
+    let mut missing = self
+      .states
+      .iter()
+      .filter(|s| actions.get(&s.action).is_none())
+      .map(|s| s.action.clone())
+      .collect::<Vec<_>>();
+    missing.sort();
+    missing.dedup();
+    missing
+  }
+}
+
+/// Load and validate the state machine.
+pub fn load(
+  path: impl AsRef<Path>,
+) -> Result<StateMachineAsset, StdError> {
+  let path = path.as_ref();
+  let machine: StateMachineAsset =
+    format_of(path)?.decode(&std::fs::read(path)?)?;
+  machine.validate()?;
+  Ok(machine)
+}
+
+/// Load the state machines in the directory by the name. Returns
+/// the errors of the files that are not loaded.
+pub fn load_dir(
+  dir: impl AsRef<Path>,
+) -> (BTreeMap<String, StateMachineAsset>, Vec<String>) {
+  let dir = dir.as_ref();
+  let mut machines = BTreeMap::new();
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) => {
+      return (
+        machines,
+        vec![format!("{}: {e}", dir.display())],
+      )
+    }
+  };
+  let mut paths = entries
+    .filter_map(|e| e.ok().map(|e| e.path()))
+    .filter(|p| format_of(p).is_ok())
+    .collect::<Vec<_>>();
+  paths.sort();
+  let mut errors = Vec::new();
+  for path in paths {
+    match load(&path) {
+      Ok(machine) => {
+        machines.insert(machine.name.clone(), machine);
+      }
+      Err(e) => {
+        errors.push(format!("{}: {e}", path.display()))
+      }
+    }
+  }
+  (machines, errors)
+}
+
+/// What the conditions read
+#[derive(Clone, Copy)]
+pub struct MachineContext<'a> {
+  pub buffer: &'a InputBuffer,
+  pub actions: &'a ActionLibrary,
+  pub grounded: bool,
+}
+
+/// Fired transition, for the debugger
+#[derive(Debug, Clone)]
+pub struct FiredTransition {
+  /// Step of the machine
+  pub step: u64,
+  pub from: String,
+  pub to: String,
+  /// Ticks in the `from` state
+  pub tick: u32,
+  /// The conditions that held
+  pub reasons: Vec<String>,
+  /// From the `any` transitions
+  pub any: bool,
+}
+
+/// State machine of an entity, which chooses the action of its
+/// `Fighter`
+///
+/// The machine asset is referred by the name, as the actions of the
+/// fighters. The machine starts in the initial state at the first
+/// step.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateMachine {
+  /// Name of the machine asset
+  pub machine: String,
+  /// Player whose input buffer drives the machine
+  #[serde(default)]
+  pub player: usize,
+  /// Current state (empty before the first step)
+  #[serde(
+    default,
+    skip_serializing_if = "String::is_empty"
+  )]
+  pub state: String,
+  /// Ticks in the state (the tick of the action)
+  #[serde(default)]
+  pub tick: u32,
+  /// The action hit something in the state
+  #[serde(default)]
+  pub hit: bool,
+  #[serde(skip)]
+  step: u64,
+  /// The latest at the front
+  #[serde(skip)]
+  history: VecDeque<FiredTransition>,
+}
+impl StateMachine {
+  /// Current state. None for an unknown state.
+  pub fn state<'a>(
+    &self,
+    asset: &'a StateMachineAsset,
+  ) -> Option<&'a State> {
+    let name = if self.state.is_empty() {
+      &asset.initial
+    } else {
+      &self.state
+    };
+    asset.state_index(name).map(|i| &asset.states[i])
+  }
+
+  /// Action of the current state
+  pub fn action<'a>(
+    &self,
+    asset: &StateMachineAsset,
+    actions: &'a ActionLibrary,
+  ) -> Option<&'a ActionAsset> {
+    actions.get(&self.state(asset)?.action)
+  }
+
+  /// Tell that the action hit something, for the `hit` and the
+  /// on-hit cancels.
+  pub fn notify_hit(&mut self) {
+    self.hit = true;
+  }
+
+  /// Fired transitions, the latest first
+  pub fn history(
+    &self,
+  ) -> impl Iterator<Item = &FiredTransition> {
+    self.history.iter()
+  }
+
+  fn enter(&mut self, state: &str) {
+    self.state = state.to_string();
+    self.tick = 0;
+    self.hit = false;
+  }
+
+  /// Transitions checked in the current state, in the order. True
+  /// for the `any` ones.
+  pub fn candidates<'a>(
+    &self,
+    asset: &'a StateMachineAsset,
+  ) -> impl Iterator<Item = (&'a Transition, bool)> {
+    let state = self.state(asset);
+    let current = state.map(|s| s.name.clone());
+    state
+      .into_iter()
+      .flat_map(|s| s.transitions.iter())
+      .map(|t| (t, false))
+      .chain(
+        asset
+          .any
+          .iter()
+          .filter(move |t| current.as_ref() != Some(&t.to))
+          .map(|t| (t, true)),
+      )
+  }
+
+  /// The condition holds now, for the transition into the state.
+  pub fn check(
+    &self,
+    asset: &StateMachineAsset,
+    condition: &Condition,
+    to: &str,
+    ctx: &MachineContext,
+  ) -> bool {
+    let action = self.action(asset, ctx.actions);
+    match condition {
+      Condition::Pressed(a) => ctx.buffer.buffered(a, None),
+      Condition::Held(a) => ctx
+        .buffer
+        .history()
+        .next()
+        .is_some_and(|f| f.held.iter().any(|h| h == a)),
+      Condition::Command(c) => ctx.buffer.command(c),
+      Condition::Direction(d) => {
+        d.contains(&ctx.buffer.direction().numpad())
+      }
+      Condition::Grounded => ctx.grounded,
+      Condition::Airborne => !ctx.grounded,
+      // The tick played is the last one. A state without the
+      // action ends at once.
+      Condition::Finished => {
+        action.is_none_or(|a| a.is_finished(self.tick + 1))
+      }
+      Condition::After(t) => *t <= self.tick,
+      Condition::Before(t) => self.tick < *t,
+      Condition::Cancel => {
+        let into = asset
+          .state_index(to)
+          .map(|i| &asset.states[i].action);
+        action.zip(into).is_some_and(|(a, into)| {
+          a.can_cancel(self.tick, into, self.hit)
+        })
+      }
+      Condition::Hit => self.hit,
+      Condition::Not(c) => !self.check(asset, c, to, ctx),
+    }
+  }
+
+  /// Check the transitions at the tick played last, and play the
+  /// next tick, or the tick 0 of the new state. Returns the
+  /// transition if fired.
+  pub fn step(
+    &mut self,
+    asset: &StateMachineAsset,
+    buffer: &mut InputBuffer,
+    actions: &ActionLibrary,
+    grounded: bool,
+  ) -> Option<&FiredTransition> {
+    self.step += 1;
+    if self.state.is_empty() {
+      self.enter(&asset.initial);
+      return None;
+    }
+    let ctx = MachineContext {
+      buffer,
+      actions,
+      grounded,
+    };
+    let fired = self
+      .candidates(asset)
+      .find(|(t, _)| {
+        t.when
+          .iter()
+          .all(|c| self.check(asset, c, &t.to, &ctx))
+      })
+      .map(|(t, any)| (t.clone(), any));
+    let Some((transition, any)) = fired else {
+      self.tick = self.tick.saturating_add(1);
+      return None;
+    };
+    // Use the presses, so that a buffered input fires once.
+    for c in transition.when.iter() {
+      match c {
+        Condition::Pressed(a) => {
+          buffer.consume(a);
+        }
+        Condition::Command(name) => {
+          let action = buffer
+            .commands()
+            .iter()
+            .find(|c| &c.name == name)
+            .and_then(|c| c.action.clone());
+          if let Some(a) = action {
+            buffer.consume(&a);
+          }
+        }
+        _ => {}
+      }
+    }
+    let reasons = if transition.when.is_empty() {
+      vec!["always".to_string()]
+    } else {
+      transition
+        .when
+        .iter()
+        .map(|c| c.to_string())
+        .collect()
+    };
+    self.fire(&transition.to, reasons, any)
+  }
+
+  /// Step in the hitstun: go back to the initial state without
+  /// reading the input.
+  pub fn stun(
+    &mut self,
+    asset: &StateMachineAsset,
+  ) -> Option<&FiredTransition> {
+    self.step += 1;
+    if self.state.is_empty() {
+      self.enter(&asset.initial);
+      return None;
+    }
+    if self.state == asset.initial {
+      self.tick = self.tick.saturating_add(1);
+      return None;
+    }
+    let reasons = vec!["hitstun".to_string()];
+    self.fire(&asset.initial, reasons, true)
+  }
+
+  fn fire(
+    &mut self,
+    to: &str,
+    reasons: Vec<String>,
+    any: bool,
+  ) -> Option<&FiredTransition> {
+    let record = FiredTransition {
+      step: self.step,
+      from: self.state.clone(),
+      to: to.to_string(),
+      tick: self.tick,
+      reasons,
+      any,
+    };
+    self.enter(to);
+    self.history.push_front(record);
+    self.history.truncate(HISTORY);
+    self.history.front()
+  }
+}
+
+/// Step the machines of the fighters, and play the actions of their
+/// states. Called once per simulation step, before `Combat::step`.
+///
+/// A frozen fighter (hitstop) does not step. A fighter in the
+/// hitstun goes back to the initial state. The ground state is the
+/// one of the `Body`, and a fighter without a body stands.
+pub fn step_fighters(
+  world: &World,
+  machines: &BTreeMap<String, StateMachineAsset>,
+  actions: &ActionLibrary,
+  inputs: &mut PlayerInputs,
+) {
+  world.query::<(&mut StateMachine, &mut Fighter)>(
+    |e, (m, f)| {
+      let Some(asset) = machines.get(&m.machine) else {
+        return;
+      };
+      if f.is_frozen() {
+        return;
+      }
+      if 0 < f.hitstun {
+        m.stun(asset);
+      } else if let Some(buffer) =
+        inputs.player_mut(m.player)
+      {
+        let grounded = world
+          .get::<Body>(e)
+          .is_none_or(|b| b.is_grounded());
+        buffer.set_facing(f.facing_right);
+        m.step(asset, buffer, actions, grounded);
+      }
+      if let Some(state) = m.state(asset) {
+        f.action.clone_from(&state.action);
+      }
+      f.tick = m.tick;
+    },
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_sys::input::buffer::{
+    BufferConfig, Direction, InputFrame,
+  };
+
+  const ACTIONS: [&str; 3] = [
+    r#"
+name = "idle"
+looping = true
+frames = [{ duration = 4 }]
+"#,
+    r#"
+name = "jab"
+frames = [{ duration = 3 }, { duration = 2 }, { duration = 6 }]
+cancels = [{ start = 3, end = 8, into = ["jab"], on_hit = true }]
+"#,
+    r#"
+name = "kick"
+frames = [{ duration = 10 }]
+"#,
+  ];
+
+  const MACHINE: &str = r#"
+name = "test"
+initial = "idle"
+any = [
+  { to = "kick", when = ["airborne", { pressed = "attack" }] },
+]
+
+[[states]]
+name = "idle"
+action = "idle"
+transitions = [
+  { to = "jab", when = ["grounded", { pressed = "attack" }] },
+  { to = "kick", when = ["grounded", { pressed = "attack" }] },
+]
+
+[[states]]
+name = "jab"
+action = "jab"
+transitions = [
+  { to = "jab", when = ["cancel", { pressed = "attack" }] },
+  { to = "idle", when = ["finished"] },
+]
+
+[[states]]
+name = "kick"
+action = "kick"
+transitions = [{ to = "idle", when = ["finished"] }]
+"#;
+
+  fn setup(
+  ) -> (StateMachineAsset, ActionLibrary, InputBuffer) {
+    let mut actions = ActionLibrary::new();
+    for a in ACTIONS {
+      actions.insert(toml::from_str(a).unwrap());
+    }
+    let asset: StateMachineAsset =
+      toml::from_str(MACHINE).unwrap();
+    asset.validate().unwrap();
+    let buffer = InputBuffer::new(BufferConfig::default());
+    (asset, actions, buffer)
+  }
+
+  fn machine() -> StateMachine {
+    toml::from_str(r#"machine = "test""#).unwrap()
+  }
+
+  fn push(buffer: &mut InputBuffer, pressed: &[&str]) {
+    buffer.push(InputFrame {
+      dir: Direction::NEUTRAL,
+      pressed: pressed
+        .iter()
+        .map(|a| a.to_string())
+        .collect(),
+      held: Vec::new(),
+    });
+  }
+
+  /// Step with the input, and return the state entered
+  fn step(
+    m: &mut StateMachine,
+    (asset, actions, buffer): &mut (
+      StateMachineAsset,
+      ActionLibrary,
+      InputBuffer,
+    ),
+    pressed: &[&str],
+    grounded: bool,
+  ) -> Option<String> {
+    push(buffer, pressed);
+    m.step(asset, buffer, actions, grounded)
+      .map(|f| f.to.clone())
+  }
+
+  #[test]
+  fn first_check_sees_the_tick_0_of_the_initial_state() {
+    let mut s = setup();
+    let mut m = machine();
+    assert_eq!(step(&mut m, &mut s, &[], true), None);
+    assert_eq!((m.state.as_str(), m.tick), ("idle", 0));
+    assert_eq!(step(&mut m, &mut s, &[], true), None);
+    assert_eq!(m.tick, 1);
+  }
+
+  #[test]
+  fn state_transitions_come_first_in_the_order() {
+    let mut s = setup();
+    let mut m = machine();
+    step(&mut m, &mut s, &[], true);
+    let to = step(&mut m, &mut s, &["attack"], true);
+    assert_eq!(to.as_deref(), Some("jab"));
+    assert_eq!(m.tick, 0);
+    let fired = m.history().next().unwrap();
+    assert_eq!(
+      fired.reasons,
+      ["grounded", "pressed attack"]
+    );
+    assert!(!fired.any);
+    // The press is consumed, and fires once.
+    assert_eq!(step(&mut m, &mut s, &[], true), None);
+    assert_eq!((m.state.as_str(), m.tick), ("jab", 1));
+  }
+
+  #[test]
+  fn any_transitions_follow_the_state_ones() {
+    let mut s = setup();
+    let mut m = machine();
+    step(&mut m, &mut s, &[], true);
+    let to = step(&mut m, &mut s, &["attack"], false);
+    assert_eq!(to.as_deref(), Some("kick"));
+    assert!(m.history().next().unwrap().any);
+  }
+
+  #[test]
+  fn finished_action_leaves_without_gap() {
+    let mut s = setup();
+    let mut m = machine();
+    step(&mut m, &mut s, &[], true);
+    step(&mut m, &mut s, &["attack"], true);
+    for _ in 0..10 {
+      assert_eq!(step(&mut m, &mut s, &[], true), None);
+    }
+    // The last of the 11 ticks was played.
+    assert_eq!((m.state.as_str(), m.tick), ("jab", 10));
+    let to = step(&mut m, &mut s, &[], true);
+    assert_eq!(to.as_deref(), Some("idle"));
+    assert_eq!(m.tick, 0);
+  }
+
+  #[test]
+  fn cancel_needs_the_hit_and_the_window() {
+    let mut s = setup();
+    let mut m = machine();
+    step(&mut m, &mut s, &[], true);
+    step(&mut m, &mut s, &["attack"], true);
+    for _ in 0..3 {
+      step(&mut m, &mut s, &[], true);
+    }
+    // In the window, but no hit
+    assert_eq!(m.tick, 3);
+    assert_eq!(
+      step(&mut m, &mut s, &["attack"], true),
+      None
+    );
+    assert_eq!((m.state.as_str(), m.tick), ("jab", 4));
+    // Hit-confirm: the buffered press cancels.
+    m.notify_hit();
+    let to = step(&mut m, &mut s, &[], true);
+    assert_eq!(to.as_deref(), Some("jab"));
+    assert_eq!((m.tick, m.hit), (0, false));
+
+    // After the window, the hit does not cancel.
+    m.notify_hit();
+    for _ in 0..8 {
+      step(&mut m, &mut s, &[], true);
+    }
+    assert_eq!(m.tick, 8);
+    assert_eq!(
+      step(&mut m, &mut s, &["attack"], true),
+      None
+    );
+    assert_eq!((m.state.as_str(), m.tick), ("jab", 9));
+  }
+
+  #[test]
+  fn hit_from_the_world_confirms_the_cancel() {
+    let (asset, actions, _) = setup();
+    let machines =
+      BTreeMap::from([(asset.name.clone(), asset)]);
+    let mut inputs =
+      PlayerInputs::new(1, BufferConfig::default());
+    let mut world = World::new();
+    let fighter: Fighter = toml::from_str(
+      r#"
+facing_right = true
+action = "idle"
+health = 100
+"#,
+    )
+    .unwrap();
+    let e = world.spawn((machine(), fighter));
+    let mut run = |world: &mut World, pressed: &[&str]| {
+      push(inputs.player_mut(0).unwrap(), pressed);
+      step_fighters(
+        world,
+        &machines,
+        &actions,
+        &mut inputs,
+      );
+      let f = world.get::<Fighter>(e).unwrap();
+      (f.action.clone(), f.tick)
+    };
+    run(&mut world, &[]);
+    assert_eq!(
+      run(&mut world, &["attack"]),
+      ("jab".into(), 0)
+    );
+    for _ in 0..3 {
+      run(&mut world, &[]);
+    }
+    // As the hit handler of the app
+    world.get_mut::<StateMachine>(e).unwrap().notify_hit();
+    assert_eq!(
+      run(&mut world, &["attack"]),
+      ("jab".into(), 0)
+    );
+  }
+}
@@ -0,0 +1,253 @@
+//! State machine debugger
+//!
+//! Loads the machine assets that the game step runs, and shows the
+//! machine of an entity: the current state, the live result of
+//! every condition of the transitions, and the fired transitions
+//! with their reasons.
+
+use super::{
+  format::ActionLibrary,
+  machine::{
+    self, MachineContext, StateMachine, StateMachineAsset,
+    MACHINE_ROOT,
+  },
+};
+use crate::app_sys::{
+  ecs::{Entity, World},
+  input::buffer::PlayerInputs,
+  physics::body::Body,
+};
+use egui::Color32;
+use std::collections::BTreeMap;
+
+const HOLD_COLOR: Color32 = Color32::from_rgb(90, 200, 90);
+const FAIL_COLOR: Color32 = Color32::from_rgb(200, 90, 90);
+
+pub struct MachineDebugger {
+  machines: BTreeMap<String, StateMachineAsset>,
+  /// Entity inspected
+  selected: Option<Entity>,
+  status: Option<String>,
+}
+impl MachineDebugger {
+  /// Load the machines under `MACHINE_ROOT`.
+  pub fn new() -> Self {
+    let mut debugger = Self {
+      machines: BTreeMap::new(),
+      selected: None,
+      status: None,
+    };
+    debugger.reload();
+    debugger
+  }
+
+  pub fn reload(&mut self) {
+    let (machines, errors) =
+      machine::load_dir(MACHINE_ROOT);
+    for e in errors.iter() {
+      log::warn!("State machine load error: {e}");
+    }
+    self.status = errors.into_iter().next();
+    self.machines = machines;
+  }
+
+  /// Machine assets by the name
+  pub fn machines(
+    &self,
+  ) -> &BTreeMap<String, StateMachineAsset> {
+    &self.machines
+  }
+
+  /// Draw the debugger window.
+  pub fn show(
+    &mut self,
+    ctx: &egui::Context,
+    world: &World,
+    inputs: &PlayerInputs,
+    actions: &ActionLibrary,
+  ) {
+    egui::Window::new("State machine")
+      .default_open(false)
+      .resizable(true)
+      .show(ctx, |ui| self.ui(ui, world, inputs, actions));
+  }
+
+  fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    world: &World,
+    inputs: &PlayerInputs,
+    actions: &ActionLibrary,
+  ) {
+    let entities = world.query_entities::<&StateMachine>();
+    if self.selected.is_none_or(|e| !entities.contains(&e))
+    {
+      self.selected = entities.first().copied();
+    }
+    ui.horizontal(|ui| {
+      let name = |e: Entity| {
+        let machine = world
+          .get::<StateMachine>(e)
+          .map(|m| m.machine.clone())
+          .unwrap_or_default();
+        format!("#{} {machine}", e.index())
+      };
+      egui::ComboBox::from_id_salt(
+        "machine debugger entity",
+      )
+      .selected_text(
+        self.selected.map(name).unwrap_or_default(),
+      )
+      .show_ui(ui, |ui| {
+        for e in entities.iter() {
+          ui.selectable_value(
+            &mut self.selected,
+            Some(*e),
+            name(*e),
+          );
+        }
+      });
+      if ui.button("Reload").clicked() {
+        self.reload();
+      }
+    });
+    if let Some(status) = &self.status {
+      ui.label(status);
+    }
+    let Some(e) = self.selected else {
+      ui.label("no entity with a state machine");
+      return;
+    };
+    let Some(m) =
+      world.get::<StateMachine>(e).map(|m| m.clone())
+    else {
+      return;
+    };
+    let Some(asset) = self.machines.get(&m.machine) else {
+      ui.colored_label(
+        FAIL_COLOR,
+        format!(
+          "no state machine \"{}\" in {MACHINE_ROOT}",
+          m.machine
+        ),
+      );
+      return;
+    };
+    let missing = asset.missing_actions(actions);
+    if !missing.is_empty() {
+      ui.colored_label(
+        FAIL_COLOR,
+        format!("missing actions: {}", missing.join(", ")),
+      );
+    }
+    let grounded =
+      world.get::<Body>(e).is_none_or(|b| b.is_grounded());
+
+    ui.separator();
+    let Some(state) = m.state(asset) else {
+      ui.colored_label(
+        FAIL_COLOR,
+        format!("unknown state \"{}\"", m.state),
+      );
+      return;
+    };
+    let frame = m
+      .action(asset, actions)
+      .and_then(|a| a.frame_at(m.tick))
+      .map_or("-".to_string(), |(i, _)| i.to_string());
+    ui.horizontal(|ui| {
+      ui.strong(&state.name);
+      ui.label(format!(
+        "action {}, tick {}, frame {frame}{}{}",
+        state.action,
+        m.tick,
+        if m.hit { ", hit" } else { "" },
+        if grounded { ", grounded" } else { ", airborne" },
+      ));
+    });
+
+    egui::CollapsingHeader::new("Transitions")
+      .default_open(true)
+      .show(ui, |ui| match inputs.player(m.player) {
+        Some(buffer) => {
+          let ctx = MachineContext {
+            buffer,
+            actions,
+            grounded,
+          };
+          egui::Grid::new("machine transitions")
+            .striped(true)
+            .show(ui, |ui| {
+              let mut fires = true;
+              for (t, any) in m.candidates(asset) {
+                let held = t
+                  .when
+                  .iter()
+                  .map(|c| {
+                    (c, m.check(asset, c, &t.to, &ctx))
+                  })
+                  .collect::<Vec<_>>();
+                let all = held.iter().all(|(_, h)| *h);
+                let to = format!(
+                  "{}{}",
+                  if any { "(any) " } else { "" },
+                  t.to
+                );
+                if all && fires {
+                  ui.strong(format!("→ {to}"));
+                  fires = false;
+                } else {
+                  ui.label(format!("→ {to}"));
+                }
+                ui.horizontal(|ui| {
+                  if held.is_empty() {
+                    ui.colored_label(HOLD_COLOR, "always");
+                  }
+                  for (c, h) in held {
+                    let color = if h {
+                      HOLD_COLOR
+                    } else {
+                      FAIL_COLOR
+                    };
+                    ui.colored_label(color, c.to_string());
+                  }
+                });
+                ui.end_row();
+              }
+            });
+        }
+        None => {
+          ui.label(format!(
+            "no input of the player {}",
+            m.player + 1
+          ));
+        }
+      });
+
+    egui::CollapsingHeader::new("History")
+      .default_open(true)
+      .show(ui, |ui| {
+        egui::ScrollArea::vertical().max_height(160.).show(
+          ui,
+          |ui| {
+            for f in m.history() {
+              ui.label(format!(
+                "#{} {} → {}{} at tick {}: {}",
+                f.step,
+                f.from,
+                f.to,
+                if f.any { " (any)" } else { "" },
+                f.tick,
+                f.reasons.join(", ")
+              ));
+            }
+          },
+        );
+      });
+  }
+}
+impl Default for MachineDebugger {
+  fn default() -> Self {
+    Self::new()
+  }
+}
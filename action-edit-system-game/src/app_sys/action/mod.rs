@@ -0,0 +1,348 @@
+//! Action assets
+//! アクション(技)のフレームデータ
+//!
+//! An action is the ordered frames of a move. Every frame shows a
+//! sprite for its duration (ticks of the game loop), moves the
+//! character by the root motion, and has the hitboxes and
+//! hurtboxes.
+//!
+//! The boxes and the motion are in pixels from the character
+//! origin, for the character facing right. They are mirrored for
+//! the left.
+//!
+//! The continuous properties (velocity, scale, color, cues) are the
+//! keyframed tracks of the `timeline`.
+//!
+//! A character selects its actions with a state machine (`machine`).
+//!
+//! The assets are edited as TOML or JSON, and shipped as
+//! MessagePack (see `format`).
+
+use super::{
+  gfx::util::{TextureID, TextureStorage},
+  physics::shape::Aabb,
+};
+use nalgebra::{Point2, Vector2};
+use serde::{Deserialize, Serialize};
+use timeline::Timeline;
+
+pub mod editor;
+pub mod format;
+pub mod machine;
+pub mod machine_debugger;
+pub mod timeline;
+pub mod timeline_editor;
+
+/// Directory of the action assets
+pub const ACTION_ROOT: &str = "./actions";
+
+/// Section of a texture
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct SpriteRef {
+  /// Texture name in `TextureStorage`
+  pub texture: String,
+  /// Section name. None for the whole texture.
+  #[serde(
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub section: Option<String>,
+  /// Center of the sprite from the origin
+  #[serde(default)]
+  pub offset: [f32; 2],
+  /// Size of the sprite
+  pub size: [f32; 2],
+}
+impl SpriteRef {
+  /// Texture and UV (`[origin, extent]`) of the sprite
+  pub fn resolve(
+    &self,
+    textures: &TextureStorage,
+  ) -> Option<(TextureID, [[f32; 2]; 2])> {
+    let id = textures.get_id(&self.texture)?;
+    let uv = match &self.section {
+      Some(name) => textures
+        .section_uv(id, textures.section_id(id, name)?)?,
+      None => [[0., 0.], [1., 1.]],
+    };
+    Some((id, uv))
+  }
+}
+
+/// Box from the origin (center and size)
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  Default,
+  PartialEq,
+  Serialize,
+  Deserialize,
+)]
+pub struct BoxRect {
+  pub pos: [f32; 2],
+  pub size: [f32; 2],
+}
+impl BoxRect {
+  /// Box in the world
+  pub fn aabb(
+    &self,
+    origin: Point2<f32>,
+    facing_right: bool,
+  ) -> Aabb {
+    let x = if facing_right {
+      self.pos[0]
+    } else {
+      -self.pos[0]
+    };
+    Aabb::from_center(
+      origin + Vector2::new(x, self.pos[1]),
+      Vector2::new(self.size[0], self.size[1]).abs() * 0.5,
+    )
+  }
+}
+
+fn default_hitstop() -> u32 {
+  4
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Hitbox {
+  #[serde(flatten)]
+  pub rect: BoxRect,
+  pub damage: u32,
+  /// Velocity of the target in the hitstun (x toward the facing)
+  #[serde(default)]
+  pub knockback: [f32; 2],
+  /// Ticks the target cannot act
+  #[serde(default)]
+  pub hitstun: u32,
+  /// Ticks both sides freeze on the hit
+  #[serde(default = "default_hitstop")]
+  pub hitstop: u32,
+  /// Boxes of the same group hit a target once in the action.
+  #[serde(default)]
+  pub group: u32,
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Hurtbox {
+  #[serde(flatten)]
+  pub rect: BoxRect,
+  /// Hit by nothing (as the start of a reversal)
+  #[serde(default)]
+  pub invincible: bool,
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Frame {
+  #[serde(
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub sprite: Option<SpriteRef>,
+  /// Ticks the frame lasts (1 or more)
+  pub duration: u32,
+  /// Movement over the frame
+  #[serde(default)]
+  pub root_motion: [f32; 2],
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub hitboxes: Vec<Hitbox>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub hurtboxes: Vec<Hurtbox>,
+}
+impl Frame {
+  pub fn new(duration: u32) -> Self {
+    Self {
+      sprite: None,
+      duration,
+      root_motion: [0.; 2],
+      hitboxes: Vec::new(),
+      hurtboxes: Vec::new(),
+    }
+  }
+}
+
+/// How often the hitboxes of an action hit the same target
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  Default,
+  PartialEq,
+  Eq,
+  Serialize,
+  Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum HitRule {
+  /// Each group hits once
+  #[default]
+  Once,
+  /// The first hit of any group only
+  Single,
+  /// A group hits again after the ticks (multi-hit)
+  Interval(u32),
+}
+impl HitRule {
+  pub fn is_once(&self) -> bool {
+    *self == Self::Once
+  }
+}
+
+/// Ticks in which the action can be canceled into others
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct CancelWindow {
+  /// First tick (from the action start)
+  pub start: u32,
+  /// Tick after the last
+  pub end: u32,
+  /// Actions it can be canceled into. Empty for any.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub into: Vec<String>,
+  /// Only after a hitbox of the action hit
+  #[serde(default)]
+  pub on_hit: bool,
+}
+impl CancelWindow {
+  pub fn allows(
+    &self,
+    tick: u32,
+    into: &str,
+    hit: bool,
+  ) -> bool {
+    (self.start..self.end).contains(&tick)
+      && (hit || !self.on_hit)
+      && (self.into.is_empty()
+        || self.into.iter().any(|a| a == into))
+  }
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct ActionAsset {
+  pub name: String,
+  /// Restart from the first frame after the last
+  #[serde(default)]
+  pub looping: bool,
+  pub frames: Vec<Frame>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub cancels: Vec<CancelWindow>,
+  #[serde(
+    default,
+    skip_serializing_if = "HitRule::is_once"
+  )]
+  pub hit_rule: HitRule,
+  #[serde(
+    default,
+    skip_serializing_if = "Timeline::is_empty"
+  )]
+  pub timeline: Timeline,
+}
+impl ActionAsset {
+  pub fn new(name: impl ToString) -> Self {
+    Self {
+      name: name.to_string(),
+      looping: false,
+      frames: vec![Frame::new(1)],
+      cancels: Vec::new(),
+      hit_rule: HitRule::Once,
+      timeline: Timeline::default(),
+    }
+  }
+
+  /// Ticks of the whole action
+  pub fn total_ticks(&self) -> u32 {
+    self.frames.iter().map(|f| f.duration).sum()
+  }
+
+  /// First tick of the frame
+  pub fn frame_start(&self, index: usize) -> u32 {
+    self.frames.iter().take(index).map(|f| f.duration).sum()
+  }
+
+  /// Frame shown at the tick. A looping action wraps around, and
+  /// others are None after the end.
+  pub fn frame_at(
+    &self,
+    tick: u32,
+  ) -> Option<(usize, &Frame)> {
+    let total = self.total_ticks();
+    if total == 0 {
+      return None;
+    }
+    let mut t =
+      if self.looping { tick % total } else { tick };
+    for (i, frame) in self.frames.iter().enumerate() {
+      if t < frame.duration {
+        return Some((i, frame));
+      }
+      t -= frame.duration;
+    }
+    None
+  }
+
+  /// The action ended (never for a looping action)
+  pub fn is_finished(&self, tick: u32) -> bool {
+    !self.looping && self.total_ticks() <= tick
+  }
+
+  /// Movement at the tick. The root motion of a frame is spread
+  /// over its ticks.
+  pub fn root_motion_at(&self, tick: u32) -> [f32; 2] {
+    self.frame_at(tick).map_or([0.; 2], |(_, f)| {
+      let d = f.duration.max(1) as f32;
+      [f.root_motion[0] / d, f.root_motion[1] / d]
+    })
+  }
+
+  pub fn can_cancel(
+    &self,
+    tick: u32,
+    into: &str,
+    hit: bool,
+  ) -> bool {
+    self.cancels.iter().any(|c| c.allows(tick, into, hit))
+  }
+
+  /// Check the data that the runtime does not accept.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty() {
+      return Err("the action has no name".to_string());
+    }
+    if self.frames.is_empty() {
+      return Err(format!("{}: no frame", self.name));
+    }
+    if let Some(i) =
+      self.frames.iter().position(|f| f.duration == 0)
+    {
+      return Err(format!(
+        "{}: frame {i} has the duration 0",
+        self.name
+      ));
+    }
+    let total = self.total_ticks();
+    if let Some(c) = self
+      .cancels
+      .iter()
+      .find(|c| c.end < c.start || total < c.start)
+    {
+      return Err(format!(
+        "{}: cancel window {}..{} is out of 0..{total}",
+        self.name, c.start, c.end
+      ));
+    }
+    Ok(())
+  }
+}
@@ -0,0 +1,554 @@
+//! Action timeline
+//!
+//! Continuous properties of an action, as the keyframed tracks over
+//! the ticks of the action.
+//!
+//! - Curves (velocity, scale, color flash) are sampled at any tick,
+//!   including the fractions for the interpolated drawing. The ease
+//!   of a key shapes the way to the next key.
+//! - Cues (camera shake, sound) fire once when the tick passes them.
+
+use serde::{Deserialize, Serialize};
+
+/// Way from a key to the next
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  Default,
+  PartialEq,
+  Serialize,
+  Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Ease {
+  /// Keep the value until the next key
+  Step,
+  #[default]
+  Linear,
+  /// Cubic bezier `[x1, y1, x2, y2]` from (0, 0) to (1, 1), as CSS
+  /// `cubic-bezier`
+  Bezier([f32; 4]),
+}
+impl Ease {
+  pub const EASE_IN_OUT: Self =
+    Self::Bezier([0.42, 0., 0.58, 1.]);
+
+  /// Progress of the value at the time progress (0..1)
+  pub fn apply(&self, t: f32) -> f32 {
+    let t = t.clamp(0., 1.);
+    match *self {
+      Ease::Step => 0.,
+      Ease::Linear => t,
+      Ease::Bezier([x1, y1, x2, y2]) => {
+        let bezier = |a: f32, b: f32, s: f32| {
+          let r = 1. - s;
+          3. * r * r * s * a
+            + 3. * r * s * s * b
+            + s * s * s
+        };
+        // Solve x(s) = t by bisection (x is monotonic for x1 and
+        // x2 in 0..1).
+        let (x1, x2) = (x1.clamp(0., 1.), x2.clamp(0., 1.));
+        let (mut lo, mut hi) = (0f32, 1f32);
+        for _ in 0..24 {
+          let mid = (lo + hi) * 0.5;
+          if bezier(x1, x2, mid) < t {
+            lo = mid;
+          } else {
+            hi = mid;
+          }
+        }
+        bezier(y1, y2, (lo + hi) * 0.5)
+      }
+    }
+  }
+}
+
+/// Values that the curves interpolate
+pub trait Lerp: Clone {
+  fn lerp(&self, other: &Self, t: f32) -> Self;
+}
+impl Lerp for f32 {
+  fn lerp(&self, other: &Self, t: f32) -> Self {
+    self + (other - self) * t
+  }
+}
+impl<const N: usize> Lerp for [f32; N] {
+  fn lerp(&self, other: &Self, t: f32) -> Self {
+    std::array::from_fn(|i| self[i].lerp(&other[i], t))
+  }
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Key<T> {
+  pub tick: u32,
+  pub value: T,
+  /// Way to the next key
+  #[serde(default)]
+  pub ease: Ease,
+}
+impl<T> Key<T> {
+  pub fn new(tick: u32, value: T) -> Self {
+    Self {
+      tick,
+      value,
+      ease: Ease::default(),
+    }
+  }
+}
+
+/// Sample the keys (sorted by the tick). The value is held before
+/// the first key and after the last key.
+pub fn sample<T: Lerp>(
+  keys: &[Key<T>],
+  tick: f32,
+) -> Option<T> {
+  let next =
+    keys.partition_point(|k| k.tick as f32 <= tick);
+  match (
+    next.checked_sub(1).map(|i| &keys[i]),
+    keys.get(next),
+  ) {
+    (None, first) => first.map(|k| k.value.clone()),
+    (Some(k), None) => Some(k.value.clone()),
+    (Some(a), Some(b)) => {
+      let t =
+        (tick - a.tick as f32) / (b.tick - a.tick) as f32;
+      Some(a.value.lerp(&b.value, a.ease.apply(t)))
+    }
+  }
+}
+
+/// Camera shake cue
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  Default,
+  PartialEq,
+  Serialize,
+  Deserialize,
+)]
+pub struct Shake {
+  /// Amplitude (pixel)
+  pub amplitude: f32,
+  /// Length (tick)
+  pub duration: u32,
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+#[serde(tag = "track", rename_all = "snake_case")]
+pub enum Track {
+  /// Velocity added to the root motion (pixel / tick)
+  Velocity {
+    keys: Vec<Key<[f32; 2]>>,
+  },
+  /// Scale of the sprite
+  Scale {
+    keys: Vec<Key<[f32; 2]>>,
+  },
+  /// Color multiplied to the sprite (RGBA)
+  ColorFlash {
+    keys: Vec<Key<[f32; 4]>>,
+  },
+  CameraShake {
+    keys: Vec<Key<Shake>>,
+  },
+  /// Sound name
+  Sound {
+    keys: Vec<Key<String>>,
+  },
+}
+
+/// Applies the function to the keys of any track type.
+macro_rules! with_keys {
+  ($track:expr, $keys:ident => $e:expr) => {
+    match $track {
+      Track::Velocity { $keys } => $e,
+      Track::Scale { $keys } => $e,
+      Track::ColorFlash { $keys } => $e,
+      Track::CameraShake { $keys } => $e,
+      Track::Sound { $keys } => $e,
+    }
+  };
+}
+pub(crate) use with_keys;
+
+impl Track {
+  /// Track names for the editor
+  pub const NAMES: [&'static str; 5] = [
+    "velocity",
+    "scale",
+    "color_flash",
+    "camera_shake",
+    "sound",
+  ];
+
+  /// Empty track of the name in `NAMES`
+  pub fn from_name(name: &str) -> Option<Self> {
+    Some(match name {
+      "velocity" => Track::Velocity { keys: Vec::new() },
+      "scale" => Track::Scale { keys: Vec::new() },
+      "color_flash" => {
+        Track::ColorFlash { keys: Vec::new() }
+      }
+      "camera_shake" => {
+        Track::CameraShake { keys: Vec::new() }
+      }
+      "sound" => Track::Sound { keys: Vec::new() },
+      _ => return None,
+    })
+  }
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Track::Velocity { .. } => "velocity",
+      Track::Scale { .. } => "scale",
+      Track::ColorFlash { .. } => "color_flash",
+      Track::CameraShake { .. } => "camera_shake",
+      Track::Sound { .. } => "sound",
+    }
+  }
+
+  /// Cues fire at the ticks, and are not interpolated.
+  pub fn is_cue(&self) -> bool {
+    matches!(
+      self,
+      Track::CameraShake { .. } | Track::Sound { .. }
+    )
+  }
+
+  pub fn ticks(&self) -> Vec<u32> {
+    with_keys!(self, keys => keys.iter().map(|k| k.tick).collect())
+  }
+
+  pub fn len(&self) -> usize {
+    with_keys!(self, keys => keys.len())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len() == 0
+  }
+
+  /// Sort the keys by the tick. Of the keys at the same tick, the
+  /// last one stays. Returns the new indices of the old ones.
+  pub fn sort(&mut self) -> Vec<usize> {
+    with_keys!(self, keys => {
+      let mut order = (0..keys.len()).collect::<Vec<_>>();
+      order.sort_by_key(|i| keys[*i].tick);
+      let mut remap = vec![0; keys.len()];
+      let mut sorted = keys[..0].to_vec();
+      for i in order {
+        let k = keys[i].clone();
+        match sorted.last_mut().filter(|l| l.tick == k.tick) {
+          Some(last) => *last = k,
+          None => sorted.push(k),
+        }
+        remap[i] = sorted.len() - 1;
+      }
+      *keys = sorted;
+      remap
+    })
+  }
+
+  /// Remove the keys at the indices.
+  pub fn remove_keys(&mut self, indices: &[usize]) {
+    with_keys!(self, keys => {
+      let mut i = 0;
+      keys.retain(|_| {
+        i += 1;
+        !indices.contains(&(i - 1))
+      });
+    })
+  }
+
+  /// Move the keys by the ticks (clamped at 0).
+  pub fn shift_keys(
+    &mut self,
+    indices: &[usize],
+    delta: i64,
+  ) {
+    with_keys!(self, keys => {
+      for i in indices {
+        if let Some(k) = keys.get_mut(*i) {
+          k.tick = (k.tick as i64 + delta).max(0) as u32;
+        }
+      }
+    })
+  }
+
+  /// Add a key at the tick with the sampled value (the default
+  /// value for the cues). Returns the index.
+  pub fn insert_key(&mut self, tick: u32) -> usize {
+    fn insert<T: Clone>(
+      keys: &mut Vec<Key<T>>,
+      tick: u32,
+      value: T,
+    ) -> usize {
+      let i = keys.partition_point(|k| k.tick < tick);
+      match keys.get_mut(i).filter(|k| k.tick == tick) {
+        Some(k) => k.value = value,
+        None => keys.insert(i, Key::new(tick, value)),
+      }
+      i
+    }
+    let t = tick as f32;
+    match self {
+      Track::Velocity { keys } => {
+        let v = sample(keys, t).unwrap_or_default();
+        insert(keys, tick, v)
+      }
+      Track::Scale { keys } => {
+        let v = sample(keys, t).unwrap_or([1.; 2]);
+        insert(keys, tick, v)
+      }
+      Track::ColorFlash { keys } => {
+        let v = sample(keys, t).unwrap_or([1.; 4]);
+        insert(keys, tick, v)
+      }
+      Track::CameraShake { keys } => insert(
+        keys,
+        tick,
+        Shake {
+          amplitude: 4.,
+          duration: 8,
+        },
+      ),
+      Track::Sound { keys } => {
+        insert(keys, tick, String::new())
+      }
+    }
+  }
+
+  /// Copy of the track with the keys at the indices, and the ticks
+  /// moved by the delta
+  pub fn extract(
+    &self,
+    indices: &[usize],
+    delta: i64,
+  ) -> Self {
+    let mut track = self.clone();
+    with_keys!(&mut track, keys => {
+      let mut i = 0;
+      keys.retain(|_| {
+        i += 1;
+        indices.contains(&(i - 1))
+      });
+    });
+    let all = (0..track.len()).collect::<Vec<_>>();
+    track.shift_keys(&all, delta);
+    track
+  }
+
+  /// Add the keys of the same type of track. Returns false for
+  /// another type.
+  pub fn merge(&mut self, other: &Track) -> bool {
+    fn merge<T: Clone>(
+      to: &mut Vec<Key<T>>,
+      from: &[Key<T>],
+    ) {
+      for k in from {
+        let i = to.partition_point(|t| t.tick < k.tick);
+        match to.get_mut(i).filter(|t| t.tick == k.tick) {
+          Some(t) => *t = k.clone(),
+          None => to.insert(i, k.clone()),
+        }
+      }
+    }
+    match (self, other) {
+      (
+        Track::Velocity { keys: a },
+        Track::Velocity { keys: b },
+      )
+      | (
+        Track::Scale { keys: a },
+        Track::Scale { keys: b },
+      ) => merge(a, b),
+      (
+        Track::ColorFlash { keys: a },
+        Track::ColorFlash { keys: b },
+      ) => merge(a, b),
+      (
+        Track::CameraShake { keys: a },
+        Track::CameraShake { keys: b },
+      ) => merge(a, b),
+      (
+        Track::Sound { keys: a },
+        Track::Sound { keys: b },
+      ) => merge(a, b),
+      _ => return false,
+    }
+    true
+  }
+}
+
+/// Curve values at a tick (None if the track does not exist)
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct TimelineSample {
+  pub velocity: Option<[f32; 2]>,
+  pub scale: Option<[f32; 2]>,
+  pub color: Option<[f32; 4]>,
+}
+
+/// Cue fired in a tick range
+#[derive(Debug, Clone, PartialEq)]
+pub enum Cue {
+  CameraShake(Shake),
+  Sound(String),
+}
+
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct Timeline {
+  pub tracks: Vec<Track>,
+}
+impl Timeline {
+  pub fn is_empty(&self) -> bool {
+    self.tracks.is_empty()
+  }
+
+  /// Sample the curves. The first track of a kind is used.
+  pub fn sample(&self, tick: f32) -> TimelineSample {
+    let mut s = TimelineSample::default();
+    for track in self.tracks.iter() {
+      match track {
+        Track::Velocity { keys }
+          if s.velocity.is_none() =>
+        {
+          s.velocity = sample(keys, tick)
+        }
+        Track::Scale { keys } if s.scale.is_none() => {
+          s.scale = sample(keys, tick)
+        }
+        Track::ColorFlash { keys } if s.color.is_none() => {
+          s.color = sample(keys, tick)
+        }
+        _ => {}
+      }
+    }
+    s
+  }
+
+  /// Cues of the ticks in `from..to`, in the tick order
+  pub fn cues(&self, from: u32, to: u32) -> Vec<Cue> {
+    let mut cues = Vec::new();
+    for track in self.tracks.iter() {
+      match track {
+        Track::CameraShake { keys } => cues.extend(
+          keys
+            .iter()
+            .filter(|k| (from..to).contains(&k.tick))
+            .map(|k| (k.tick, Cue::CameraShake(k.value))),
+        ),
+        Track::Sound { keys } => cues.extend(
+          keys
+            .iter()
+            .filter(|k| (from..to).contains(&k.tick))
+            .map(|k| (k.tick, Cue::Sound(k.value.clone()))),
+        ),
+        _ => {}
+      }
+    }
+    cues.sort_by_key(|(tick, _)| *tick);
+    cues.into_iter().map(|(_, c)| c).collect()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn close(a: f32, b: f32) -> bool {
+    (a - b).abs() < 1e-3
+  }
+
+  #[test]
+  fn bezier_solves_the_time() {
+    let ease = Ease::EASE_IN_OUT;
+    assert!(close(ease.apply(0.), 0.));
+    assert!(close(ease.apply(0.5), 0.5));
+    assert!(close(ease.apply(1.), 1.));
+    assert!(ease.apply(0.25) < 0.25);
+    assert!(0.75 < ease.apply(0.75));
+    // The linear bezier is the identity.
+    let linear = Ease::Bezier([0.25, 0.25, 0.75, 0.75]);
+    for t in [0.1, 0.3, 0.6, 0.9] {
+      assert!(close(linear.apply(t), t), "{t}");
+    }
+    assert_eq!(Ease::Step.apply(0.9), 0.);
+  }
+
+  #[test]
+  fn samples_hold_and_fractions() {
+    assert_eq!(sample::<f32>(&[], 1.), None);
+    let keys = vec![Key::new(2, 1f32), Key::new(6, 5.)];
+    assert_eq!(sample(&keys, 0.), Some(1.));
+    assert_eq!(sample(&keys, 2.), Some(1.));
+    assert_eq!(sample(&keys, 3.5), Some(2.5));
+    assert_eq!(sample(&keys, 6.), Some(5.));
+    assert_eq!(sample(&keys, 9.), Some(5.));
+
+    let mut step = keys.clone();
+    step[0].ease = Ease::Step;
+    assert_eq!(sample(&step, 5.9), Some(1.));
+  }
+
+  #[test]
+  fn cues_fire_once_per_pass() {
+    let timeline = Timeline {
+      tracks: vec![
+        Track::Sound {
+          keys: vec![
+            Key::new(0, "start".to_string()),
+            Key::new(3, "hit".to_string()),
+          ],
+        },
+        Track::CameraShake {
+          keys: vec![Key::new(
+            2,
+            Shake {
+              amplitude: 4.,
+              duration: 6,
+            },
+          )],
+        },
+      ],
+    };
+    let mut fired = Vec::new();
+    for tick in 0..5 {
+      fired.extend(timeline.cues(tick, tick + 1));
+    }
+    assert_eq!(fired.len(), 3);
+    assert_eq!(fired[0], Cue::Sound("start".into()));
+    assert!(matches!(fired[1], Cue::CameraShake(_)));
+    assert_eq!(fired[2], Cue::Sound("hit".into()));
+    // An empty range fires nothing.
+    assert!(timeline.cues(3, 3).is_empty());
+  }
+
+  #[test]
+  fn sort_keeps_the_last_key_of_a_tick() {
+    let mut track = Track::Velocity {
+      keys: vec![
+        Key::new(4, [1., 0.]),
+        Key::new(1, [2., 0.]),
+        Key::new(4, [3., 0.]),
+      ],
+    };
+    assert_eq!(track.sort(), vec![1, 0, 1]);
+    let Track::Velocity { keys } = &track else {
+      unreachable!()
+    };
+    assert_eq!(
+      keys,
+      &vec![Key::new(1, [2., 0.]), Key::new(4, [3., 0.])]
+    );
+  }
+}
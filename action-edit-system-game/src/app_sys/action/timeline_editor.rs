@@ -0,0 +1,621 @@
+//! Timeline widget
+//!
+//! - Ruler: click or drag to scrub the playhead.
+//! - Keys: click to select, Shift/Ctrl+click to add to the
+//!   selection, drag to move the selected keys, double-click a lane
+//!   to add a key.
+//! - Copy/Paste (Ctrl+C, Ctrl+V) pastes the keys at the playhead,
+//!   Delete removes them.
+
+use super::timeline::{
+  with_keys, Ease, Key, Shake, Timeline, Track,
+};
+use egui::{Color32, Pos2, Rect, Sense, Stroke, Vec2};
+use hashbrown::HashSet;
+
+const LABEL_WIDTH: f32 = 90.;
+const RULER_HEIGHT: f32 = 18.;
+const ROW_HEIGHT: f32 = 20.;
+const KEY_SIZE: f32 = 5.;
+
+pub struct TimelineEditor {
+  /// Width of a tick (point)
+  zoom: f32,
+  /// (track, key)
+  selection: HashSet<(usize, usize)>,
+  /// Copied keys by the track, with the ticks from the first key
+  clipboard: Vec<(usize, Track)>,
+  /// Pointer x at the drag start, and the ticks applied
+  drag: Option<(f32, i64)>,
+  scrubbing: bool,
+  new_track: &'static str,
+}
+impl TimelineEditor {
+  pub fn new() -> Self {
+    Self {
+      zoom: 12.,
+      selection: HashSet::new(),
+      clipboard: Vec::new(),
+      drag: None,
+      scrubbing: false,
+      new_track: Track::NAMES[0],
+    }
+  }
+
+  /// Forget the selection (as the timeline is replaced).
+  pub fn clear_selection(&mut self) {
+    self.selection.clear();
+    self.drag = None;
+  }
+
+  fn selected_in(&self, track: usize) -> Vec<usize> {
+    let mut keys = self
+      .selection
+      .iter()
+      .filter(|(t, _)| *t == track)
+      .map(|(_, k)| *k)
+      .collect::<Vec<_>>();
+    keys.sort();
+    keys
+  }
+
+  /// Draw the timeline. Returns true if the timeline is changed.
+  pub fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    timeline: &mut Timeline,
+    playhead: &mut u32,
+    length: u32,
+  ) -> bool {
+    let mut changed = false;
+    ui.horizontal(|ui| {
+      egui::ComboBox::from_id_salt("timeline new track")
+        .selected_text(self.new_track)
+        .show_ui(ui, |ui| {
+          for name in Track::NAMES {
+            ui.selectable_value(
+              &mut self.new_track,
+              name,
+              name,
+            );
+          }
+        });
+      if ui.button("Add track").clicked() {
+        if let Some(t) = Track::from_name(self.new_track) {
+          timeline.tracks.push(t);
+          changed = true;
+        }
+      }
+      ui.add(
+        egui::Slider::new(&mut self.zoom, 2.0..=40.)
+          .text("zoom"),
+      );
+    });
+    ui.horizontal(|ui| {
+      if ui.button("Copy").clicked() {
+        self.copy(timeline);
+      }
+      if ui
+        .add_enabled(
+          !self.clipboard.is_empty(),
+          egui::Button::new("Paste"),
+        )
+        .clicked()
+      {
+        changed |= self.paste(timeline, *playhead);
+      }
+      if ui
+        .add_enabled(
+          !self.selection.is_empty(),
+          egui::Button::new("Delete"),
+        )
+        .clicked()
+      {
+        changed |= self.delete(timeline);
+      }
+    });
+
+    egui::ScrollArea::horizontal()
+      .id_salt("timeline scroll")
+      .show(ui, |ui| {
+        changed |=
+          self.lanes(ui, timeline, playhead, length);
+      });
+    changed |= self.inspector(ui, timeline);
+    changed
+  }
+
+  fn lanes(
+    &mut self,
+    ui: &mut egui::Ui,
+    timeline: &mut Timeline,
+    playhead: &mut u32,
+    length: u32,
+  ) -> bool {
+    let mut changed = false;
+    let size = Vec2::new(
+      LABEL_WIDTH
+        + (length + 1) as f32 * self.zoom
+        + KEY_SIZE,
+      RULER_HEIGHT
+        + timeline.tracks.len() as f32 * ROW_HEIGHT,
+    );
+    let (response, painter) =
+      ui.allocate_painter(size, Sense::click_and_drag());
+    let area = response.rect;
+    let zoom = self.zoom;
+    let x_of =
+      |tick: f32| area.left() + LABEL_WIDTH + tick * zoom;
+    let tick_of = |x: f32| {
+      ((x - area.left() - LABEL_WIDTH) / zoom)
+        .round()
+        .max(0.)
+    };
+    let row_y = |i: usize| {
+      area.top()
+        + RULER_HEIGHT
+        + (i as f32 + 0.5) * ROW_HEIGHT
+    };
+    let text = ui.visuals().text_color();
+    let font = egui::FontId::proportional(11.);
+
+    // Ruler
+    painter.rect_filled(
+      Rect::from_min_size(
+        area.min,
+        Vec2::new(area.width(), RULER_HEIGHT),
+      ),
+      0.,
+      Color32::from_gray(40),
+    );
+    let label_every = if 8. <= self.zoom { 5 } else { 10 };
+    for t in 0..=length {
+      let x = x_of(t as f32);
+      let long = t % label_every == 0;
+      painter.vline(
+        x,
+        (area.top() + if long { 4. } else { 12. })
+          ..=area.top() + RULER_HEIGHT,
+        Stroke::new(1., Color32::from_gray(110)),
+      );
+      if long {
+        painter.text(
+          Pos2::new(x + 2., area.top()),
+          egui::Align2::LEFT_TOP,
+          t.to_string(),
+          font.clone(),
+          text,
+        );
+      }
+    }
+
+    // Lanes and keys
+    let mut keys = Vec::new();
+    for (i, track) in timeline.tracks.iter().enumerate() {
+      let y = row_y(i);
+      if i % 2 == 0 {
+        painter.rect_filled(
+          Rect::from_center_size(
+            Pos2::new(area.center().x, y),
+            Vec2::new(area.width(), ROW_HEIGHT),
+          ),
+          0.,
+          Color32::from_gray(28),
+        );
+      }
+      painter.text(
+        Pos2::new(area.left() + 4., y),
+        egui::Align2::LEFT_CENTER,
+        track.name(),
+        font.clone(),
+        text,
+      );
+      let cue = track.is_cue();
+      for (k, tick) in track.ticks().into_iter().enumerate()
+      {
+        let center = Pos2::new(x_of(tick as f32), y);
+        let selected = self.selection.contains(&(i, k));
+        let color = if selected {
+          Color32::YELLOW
+        } else if cue {
+          Color32::from_rgb(120, 200, 120)
+        } else {
+          Color32::from_rgb(200, 200, 230)
+        };
+        if cue {
+          painter.circle_filled(center, KEY_SIZE, color);
+        } else {
+          painter.add(egui::Shape::convex_polygon(
+            vec![
+              center + Vec2::new(0., -KEY_SIZE),
+              center + Vec2::new(KEY_SIZE, 0.),
+              center + Vec2::new(0., KEY_SIZE),
+              center + Vec2::new(-KEY_SIZE, 0.),
+            ],
+            color,
+            Stroke::NONE,
+          ));
+        }
+        keys.push((i, k, center));
+      }
+    }
+    let head = x_of(*playhead as f32);
+    painter.vline(
+      head,
+      area.y_range(),
+      Stroke::new(1.5, Color32::from_rgb(230, 80, 80)),
+    );
+
+    // Interaction
+    let pointer = response.interact_pointer_pos();
+    let key_at = |p: Pos2| {
+      keys
+        .iter()
+        .find(|(_, _, c)| {
+          (p - *c).length() <= KEY_SIZE + 2.
+        })
+        .map(|(t, k, _)| (*t, *k))
+    };
+    let additive = ui
+      .input(|i| i.modifiers.shift || i.modifiers.command);
+    let on_ruler =
+      |p: Pos2| p.y < area.top() + RULER_HEIGHT;
+    if response.drag_started() || response.clicked() {
+      // The drag starts after the pointer moved a little
+      let press = ui.input(|i| i.pointer.press_origin());
+      if let Some(p) = press.or(pointer) {
+        self.scrubbing = on_ruler(p);
+        if !self.scrubbing {
+          match key_at(p) {
+            Some(key) if additive && response.clicked() => {
+              if self.selection.contains(&key) {
+                self.selection.remove(&key);
+              } else {
+                self.selection.insert(key);
+              }
+            }
+            Some(key) => {
+              if !self.selection.contains(&key) {
+                if !additive {
+                  self.selection.clear();
+                }
+                self.selection.insert(key);
+              }
+              if response.drag_started() {
+                self.drag = Some((p.x, 0));
+              }
+            }
+            None if !additive => self.selection.clear(),
+            None => {}
+          }
+        }
+      }
+    }
+    if self.scrubbing {
+      if let Some(p) = pointer {
+        *playhead = (tick_of(p.x) as u32).min(length);
+      }
+    }
+    if let (Some((start, applied)), Some(p)) =
+      (self.drag, pointer)
+    {
+      let delta =
+        ((p.x - start) / self.zoom).round() as i64;
+      if delta != applied {
+        for (t, track) in
+          timeline.tracks.iter_mut().enumerate()
+        {
+          let keys = self.selected_in(t);
+          track.shift_keys(&keys, delta - applied);
+        }
+        self.drag = Some((start, delta));
+        changed = true;
+      }
+    }
+    if response.drag_stopped() || response.clicked() {
+      self.scrubbing = false;
+      if self.drag.take().is_some() {
+        self.sort(timeline);
+      }
+    }
+    if response.double_clicked() {
+      if let Some(p) = pointer.filter(|p| !on_ruler(*p)) {
+        let row = ((p.y - area.top() - RULER_HEIGHT)
+          / ROW_HEIGHT) as usize;
+        if let Some(track) = timeline.tracks.get_mut(row) {
+          let k = track.insert_key(tick_of(p.x) as u32);
+          self.selection.clear();
+          self.selection.insert((row, k));
+          changed = true;
+        }
+      }
+    }
+    if response.hovered() {
+      let (copy, paste, delete) = ui.input(|i| {
+        (
+          i.events
+            .iter()
+            .any(|e| matches!(e, egui::Event::Copy)),
+          i.events
+            .iter()
+            .any(|e| matches!(e, egui::Event::Paste(_))),
+          i.key_pressed(egui::Key::Delete),
+        )
+      });
+      if copy {
+        self.copy(timeline);
+      }
+      if paste {
+        changed |= self.paste(timeline, *playhead);
+      }
+      if delete {
+        changed |= self.delete(timeline);
+      }
+    }
+    changed
+  }
+
+  /// Sort the keys after a move, and follow the selection.
+  fn sort(&mut self, timeline: &mut Timeline) {
+    let mut selection = HashSet::new();
+    for (t, track) in timeline.tracks.iter_mut().enumerate()
+    {
+      let remap = track.sort();
+      selection.extend(
+        self
+          .selection
+          .iter()
+          .filter(|(st, _)| *st == t)
+          .filter_map(|(_, k)| {
+            remap.get(*k).map(|k| (t, *k))
+          }),
+      );
+    }
+    self.selection = selection;
+  }
+
+  fn copy(&mut self, timeline: &Timeline) {
+    let first = self
+      .selection
+      .iter()
+      .filter_map(|(t, k)| {
+        timeline.tracks.get(*t)?.ticks().get(*k).copied()
+      })
+      .min();
+    let Some(first) = first else {
+      return;
+    };
+    self.clipboard = timeline
+      .tracks
+      .iter()
+      .enumerate()
+      .map(|(t, track)| {
+        (
+          t,
+          track
+            .extract(&self.selected_in(t), -(first as i64)),
+        )
+      })
+      .filter(|(_, track)| !track.is_empty())
+      .collect();
+  }
+
+  /// Paste the clipboard at the tick, to the same track, or the
+  /// first track of the type.
+  fn paste(
+    &mut self,
+    timeline: &mut Timeline,
+    tick: u32,
+  ) -> bool {
+    self.selection.clear();
+    for (t, keys) in self.clipboard.iter() {
+      let all = (0..keys.len()).collect::<Vec<_>>();
+      let keys = keys.extract(&all, tick as i64);
+      let target = Some(*t)
+        .filter(|t| {
+          timeline.tracks.get(*t).is_some_and(|track| {
+            track.name() == keys.name()
+          })
+        })
+        .or_else(|| {
+          timeline
+            .tracks
+            .iter()
+            .position(|track| track.name() == keys.name())
+        });
+      let target = match target {
+        Some(target) => target,
+        None => {
+          timeline
+            .tracks
+            .push(Track::from_name(keys.name()).unwrap());
+          timeline.tracks.len() - 1
+        }
+      };
+      let track = &mut timeline.tracks[target];
+      track.merge(&keys);
+      let ticks = keys.ticks();
+      self.selection.extend(
+        track
+          .ticks()
+          .into_iter()
+          .enumerate()
+          .filter(|(_, tick)| ticks.contains(tick))
+          .map(|(k, _)| (target, k)),
+      );
+    }
+    !self.clipboard.is_empty()
+  }
+
+  fn delete(&mut self, timeline: &mut Timeline) -> bool {
+    if self.selection.is_empty() {
+      return false;
+    }
+    for (t, track) in timeline.tracks.iter_mut().enumerate()
+    {
+      track.remove_keys(&self.selected_in(t));
+    }
+    self.selection.clear();
+    true
+  }
+
+  /// Editor of the single selected key
+  fn inspector(
+    &mut self,
+    ui: &mut egui::Ui,
+    timeline: &mut Timeline,
+  ) -> bool {
+    let Some((t, k)) = self
+      .selection
+      .iter()
+      .next()
+      .copied()
+      .filter(|_| self.selection.len() == 1)
+    else {
+      return false;
+    };
+    let Some(track) = timeline.tracks.get_mut(t) else {
+      return false;
+    };
+    let before = track.clone();
+    let mut remove_track = false;
+    ui.horizontal(|ui| {
+      ui.strong(format!("{} key", track.name()));
+      if ui.small_button("Remove track").clicked() {
+        remove_track = true;
+      }
+    });
+    egui::Grid::new("timeline key").show(ui, |ui| {
+      match track {
+        Track::Velocity { keys }
+        | Track::Scale { keys } => {
+          if let Some(key) = keys.get_mut(k) {
+            key_ui(ui, key, |ui, v| {
+              ui.horizontal(|ui| {
+                for c in v.iter_mut() {
+                  ui.add(
+                    egui::DragValue::new(c).speed(0.05),
+                  );
+                }
+              });
+            });
+          }
+        }
+        Track::ColorFlash { keys } => {
+          if let Some(key) = keys.get_mut(k) {
+            key_ui(ui, key, |ui, v| {
+              ui.color_edit_button_rgba_unmultiplied(v);
+            });
+          }
+        }
+        Track::CameraShake { keys } => {
+          if let Some(key) = keys.get_mut(k) {
+            cue_ui(ui, key, |ui, v: &mut Shake| {
+              ui.add(
+                egui::DragValue::new(&mut v.amplitude)
+                  .prefix("amp "),
+              );
+              ui.add(
+                egui::DragValue::new(&mut v.duration)
+                  .prefix("ticks "),
+              );
+            });
+          }
+        }
+        Track::Sound { keys } => {
+          if let Some(key) = keys.get_mut(k) {
+            cue_ui(ui, key, |ui, v: &mut String| {
+              ui.text_edit_singleline(v);
+            });
+          }
+        }
+      }
+    });
+    if remove_track {
+      timeline.tracks.remove(t);
+      self.selection.clear();
+      return true;
+    }
+    if *track == before {
+      return false;
+    }
+    if with_keys!(&*track, keys => keys.get(k).map(|k| k.tick))
+      != with_keys!(&before, keys => keys.get(k).map(|k| k.tick))
+    {
+      self.sort(timeline);
+    }
+    true
+  }
+}
+impl Default for TimelineEditor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+fn key_ui<T>(
+  ui: &mut egui::Ui,
+  key: &mut Key<T>,
+  value: impl FnOnce(&mut egui::Ui, &mut T),
+) {
+  ui.label("tick");
+  ui.add(egui::DragValue::new(&mut key.tick));
+  ui.end_row();
+  ui.label("value");
+  value(ui, &mut key.value);
+  ui.end_row();
+  ui.label("ease");
+  ui.horizontal(|ui| {
+    let name = match key.ease {
+      Ease::Step => "step",
+      Ease::Linear => "linear",
+      Ease::Bezier(_) => "bezier",
+    };
+    egui::ComboBox::from_id_salt("timeline ease")
+      .selected_text(name)
+      .show_ui(ui, |ui| {
+        ui.selectable_value(
+          &mut key.ease,
+          Ease::Step,
+          "step",
+        );
+        ui.selectable_value(
+          &mut key.ease,
+          Ease::Linear,
+          "linear",
+        );
+        if ui
+          .selectable_label(
+            matches!(key.ease, Ease::Bezier(_)),
+            "bezier",
+          )
+          .clicked()
+        {
+          key.ease = Ease::EASE_IN_OUT;
+        }
+      });
+    if let Ease::Bezier(points) = &mut key.ease {
+      for (i, p) in points.iter_mut().enumerate() {
+        let range =
+          if i % 2 == 0 { 0.0..=1.0 } else { -1.0..=2.0 };
+        ui.add(
+          egui::DragValue::new(p).speed(0.01).range(range),
+        );
+      }
+    }
+  });
+  ui.end_row();
+}
+
+fn cue_ui<T>(
+  ui: &mut egui::Ui,
+  key: &mut Key<T>,
+  value: impl FnOnce(&mut egui::Ui, &mut T),
+) {
+  ui.label("tick");
+  ui.add(egui::DragValue::new(&mut key.tick));
+  ui.end_row();
+  ui.label("value");
+  ui.horizontal(|ui| value(ui, &mut key.value));
+  ui.end_row();
+}
@@ -0,0 +1,502 @@
+//! Hit detection and damage resolution
+//! 攻撃判定とダメージ処理
+//!
+//! Every tick, `Combat::step` intersects the hitboxes of the action
+//! frame of each `Fighter` with the hurtboxes of the others. A hit
+//! freezes both sides for the hitstop, and gives the target the
+//! damage, the knockback and the hitstun.
+//!
+//! The `StateMachine` of a fighter writes its action and tick
+//! before the step. A fighter without a machine plays its action on
+//! by a tick every step. Nothing advances while `is_frozen`. The
+//! hits of an action are forgotten when another action starts, or
+//! the tick goes back.
+//!
+//! The step moves the fighters by the root motion of the tick
+//! played, or by the knockback in the hitstun. A fighter with a
+//! `Body` is moved through the body, so that it collides with the
+//! level. A fighter in the hitstun hits nothing.
+//!
+//! The result depends only on the world and the actions: the
+//! fighters are resolved in the entity order, and the hits of a
+//! tick are found before any is applied, so two fighters can trade
+//! hits.
+
+use super::{
+  action::{
+    format::ActionLibrary, machine::StateMachine, HitRule,
+    Hitbox,
+  },
+  ecs::{Entity, World},
+  physics::{body::Body, shape::Aabb},
+  script::ScriptEvent,
+};
+use nalgebra::Point2;
+use serde::{Deserialize, Serialize};
+
+/// Hit given in the current action
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+struct HitRecord {
+  target: Entity,
+  action: String,
+  group: u32,
+  /// Action tick of the hit
+  tick: u32,
+}
+
+/// Combat state of an entity
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Fighter {
+  /// Origin in the world
+  #[serde(default)]
+  pub pos: [f32; 2],
+  pub facing_right: bool,
+  pub action: String,
+  /// Tick of the action
+  #[serde(default)]
+  pub tick: u32,
+  pub health: u32,
+  /// Ticks frozen by a hit
+  #[serde(default)]
+  pub hitstop: u32,
+  /// Ticks unable to act after a hit
+  #[serde(default)]
+  pub hitstun: u32,
+  /// Velocity given by the last hit, for the hitstun
+  #[serde(default)]
+  pub knockback: [f32; 2],
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  hits: Vec<HitRecord>,
+}
+impl Fighter {
+  /// In the hitstop. The action must not advance.
+  pub fn is_frozen(&self) -> bool {
+    0 < self.hitstop
+  }
+
+  pub fn is_down(&self) -> bool {
+    self.health == 0
+  }
+
+  fn allows_hit(
+    &self,
+    rule: HitRule,
+    target: Entity,
+    group: u32,
+  ) -> bool {
+    let mut hits =
+      self.hits.iter().filter(|h| h.target == target);
+    match rule {
+      HitRule::Once => hits.all(|h| h.group != group),
+      HitRule::Single => hits.next().is_none(),
+      HitRule::Interval(t) => hits
+        .filter(|h| h.group == group)
+        .all(|h| h.tick + t <= self.tick),
+    }
+  }
+}
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum CombatEvent {
+  Hit {
+    /// Combat step
+    step: u64,
+    attacker: Entity,
+    target: Entity,
+    action: String,
+    group: u32,
+    damage: u32,
+    /// Knockback given to the target
+    knockback: [f32; 2],
+    hitstun: u32,
+    hitstop: u32,
+    /// Center of the overlap of the boxes, for the effects
+    point: [f32; 2],
+  },
+  /// The health of the target reached 0
+  Down {
+    step: u64,
+    target: Entity,
+    by: Entity,
+  },
+}
+impl CombatEvent {
+  /// Event for the scripts (`hit` or `down`, with the JSON payload)
+  pub fn to_script_event(&self) -> ScriptEvent {
+    let name = match self {
+      Self::Hit { .. } => "hit",
+      Self::Down { .. } => "down",
+    };
+    ScriptEvent::new(
+      name,
+      serde_json::to_string(self).unwrap_or_default(),
+    )
+  }
+}
+
+/// Hit found in a step
+struct Contact {
+  attacker: Entity,
+  target: Entity,
+  hitbox: Hitbox,
+  point: [f32; 2],
+}
+
+#[derive(Debug, Default)]
+pub struct Combat {
+  step: u64,
+  events: Vec<CombatEvent>,
+}
+impl Combat {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Events of the steps since the last `take_events`
+  pub fn take_events(&mut self) -> Vec<CombatEvent> {
+    std::mem::take(&mut self.events)
+  }
+
+  /// Advance the timers, resolve the hits of the tick, and play
+  /// the tick.
+  pub fn step(
+    &mut self,
+    world: &mut World,
+    actions: &ActionLibrary,
+    dt: f32,
+  ) {
+    self.step += 1;
+    let mut fighters = Vec::new();
+    let mut playing = Vec::new();
+    world.query::<&mut Fighter>(|e, f| {
+      // The hitstun runs after the hitstop.
+      if 0 < f.hitstop {
+        f.hitstop -= 1;
+      } else {
+        playing.push((e, 0 < f.hitstun));
+        f.hitstun = f.hitstun.saturating_sub(1);
+      }
+      let (action, tick) = (&f.action, f.tick);
+      f.hits
+        .retain(|h| h.action == *action && h.tick <= tick);
+      fighters.push((e, f.clone()));
+    });
+    fighters.sort_by_key(|(e, _)| *e);
+
+    let contacts = find_contacts(&fighters, actions);
+    for c in contacts {
+      self.apply(world, &fighters, c);
+    }
+    play(world, &playing, actions, dt);
+  }
+
+  fn apply(
+    &mut self,
+    world: &mut World,
+    fighters: &[(Entity, Fighter)],
+    c: Contact,
+  ) {
+    let Some((_, attacker)) =
+      fighters.iter().find(|(e, _)| *e == c.attacker)
+    else {
+      return;
+    };
+    let hb = &c.hitbox;
+    let action = attacker.action.clone();
+    let sign = if attacker.facing_right { 1. } else { -1. };
+    let knockback =
+      [hb.knockback[0] * sign, hb.knockback[1]];
+    if let Some(a) = world.get_mut::<Fighter>(c.attacker) {
+      a.hits.push(HitRecord {
+        target: c.target,
+        action: a.action.clone(),
+        group: hb.group,
+        tick: a.tick,
+      });
+      a.hitstop = a.hitstop.max(hb.hitstop);
+    }
+    let Some(t) = world.get_mut::<Fighter>(c.target) else {
+      return;
+    };
+    let was_up = !t.is_down();
+    t.health = t.health.saturating_sub(hb.damage);
+    t.hitstop = t.hitstop.max(hb.hitstop);
+    t.hitstun = t.hitstun.max(hb.hitstun);
+    t.knockback = knockback;
+    let down = was_up && t.is_down();
+    self.events.push(CombatEvent::Hit {
+      step: self.step,
+      attacker: c.attacker,
+      target: c.target,
+      action,
+      group: hb.group,
+      damage: hb.damage,
+      knockback,
+      hitstun: hb.hitstun,
+      hitstop: hb.hitstop,
+      point: c.point,
+    });
+    if down {
+      self.events.push(CombatEvent::Down {
+        step: self.step,
+        target: c.target,
+        by: c.attacker,
+      });
+    }
+  }
+}
+
+/// Move the fighters that were not frozen at the start of the
+/// step (with the hitstun then), and advance the ones without a
+/// machine.
+fn play(
+  world: &mut World,
+  playing: &[(Entity, bool)],
+  actions: &ActionLibrary,
+  dt: f32,
+) {
+  let mut motions = Vec::new();
+  world.query::<&mut Fighter>(|e, f| {
+    let Some((_, stunned)) =
+      playing.iter().find(|(p, _)| *p == e)
+    else {
+      return;
+    };
+    let sign = if f.facing_right { 1. } else { -1. };
+    // The knockback replaces the root motion in the hitstun, and
+    // starts after the hitstop of the hit.
+    let d = if !*stunned {
+      let root = actions
+        .get(&f.action)
+        .map_or([0.; 2], |a| a.root_motion_at(f.tick));
+      [root[0] * sign, root[1]]
+    } else if !f.is_frozen() {
+      [f.knockback[0] * dt, f.knockback[1] * dt]
+    } else {
+      [0.; 2]
+    };
+    if f.hitstun == 0 {
+      f.knockback = [0.; 2];
+    }
+    if !world.has::<StateMachine>(e) {
+      f.tick = f.tick.saturating_add(1);
+    }
+    if d != [0.; 2] {
+      motions.push((e, d));
+    }
+  });
+  for (e, d) in motions {
+    if let Some(b) = world.get_mut::<Body>(e) {
+      b.add_motion(d);
+    } else if let Some(f) = world.get_mut::<Fighter>(e) {
+      f.pos = [f.pos[0] + d[0], f.pos[1] + d[1]];
+    }
+  }
+}
+
+/// Hit rule, hitboxes and hurtboxes of a fighter
+type FighterBoxes<'a> =
+  (HitRule, Vec<(&'a Hitbox, Aabb)>, Vec<Aabb>);
+
+/// Boxes of the fighter at its tick, in the world
+fn boxes<'a>(
+  f: &Fighter,
+  actions: &'a ActionLibrary,
+) -> Option<FighterBoxes<'a>> {
+  let action = actions.get(&f.action)?;
+  let (_, frame) = action.frame_at(f.tick)?;
+  let origin = Point2::new(f.pos[0], f.pos[1]);
+  let hit = frame
+    .hitboxes
+    .iter()
+    .map(|h| (h, h.rect.aabb(origin, f.facing_right)))
+    .collect();
+  let hurt = frame
+    .hurtboxes
+    .iter()
+    .filter(|h| !h.invincible)
+    .map(|h| h.rect.aabb(origin, f.facing_right))
+    .collect();
+  Some((action.hit_rule, hit, hurt))
+}
+
+/// Hits of the tick, at most one for an attacker and a target (the
+/// first hitbox in the frame wins).
+fn find_contacts(
+  fighters: &[(Entity, Fighter)],
+  actions: &ActionLibrary,
+) -> Vec<Contact> {
+  let boxes = fighters
+    .iter()
+    .map(|(_, f)| boxes(f, actions))
+    .collect::<Vec<_>>();
+  let mut contacts = Vec::new();
+  for (i, (attacker, a)) in fighters.iter().enumerate() {
+    let Some((rule, hitboxes, _)) = &boxes[i] else {
+      continue;
+    };
+    if a.is_frozen() || 0 < a.hitstun || hitboxes.is_empty()
+    {
+      continue;
+    }
+    for (j, (target, _)) in fighters.iter().enumerate() {
+      let Some((_, _, hurtboxes)) =
+        boxes[j].as_ref().filter(|_| i != j)
+      else {
+        continue;
+      };
+      let contact =
+        hitboxes.iter().find_map(|(hb, hit)| {
+          if !a.allows_hit(*rule, *target, hb.group) {
+            return None;
+          }
+          let hurt =
+            hurtboxes.iter().find(|h| hit.overlaps(h))?;
+          let overlap = Aabb::new(
+            hit.min.sup(&hurt.min),
+            hit.max.inf(&hurt.max),
+          );
+          let point = overlap.center();
+          Some(Contact {
+            attacker: *attacker,
+            target: *target,
+            hitbox: (*hb).clone(),
+            point: [point.x, point.y],
+          })
+        });
+      contacts.extend(contact);
+    }
+  }
+  contacts
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const IDLE: &str = r#"
+name = "idle"
+looping = true
+
+[[frames]]
+duration = 4
+hurtboxes = [{ pos = [0, 16], size = [32, 32] }]
+"#;
+
+  const JAB: &str = r#"
+name = "jab"
+
+[[frames]]
+duration = 3
+hurtboxes = [{ pos = [0, 16], size = [32, 32] }]
+
+[[frames]]
+duration = 2
+root_motion = [4, 0]
+hurtboxes = [{ pos = [4, 16], size = [32, 32] }]
+
+[[frames.hitboxes]]
+pos = [28, 18]
+size = [20, 10]
+damage = 30
+knockback = [60, 0]
+hitstun = 12
+
+[[frames]]
+duration = 6
+hurtboxes = [{ pos = [0, 16], size = [32, 32] }]
+"#;
+
+  fn fighter(x: f32, facing_right: bool) -> Fighter {
+    let mut f: Fighter = toml::from_str(
+      r#"
+facing_right = true
+action = "idle"
+health = 100
+"#,
+    )
+    .unwrap();
+    f.pos = [x, 0.];
+    f.facing_right = facing_right;
+    f
+  }
+
+  /// Events, hitstun and positions of every step
+  type Trace =
+    Vec<(Vec<CombatEvent>, [u32; 2], [[f32; 2]; 2])>;
+
+  /// A jabs, B jabs back in the hitstun (blocked), then after it.
+  fn exchange() -> Trace {
+    let mut actions = ActionLibrary::new();
+    actions.insert(toml::from_str(IDLE).unwrap());
+    actions.insert(toml::from_str(JAB).unwrap());
+    let mut world = World::new();
+    let a = world.spawn((fighter(0., true),));
+    let b = world.spawn((fighter(40., false),));
+    let script = [
+      (0, a, "jab"),
+      (6, b, "jab"),
+      (20, a, "idle"),
+      (24, b, "idle"),
+      (40, b, "jab"),
+    ];
+    let mut combat = Combat::new();
+    let mut trace = Vec::new();
+    for step in 0..60 {
+      for (_, e, action) in
+        script.iter().filter(|(s, ..)| *s == step)
+      {
+        let f = world.get_mut::<Fighter>(*e).unwrap();
+        f.action = action.to_string();
+        f.tick = 0;
+      }
+      combat.step(&mut world, &actions, 1. / 60.);
+      let [fa, fb] = [a, b]
+        .map(|e| world.get::<Fighter>(e).unwrap().clone());
+      trace.push((
+        combat.take_events(),
+        [fa.hitstun, fb.hitstun],
+        [fa.pos, fb.pos],
+      ));
+    }
+    trace
+  }
+
+  #[test]
+  fn exchange_is_deterministic() {
+    let trace = exchange();
+    assert_eq!(trace, exchange());
+
+    let hits = trace
+      .iter()
+      .enumerate()
+      .flat_map(|(i, (events, ..))| {
+        events.iter().map(move |e| (i, e))
+      })
+      .filter_map(|(i, e)| match e {
+        CombatEvent::Hit { attacker, .. } => {
+          Some((i, attacker.index()))
+        }
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    // The jab of B in the hitstun hits nothing.
+    assert_eq!(hits, [(3, 0), (43, 1)]);
+    // Knocked back through the hitstun after the hitstop
+    let (_, stun, pos) = &trace[7];
+    assert_eq!(stun[1], 12);
+    assert_eq!(pos[1], [40., 0.]);
+    let (_, stun, pos) = &trace[19];
+    assert_eq!(stun[1], 0);
+    assert!((pos[1][0] - 52.).abs() < 1e-3);
+    // The root motion of the jab of A
+    assert!((pos[0][0] - 4.).abs() < 1e-3);
+  }
+}
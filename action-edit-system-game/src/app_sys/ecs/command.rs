@@ -0,0 +1,85 @@
+//! Deferred world commands
+//!
+//! Systems that iterate a query cannot change the entities. They
+//! queue the changes to `Commands`, and the queue is applied after
+//! the iteration in the queued order.
+
+use super::World;
+
+type Command = Box<dyn FnOnce(&mut World) + Send>;
+
+#[derive(Default)]
+pub struct Commands {
+  queue: Vec<Command>,
+}
+impl Commands {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Queue the change.
+  pub fn add(
+    &mut self,
+    f: impl FnOnce(&mut World) + Send + 'static,
+  ) {
+    self.queue.push(Box::new(f));
+  }
+
+  /// Apply the queued commands, and clear the queue.
+  pub fn apply(&mut self, world: &mut World) {
+    for command in self.queue.drain(..) {
+      command(world);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn commands_are_applied_in_order() {
+    let mut world = World::new();
+    let a = world.spawn((1u32,));
+    let mut commands = Commands::new();
+    commands.add(move |w| {
+      w.insert(a, 2u32);
+    });
+    commands.add(move |w| {
+      *w.get_mut::<u32>(a).unwrap() += 1;
+    });
+    commands.add(move |w| {
+      w.spawn((10u32,));
+    });
+    assert_eq!(*world.get::<u32>(a).unwrap(), 1);
+    commands.apply(&mut world);
+    assert_eq!(*world.get::<u32>(a).unwrap(), 3);
+    assert_eq!(world.query_entities::<&u32>().len(), 2);
+    commands.add(move |w| {
+      w.remove::<u32>(a);
+    });
+    commands.apply(&mut world);
+    assert!(!world.has::<u32>(a));
+    commands.apply(&mut world);
+    assert_eq!(world.query_entities::<&u32>().len(), 1);
+  }
+
+  #[test]
+  fn despawn_in_query_is_deferred() {
+    let mut world = World::new();
+    for i in 0..4u32 {
+      world.spawn((i,));
+    }
+    let mut commands = Commands::new();
+    world.query::<&u32>(|e, i| {
+      if i % 2 == 0 {
+        commands.add(move |w| {
+          w.despawn(e);
+        });
+      }
+    });
+    assert_eq!(world.entities().count(), 4);
+    commands.apply(&mut world);
+    assert_eq!(world.entities().count(), 2);
+  }
+}
@@ -0,0 +1,279 @@
+//! Entity-component storage
+//! ゲームオブジェクトのエンティティとコンポーネント
+//!
+//! Every component type has its own sparse set. An entity is an
+//! index with a generation, so a handle of a despawned entity does
+//! not touch the entity that reuses the index.
+//!
+//! - `query`: typed queries over the storages
+//! - `command`: spawn/despawn deferred until `Commands::apply`
+//! - `persist`: save and load the registered components with serde
+
+use hashbrown::HashMap;
+use parking_lot::{
+  MappedRwLockReadGuard, RwLock, RwLockReadGuard,
+};
+use serde::{Deserialize, Serialize};
+use std::any::{Any, TypeId};
+
+pub mod command;
+pub mod persist;
+pub mod query;
+pub mod storage;
+use storage::SparseSet;
+
+/// Data attached to the entities
+pub trait Component: Send + Sync + 'static {}
+impl<T: Send + Sync + 'static> Component for T {}
+
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Hash,
+  PartialOrd,
+  Ord,
+  Serialize,
+  Deserialize,
+)]
+pub struct Entity {
+  index: u32,
+  generation: u32,
+}
+impl Entity {
+  pub fn index(self) -> u32 {
+    self.index
+  }
+}
+
+/// Components spawned together (a tuple of the components)
+pub trait Bundle: Send + 'static {
+  fn insert_into(self, world: &mut World, e: Entity);
+}
+impl Bundle for () {
+  fn insert_into(self, _world: &mut World, _e: Entity) {}
+}
+macro_rules! impl_bundle_tuple {
+  ($($c:ident $i:tt),+) => {
+    impl<$($c: Component),+> Bundle for ($($c,)+) {
+      fn insert_into(self, world: &mut World, e: Entity) {
+        $(world.insert(e, self.$i);)+
+      }
+    }
+  };
+}
+impl_bundle_tuple!(A 0);
+impl_bundle_tuple!(A 0, B 1);
+impl_bundle_tuple!(A 0, B 1, C 2);
+impl_bundle_tuple!(A 0, B 1, C 2, D 3);
+impl_bundle_tuple!(A 0, B 1, C 2, D 3, E 4);
+impl_bundle_tuple!(A 0, B 1, C 2, D 3, E 4, F 5);
+
+/// Storage with the type erased
+trait ErasedStorage: Send + Sync {
+  fn remove(&mut self, e: Entity);
+  fn as_any(&self) -> &dyn Any;
+  fn as_any_mut(&mut self) -> &mut dyn Any;
+}
+impl<T: Component> ErasedStorage for RwLock<SparseSet<T>> {
+  fn remove(&mut self, e: Entity) {
+    self.get_mut().remove(e);
+  }
+
+  fn as_any(&self) -> &dyn Any {
+    self
+  }
+
+  fn as_any_mut(&mut self) -> &mut dyn Any {
+    self
+  }
+}
+
+#[derive(Default)]
+pub struct World {
+  /// Current generation of each index
+  generations: Vec<u32>,
+  alive: Vec<bool>,
+  free: Vec<u32>,
+  storages: HashMap<TypeId, Box<dyn ErasedStorage>>,
+}
+impl World {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Spawn the entity with the components.
+  pub fn spawn(&mut self, bundle: impl Bundle) -> Entity {
+    let e = match self.free.pop() {
+      Some(index) => {
+        self.alive[index as usize] = true;
+        Entity {
+          index,
+          generation: self.generations[index as usize],
+        }
+      }
+      None => {
+        self.generations.push(0);
+        self.alive.push(true);
+        Entity {
+          index: self.generations.len() as u32 - 1,
+          generation: 0,
+        }
+      }
+    };
+    bundle.insert_into(self, e);
+    e
+  }
+
+  /// Despawn the entity and remove its components.
+  pub fn despawn(&mut self, e: Entity) -> bool {
+    if !self.is_alive(e) {
+      return false;
+    }
+    for storage in self.storages.values_mut() {
+      storage.remove(e);
+    }
+    let i = e.index as usize;
+    self.alive[i] = false;
+    self.generations[i] =
+      self.generations[i].wrapping_add(1);
+    self.free.push(e.index);
+    true
+  }
+
+  pub fn is_alive(&self, e: Entity) -> bool {
+    let i = e.index as usize;
+    self.alive.get(i).copied().unwrap_or(false)
+      && self.generations[i] == e.generation
+  }
+
+  /// Alive entities in the index order
+  pub fn entities(
+    &self,
+  ) -> impl Iterator<Item = Entity> + '_ {
+    self
+      .alive
+      .iter()
+      .zip(self.generations.iter())
+      .enumerate()
+      .filter(|(_, (alive, _))| **alive)
+      .map(|(index, (_, generation))| Entity {
+        index: index as u32,
+        generation: *generation,
+      })
+  }
+
+  fn storage<T: Component>(
+    &self,
+  ) -> Option<&RwLock<SparseSet<T>>> {
+    self
+      .storages
+      .get(&TypeId::of::<T>())
+      .and_then(|s| s.as_any().downcast_ref())
+  }
+
+  fn storage_mut<T: Component>(
+    &mut self,
+  ) -> &mut SparseSet<T> {
+    self
+      .storages
+      .entry(TypeId::of::<T>())
+      .or_insert_with(|| {
+        Box::new(RwLock::new(SparseSet::<T>::new()))
+      })
+      .as_any_mut()
+      .downcast_mut::<RwLock<SparseSet<T>>>()
+      .expect("storage is keyed by the component type")
+      .get_mut()
+  }
+
+  /// Insert the component. Returns false if the entity is not
+  /// alive.
+  pub fn insert<T: Component>(
+    &mut self,
+    e: Entity,
+    component: T,
+  ) -> bool {
+    if !self.is_alive(e) {
+      return false;
+    }
+    self.storage_mut::<T>().insert(e, component);
+    true
+  }
+
+  pub fn remove<T: Component>(
+    &mut self,
+    e: Entity,
+  ) -> Option<T> {
+    self.storage_mut::<T>().remove(e)
+  }
+
+  pub fn has<T: Component>(&self, e: Entity) -> bool {
+    self
+      .storage::<T>()
+      .is_some_and(|s| s.read().contains(e))
+  }
+
+  /// Component of the entity. The storage is locked while the guard
+  /// is alive.
+  pub fn get<T: Component>(
+    &self,
+    e: Entity,
+  ) -> Option<MappedRwLockReadGuard<'_, T>> {
+    RwLockReadGuard::try_map(
+      self.storage::<T>()?.read(),
+      |s| s.get(e),
+    )
+    .ok()
+  }
+
+  pub fn get_mut<T: Component>(
+    &mut self,
+    e: Entity,
+  ) -> Option<&mut T> {
+    self.storage_mut::<T>().get_mut(e)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn despawned_index_is_reused_with_next_generation() {
+    let mut world = World::new();
+    let a = world.spawn((1u32,));
+    assert!(world.despawn(a));
+    let b = world.spawn((2u32,));
+    assert_eq!(b.index(), a.index());
+    assert_ne!(b, a);
+    assert_eq!(world.entities().collect::<Vec<_>>(), [b]);
+  }
+
+  #[test]
+  fn stale_handle_is_rejected() {
+    let mut world = World::new();
+    let a = world.spawn((1u32,));
+    world.despawn(a);
+    let b = world.spawn((2u32,));
+    assert!(!world.is_alive(a));
+    assert!(!world.despawn(a));
+    assert!(!world.insert(a, 3u32));
+    assert!(world.get::<u32>(a).is_none());
+    assert!(world.get_mut::<u32>(a).is_none());
+    assert_eq!(*world.get::<u32>(b).unwrap(), 2);
+  }
+
+  #[test]
+  fn despawn_removes_components() {
+    let mut world = World::new();
+    let a = world.spawn((1u32, "a"));
+    world.despawn(a);
+    let b = world.spawn(());
+    assert_eq!(b.index(), a.index());
+    assert!(!world.has::<u32>(b));
+    assert!(!world.has::<&str>(b));
+  }
+}
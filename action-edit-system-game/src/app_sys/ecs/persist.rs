@@ -0,0 +1,303 @@
+//! Persistence of the world
+//!
+//! Components registered to `ComponentRegistry` by name are saved to
+//! `WorldData`, which is serializable with any serde format of the
+//! application (toml, json, msgpack). Unregistered components are
+//! not saved.
+//!
+//! `restore` brings the world back to the saved state in place (for
+//! the play in the level editor). The saved entities that are not
+//! alive get new handles, and `restore` returns the map from the
+//! saved handles, to fix the components that refer to entities.
+
+use super::{Component, Entity, World};
+use crate::StdError;
+use hashbrown::{HashMap, HashSet};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Saved world
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct WorldData {
+  pub entities: Vec<EntityData>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EntityData {
+  /// Handle when saved
+  pub id: Entity,
+  /// Components by the registered name
+  pub components: BTreeMap<String, serde_json::Value>,
+}
+
+type SaveFn =
+  fn(
+    &World,
+    Entity,
+  ) -> Option<serde_json::Result<serde_json::Value>>;
+type LoadFn = fn(
+  &mut World,
+  Entity,
+  serde_json::Value,
+) -> serde_json::Result<()>;
+
+type RemoveFn = fn(&mut World, Entity);
+
+struct Registration {
+  name: String,
+  save: SaveFn,
+  load: LoadFn,
+  remove: RemoveFn,
+}
+
+fn save_component<T: Component + Serialize>(
+  world: &World,
+  e: Entity,
+) -> Option<serde_json::Result<serde_json::Value>> {
+  world.get::<T>(e).map(|c| serde_json::to_value(&*c))
+}
+
+fn load_component<T: Component + DeserializeOwned>(
+  world: &mut World,
+  e: Entity,
+  value: serde_json::Value,
+) -> serde_json::Result<()> {
+  world.insert(e, serde_json::from_value::<T>(value)?);
+  Ok(())
+}
+
+fn remove_component<T: Component>(
+  world: &mut World,
+  e: Entity,
+) {
+  world.remove::<T>(e);
+}
+
+#[derive(Default)]
+pub struct ComponentRegistry {
+  components: Vec<Registration>,
+}
+impl ComponentRegistry {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Register the component type by the name. The name is the key
+  /// in the saved data, so it must not be changed.
+  pub fn register<T>(
+    &mut self,
+    name: impl ToString,
+  ) -> &mut Self
+  where
+    T: Component + Serialize + DeserializeOwned,
+  {
+    let name = name.to_string();
+    self.components.retain(|r| r.name != name);
+    self.components.push(Registration {
+      name,
+      save: save_component::<T>,
+      load: load_component::<T>,
+      remove: remove_component::<T>,
+    });
+    self
+  }
+
+  /// Save the registered components of every entity.
+  pub fn save(
+    &self,
+    world: &World,
+  ) -> Result<WorldData, StdError> {
+    let mut entities = Vec::new();
+    for e in world.entities() {
+      let components = self.save_entity(world, e)?;
+      entities.push(EntityData { id: e, components });
+    }
+    Ok(WorldData { entities })
+  }
+
+  /// Registered components of the entity by the name
+  pub fn save_entity(
+    &self,
+    world: &World,
+    e: Entity,
+  ) -> Result<BTreeMap<String, serde_json::Value>, StdError>
+  {
+    let mut components = BTreeMap::new();
+    for r in self.components.iter() {
+      if let Some(value) = (r.save)(world, e) {
+        components.insert(r.name.clone(), value?);
+      }
+    }
+    Ok(components)
+  }
+
+  /// Insert the components by the registered name to the entity.
+  ///
+  /// The components inserted before an error stay in the entity.
+  pub fn insert(
+    &self,
+    world: &mut World,
+    e: Entity,
+    components: &BTreeMap<String, serde_json::Value>,
+  ) -> Result<(), StdError> {
+    for (name, value) in components.iter() {
+      let r = self
+        .components
+        .iter()
+        .find(|r| &r.name == name)
+        .ok_or_else(|| {
+          format!("component \"{name}\" is not registered")
+        })?;
+      (r.load)(world, e, value.clone()).map_err(|err| {
+        format!("component \"{name}\": {err}")
+      })?;
+    }
+    Ok(())
+  }
+
+  /// Bring the world back to the saved state. The entities that
+  /// are not saved are despawned, and the saved entities get the
+  /// saved components in place of the registered ones. The saved
+  /// entities despawned since are spawned again with new handles.
+  /// Unregistered components are kept.
+  ///
+  /// Returns the current handles of the saved handles.
+  pub fn restore(
+    &self,
+    world: &mut World,
+    data: &WorldData,
+  ) -> Result<HashMap<Entity, Entity>, StdError> {
+    let saved = data
+      .entities
+      .iter()
+      .map(|e| e.id)
+      .collect::<HashSet<_>>();
+    let stale = world
+      .entities()
+      .filter(|e| !saved.contains(e))
+      .collect::<Vec<_>>();
+    for e in stale {
+      world.despawn(e);
+    }
+    let mut map = HashMap::new();
+    for entity in data.entities.iter() {
+      let e = if world.is_alive(entity.id) {
+        for r in self.components.iter() {
+          (r.remove)(world, entity.id);
+        }
+        entity.id
+      } else {
+        world.spawn(())
+      };
+      map.insert(entity.id, e);
+      self
+        .insert(world, e, &entity.components)
+        .map_err(|err| format!("{:?}: {err}", entity.id))?;
+    }
+    Ok(map)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct Position {
+    x: f32,
+    y: f32,
+  }
+
+  #[derive(Debug, PartialEq, Serialize, Deserialize)]
+  struct Name(String);
+
+  /// Not registered
+  struct Cache;
+
+  fn registry() -> ComponentRegistry {
+    let mut registry = ComponentRegistry::new();
+    registry
+      .register::<Position>("position")
+      .register::<Name>("name");
+    registry
+  }
+
+  fn world() -> (World, Entity, Entity) {
+    let mut world = World::new();
+    let a = world.spawn((
+      Position { x: 1., y: 2. },
+      Name("a".to_string()),
+      Cache,
+    ));
+    let b = world.spawn((Position { x: 3., y: 4. },));
+    (world, a, b)
+  }
+
+  fn check(
+    world: &World,
+    map: &HashMap<Entity, Entity>,
+    a: Entity,
+    b: Entity,
+  ) {
+    assert_eq!(world.entities().count(), 2);
+    assert_eq!(
+      *world.get::<Position>(map[&a]).unwrap(),
+      Position { x: 1., y: 2. }
+    );
+    assert_eq!(world.get::<Name>(map[&a]).unwrap().0, "a");
+    assert_eq!(
+      *world.get::<Position>(map[&b]).unwrap(),
+      Position { x: 3., y: 4. }
+    );
+    assert!(!world.has::<Name>(map[&b]));
+  }
+
+  #[test]
+  fn round_trips_through_formats() {
+    let registry = registry();
+    let (world, a, b) = world();
+    let data = registry.save(&world).unwrap();
+    let json = serde_json::to_string(&data).unwrap();
+    let toml = toml::to_string(&data).unwrap();
+    let msgpack = rmp_serde::to_vec_named(&data).unwrap();
+    for data in [
+      serde_json::from_str::<WorldData>(&json).unwrap(),
+      toml::from_str::<WorldData>(&toml).unwrap(),
+      rmp_serde::from_slice::<WorldData>(&msgpack).unwrap(),
+    ] {
+      let mut loaded = World::new();
+      let map =
+        registry.restore(&mut loaded, &data).unwrap();
+      check(&loaded, &map, a, b);
+      assert!(!loaded.has::<Cache>(map[&a]));
+    }
+  }
+
+  #[test]
+  fn restore_brings_world_back() {
+    let registry = registry();
+    let (mut world, a, b) = world();
+    let data = registry.save(&world).unwrap();
+    world.get_mut::<Position>(a).unwrap().x = 10.;
+    world.remove::<Name>(a);
+    world.despawn(b);
+    let c = world.spawn((Position { x: 0., y: 0. },));
+    let map = registry.restore(&mut world, &data).unwrap();
+    assert_eq!(map[&a], a);
+    assert!(!world.is_alive(c));
+    check(&world, &map, a, b);
+    assert!(world.has::<Cache>(a));
+  }
+
+  #[test]
+  fn unregistered_name_is_an_error() {
+    let mut data = registry().save(&world().0).unwrap();
+    data.entities[0].components.insert(
+      "velocity".to_string(),
+      serde_json::json!([0, 0]),
+    );
+    assert!(registry()
+      .restore(&mut World::new(), &data)
+      .is_err());
+  }
+}
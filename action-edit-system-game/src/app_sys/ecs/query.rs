@@ -0,0 +1,190 @@
+//! Typed queries
+//!
+//! A query is `&T`, `&mut T` or a tuple of them (up to 4).
+//! The storages are locked while the query runs, so a query that
+//! borrows a component type twice panics.
+//!
+//! ```ignore
+//! world.query::<(&Position, &mut Velocity)>(|e, (pos, vel)| {
+//!   vel.0.y -= 9.8;
+//! });
+//! ```
+
+use super::{storage::SparseSet, Component, Entity, World};
+use parking_lot::{RwLockReadGuard, RwLockWriteGuard};
+
+pub trait Query {
+  /// Locked storages
+  type Guard<'w>;
+  /// Components of an entity
+  type Item<'g>;
+
+  /// Lock the storages. None if a storage does not exist, that is,
+  /// no entity matches.
+  fn lock(world: &World) -> Option<Self::Guard<'_>>;
+
+  /// Candidate entities (the smallest storage)
+  fn entities<'a>(
+    guard: &'a Self::Guard<'_>,
+  ) -> &'a [Entity];
+
+  fn fetch<'g>(
+    guard: &'g mut Self::Guard<'_>,
+    e: Entity,
+  ) -> Option<Self::Item<'g>>;
+}
+
+fn conflict<T>() -> ! {
+  panic!(
+    "component {} is borrowed twice by the query",
+    std::any::type_name::<T>()
+  )
+}
+
+impl<T: Component> Query for &T {
+  type Guard<'w> = RwLockReadGuard<'w, SparseSet<T>>;
+  type Item<'g> = &'g T;
+
+  fn lock(world: &World) -> Option<Self::Guard<'_>> {
+    let storage = world.storage::<T>()?;
+    Some(
+      storage.try_read().unwrap_or_else(|| conflict::<T>()),
+    )
+  }
+
+  fn entities<'a>(
+    guard: &'a Self::Guard<'_>,
+  ) -> &'a [Entity] {
+    guard.entities()
+  }
+
+  fn fetch<'g>(
+    guard: &'g mut Self::Guard<'_>,
+    e: Entity,
+  ) -> Option<Self::Item<'g>> {
+    guard.get(e)
+  }
+}
+
+impl<T: Component> Query for &mut T {
+  type Guard<'w> = RwLockWriteGuard<'w, SparseSet<T>>;
+  type Item<'g> = &'g mut T;
+
+  fn lock(world: &World) -> Option<Self::Guard<'_>> {
+    let storage = world.storage::<T>()?;
+    Some(
+      storage
+        .try_write()
+        .unwrap_or_else(|| conflict::<T>()),
+    )
+  }
+
+  fn entities<'a>(
+    guard: &'a Self::Guard<'_>,
+  ) -> &'a [Entity] {
+    guard.entities()
+  }
+
+  fn fetch<'g>(
+    guard: &'g mut Self::Guard<'_>,
+    e: Entity,
+  ) -> Option<Self::Item<'g>> {
+    guard.get_mut(e)
+  }
+}
+
+macro_rules! impl_query_tuple {
+  ($($q:ident $i:tt),+) => {
+    impl<$($q: Query),+> Query for ($($q,)+) {
+      type Guard<'w> = ($($q::Guard<'w>,)+);
+      type Item<'g> = ($($q::Item<'g>,)+);
+
+      fn lock(world: &World) -> Option<Self::Guard<'_>> {
+        Some(($($q::lock(world)?,)+))
+      }
+
+      fn entities<'a>(
+        guard: &'a Self::Guard<'_>,
+      ) -> &'a [Entity] {
+        [$($q::entities(&guard.$i)),+]
+          .into_iter()
+          .min_by_key(|e| e.len())
+          .unwrap_or_default()
+      }
+
+      fn fetch<'g>(
+        guard: &'g mut Self::Guard<'_>,
+        e: Entity,
+      ) -> Option<Self::Item<'g>> {
+        Some(($($q::fetch(&mut guard.$i, e)?,)+))
+      }
+    }
+  };
+}
+impl_query_tuple!(A 0);
+impl_query_tuple!(A 0, B 1);
+impl_query_tuple!(A 0, B 1, C 2);
+impl_query_tuple!(A 0, B 1, C 2, D 3);
+
+impl World {
+  /// Call the function for every entity that matches the query.
+  pub fn query<Q: Query>(
+    &self,
+    mut f: impl FnMut(Entity, Q::Item<'_>),
+  ) {
+    let Some(mut guard) = Q::lock(self) else {
+      return;
+    };
+    let entities = Q::entities(&guard).to_vec();
+    for e in entities {
+      if let Some(item) = Q::fetch(&mut guard, e) {
+        f(e, item);
+      }
+    }
+  }
+
+  /// Entities that match the query
+  pub fn query_entities<Q: Query>(&self) -> Vec<Entity> {
+    let mut entities = Vec::new();
+    self.query::<Q>(|e, _| entities.push(e));
+    entities
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::super::World;
+
+  #[test]
+  fn query_matches_every_component() {
+    let mut world = World::new();
+    let a = world.spawn((1u32, 1.0f32));
+    world.spawn((2u32,));
+    let c = world.spawn((3u32, 3.0f32));
+    world.query::<(&u32, &mut f32)>(|_, (i, f)| {
+      *f += *i as f32;
+    });
+    assert_eq!(
+      world.query_entities::<(&u32, &f32)>(),
+      [a, c]
+    );
+    assert_eq!(*world.get::<f32>(a).unwrap(), 2.);
+    assert_eq!(*world.get::<f32>(c).unwrap(), 6.);
+  }
+
+  #[test]
+  #[should_panic(expected = "borrowed twice")]
+  fn double_mutable_borrow_panics() {
+    let mut world = World::new();
+    world.spawn((1u32,));
+    world.query::<(&mut u32, &mut u32)>(|_, _| {});
+  }
+
+  #[test]
+  #[should_panic(expected = "borrowed twice")]
+  fn shared_and_mutable_borrow_panics() {
+    let mut world = World::new();
+    world.spawn((1u32,));
+    world.query::<(&u32, &mut u32)>(|_, _| {});
+  }
+}
@@ -0,0 +1,88 @@
+//! Sparse set storage of a component type
+
+use super::Entity;
+
+/// Components packed in the dense arrays, indexed by the entity
+/// index through the sparse array
+pub struct SparseSet<T> {
+  sparse: Vec<Option<u32>>,
+  dense: Vec<Entity>,
+  data: Vec<T>,
+}
+impl<T> SparseSet<T> {
+  pub fn new() -> Self {
+    Self {
+      sparse: Vec::new(),
+      dense: Vec::new(),
+      data: Vec::new(),
+    }
+  }
+
+  fn dense_index(&self, e: Entity) -> Option<usize> {
+    let i =
+      (*self.sparse.get(e.index() as usize)?)? as usize;
+    (self.dense[i] == e).then_some(i)
+  }
+
+  pub fn contains(&self, e: Entity) -> bool {
+    self.dense_index(e).is_some()
+  }
+
+  /// Insert the component. Returns the old one.
+  pub fn insert(
+    &mut self,
+    e: Entity,
+    value: T,
+  ) -> Option<T> {
+    if let Some(i) = self.dense_index(e) {
+      return Some(std::mem::replace(
+        &mut self.data[i],
+        value,
+      ));
+    }
+    let index = e.index() as usize;
+    if self.sparse.len() <= index {
+      self.sparse.resize(index + 1, None);
+    }
+    // An older generation of the index is overwritten.
+    if let Some(i) = self.sparse[index] {
+      let i = i as usize;
+      self.dense[i] = e;
+      self.data[i] = value;
+      return None;
+    }
+    self.sparse[index] = Some(self.dense.len() as u32);
+    self.dense.push(e);
+    self.data.push(value);
+    None
+  }
+
+  pub fn remove(&mut self, e: Entity) -> Option<T> {
+    let i = self.dense_index(e)?;
+    self.sparse[e.index() as usize] = None;
+    self.dense.swap_remove(i);
+    let value = self.data.swap_remove(i);
+    if let Some(moved) = self.dense.get(i) {
+      self.sparse[moved.index() as usize] = Some(i as u32);
+    }
+    Some(value)
+  }
+
+  pub fn get(&self, e: Entity) -> Option<&T> {
+    self.dense_index(e).map(|i| &self.data[i])
+  }
+
+  pub fn get_mut(&mut self, e: Entity) -> Option<&mut T> {
+    self.dense_index(e).map(|i| &mut self.data[i])
+  }
+
+  /// Entities that have the component (in the storage order)
+  pub fn entities(&self) -> &[Entity] {
+    &self.dense
+  }
+}
+impl<T> Default for SparseSet<T> {
+  fn default() -> Self {
+    Self::new()
+  }
+}
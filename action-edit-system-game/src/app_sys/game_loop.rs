@@ -0,0 +1,147 @@
+//! Game loop
+//! 描画と分離した固定タイムステップのゲームループ
+//!
+//! The simulation advances by the fixed step. The real time between
+//! the frames is accumulated, and consumed by the steps. The rest of
+//! the accumulator is the interpolation alpha between the last two
+//! simulation states.
+//!
+//! A frame runs at most `max_steps` steps, and the time over it is
+//! dropped, so a slow frame cannot make the next frame slower.
+//! (spiral of death)
+
+use std::time::Instant;
+
+/// Default simulation rate (Hz)
+pub const DEFAULT_TICK_RATE: f64 = 60.;
+/// Default step limit per frame
+pub const DEFAULT_MAX_STEPS: u32 = 5;
+
+/// Steps to run in a frame
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct FrameSteps {
+  pub steps: u32,
+  /// Position between the previous and the current simulation
+  /// state (0..1)
+  pub alpha: f32,
+}
+
+pub struct GameLoop {
+  /// Simulation step (second)
+  step: f64,
+  max_steps: u32,
+  accumulator: f64,
+  last: Option<Instant>,
+  paused: bool,
+  /// Steps requested while paused
+  step_requests: u32,
+  /// Simulation tick count
+  tick: u64,
+  /// Total time dropped by the step limit (second)
+  dropped: f64,
+}
+impl GameLoop {
+  pub fn new(tick_rate: f64, max_steps: u32) -> Self {
+    Self {
+      step: 1. / tick_rate,
+      max_steps: max_steps.max(1),
+      accumulator: 0.,
+      last: None,
+      paused: false,
+      step_requests: 0,
+      tick: 0,
+      dropped: 0.,
+    }
+  }
+
+  /// Simulation step (second)
+  pub fn dt(&self) -> f32 {
+    self.step as f32
+  }
+
+  /// Simulation rate (Hz)
+  pub fn tick_rate(&self) -> f64 {
+    1. / self.step
+  }
+
+  /// Simulation time (second)
+  pub fn time(&self) -> f64 {
+    self.tick as f64 * self.step
+  }
+
+  /// Pause or resume the simulation. The accumulated time is
+  /// discarded.
+  pub fn set_paused(&mut self, paused: bool) {
+    self.paused = paused;
+    self.accumulator = 0.;
+    self.step_requests = 0;
+  }
+
+  /// Run one step at the next frame while paused.
+  pub fn step_once(&mut self) {
+    if self.paused {
+      self.step_requests += 1;
+    }
+  }
+
+  /// Advance by the real time since the last call.
+  pub fn advance(&mut self) -> FrameSteps {
+    let now = Instant::now();
+    let elapsed = self
+      .last
+      .replace(now)
+      .map_or(0., |last| (now - last).as_secs_f64());
+    self.advance_by(elapsed)
+  }
+
+  /// Advance by the elapsed time (second).
+  pub fn advance_by(&mut self, elapsed: f64) -> FrameSteps {
+    if self.paused {
+      let steps = self.step_requests.min(self.max_steps);
+      self.step_requests -= steps;
+      self.tick += steps as u64;
+      return FrameSteps { steps, alpha: 1. };
+    }
+    self.accumulator += elapsed.max(0.);
+    let steps = ((self.accumulator / self.step) as u64)
+      .min(self.max_steps as u64) as u32;
+    self.accumulator -= steps as f64 * self.step;
+    if self.step <= self.accumulator {
+      let rest = self.accumulator % self.step;
+      self.dropped += self.accumulator - rest;
+      self.accumulator = rest;
+    }
+    self.tick += steps as u64;
+    FrameSteps {
+      steps,
+      alpha: (self.accumulator / self.step) as f32,
+    }
+  }
+
+  pub fn ui(&mut self, ui: &mut egui::Ui) {
+    ui.horizontal(|ui| {
+      let label = if self.paused { "Resume" } else { "Pause" };
+      if ui.button(label).clicked() {
+        self.set_paused(!self.paused);
+      }
+      if ui
+        .add_enabled(self.paused, egui::Button::new("Step"))
+        .clicked()
+      {
+        self.step_once();
+      }
+    });
+    ui.label(format!(
+      "tick {} ({:.2} sec, {:.0} Hz)",
+      self.tick,
+      self.time(),
+      1. / self.step
+    ));
+    ui.label(format!("dropped {:.3} sec", self.dropped));
+  }
+}
+impl Default for GameLoop {
+  fn default() -> Self {
+    Self::new(DEFAULT_TICK_RATE, DEFAULT_MAX_STEPS)
+  }
+}
@@ -1,5 +1,5 @@
 use crate::StdError;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use std::sync::Arc;
 use wgpu::{
   Device, Instance, Queue, RequestAdapterOptionsBase, Surface,
@@ -34,6 +34,7 @@ pub struct AppGfxService {
   queue: Queue,
   config: RwLock<AppGfxConfig>,
   chain_base: render_chain::RenderChainBase,
+  upload_belt: Arc<Mutex<util::UploadBelt>>,
 }
 impl AppGfxService {
   pub async fn new(window: &Arc<Window>) -> Result<Self, StdError> {
@@ -98,9 +99,29 @@ impl AppGfxService {
       queue,
       config,
       chain_base: render_chain::RenderChainBase::new(),
+      upload_belt: Arc::new(Mutex::new(util::UploadBelt::new(
+        util::upload_belt::DEFAULT_CHUNK_SIZE,
+      ))),
     })
   }
 
+  pub fn device(&self) -> &Device {
+    &self.device
+  }
+
+  pub fn queue(&self) -> &Queue {
+    &self.queue
+  }
+
+  pub fn surface_format(&self) -> wgpu::TextureFormat {
+    self.config.read().config.format
+  }
+
+  /// Staging belt shared by the renderers
+  pub fn upload_belt(&self) -> &Arc<Mutex<util::UploadBelt>> {
+    &self.upload_belt
+  }
+
   pub fn reconfigure(&self) {
     self.config.read().configure(&self.device, &self.surface);
   }
@@ -1,36 +1,49 @@
-use crate::{app_sys::gfx::render_chain, StdError};
+use crate::app_sys::gfx::{render_chain, util::UploadBelt};
 use bytemuck::{Pod, Zeroable};
 use nalgebra::{Matrix4, Vector4};
+use parking_lot::Mutex;
 use std::sync::Arc;
 use wgpu::{
   util::DeviceExt, BindGroup, BindGroupLayout, Buffer,
-  BufferAsyncError, BufferDescriptor, BufferUsages, Device,
-  MapMode,
+  BufferUsages, Device,
 };
 
+#[derive(Debug, Clone)]
 pub struct Camera2D {
   pub pos: nalgebra::Point2<f32>,
   pub size: nalgebra::Vector2<f32>,
   pub rot: f32,
   pub zoom: f32,
 }
+impl Camera2D {
+  /// Camera that maps one world unit to one pixel of the view
+  pub fn with_view_size(width: f32, height: f32) -> Self {
+    let mut camera = Self {
+      pos: nalgebra::Point2::origin(),
+      size: nalgebra::Vector2::zeros(),
+      rot: 0.,
+      zoom: 1.,
+    };
+    camera.set_view_size(width, height);
+    camera
+  }
+  pub fn set_view_size(&mut self, width: f32, height: f32) {
+    self.size = nalgebra::Vector2::new(2. / width, 2. / height);
+  }
+}
 
 pub struct Camera2DWGPUObject {
   uniform: Camera2DUniform,
-  mapped: Vec<Arc<Buffer>>,
-  recv: crossbeam::channel::Receiver<
-    Result<Arc<Buffer>, BufferAsyncError>,
-  >,
-  send: crossbeam::channel::Sender<
-    Result<Arc<Buffer>, BufferAsyncError>,
-  >,
+  upload_belt: Arc<Mutex<UploadBelt>>,
   buffer: Buffer,
   bindgroup_layout: BindGroupLayout,
   bindgroup: BindGroup,
 }
 impl Camera2DWGPUObject {
-  pub fn new(device: &Device) -> Self {
-    let (send, recv) = crossbeam::channel::unbounded();
+  pub fn new(
+    device: &Device,
+    upload_belt: Arc<Mutex<UploadBelt>>,
+  ) -> Self {
     let buffer = device.create_buffer_init(
       &wgpu::util::BufferInitDescriptor {
         label: Some("2D Camera buffer"),
@@ -68,52 +81,17 @@ impl Camera2DWGPUObject {
     );
     Self {
       uniform: Camera2DUniform::new(),
-      mapped: Vec::new(),
-      recv,
-      send,
+      upload_belt,
       bindgroup_layout,
       bindgroup,
       buffer,
     }
   }
-  fn update(
-    &mut self,
-    device: &Device,
-    camera: &Camera2D,
-  ) -> Result<Arc<Buffer>, StdError> {
-    self.uniform.update(camera);
-    let mapped = if 1 < self.mapped.len() {
-      self.mapped.swap_remove(0)
-    } else {
-      self.recv.iter().fold(
-        Ok::<_, StdError>(()),
-        |e, b| {
-          e?;
-          self.mapped.push(b?);
-          Ok(())
-        },
-      )?;
-      if 1 < self.mapped.len() {
-        self.mapped.swap_remove(0)
-      } else {
-        Arc::new(device.create_buffer(&BufferDescriptor {
-          label: Some(
-            "(internal) Camera2DWGPUObject Staging buffer",
-          ),
-          size: std::mem::size_of_val(&self.uniform.0) as _,
-          usage: BufferUsages::COPY_SRC
-            | BufferUsages::MAP_WRITE,
-          mapped_at_creation: true,
-        }))
-      }
-    };
-    mapped
-      .slice(..)
-      .get_mapped_range_mut()
-      .copy_from_slice(bytemuck::cast_slice(
-        &self.uniform.0,
-      ));
-    Ok(mapped)
+  pub fn bindgroup_layout(&self) -> &BindGroupLayout {
+    &self.bindgroup_layout
+  }
+  pub fn bindgroup(&self) -> &BindGroup {
+    &self.bindgroup
   }
 }
 impl<'c> render_chain::Renderer<&'c Camera2D>
@@ -135,21 +113,14 @@ impl<'c> render_chain::Renderer<&'c Camera2D>
     crate::app_sys::RenderChainCommand,
     crate::StdError,
   > {
-    let staging = self.update(device, camera)?;
-    encoder[0].copy_buffer_to_buffer(
-      &staging,
-      0,
+    self.uniform.update(camera);
+    self.upload_belt.lock().write(
+      device,
+      &mut encoder[0],
       &self.buffer,
       0,
-      std::mem::size_of_val(&self.uniform.0) as _,
-    );
-    let send = self.send.clone();
-    staging
-      .clone()
-      .slice(..)
-      .map_async(MapMode::Write, move |e| {
-        send.send(e.map(|_| staging)).unwrap()
-      });
+      bytemuck::cast_slice(&self.uniform.0),
+    )?;
     Ok(crate::app_sys::RenderChainCommand::AllowContinue)
   }
 }
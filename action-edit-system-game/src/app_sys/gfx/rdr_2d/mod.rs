@@ -1,5 +1,13 @@
+use super::util::UploadBelt;
+use crate::StdError;
 use bytemuck::{Pod, Zeroable};
-use wgpu::{vertex_attr_array, VertexAttribute, VertexBufferLayout};
+use std::marker::PhantomData;
+use wgpu::{
+  vertex_attr_array, BindGroup, BindGroupLayout, Buffer,
+  BufferUsages, CommandEncoder, Device, RenderPipeline,
+  ShaderModule, Texture, TextureFormat, VertexAttribute,
+  VertexBufferLayout,
+};
 pub mod camera;
 pub mod square;
 pub mod tile;
@@ -44,3 +52,195 @@ pub const VERTICES: &'static [Vertex] = &[
 ];
 
 pub const INDICES: &'static [u16] = &[0, 1, 3, 0, 3, 2];
+
+/// GPU side instance buffer that is updated through the upload belt
+pub struct InstanceBuffer<T> {
+  label: &'static str,
+  buffer: Buffer,
+  capacity: usize,
+  len: u32,
+  _marker: PhantomData<T>,
+}
+impl<T: Pod> InstanceBuffer<T> {
+  pub fn new(
+    device: &Device,
+    label: &'static str,
+    capacity: usize,
+  ) -> Self {
+    Self {
+      label,
+      buffer: Self::create_buffer(device, label, capacity),
+      capacity,
+      len: 0,
+      _marker: PhantomData,
+    }
+  }
+
+  fn create_buffer(
+    device: &Device,
+    label: &'static str,
+    capacity: usize,
+  ) -> Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+      label: Some(label),
+      size: (std::mem::size_of::<T>() * capacity.max(1)) as _,
+      usage: BufferUsages::VERTEX | BufferUsages::COPY_DST,
+      mapped_at_creation: false,
+    })
+  }
+
+  /// Replace the contents. The buffer grows when it is too small.
+  pub fn upload(
+    &mut self,
+    device: &Device,
+    encoder: &mut CommandEncoder,
+    upload_belt: &mut UploadBelt,
+    instances: &[T],
+  ) -> Result<(), StdError> {
+    if self.capacity < instances.len() {
+      self.capacity = instances.len().next_power_of_two();
+      self.buffer =
+        Self::create_buffer(device, self.label, self.capacity);
+    }
+    upload_belt.write(
+      device,
+      encoder,
+      &self.buffer,
+      0,
+      bytemuck::cast_slice(instances),
+    )?;
+    self.len = instances.len() as u32;
+    Ok(())
+  }
+
+  pub fn len(&self) -> u32 {
+    self.len
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  pub fn buffer(&self) -> &Buffer {
+    &self.buffer
+  }
+}
+
+/// Bindgroup layout of the diffuse texture (group 0)
+pub fn diffuse_bindgroup_layout(device: &Device) -> BindGroupLayout {
+  device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+    label: Some("2D Diffuse texture bindgroup layout"),
+    entries: &[
+      wgpu::BindGroupLayoutEntry {
+        binding: 0,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Texture {
+          multisampled: false,
+          view_dimension: wgpu::TextureViewDimension::D2,
+          sample_type: wgpu::TextureSampleType::Float {
+            filterable: true,
+          },
+        },
+        count: None,
+      },
+      wgpu::BindGroupLayoutEntry {
+        binding: 1,
+        visibility: wgpu::ShaderStages::FRAGMENT,
+        ty: wgpu::BindingType::Sampler(
+          wgpu::SamplerBindingType::Filtering,
+        ),
+        count: None,
+      },
+    ],
+  })
+}
+
+/// Bindgroup of the diffuse texture. (Nearest filtering)
+pub fn diffuse_bindgroup(
+  device: &Device,
+  layout: &BindGroupLayout,
+  texture: &Texture,
+) -> BindGroup {
+  let view =
+    texture.create_view(&wgpu::TextureViewDescriptor::default());
+  let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+    label: Some("2D Diffuse sampler"),
+    address_mode_u: wgpu::AddressMode::ClampToEdge,
+    address_mode_v: wgpu::AddressMode::ClampToEdge,
+    address_mode_w: wgpu::AddressMode::ClampToEdge,
+    mag_filter: wgpu::FilterMode::Nearest,
+    min_filter: wgpu::FilterMode::Nearest,
+    mipmap_filter: wgpu::FilterMode::Nearest,
+    ..Default::default()
+  });
+  device.create_bind_group(&wgpu::BindGroupDescriptor {
+    label: Some("2D Diffuse bindgroup"),
+    layout,
+    entries: &[
+      wgpu::BindGroupEntry {
+        binding: 0,
+        resource: wgpu::BindingResource::TextureView(&view),
+      },
+      wgpu::BindGroupEntry {
+        binding: 1,
+        resource: wgpu::BindingResource::Sampler(&sampler),
+      },
+    ],
+  })
+}
+
+/// Pipeline of the instanced quad renderers
+///
+/// group 0 is the diffuse texture, group 1 is the camera.
+pub fn create_pipeline(
+  device: &Device,
+  label: &str,
+  shader: &ShaderModule,
+  format: TextureFormat,
+  diffuse_bindgroup_layout: &BindGroupLayout,
+  camera_bindgroup_layout: &BindGroupLayout,
+  instance_desc: VertexBufferLayout<'static>,
+) -> RenderPipeline {
+  let pipeline_layout =
+    device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+      label: Some(label),
+      bind_group_layouts: &[
+        diffuse_bindgroup_layout,
+        camera_bindgroup_layout,
+      ],
+      push_constant_ranges: &[],
+    });
+  device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+    label: Some(label),
+    layout: Some(&pipeline_layout),
+    vertex: wgpu::VertexState {
+      module: shader,
+      entry_point: Some("vs_main"),
+      compilation_options: Default::default(),
+      buffers: &[Vertex::desc(), instance_desc],
+    },
+    fragment: Some(wgpu::FragmentState {
+      module: shader,
+      entry_point: Some("fs_main"),
+      compilation_options: Default::default(),
+      targets: &[Some(wgpu::ColorTargetState {
+        format,
+        blend: Some(wgpu::BlendState::ALPHA_BLENDING),
+        write_mask: wgpu::ColorWrites::all(),
+      })],
+    }),
+    primitive: wgpu::PrimitiveState {
+      topology: wgpu::PrimitiveTopology::TriangleList,
+      strip_index_format: None,
+      front_face: wgpu::FrontFace::Ccw,
+      cull_mode: None,
+      polygon_mode: wgpu::PolygonMode::Fill,
+      unclipped_depth: false,
+      conservative: false,
+    },
+    depth_stencil: None,
+    multisample: wgpu::MultisampleState::default(),
+    multiview: None,
+    cache: None,
+  })
+}
@@ -1,10 +1,15 @@
-use std::sync::Arc;
+use std::{ops::Range, sync::Arc};
 
+use super::camera::Camera2DWGPUObject;
+use crate::app_sys::gfx::{
+  render_chain,
+  util::{TextureID, TextureStorage, UploadBelt},
+};
 use bytemuck::{Pod, Zeroable};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use wgpu::{
-  vertex_attr_array, BindGroup, BindGroupLayout, Buffer,
-  PipelineLayout, RenderPipeline, VertexAttribute,
+  util::DeviceExt, vertex_attr_array, Buffer, Device,
+  RenderPipeline, TextureFormat, VertexAttribute,
   VertexBufferLayout,
 };
 
@@ -35,11 +40,151 @@ impl Instance {
 }
 
 pub struct SquareRenderer {
-  camera: Arc<RwLock<super::camera::Camera2DWGPUObject>>,
+  camera: Arc<RwLock<Camera2DWGPUObject>>,
+  upload_belt: Arc<Mutex<UploadBelt>>,
   vertices: Buffer,
   indices: Buffer,
-  pipeline_layout: PipelineLayout,
   pipeline: RenderPipeline,
-  diffuse_bindgroup_layout: BindGroupLayout,
-  diffuse_bindgroup: BindGroup,
+  textures: Arc<RwLock<TextureStorage>>,
+  /// インスタンスの格納ベクトルと更新フラグ
+  instances: (Vec<Instance>, bool),
+  /// Runs of the instances that use the same texture
+  batches: Vec<(TextureID, Range<u32>)>,
+  instance_buffer: super::InstanceBuffer<Instance>,
+}
+impl SquareRenderer {
+  pub fn new(
+    device: &Device,
+    format: TextureFormat,
+    camera: Arc<RwLock<Camera2DWGPUObject>>,
+    upload_belt: Arc<Mutex<UploadBelt>>,
+    textures: Arc<RwLock<TextureStorage>>,
+  ) -> Self {
+    let vertices =
+      device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Square renderer vertex buffer"),
+        contents: bytemuck::cast_slice(super::VERTICES),
+        usage: wgpu::BufferUsages::VERTEX,
+      });
+    let indices =
+      device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        label: Some("Square renderer index buffer"),
+        contents: bytemuck::cast_slice(super::INDICES),
+        usage: wgpu::BufferUsages::INDEX,
+      });
+    let shader =
+      device.create_shader_module(wgpu::ShaderModuleDescriptor {
+        label: Some("Square renderer shader"),
+        source: wgpu::ShaderSource::Wgsl(
+          include_str!("square.wgsl").into(),
+        ),
+      });
+    let pipeline = super::create_pipeline(
+      device,
+      "Square renderer pipeline",
+      &shader,
+      format,
+      textures.write().bindgroup_layout(device),
+      camera.read().bindgroup_layout(),
+      Instance::desc(),
+    );
+    Self {
+      camera,
+      upload_belt,
+      vertices,
+      indices,
+      pipeline,
+      textures,
+      instances: (Vec::new(), false),
+      batches: Vec::new(),
+      instance_buffer: super::InstanceBuffer::new(
+        device,
+        "Square renderer instance buffer",
+        64,
+      ),
+    }
+  }
+
+  /// Replace the instances. They are drawn in the given order.
+  pub fn set_instances(
+    &mut self,
+    instances: impl IntoIterator<Item = (TextureID, Instance)>,
+  ) {
+    self.instances.0.clear();
+    self.batches.clear();
+    for (texture, instance) in instances {
+      let i = self.instances.0.len() as u32;
+      match self.batches.last_mut() {
+        Some((t, range)) if *t == texture => range.end = i + 1,
+        _ => self.batches.push((texture, i..i + 1)),
+      }
+      self.instances.0.push(instance);
+    }
+    self.instances.1 = true;
+  }
+}
+impl render_chain::Renderer<()> for SquareRenderer {
+  fn request_encoder_count(&self) -> usize {
+    1
+  }
+
+  fn rendering(
+    &mut self,
+    _surface_texture: &wgpu::SurfaceTexture,
+    surface_view: &wgpu::TextureView,
+    device: &wgpu::Device,
+    _queue: &wgpu::Queue,
+    encoder: &mut [wgpu::CommandEncoder],
+    _param: (),
+  ) -> Result<render_chain::RenderChainCommand, crate::StdError> {
+    if self.instances.1 {
+      self.instance_buffer.upload(
+        device,
+        &mut encoder[0],
+        &mut self.upload_belt.lock(),
+        &self.instances.0,
+      )?;
+      self.instances.1 = false;
+    }
+    if self.instance_buffer.is_empty() {
+      return Ok(render_chain::RenderChainCommand::AllowContinue);
+    }
+    let camera = self.camera.read();
+    let textures = self.textures.read();
+    let mut rpass =
+      encoder[0].begin_render_pass(&wgpu::RenderPassDescriptor {
+        label: Some("Square renderer"),
+        color_attachments: &[Some(wgpu::RenderPassColorAttachment {
+          view: surface_view,
+          resolve_target: None,
+          ops: wgpu::Operations {
+            load: wgpu::LoadOp::Load,
+            store: wgpu::StoreOp::Store,
+          },
+        })],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+      });
+    rpass.set_pipeline(&self.pipeline);
+    rpass.set_bind_group(1, camera.bindgroup(), &[]);
+    rpass.set_vertex_buffer(0, self.vertices.slice(..));
+    rpass.set_vertex_buffer(1, self.instance_buffer.buffer().slice(..));
+    rpass.set_index_buffer(
+      self.indices.slice(..),
+      wgpu::IndexFormat::Uint16,
+    );
+    for (texture, range) in self.batches.iter() {
+      let Some(bindgroup) = textures.bindgroup(*texture) else {
+        continue;
+      };
+      rpass.set_bind_group(0, bindgroup, &[]);
+      rpass.draw_indexed(
+        0..super::INDICES.len() as _,
+        0,
+        range.clone(),
+      );
+    }
+    Ok(render_chain::RenderChainCommand::AllowContinue)
+  }
 }
@@ -1,18 +1,32 @@
+use std::sync::Arc;
+
+use super::camera::Camera2DWGPUObject;
+use crate::app_sys::gfx::{
+  render_chain,
+  util::{TextureID, TextureStorage, UploadBelt},
+};
 use bytemuck::{Pod, Zeroable};
-use wgpu::{vertex_attr_array, VertexAttribute, VertexBufferLayout};
+use parking_lot::{Mutex, RwLock};
+use wgpu::{
+  util::DeviceExt, vertex_attr_array, Buffer, Device,
+  RenderPipeline, TextureFormat, VertexAttribute,
+  VertexBufferLayout,
+};
 
 #[repr(C)]
 #[derive(Debug, Clone, Copy, Pod, Zeroable)]
 pub struct Instance {
   pub pos: [f32; 2],
-  pub filter: [f32; 2],
+  pub size: [f32; 2],
+  pub filter: [f32; 4],
   pub uv: [[f32; 2]; 2],
 }
 impl Instance {
-  pub const VB_ATTRIB: [VertexAttribute; 3] = vertex_attr_array![
+  pub const VB_ATTRIB: [VertexAttribute; 4] = vertex_attr_array![
     5 => Float32x2,
-    6 => Float32x4,
+    6 => Float32x2,
     7 => Float32x4,
+    8 => Float32x4,
   ];
   pub fn desc() -> VertexBufferLayout<'static> {
     VertexBufferLayout {
@@ -22,3 +36,189 @@ impl Instance {
     }
   }
 }
+
+/// Block of tiles that is uploaded at once
+pub struct TileChunk {
+  /// Tileset texture of the chunk
+  pub texture: Option<TextureID>,
+  /// インスタンスの格納ベクトルと更新フラグ
+  instances: (Vec<Instance>, bool),
+  instance_buffer: super::InstanceBuffer<Instance>,
+}
+impl TileChunk {
+  pub fn new(device: &Device) -> Self {
+    Self {
+      texture: None,
+      instances: (Vec::new(), false),
+      instance_buffer: super::InstanceBuffer::new(
+        device,
+        "Tile chunk instance buffer",
+        0,
+      ),
+    }
+  }
+
+  pub fn set_instances(
+    &mut self,
+    instances: impl IntoIterator<Item = Instance>,
+  ) {
+    self.instances.0.clear();
+    self.instances.0.extend(instances);
+    self.instances.1 = true;
+  }
+
+  fn upload(
+    &mut self,
+    device: &Device,
+    encoder: &mut wgpu::CommandEncoder,
+    upload_belt: &mut UploadBelt,
+  ) -> Result<(), crate::StdError> {
+    if self.instances.1 {
+      self.instance_buffer.upload(
+        device,
+        encoder,
+        upload_belt,
+        &self.instances.0,
+      )?;
+      self.instances.1 = false;
+    }
+    Ok(())
+  }
+}
+
+pub struct TileRenderer {
+  camera: Arc<RwLock<Camera2DWGPUObject>>,
+  upload_belt: Arc<Mutex<UploadBelt>>,
+  vertices: Buffer,
+  indices: Buffer,
+  pipeline: RenderPipeline,
+  textures: Arc<RwLock<TextureStorage>>,
+}
+impl TileRenderer {
+  pub fn new(
+    device: &Device,
+    format: TextureFormat,
+    camera: Arc<RwLock<Camera2DWGPUObject>>,
+    upload_belt: Arc<Mutex<UploadBelt>>,
+    textures: Arc<RwLock<TextureStorage>>,
+  ) -> Self {
+    let vertices = device.create_buffer_init(
+      &wgpu::util::BufferInitDescriptor {
+        label: Some("Tile renderer vertex buffer"),
+        contents: bytemuck::cast_slice(super::VERTICES),
+        usage: wgpu::BufferUsages::VERTEX,
+      },
+    );
+    let indices = device.create_buffer_init(
+      &wgpu::util::BufferInitDescriptor {
+        label: Some("Tile renderer index buffer"),
+        contents: bytemuck::cast_slice(super::INDICES),
+        usage: wgpu::BufferUsages::INDEX,
+      },
+    );
+    let shader = device.create_shader_module(
+      wgpu::ShaderModuleDescriptor {
+        label: Some("Tile renderer shader"),
+        source: wgpu::ShaderSource::Wgsl(
+          include_str!("tile.wgsl").into(),
+        ),
+      },
+    );
+    let pipeline = super::create_pipeline(
+      device,
+      "Tile renderer pipeline",
+      &shader,
+      format,
+      textures.write().bindgroup_layout(device),
+      camera.read().bindgroup_layout(),
+      Instance::desc(),
+    );
+    Self {
+      camera,
+      upload_belt,
+      vertices,
+      indices,
+      pipeline,
+      textures,
+    }
+  }
+}
+impl<'c> render_chain::Renderer<&'c mut [TileChunk]>
+  for TileRenderer
+{
+  fn request_encoder_count(&self) -> usize {
+    1
+  }
+
+  fn rendering(
+    &mut self,
+    _surface_texture: &wgpu::SurfaceTexture,
+    surface_view: &wgpu::TextureView,
+    device: &wgpu::Device,
+    _queue: &wgpu::Queue,
+    encoder: &mut [wgpu::CommandEncoder],
+    chunks: &'c mut [TileChunk],
+  ) -> Result<
+    render_chain::RenderChainCommand,
+    crate::StdError,
+  > {
+    {
+      let mut upload_belt = self.upload_belt.lock();
+      for chunk in chunks.iter_mut() {
+        chunk.upload(
+          device,
+          &mut encoder[0],
+          &mut upload_belt,
+        )?;
+      }
+    }
+    let camera = self.camera.read();
+    let textures = self.textures.read();
+    let mut rpass = encoder[0].begin_render_pass(
+      &wgpu::RenderPassDescriptor {
+        label: Some("Tile renderer"),
+        color_attachments: &[Some(
+          wgpu::RenderPassColorAttachment {
+            view: surface_view,
+            resolve_target: None,
+            ops: wgpu::Operations {
+              load: wgpu::LoadOp::Load,
+              store: wgpu::StoreOp::Store,
+            },
+          },
+        )],
+        depth_stencil_attachment: None,
+        timestamp_writes: None,
+        occlusion_query_set: None,
+      },
+    );
+    rpass.set_pipeline(&self.pipeline);
+    rpass.set_bind_group(1, camera.bindgroup(), &[]);
+    rpass.set_vertex_buffer(0, self.vertices.slice(..));
+    rpass.set_index_buffer(
+      self.indices.slice(..),
+      wgpu::IndexFormat::Uint16,
+    );
+    for chunk in chunks
+      .iter()
+      .filter(|c| !c.instance_buffer.is_empty())
+    {
+      let Some(bindgroup) =
+        chunk.texture.and_then(|t| textures.bindgroup(t))
+      else {
+        continue;
+      };
+      rpass.set_bind_group(0, bindgroup, &[]);
+      rpass.set_vertex_buffer(
+        1,
+        chunk.instance_buffer.buffer().slice(..),
+      );
+      rpass.draw_indexed(
+        0..super::INDICES.len() as _,
+        0,
+        0..chunk.instance_buffer.len(),
+      );
+    }
+    Ok(render_chain::RenderChainCommand::AllowContinue)
+  }
+}
@@ -0,0 +1,49 @@
+struct CameraUniform {
+  view_proj: mat4x4<f32>,
+}
+@group(1) @binding(0)
+var<uniform> camera: CameraUniform;
+
+struct VertexInput {
+  @location(0) pos: vec2<f32>,
+  @location(1) uv: vec2<f32>,
+}
+
+struct InstanceInput {
+  @location(5) pos: vec2<f32>,
+  @location(6) size: vec2<f32>,
+  @location(7) filter: vec4<f32>,
+  @location(8) uv: vec4<f32>,
+}
+
+struct VertexOutput {
+  @builtin(position) clip_position: vec4<f32>,
+  @location(0) uv: vec2<f32>,
+  @location(1) filter: vec4<f32>,
+}
+
+@vertex
+fn vs_main(
+  model: VertexInput,
+  instance: InstanceInput,
+) -> VertexOutput {
+  var out: VertexOutput;
+  out.uv = instance.uv.xy + model.uv * instance.uv.zw;
+  out.filter = instance.filter;
+  out.clip_position = camera.view_proj * vec4<f32>(
+    instance.pos + instance.size * model.pos,
+    1.,
+    1.,
+  );
+  return out;
+}
+
+@group(0) @binding(0)
+var t_diffuse: texture_2d<f32>;
+@group(0) @binding(1)
+var s_diffuse: sampler;
+
+@fragment
+fn fs_main(in: VertexOutput) -> @location(0) vec4<f32> {
+  return textureSample(t_diffuse, s_diffuse, in.uv) * in.filter;
+}
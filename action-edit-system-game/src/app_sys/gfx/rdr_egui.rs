@@ -1,10 +1,12 @@
 use std::sync::Arc;
 
+use super::util::{TextureID, TextureStorage};
 use egui::{
   epaint::text::FontInsert, Context, FontDefinitions, ViewportId,
 };
 use egui_wgpu::ScreenDescriptor;
 use egui_winit::{EventResponse, State};
+use hashbrown::HashMap;
 use wgpu::TextureFormat;
 use winit::{event::WindowEvent, window::Window};
 
@@ -12,6 +14,8 @@ pub struct EguiRenderer {
   state: State,
   renderer: egui_wgpu::Renderer,
   pixels_per_point: f32,
+  /// Egui textures of the `TextureStorage` textures
+  native_textures: HashMap<TextureID, egui::TextureId>,
 }
 impl EguiRenderer {
   pub fn new(
@@ -43,6 +47,7 @@ impl EguiRenderer {
       state,
       renderer,
       pixels_per_point,
+      native_textures: HashMap::new(),
     }
   }
   pub fn set_pixels_per_point(&mut self, v: f32) {
@@ -76,6 +81,44 @@ impl EguiRenderer {
   pub fn add_font(&self, font_insert: FontInsert) {
     self.state.egui_ctx().add_font(font_insert)
   }
+  /// Make the storage textures drawable by egui. `uploaded` is the
+  /// result of `TextureStorage::prepare`.
+  pub fn sync_textures(
+    &mut self,
+    device: &wgpu::Device,
+    textures: &TextureStorage,
+    uploaded: &[TextureID],
+  ) {
+    let renderer = &mut self.renderer;
+    self.native_textures.retain(|id, egui_id| {
+      let keep = textures.contains(*id);
+      if !keep {
+        renderer.free_texture(egui_id);
+      }
+      keep
+    });
+    for id in uploaded {
+      let Some(texture) = textures.texture(*id) else {
+        continue;
+      };
+      let view = texture.create_view(&Default::default());
+      let filter = wgpu::FilterMode::Nearest;
+      match self.native_textures.get(id) {
+        Some(egui_id) => renderer
+          .update_egui_texture_from_wgpu_texture(
+            device, &view, filter, *egui_id,
+          ),
+        None => {
+          let egui_id =
+            renderer.register_native_texture(device, &view, filter);
+          self.native_textures.insert(*id, egui_id);
+        }
+      }
+    }
+  }
+  pub fn native_textures(&self) -> &HashMap<TextureID, egui::TextureId> {
+    &self.native_textures
+  }
 }
 
 impl<'c, 'd, F> super::render_chain::Renderer<(&'d Arc<Window>, F)>
@@ -3,6 +3,7 @@ use parking_lot::{
   lock_api::{MappedMutexGuard, MutexGuard},
   Mutex,
 };
+use std::sync::Arc;
 use wgpu::{CommandEncoder, Device, Queue};
 
 /// Trait for renderer that use WGPU
@@ -78,6 +79,7 @@ pub struct RenderChain<'gfx> {
   device: &'gfx Device,
   queue: &'gfx Queue,
   base: &'gfx RenderChainBase,
+  upload_belt: &'gfx Arc<Mutex<super::util::UploadBelt>>,
   error: Result<(), StdError>,
 }
 impl<'gfx> RenderChain<'gfx> {
@@ -92,6 +94,7 @@ impl<'gfx> RenderChain<'gfx> {
       device: &context.device,
       queue: &context.queue,
       base: &context.chain_base,
+      upload_belt: &context.upload_belt,
       error: Ok(()),
     }
   }
@@ -124,6 +127,7 @@ impl<'gfx> RenderChain<'gfx> {
               device: self.device,
               queue: self.queue,
               base: self.base,
+              upload_belt: self.upload_belt,
               error: Ok(()),
             }
           }
@@ -135,6 +139,7 @@ impl<'gfx> RenderChain<'gfx> {
               device: self.device,
               queue: self.queue,
               base: self.base,
+              upload_belt: self.upload_belt,
               error: Err(e),
             }
           }
@@ -148,6 +153,7 @@ impl<'gfx> RenderChain<'gfx> {
           device: self.device,
           queue: self.queue,
           base: self.base,
+          upload_belt: self.upload_belt,
           error: Err(e),
         }
       }
@@ -156,9 +162,11 @@ impl<'gfx> RenderChain<'gfx> {
 
   pub fn finish(self) -> Result<(), StdError> {
     self.base.submit();
+    self.upload_belt.lock().finish();
     self
       .queue
       .submit(self.base.finished.lock().drain(..).map(|e| e.finish()));
+    self.upload_belt.lock().recall();
     self.texture.present();
     self.error
   }
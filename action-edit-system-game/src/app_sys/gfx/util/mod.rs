@@ -1,18 +1,27 @@
-use std::collections::VecDeque;
+use std::path::Path;
 
+use crate::StdError;
 use bytemuck::{Pod, Zeroable};
 use hashbrown::HashMap;
-use wgpu::{BindGroup, Texture};
+use wgpu::{BindGroup, BindGroupLayout, Device, Queue, Texture};
+pub mod upload_belt;
+pub use upload_belt::UploadBelt;
 
+/// Named texture storage
+///
+/// Images are registered on the CPU side, and uploaded to the GPU
+/// on the next `prepare`. The textures are not removed: registering
+/// a name again replaces its texture in the same slot.
 pub struct TextureStorage {
   table: HashMap<String, TextureID>,
+  /// Pixel data waiting for the upload
   image: Vec<Option<Vec<u8>>>,
   pixel_size: Vec<usize>,
   size: Vec<[u32; 2]>,
   size_f: Vec<[f32; 2]>,
   texture: Vec<Option<Texture>>,
   bindgroup: Vec<Option<BindGroup>>,
-  remove_queue: VecDeque<TextureID>,
+  bindgroup_layout: Option<BindGroupLayout>,
   section: Box<TextureStorageSection>,
 }
 
@@ -20,13 +29,285 @@ pub struct TextureStorageSection {
   table: Vec<Option<HashMap<String, TextureSectionID>>>,
   range: Vec<Option<Vec<[[u32; 2]; 2]>>>,
   range_f: Vec<Option<Vec<[[f32; 2]; 2]>>>,
-  remove_queue: VecDeque<TextureSectionID>,
 }
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+  Pod,
+  Zeroable,
+)]
 pub struct TextureID(u32);
+impl TextureID {
+  pub fn from_raw(raw: u32) -> Self {
+    Self(raw)
+  }
+  pub fn raw(self) -> u32 {
+    self.0
+  }
+}
 
 #[repr(C)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Pod, Zeroable)]
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  PartialOrd,
+  Ord,
+  Hash,
+  Pod,
+  Zeroable,
+)]
 pub struct TextureSectionID(u32);
+impl TextureSectionID {
+  pub fn raw(self) -> u32 {
+    self.0
+  }
+}
+
+impl TextureStorage {
+  pub fn new() -> Self {
+    Self {
+      table: HashMap::new(),
+      image: Vec::new(),
+      pixel_size: Vec::new(),
+      size: Vec::new(),
+      size_f: Vec::new(),
+      texture: Vec::new(),
+      bindgroup: Vec::new(),
+      bindgroup_layout: None,
+      section: Box::new(TextureStorageSection {
+        table: Vec::new(),
+        range: Vec::new(),
+        range_f: Vec::new(),
+      }),
+    }
+  }
+
+  /// Register the image. A texture with the same name is replaced.
+  pub fn register(
+    &mut self,
+    name: impl ToString,
+    image: image::RgbaImage,
+  ) -> TextureID {
+    let name = name.to_string();
+    let size = [image.width(), image.height()];
+    let id = match self.table.get(&name) {
+      Some(id) => *id,
+      None => {
+        let id = TextureID(self.image.len() as u32);
+        self.image.push(None);
+        self.pixel_size.push(4);
+        self.size.push([0; 2]);
+        self.size_f.push([0.; 2]);
+        self.texture.push(None);
+        self.bindgroup.push(None);
+        self.section.table.push(None);
+        self.section.range.push(None);
+        self.section.range_f.push(None);
+        self.table.insert(name, id);
+        id
+      }
+    };
+    let i = id.0 as usize;
+    self.image[i] = Some(image.into_raw());
+    self.size[i] = size;
+    self.size_f[i] = [size[0] as f32, size[1] as f32];
+    self.texture[i] = None;
+    self.bindgroup[i] = None;
+    self.section.table[i] = Some(HashMap::new());
+    self.section.range[i] = Some(Vec::new());
+    self.section.range_f[i] = Some(Vec::new());
+    id
+  }
+
+  /// Load the image file and register it.
+  pub fn load(
+    &mut self,
+    name: impl ToString,
+    path: impl AsRef<Path>,
+  ) -> Result<TextureID, StdError> {
+    let image = image::open(path)?.to_rgba8();
+    Ok(self.register(name, image))
+  }
+
+  pub fn get_id(&self, name: &str) -> Option<TextureID> {
+    self.table.get(name).copied()
+  }
+
+  pub fn contains(&self, id: TextureID) -> bool {
+    self.table.values().any(|v| *v == id)
+  }
+
+  pub fn name(&self, id: TextureID) -> Option<&str> {
+    self
+      .table
+      .iter()
+      .find(|(_, v)| **v == id)
+      .map(|(k, _)| k.as_str())
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item = (&str, TextureID)> {
+    self.table.iter().map(|(k, v)| (k.as_str(), *v))
+  }
+
+  pub fn size(&self, id: TextureID) -> Option<[u32; 2]> {
+    self.contains(id).then(|| self.size[id.0 as usize])
+  }
+
+  /// Uploaded texture
+  pub fn texture(&self, id: TextureID) -> Option<&Texture> {
+    self.texture.get(id.0 as usize)?.as_ref()
+  }
+
+  pub fn bindgroup(&self, id: TextureID) -> Option<&BindGroup> {
+    self.bindgroup.get(id.0 as usize)?.as_ref()
+  }
+
+  /// Bindgroup layout shared by every texture of the storage
+  pub fn bindgroup_layout(&mut self, device: &Device) -> &BindGroupLayout {
+    self.bindgroup_layout.get_or_insert_with(|| {
+      super::rdr_2d::diffuse_bindgroup_layout(device)
+    })
+  }
+
+  /// Register the named section (`[[x, y], [w, h]]` in pixel).
+  pub fn add_section(
+    &mut self,
+    id: TextureID,
+    name: impl ToString,
+    range: [[u32; 2]; 2],
+  ) -> Option<TextureSectionID> {
+    if !self.contains(id) {
+      return None;
+    }
+    let i = id.0 as usize;
+    let size = self.size_f[i];
+    let table = self.section.table[i].as_mut()?;
+    let ranges = self.section.range[i].as_mut()?;
+    let ranges_f = self.section.range_f[i].as_mut()?;
+    let range_f = [
+      [range[0][0] as f32 / size[0], range[0][1] as f32 / size[1]],
+      [range[1][0] as f32 / size[0], range[1][1] as f32 / size[1]],
+    ];
+    let name = name.to_string();
+    let sid = match table.get(&name) {
+      Some(sid) => {
+        ranges[sid.0 as usize] = range;
+        ranges_f[sid.0 as usize] = range_f;
+        *sid
+      }
+      None => {
+        let sid = TextureSectionID(ranges.len() as u32);
+        ranges.push(range);
+        ranges_f.push(range_f);
+        table.insert(name, sid);
+        sid
+      }
+    };
+    Some(sid)
+  }
+
+  pub fn section_id(
+    &self,
+    id: TextureID,
+    name: &str,
+  ) -> Option<TextureSectionID> {
+    self.section.table.get(id.0 as usize)?.as_ref()?.get(name).copied()
+  }
+
+  /// UV range of the section (`[origin, extent]`)
+  pub fn section_uv(
+    &self,
+    id: TextureID,
+    section: TextureSectionID,
+  ) -> Option<[[f32; 2]; 2]> {
+    self
+      .section
+      .range_f
+      .get(id.0 as usize)?
+      .as_ref()?
+      .get(section.0 as usize)
+      .copied()
+  }
+
+  /// Upload the registered images. Returns the uploaded textures.
+  pub fn prepare(
+    &mut self,
+    device: &Device,
+    queue: &Queue,
+  ) -> Vec<TextureID> {
+    self.bindgroup_layout(device);
+    let layout = self.bindgroup_layout.as_ref().unwrap();
+    let mut uploaded = Vec::new();
+    for (i, image) in self.image.iter_mut().enumerate() {
+      let Some(image) = image.take() else {
+        continue;
+      };
+      let texture = create_texture(
+        device,
+        queue,
+        &format!("TextureStorage texture #{i}"),
+        self.size[i],
+        &image,
+      );
+      self.bindgroup[i] = Some(super::rdr_2d::diffuse_bindgroup(
+        device, layout, &texture,
+      ));
+      self.texture[i] = Some(texture);
+      uploaded.push(TextureID(i as u32));
+    }
+    uploaded
+  }
+}
+
+/// Create a sampled RGBA texture from the image
+pub fn create_texture(
+  device: &Device,
+  queue: &Queue,
+  label: &str,
+  size: [u32; 2],
+  rgba: &[u8],
+) -> Texture {
+  let size = wgpu::Extent3d {
+    width: size[0],
+    height: size[1],
+    depth_or_array_layers: 1,
+  };
+  let texture = device.create_texture(&wgpu::TextureDescriptor {
+    label: Some(label),
+    size,
+    mip_level_count: 1,
+    sample_count: 1,
+    dimension: wgpu::TextureDimension::D2,
+    format: wgpu::TextureFormat::Rgba8UnormSrgb,
+    usage: wgpu::TextureUsages::TEXTURE_BINDING
+      | wgpu::TextureUsages::COPY_DST,
+    view_formats: &[],
+  });
+  queue.write_texture(
+    wgpu::ImageCopyTexture {
+      texture: &texture,
+      mip_level: 0,
+      origin: wgpu::Origin3d::ZERO,
+      aspect: wgpu::TextureAspect::All,
+    },
+    rgba,
+    wgpu::ImageDataLayout {
+      offset: 0,
+      bytes_per_row: Some(4 * size.width),
+      rows_per_image: Some(size.height),
+    },
+    size,
+  );
+  texture
+}
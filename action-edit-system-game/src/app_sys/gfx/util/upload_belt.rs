@@ -0,0 +1,175 @@
+use crate::StdError;
+use std::sync::Arc;
+use wgpu::{
+  util::align_to, Buffer, BufferAddress, BufferAsyncError,
+  BufferDescriptor, BufferUsages, CommandEncoder, Device,
+  MapMode, COPY_BUFFER_ALIGNMENT, MAP_ALIGNMENT,
+};
+
+/// Default size of a staging chunk (64KiB)
+pub const DEFAULT_CHUNK_SIZE: BufferAddress = 0x10000;
+
+struct Chunk {
+  buffer: Arc<Buffer>,
+  size: BufferAddress,
+  offset: BufferAddress,
+}
+
+/// Upload belt of recycled staging buffers
+///
+/// Renderers write their data into sub-allocations of mapped
+/// staging chunks, and the copies to the destination buffers are
+/// recorded into the chain's `CommandEncoder`.
+/// `finish` must be called before the queue submit, and `recall`
+/// after it. (`RenderChain::finish` does both.)
+pub struct UploadBelt {
+  chunk_size: BufferAddress,
+  /// Chunks that are mapped and can be written in this frame
+  active: Vec<Chunk>,
+  /// Chunks that are unmapped and waiting for the submit
+  closed: Vec<Chunk>,
+  /// Chunks that are mapped again and can be reused
+  free: Vec<Chunk>,
+  recv: crossbeam::channel::Receiver<
+    Result<Chunk, BufferAsyncError>,
+  >,
+  send: crossbeam::channel::Sender<
+    Result<Chunk, BufferAsyncError>,
+  >,
+}
+impl UploadBelt {
+  pub fn new(chunk_size: BufferAddress) -> Self {
+    let (send, recv) = crossbeam::channel::unbounded();
+    Self {
+      chunk_size: align_to(
+        chunk_size.max(1),
+        MAP_ALIGNMENT,
+      ),
+      active: Vec::new(),
+      closed: Vec::new(),
+      free: Vec::new(),
+      recv,
+      send,
+    }
+  }
+
+  /// Write `data` into `target` at `offset` through the staging chunk.
+  pub fn write(
+    &mut self,
+    device: &Device,
+    encoder: &mut CommandEncoder,
+    target: &Buffer,
+    offset: BufferAddress,
+    data: &[u8],
+  ) -> Result<(), StdError> {
+    let size = data.len() as BufferAddress;
+    if size == 0 {
+      return Ok(());
+    }
+    if !size.is_multiple_of(COPY_BUFFER_ALIGNMENT)
+      || !offset.is_multiple_of(COPY_BUFFER_ALIGNMENT)
+    {
+      return Err(
+        format!(
+          "Upload range is not aligned to {COPY_BUFFER_ALIGNMENT} \
+           bytes (offset: {offset}, size: {size})"
+        )
+        .into(),
+      );
+    }
+    let chunk = self.allocate(device, size);
+    let start = chunk.offset;
+    chunk
+      .buffer
+      .slice(start..start + size)
+      .get_mapped_range_mut()
+      .copy_from_slice(data);
+    chunk.offset = align_to(start + size, MAP_ALIGNMENT);
+    encoder.copy_buffer_to_buffer(
+      &chunk.buffer,
+      start,
+      target,
+      offset,
+      size,
+    );
+    Ok(())
+  }
+
+  /// Unmap the chunks written in this frame.
+  pub fn finish(&mut self) {
+    for chunk in self.active.drain(..) {
+      chunk.buffer.unmap();
+      self.closed.push(chunk);
+    }
+  }
+
+  /// Map the submitted chunks again for the reuse.
+  pub fn recall(&mut self) {
+    for mut chunk in self.closed.drain(..) {
+      chunk.offset = 0;
+      let buffer = chunk.buffer.clone();
+      let send = self.send.clone();
+      buffer.slice(..).map_async(
+        MapMode::Write,
+        move |r| {
+          let _ = send.send(r.map(|_| chunk));
+        },
+      );
+    }
+  }
+
+  fn receive(&mut self) {
+    for r in self.recv.try_iter() {
+      match r {
+        Ok(chunk) => self.free.push(chunk),
+        Err(e) => {
+          log::warn!(
+            "Staging chunk map failure. discard it: {e}"
+          )
+        }
+      }
+    }
+  }
+
+  fn allocate(
+    &mut self,
+    device: &Device,
+    size: BufferAddress,
+  ) -> &mut Chunk {
+    if let Some(i) = self
+      .active
+      .iter()
+      .position(|c| c.offset + size <= c.size)
+    {
+      return &mut self.active[i];
+    }
+    self.receive();
+    let chunk =
+      match self.free.iter().position(|c| size <= c.size) {
+        Some(i) => self.free.swap_remove(i),
+        None => {
+          let size = align_to(
+            size.max(self.chunk_size),
+            MAP_ALIGNMENT,
+          );
+          Chunk {
+            buffer: Arc::new(device.create_buffer(
+              &BufferDescriptor {
+                label: Some(
+                  "(internal) UploadBelt Staging chunk",
+                ),
+                size,
+                usage: BufferUsages::COPY_SRC
+                  | BufferUsages::MAP_WRITE,
+                mapped_at_creation: true,
+              },
+            )),
+            size,
+            offset: 0,
+          }
+        }
+      };
+    self.active.push(chunk);
+    self.active.last_mut().unwrap()
+  }
+}
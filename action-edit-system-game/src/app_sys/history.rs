@@ -0,0 +1,444 @@
+//! Undo history of the editors
+//! エディタの取り消し・やり直し履歴
+//!
+//! An editing operation is an `Edit` of the document of an editor,
+//! which can be applied and reverted. `History` keeps the edits as
+//! the entries of the undo and redo stacks:
+//!
+//! - The steps of a drag are merged into an entry, until the
+//!   history is sealed (when the pointer is released).
+//! - The edits between `begin` and `end` are an entry (a
+//!   transaction), undone at once.
+//! - The oldest entries are dropped over the limit.
+//!
+//! Ctrl+Z undoes, and Ctrl+Shift+Z (or Ctrl+Y) redoes, in the
+//! editor under the pointer (see `History::shortcuts`).
+
+use std::{any::Any, collections::VecDeque};
+
+/// Entries kept by default
+pub const DEFAULT_LIMIT: usize = 200;
+
+pub trait Edit<T>: Any + Send {
+  /// Shown in the history
+  fn label(&self) -> String;
+
+  fn apply(&mut self, target: &mut T);
+
+  fn revert(&mut self, target: &mut T);
+
+  /// Take in the next edit of the same operation (a step of a
+  /// drag), and return true. The next edit is already applied.
+  fn merge(&mut self, _next: &mut dyn Any) -> bool {
+    false
+  }
+}
+
+/// Edit replacing the whole document (for the small documents)
+#[derive(Debug, Clone)]
+pub struct Replace<T> {
+  pub label: String,
+  pub before: T,
+  pub after: T,
+}
+impl<T> Replace<T> {
+  pub fn new(
+    label: impl ToString,
+    before: T,
+    after: T,
+  ) -> Self {
+    Self {
+      label: label.to_string(),
+      before,
+      after,
+    }
+  }
+}
+impl<T: Clone + Send + 'static> Edit<T> for Replace<T> {
+  fn label(&self) -> String {
+    self.label.clone()
+  }
+
+  fn apply(&mut self, target: &mut T) {
+    *target = self.after.clone();
+  }
+
+  fn revert(&mut self, target: &mut T) {
+    *target = self.before.clone();
+  }
+
+  fn merge(&mut self, next: &mut dyn Any) -> bool {
+    match next.downcast_mut::<Self>() {
+      Some(next) if next.label == self.label => {
+        std::mem::swap(&mut self.after, &mut next.after);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+struct Entry<T> {
+  label: String,
+  edits: Vec<Box<dyn Edit<T>>>,
+  /// Takes no more edits
+  sealed: bool,
+}
+impl<T: 'static> Entry<T> {
+  fn new(label: String) -> Self {
+    Self {
+      label,
+      edits: Vec::new(),
+      sealed: false,
+    }
+  }
+
+  /// Merge into the last edit, or add it.
+  fn push(&mut self, mut edit: Box<dyn Edit<T>>) {
+    let merged = self
+      .edits
+      .last_mut()
+      .is_some_and(|last| last.merge(edit.as_mut()));
+    if !merged {
+      self.edits.push(edit);
+    }
+  }
+
+  fn apply(&mut self, target: &mut T) {
+    for e in self.edits.iter_mut() {
+      e.apply(target);
+    }
+  }
+
+  fn revert(&mut self, target: &mut T) {
+    for e in self.edits.iter_mut().rev() {
+      e.revert(target);
+    }
+  }
+}
+
+pub struct History<T> {
+  /// The latest at the back
+  undo: VecDeque<Entry<T>>,
+  /// The latest undone at the back
+  redo: Vec<Entry<T>>,
+  limit: usize,
+  /// Open transaction, and the depth of `begin`
+  group: Option<(Entry<T>, u32)>,
+}
+impl<T: 'static> History<T> {
+  pub fn new(limit: usize) -> Self {
+    Self {
+      undo: VecDeque::new(),
+      redo: Vec::new(),
+      limit: limit.max(1),
+      group: None,
+    }
+  }
+
+  /// Apply the edit to the target, and record it.
+  pub fn apply(
+    &mut self,
+    target: &mut T,
+    edit: impl Edit<T>,
+  ) {
+    let mut edit = Box::new(edit);
+    edit.apply(target);
+    self.push(edit);
+  }
+
+  /// Record the edit already applied to the target.
+  pub fn record(&mut self, edit: impl Edit<T>) {
+    self.push(Box::new(edit));
+  }
+
+  fn push(&mut self, mut edit: Box<dyn Edit<T>>) {
+    self.redo.clear();
+    if let Some((group, _)) = &mut self.group {
+      group.push(edit);
+      return;
+    }
+    let last = self
+      .undo
+      .back_mut()
+      .filter(|e| !e.sealed && e.edits.len() == 1);
+    if let Some(last) = last {
+      if last.edits[0].merge(edit.as_mut()) {
+        return;
+      }
+    }
+    self.seal();
+    let mut entry = Entry::new(edit.label());
+    entry.edits.push(edit);
+    self.push_entry(entry);
+  }
+
+  fn push_entry(&mut self, entry: Entry<T>) {
+    self.undo.push_back(entry);
+    while self.limit < self.undo.len() {
+      self.undo.pop_front();
+    }
+  }
+
+  /// The next edit starts a new entry.
+  pub fn seal(&mut self) {
+    if let Some(last) = self.undo.back_mut() {
+      last.sealed = true;
+    }
+  }
+
+  /// Start a transaction. The edits until the matching `end` are
+  /// an entry.
+  pub fn begin(&mut self, label: impl ToString) {
+    match &mut self.group {
+      Some((_, depth)) => *depth += 1,
+      None => {
+        self.group =
+          Some((Entry::new(label.to_string()), 1))
+      }
+    }
+  }
+
+  pub fn end(&mut self) {
+    let Some((_, depth)) = &mut self.group else {
+      return;
+    };
+    *depth -= 1;
+    if 0 < *depth {
+      return;
+    }
+    if let Some((mut entry, _)) = self.group.take() {
+      if !entry.edits.is_empty() {
+        self.seal();
+        entry.sealed = true;
+        self.push_entry(entry);
+      }
+    }
+  }
+
+  /// Revert and drop the open transaction.
+  pub fn cancel(&mut self, target: &mut T) {
+    if let Some((mut entry, _)) = self.group.take() {
+      entry.revert(target);
+    }
+  }
+
+  pub fn in_transaction(&self) -> bool {
+    self.group.is_some()
+  }
+
+  pub fn can_undo(&self) -> bool {
+    !self.undo.is_empty()
+  }
+
+  pub fn can_redo(&self) -> bool {
+    !self.redo.is_empty()
+  }
+
+  pub fn undo_label(&self) -> Option<&str> {
+    self.undo.back().map(|e| e.label.as_str())
+  }
+
+  pub fn redo_label(&self) -> Option<&str> {
+    self.redo.last().map(|e| e.label.as_str())
+  }
+
+  /// Revert the latest entry. An open transaction is closed first.
+  /// Returns false if nothing is undone.
+  pub fn undo(&mut self, target: &mut T) -> bool {
+    while self.group.is_some() {
+      self.end();
+    }
+    let Some(mut entry) = self.undo.pop_back() else {
+      return false;
+    };
+    entry.revert(target);
+    entry.sealed = true;
+    self.redo.push(entry);
+    self.seal();
+    true
+  }
+
+  /// Apply the latest undone entry again.
+  pub fn redo(&mut self, target: &mut T) -> bool {
+    let Some(mut entry) = self.redo.pop() else {
+      return false;
+    };
+    entry.apply(target);
+    self.seal();
+    self.push_entry(entry);
+    true
+  }
+
+  pub fn clear(&mut self) {
+    self.undo.clear();
+    self.redo.clear();
+    self.group = None;
+  }
+
+  /// Undo or redo by the keyboard, while the pointer is over the
+  /// ui and no text field has the focus. Returns true if the target
+  /// is changed.
+  pub fn shortcuts(
+    &mut self,
+    ui: &egui::Ui,
+    target: &mut T,
+  ) -> bool {
+    use egui::{Key, KeyboardShortcut, Modifiers};
+    if !ui.ui_contains_pointer()
+      || ui.ctx().wants_keyboard_input()
+    {
+      return false;
+    }
+    let redo = ui.input_mut(|i| {
+      i.consume_shortcut(&KeyboardShortcut::new(
+        Modifiers::COMMAND | Modifiers::SHIFT,
+        Key::Z,
+      )) || i.consume_shortcut(&KeyboardShortcut::new(
+        Modifiers::COMMAND,
+        Key::Y,
+      ))
+    });
+    if redo {
+      return self.redo(target);
+    }
+    let undo = ui.input_mut(|i| {
+      i.consume_shortcut(&KeyboardShortcut::new(
+        Modifiers::COMMAND,
+        Key::Z,
+      ))
+    });
+    undo && self.undo(target)
+  }
+
+  /// Undo and redo buttons. Returns true if the target is changed.
+  pub fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    target: &mut T,
+  ) -> bool {
+    let mut changed = false;
+    let undo = ui
+      .add_enabled(
+        self.can_undo(),
+        egui::Button::new("Undo"),
+      )
+      .on_hover_text(self.undo_label().unwrap_or_default());
+    if undo.clicked() {
+      changed |= self.undo(target);
+    }
+    let redo = ui
+      .add_enabled(
+        self.can_redo(),
+        egui::Button::new("Redo"),
+      )
+      .on_hover_text(self.redo_label().unwrap_or_default());
+    if redo.clicked() {
+      changed |= self.redo(target);
+    }
+    changed
+  }
+}
+impl<T: 'static> Default for History<T> {
+  fn default() -> Self {
+    Self::new(DEFAULT_LIMIT)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn set(
+    history: &mut History<i32>,
+    doc: &mut i32,
+    label: &str,
+    value: i32,
+  ) {
+    let before = *doc;
+    history.apply(doc, Replace::new(label, before, value));
+  }
+
+  #[test]
+  fn replace_merges_the_same_label_until_sealed() {
+    let mut history = History::default();
+    let mut doc = 0;
+    set(&mut history, &mut doc, "move", 1);
+    set(&mut history, &mut doc, "move", 2);
+    set(&mut history, &mut doc, "move", 3);
+    assert_eq!(history.undo.len(), 1);
+    set(&mut history, &mut doc, "resize", 4);
+    assert_eq!(history.undo.len(), 2);
+    history.seal();
+    set(&mut history, &mut doc, "resize", 5);
+    assert_eq!(history.undo.len(), 3);
+
+    assert!(history.undo(&mut doc));
+    assert_eq!(doc, 4);
+    assert!(history.undo(&mut doc));
+    assert_eq!(doc, 3);
+    assert!(history.undo(&mut doc));
+    assert_eq!(doc, 0);
+    assert!(!history.undo(&mut doc));
+  }
+
+  #[test]
+  fn nested_transactions_are_an_entry() {
+    let mut history = History::default();
+    let mut doc = 0;
+    history.begin("paste");
+    set(&mut history, &mut doc, "a", 1);
+    history.begin("inner");
+    set(&mut history, &mut doc, "b", 2);
+    history.end();
+    assert!(history.in_transaction());
+    set(&mut history, &mut doc, "c", 3);
+    history.end();
+    assert!(!history.in_transaction());
+    assert_eq!(history.undo_label(), Some("paste"));
+
+    // A sealed transaction takes no more edits.
+    set(&mut history, &mut doc, "c", 4);
+    assert_eq!(history.undo.len(), 2);
+    history.undo(&mut doc);
+    history.undo(&mut doc);
+    assert_eq!(doc, 0);
+
+    history.begin("cancelled");
+    set(&mut history, &mut doc, "a", 7);
+    history.cancel(&mut doc);
+    assert_eq!(doc, 0);
+    assert!(!history.in_transaction());
+  }
+
+  #[test]
+  fn drops_the_oldest_over_the_limit() {
+    let mut history = History::new(3);
+    let mut doc = 0;
+    for i in 1..=5 {
+      set(&mut history, &mut doc, &format!("set {i}"), i);
+    }
+    while history.undo(&mut doc) {}
+    assert_eq!(doc, 2);
+  }
+
+  #[test]
+  fn redoes_after_undo_until_a_new_edit() {
+    let mut history = History::default();
+    let mut doc = 0;
+    set(&mut history, &mut doc, "a", 1);
+    set(&mut history, &mut doc, "b", 2);
+    history.undo(&mut doc);
+    history.undo(&mut doc);
+    assert_eq!(history.redo_label(), Some("a"));
+    assert!(history.redo(&mut doc));
+    assert_eq!(doc, 1);
+    // The redone entry is not merged with the next edit.
+    set(&mut history, &mut doc, "a", 5);
+    assert!(!history.can_redo());
+    history.undo(&mut doc);
+    assert_eq!(doc, 1);
+    assert!(history.redo(&mut doc));
+    assert_eq!(doc, 5);
+  }
+}
@@ -0,0 +1,533 @@
+//! Input buffer
+//! 先行入力とコマンド入力の認識
+//!
+//! Every player has the history of the recent steps: the direction
+//! in the numpad notation, and the actions pressed and held in the
+//! step. The history answers two questions:
+//!
+//! - `buffered`: was the action pressed in the last frames? A jump
+//!   pressed a few frames before landing still jumps. A press is
+//!   used once by `consume`.
+//! - `matches`: was a command sequence entered? (`236+attack` is
+//!   down, down-forward, forward and attack)
+//!
+//! The directions are relative to the facing, so a command works on
+//! both sides.
+//!
+//! Every player records the same action map. Player 0 reads the
+//! actions and the axes as bound (`attack`, `move_x`), and player `n`
+//! the ones prefixed by `pn.` (`p1.attack`, `p1.move_x`).
+//!
+//! ```text
+//! 7 8 9    up-back    up    up-forward
+//! 4 5 6    back     neutral forward
+//! 1 2 3    down-back  down  down-forward
+//! ```
+
+use super::map::InputMap;
+use std::collections::VecDeque;
+
+/// Axis value to count as a direction
+const DIRECTION_THRESHOLD: f32 = 0.5;
+
+/// Direction in the numpad notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Direction(u8);
+impl Direction {
+  pub const NEUTRAL: Self = Self(5);
+
+  /// Direction of the numpad number (1..=9)
+  pub fn from_numpad(n: u8) -> Option<Self> {
+    (1..=9).contains(&n).then_some(Self(n))
+  }
+
+  /// Direction of the axes (y up)
+  pub fn from_axes(
+    x: f32,
+    y: f32,
+    facing_right: bool,
+  ) -> Self {
+    let step = |v: f32| {
+      if DIRECTION_THRESHOLD <= v {
+        1
+      } else if v <= -DIRECTION_THRESHOLD {
+        -1
+      } else {
+        0
+      }
+    };
+    let x = if facing_right { step(x) } else { -step(x) };
+    Self((5 + x + step(y) * 3) as u8)
+  }
+
+  pub fn numpad(self) -> u8 {
+    self.0
+  }
+}
+
+/// Input of a step
+#[derive(Debug, Clone, PartialEq)]
+pub struct InputFrame {
+  pub dir: Direction,
+  pub pressed: Vec<String>,
+  pub held: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BufferConfig {
+  /// Steps kept in the history
+  pub history: usize,
+  /// Steps a press stays buffered
+  pub press_window: u32,
+  /// Default steps a command sequence can take
+  pub command_window: u32,
+}
+impl Default for BufferConfig {
+  fn default() -> Self {
+    Self {
+      history: 60,
+      press_window: 6,
+      command_window: 20,
+    }
+  }
+}
+
+/// Command sequence (motion, then an optional action)
+#[derive(Debug, Clone, PartialEq)]
+pub struct CommandPattern {
+  pub name: String,
+  pub motion: Vec<Direction>,
+  pub action: Option<String>,
+  /// Steps from the first direction to the action
+  pub window: u32,
+}
+impl CommandPattern {
+  /// Parse the notation, the numpad digits and an optional
+  /// `+action` (`"236+attack"`, `"66"`). Spaces are ignored.
+  pub fn parse(
+    name: impl ToString,
+    notation: &str,
+    window: u32,
+  ) -> Result<Self, String> {
+    let (motion, action) = match notation.split_once('+') {
+      Some((m, a)) => (m, Some(a.trim().to_string())),
+      None => (notation, None),
+    };
+    let motion = motion
+      .chars()
+      .filter(|c| !c.is_whitespace())
+      .map(|c| {
+        c.to_digit(10)
+          .and_then(|n| Direction::from_numpad(n as u8))
+          .ok_or_else(|| {
+            format!(
+              "invalid direction '{c}' in \"{notation}\""
+            )
+          })
+      })
+      .collect::<Result<Vec<_>, _>>()?;
+    if motion.is_empty() && action.is_none() {
+      return Err(format!("empty command \"{notation}\""));
+    }
+    if action.as_ref().is_some_and(|a| a.is_empty()) {
+      return Err(format!(
+        "empty action in \"{notation}\""
+      ));
+    }
+    Ok(Self {
+      name: name.to_string(),
+      motion,
+      action,
+      window,
+    })
+  }
+}
+
+/// Commands of the action game (the first match wins, so the longer
+/// ones come first)
+pub fn default_commands(
+  window: u32,
+) -> Vec<CommandPattern> {
+  [
+    ("dragon_punch", "623+attack"),
+    ("quarter_forward", "236+attack"),
+    ("quarter_back", "214+attack"),
+    ("dash_forward", "656"),
+    ("dash_back", "454"),
+  ]
+  .into_iter()
+  .map(|(name, notation)| {
+    CommandPattern::parse(name, notation, window)
+      .expect("default commands are valid")
+  })
+  .collect()
+}
+
+/// History of a player
+pub struct InputBuffer {
+  pub config: BufferConfig,
+  /// The latest at the front
+  frames: VecDeque<InputFrame>,
+  /// Steps recorded so far
+  tick: u64,
+  /// Tick of the last consumed press of the actions
+  consumed: Vec<(String, u64)>,
+  facing_right: bool,
+  commands: Vec<CommandPattern>,
+}
+impl InputBuffer {
+  pub fn new(config: BufferConfig) -> Self {
+    Self {
+      commands: default_commands(config.command_window),
+      config,
+      frames: VecDeque::new(),
+      tick: 0,
+      consumed: Vec::new(),
+      facing_right: true,
+    }
+  }
+
+  pub fn set_facing(&mut self, right: bool) {
+    self.facing_right = right;
+  }
+
+  pub fn commands(&self) -> &[CommandPattern] {
+    &self.commands
+  }
+
+  /// Add the input of a step.
+  pub fn push(&mut self, frame: InputFrame) {
+    self.frames.push_front(frame);
+    self.frames.truncate(self.config.history.max(1));
+    self.tick += 1;
+  }
+
+  /// Record the step from the action map (`move_x`, `move_y` and
+  /// the actions of the player prefix).
+  pub fn record(&mut self, map: &InputMap, prefix: &str) {
+    let dir = Direction::from_axes(
+      map.axis(&format!("{prefix}move_x")),
+      map.axis(&format!("{prefix}move_y")),
+      self.facing_right,
+    );
+    let names = |it: &mut dyn Iterator<Item = &str>| {
+      it.filter_map(|a| player_action(a, prefix))
+        .map(str::to_string)
+        .collect()
+    };
+    self.push(InputFrame {
+      dir,
+      pressed: names(&mut map.pressed_actions()),
+      held: names(&mut map.down_actions()),
+    });
+  }
+
+  /// Recent steps, the latest first
+  pub fn history(
+    &self,
+  ) -> impl Iterator<Item = &InputFrame> {
+    self.frames.iter()
+  }
+
+  pub fn direction(&self) -> Direction {
+    self
+      .frames
+      .front()
+      .map_or(Direction::NEUTRAL, |f| f.dir)
+  }
+
+  /// Tick of the frame at the index (0 is the latest)
+  fn tick_of(&self, index: usize) -> u64 {
+    self.tick - 1 - index as u64
+  }
+
+  fn is_consumed(
+    &self,
+    action: &str,
+    index: usize,
+  ) -> bool {
+    self.consumed.iter().any(|(a, t)| {
+      a == action && self.tick_of(index) <= *t
+    })
+  }
+
+  /// Index of the latest unconsumed press within the window
+  fn press_index(
+    &self,
+    action: &str,
+    window: u32,
+  ) -> Option<usize> {
+    self
+      .frames
+      .iter()
+      .take(window.max(1) as usize)
+      .position(|f| f.pressed.iter().any(|a| a == action))
+      .filter(|i| !self.is_consumed(action, *i))
+  }
+
+  /// The action was pressed within the window (steps), and the
+  /// press is not consumed. None for the configured window.
+  pub fn buffered(
+    &self,
+    action: &str,
+    window: Option<u32>,
+  ) -> bool {
+    let window = window.unwrap_or(self.config.press_window);
+    self.press_index(action, window).is_some()
+  }
+
+  /// Use the buffered press. Returns false if nothing is buffered.
+  pub fn consume(&mut self, action: &str) -> bool {
+    let Some(i) =
+      self.press_index(action, self.config.press_window)
+    else {
+      return false;
+    };
+    let tick = self.tick_of(i);
+    match self
+      .consumed
+      .iter_mut()
+      .find(|(a, _)| a == action)
+    {
+      Some((_, t)) => *t = tick,
+      None => {
+        self.consumed.push((action.to_string(), tick))
+      }
+    }
+    true
+  }
+
+  /// The command was entered. The action press is not consumed.
+  pub fn matches(&self, command: &CommandPattern) -> bool {
+    // The sequence ends at the action press, or at the latest
+    // direction in the press window.
+    let (end, window) = match &command.action {
+      Some(action) => {
+        let Some(i) = self
+          .press_index(action, self.config.press_window)
+        else {
+          return false;
+        };
+        (i, self.config.press_window.max(1) as usize)
+      }
+      None => (0, self.config.press_window.max(1) as usize),
+    };
+    let limit = (end + command.window as usize + 1)
+      .min(self.frames.len());
+    let mut i = end;
+    for (n, dir) in command.motion.iter().rev().enumerate()
+    {
+      // A repeated direction needs a release between.
+      if 0 < n
+        && command.motion[command.motion.len() - n] == *dir
+      {
+        match (i..limit)
+          .find(|k| self.frames[*k].dir != *dir)
+        {
+          Some(k) => i = k,
+          None => return false,
+        }
+      }
+      let Some(k) =
+        (i..limit).find(|k| self.frames[*k].dir == *dir)
+      else {
+        return false;
+      };
+      // The last direction is close to the end.
+      if n == 0 && end + window <= k {
+        return false;
+      }
+      i = k + 1;
+    }
+    true
+  }
+
+  /// First registered command that was entered
+  pub fn matched_command(&self) -> Option<&CommandPattern> {
+    self.commands.iter().find(|c| self.matches(c))
+  }
+
+  /// Is the registered command entered?
+  pub fn command(&self, name: &str) -> bool {
+    self
+      .commands
+      .iter()
+      .find(|c| c.name == name)
+      .is_some_and(|c| self.matches(c))
+  }
+}
+impl Default for InputBuffer {
+  fn default() -> Self {
+    Self::new(BufferConfig::default())
+  }
+}
+
+/// Prefix of the actions of the player (`""`, `"p1."`, ...)
+pub fn player_prefix(player: usize) -> String {
+  match player {
+    0 => String::new(),
+    n => format!("p{n}."),
+  }
+}
+
+/// Name of the action for the player of the prefix, if it is
+fn player_action<'a>(
+  action: &'a str,
+  prefix: &str,
+) -> Option<&'a str> {
+  if !prefix.is_empty() {
+    return action.strip_prefix(prefix);
+  }
+  let other = action
+    .strip_prefix('p')
+    .and_then(|a| a.split_once('.'))
+    .is_some_and(|(n, _)| {
+      !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit())
+    });
+  (!other).then_some(action)
+}
+
+/// Input buffers of the players (0-origin)
+pub struct PlayerInputs {
+  players: Vec<InputBuffer>,
+}
+impl PlayerInputs {
+  pub fn new(players: usize, config: BufferConfig) -> Self {
+    Self {
+      players: (0..players)
+        .map(|_| InputBuffer::new(config))
+        .collect(),
+    }
+  }
+
+  pub fn len(&self) -> usize {
+    self.players.len()
+  }
+
+  pub fn player(&self, i: usize) -> Option<&InputBuffer> {
+    self.players.get(i)
+  }
+
+  pub fn player_mut(
+    &mut self,
+    i: usize,
+  ) -> Option<&mut InputBuffer> {
+    self.players.get_mut(i)
+  }
+
+  /// Record the step of every player from the action map.
+  pub fn record(&mut self, map: &InputMap) {
+    for (i, p) in self.players.iter_mut().enumerate() {
+      p.record(map, &player_prefix(i));
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn push(
+    buffer: &mut InputBuffer,
+    dir: u8,
+    pressed: &[&str],
+  ) {
+    let pressed = pressed
+      .iter()
+      .map(|a| a.to_string())
+      .collect::<Vec<_>>();
+    buffer.push(InputFrame {
+      dir: Direction::from_numpad(dir).unwrap(),
+      held: pressed.clone(),
+      pressed,
+    });
+  }
+
+  #[test]
+  fn parses_the_notation() {
+    let c =
+      CommandPattern::parse("qcf", "2 3 6+attack", 20)
+        .unwrap();
+    assert_eq!(
+      c.motion
+        .iter()
+        .map(|d| d.numpad())
+        .collect::<Vec<_>>(),
+      [2, 3, 6]
+    );
+    assert_eq!(c.action.as_deref(), Some("attack"));
+    assert!(CommandPattern::parse("x", "66", 20)
+      .unwrap()
+      .action
+      .is_none());
+    for bad in ["", "20+attack", "23a", "236+"] {
+      assert!(
+        CommandPattern::parse("x", bad, 20).is_err(),
+        "{bad:?} is parsed"
+      );
+    }
+  }
+
+  #[test]
+  fn quarter_circle_matches_within_the_window() {
+    let c = CommandPattern::parse("qcf", "236+attack", 20)
+      .unwrap();
+    let mut buffer = InputBuffer::default();
+    push(&mut buffer, 2, &[]);
+    push(&mut buffer, 3, &[]);
+    push(&mut buffer, 6, &[]);
+    assert!(!buffer.matches(&c));
+    push(&mut buffer, 6, &["attack"]);
+    assert!(buffer.matches(&c));
+    assert!(buffer.command("quarter_forward"));
+    // The press is buffered for the press window only.
+    for _ in 0..buffer.config.press_window {
+      push(&mut buffer, 5, &[]);
+    }
+    assert!(!buffer.matches(&c));
+  }
+
+  #[test]
+  fn quarter_circle_fails_outside_the_window() {
+    let c = CommandPattern::parse("qcf", "236+attack", 20)
+      .unwrap();
+    let mut buffer = InputBuffer::default();
+    push(&mut buffer, 2, &[]);
+    for _ in 0..20 {
+      push(&mut buffer, 3, &[]);
+    }
+    push(&mut buffer, 6, &["attack"]);
+    assert!(!buffer.matches(&c));
+  }
+
+  #[test]
+  fn repeated_direction_needs_a_release() {
+    let c =
+      CommandPattern::parse("dash", "66", 20).unwrap();
+    let mut buffer = InputBuffer::default();
+    for _ in 0..3 {
+      push(&mut buffer, 6, &[]);
+    }
+    assert!(!buffer.matches(&c));
+    push(&mut buffer, 5, &[]);
+    push(&mut buffer, 6, &[]);
+    assert!(buffer.matches(&c));
+  }
+
+  #[test]
+  fn players_read_their_prefixed_actions() {
+    assert_eq!(player_prefix(0), "");
+    assert_eq!(player_prefix(1), "p1.");
+    assert_eq!(player_action("attack", ""), Some("attack"));
+    assert_eq!(
+      player_action("pause.menu", ""),
+      Some("pause.menu")
+    );
+    assert_eq!(player_action("p1.attack", ""), None);
+    assert_eq!(
+      player_action("p1.attack", "p1."),
+      Some("attack")
+    );
+    assert_eq!(player_action("attack", "p1."), None);
+  }
+}
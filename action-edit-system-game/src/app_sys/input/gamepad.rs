@@ -0,0 +1,152 @@
+//! Gamepad inputs
+//!
+//! The window does not deliver the gamepad events, so the event
+//! loop polls `GamepadPoll`, which feeds the state through
+//! `InputState::set_gamepad_button` and
+//! `InputState::set_gamepad_axis`. The backend is gilrs, built with
+//! the `gamepad` feature. Without it, no gamepad is polled.
+
+use super::InputState;
+use serde::{Deserialize, Serialize};
+
+/// Buttons by the position (South is A on Xbox, Cross on PS)
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Hash,
+  PartialOrd,
+  Ord,
+  Serialize,
+  Deserialize,
+)]
+pub enum GamepadButton {
+  South,
+  East,
+  West,
+  North,
+  LeftBumper,
+  RightBumper,
+  LeftTrigger,
+  RightTrigger,
+  Select,
+  Start,
+  LeftStick,
+  RightStick,
+  DPadUp,
+  DPadDown,
+  DPadLeft,
+  DPadRight,
+}
+
+/// Analog axes (-1..1, y up)
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Hash,
+  PartialOrd,
+  Ord,
+  Serialize,
+  Deserialize,
+)]
+pub enum GamepadAxis {
+  LeftStickX,
+  LeftStickY,
+  RightStickX,
+  RightStickY,
+}
+
+/// Gamepad backend. The gamepads are merged into one state.
+#[derive(Default)]
+pub struct GamepadPoll {
+  #[cfg(feature = "gamepad")]
+  gilrs: Option<gilrs::Gilrs>,
+}
+impl GamepadPoll {
+  pub fn new() -> Self {
+    Self {
+      #[cfg(feature = "gamepad")]
+      gilrs: gilrs::Gilrs::new()
+        .map_err(|e| {
+          log::warn!("Gamepad backend error: {e}")
+        })
+        .ok(),
+    }
+  }
+
+  /// Feed the events since the last poll to the input state. Called
+  /// by the event loop before the simulation steps.
+  #[cfg(feature = "gamepad")]
+  pub fn poll(&mut self, input: &mut InputState) {
+    use gilrs::EventType;
+    let Some(gilrs) = self.gilrs.as_mut() else {
+      return;
+    };
+    while let Some(event) = gilrs.next_event() {
+      match event.event {
+        EventType::ButtonPressed(b, _) => {
+          if let Some(b) = button(b) {
+            input.set_gamepad_button(b, true);
+          }
+        }
+        EventType::ButtonReleased(b, _) => {
+          if let Some(b) = button(b) {
+            input.set_gamepad_button(b, false);
+          }
+        }
+        EventType::AxisChanged(a, value, _) => {
+          if let Some(a) = axis(a) {
+            input.set_gamepad_axis(a, value);
+          }
+        }
+        EventType::Disconnected => input.release_gamepad(),
+        _ => {}
+      }
+    }
+  }
+
+  #[cfg(not(feature = "gamepad"))]
+  pub fn poll(&mut self, _input: &mut InputState) {}
+}
+
+#[cfg(feature = "gamepad")]
+fn button(b: gilrs::Button) -> Option<GamepadButton> {
+  use gilrs::Button as B;
+  Some(match b {
+    B::South => GamepadButton::South,
+    B::East => GamepadButton::East,
+    B::West => GamepadButton::West,
+    B::North => GamepadButton::North,
+    B::LeftTrigger => GamepadButton::LeftBumper,
+    B::RightTrigger => GamepadButton::RightBumper,
+    B::LeftTrigger2 => GamepadButton::LeftTrigger,
+    B::RightTrigger2 => GamepadButton::RightTrigger,
+    B::Select => GamepadButton::Select,
+    B::Start => GamepadButton::Start,
+    B::LeftThumb => GamepadButton::LeftStick,
+    B::RightThumb => GamepadButton::RightStick,
+    B::DPadUp => GamepadButton::DPadUp,
+    B::DPadDown => GamepadButton::DPadDown,
+    B::DPadLeft => GamepadButton::DPadLeft,
+    B::DPadRight => GamepadButton::DPadRight,
+    _ => return None,
+  })
+}
+
+/// The stick y of gilrs is up, as `GamepadAxis`.
+#[cfg(feature = "gamepad")]
+fn axis(a: gilrs::Axis) -> Option<GamepadAxis> {
+  use gilrs::Axis as A;
+  Some(match a {
+    A::LeftStickX => GamepadAxis::LeftStickX,
+    A::LeftStickY => GamepadAxis::LeftStickY,
+    A::RightStickX => GamepadAxis::RightStickX,
+    A::RightStickY => GamepadAxis::RightStickY,
+    _ => return None,
+  })
+}
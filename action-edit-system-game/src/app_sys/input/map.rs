@@ -0,0 +1,673 @@
+//! Action map
+//! 入力をアクション名と軸に対応付ける
+//!
+//! The game asks for the named actions ("jump") and axes ("move_x")
+//! instead of the keys. The bindings are grouped by the context
+//! (gameplay, ...), and only the active contexts are read. The
+//! editors read the egui input instead.
+//!
+//! `InputMap::update` is called once per simulation step, before
+//! `InputState::end_frame`, so pressed and released are true in
+//! exactly one step. A replay calls `InputMap::replay` instead, with
+//! the actions and the axes of the recorded step.
+//!
+//! The bindings are saved as TOML:
+//!
+//! ```toml
+//! [gameplay.actions]
+//! jump = [{ key = "Space" }, { gamepad = "South" }]
+//!
+//! [gameplay.axes.move_x]
+//! negative = [{ key = "KeyA" }]
+//! positive = [{ key = "KeyD" }]
+//! analog = ["LeftStickX"]
+//! ```
+
+use super::{
+  gamepad::{GamepadAxis, GamepadButton},
+  key_name, InputState,
+};
+use crate::{
+  app_sys::history::{History, Replace},
+  StdError,
+};
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use std::{
+  collections::BTreeMap, fmt::Display, path::Path,
+};
+use winit::event::MouseButton;
+
+/// Default bindings file
+pub const BINDINGS_PATH: &str = "./input.toml";
+pub const GAMEPLAY: &str = "gameplay";
+
+#[derive(
+  Debug,
+  Clone,
+  Copy,
+  PartialEq,
+  Eq,
+  Hash,
+  Serialize,
+  Deserialize,
+)]
+pub enum MouseBinding {
+  Left,
+  Right,
+  Middle,
+  Back,
+  Forward,
+}
+impl MouseBinding {
+  pub fn button(self) -> MouseButton {
+    match self {
+      Self::Left => MouseButton::Left,
+      Self::Right => MouseButton::Right,
+      Self::Middle => MouseButton::Middle,
+      Self::Back => MouseButton::Back,
+      Self::Forward => MouseButton::Forward,
+    }
+  }
+
+  fn from_button(button: MouseButton) -> Option<Self> {
+    match button {
+      MouseButton::Left => Some(Self::Left),
+      MouseButton::Right => Some(Self::Right),
+      MouseButton::Middle => Some(Self::Middle),
+      MouseButton::Back => Some(Self::Back),
+      MouseButton::Forward => Some(Self::Forward),
+      MouseButton::Other(_) => None,
+    }
+  }
+}
+
+/// Physical input bound to an action
+#[derive(
+  Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Binding {
+  /// Name of `KeyCode` ("Space", "KeyA", ...)
+  Key(String),
+  Mouse(MouseBinding),
+  Gamepad(GamepadButton),
+}
+impl Binding {
+  pub fn key(name: &str) -> Self {
+    Self::Key(name.to_string())
+  }
+
+  fn is_down(&self, input: &InputState) -> bool {
+    match self {
+      Self::Key(name) => input.is_down_by_name(name),
+      Self::Mouse(m) => input.is_button_down(m.button()),
+      Self::Gamepad(b) => input.is_gamepad_down(*b),
+    }
+  }
+
+  fn is_pressed(&self, input: &InputState) -> bool {
+    match self {
+      Self::Key(name) => input.is_pressed_by_name(name),
+      Self::Mouse(m) => input.is_button_pressed(m.button()),
+      Self::Gamepad(b) => input.is_gamepad_pressed(*b),
+    }
+  }
+}
+impl Display for Binding {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::Key(name) => write!(f, "{name}"),
+      Self::Mouse(m) => write!(f, "Mouse {m:?}"),
+      Self::Gamepad(b) => write!(f, "Pad {b:?}"),
+    }
+  }
+}
+
+fn default_dead_zone() -> f32 {
+  0.2
+}
+
+/// Axis (-1..1) from the digital and analog inputs
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct AxisBinding {
+  #[serde(default)]
+  pub negative: Vec<Binding>,
+  #[serde(default)]
+  pub positive: Vec<Binding>,
+  #[serde(default)]
+  pub analog: Vec<GamepadAxis>,
+  /// Analog values smaller than it are 0
+  #[serde(default = "default_dead_zone")]
+  pub dead_zone: f32,
+}
+impl AxisBinding {
+  pub fn digital(
+    negative: &[&str],
+    positive: &[&str],
+  ) -> Self {
+    let keys = |names: &[&str]| {
+      names.iter().map(|n| Binding::key(n)).collect()
+    };
+    Self {
+      negative: keys(negative),
+      positive: keys(positive),
+      analog: Vec::new(),
+      dead_zone: default_dead_zone(),
+    }
+  }
+
+  pub fn with_analog(mut self, axis: GamepadAxis) -> Self {
+    self.analog.push(axis);
+    self
+  }
+
+  fn value(&self, input: &InputState) -> f32 {
+    let down =
+      |b: &[Binding]| b.iter().any(|b| b.is_down(input));
+    let digital = down(&self.positive) as i32 as f32
+      - down(&self.negative) as i32 as f32;
+    let analog = self
+      .analog
+      .iter()
+      .map(|a| input.gamepad_axis(*a))
+      .filter(|v| self.dead_zone < v.abs())
+      .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+      .unwrap_or(0.);
+    (digital + analog).clamp(-1., 1.)
+  }
+}
+
+/// Bindings of a context
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+pub struct ActionContext {
+  #[serde(default)]
+  pub actions: BTreeMap<String, Vec<Binding>>,
+  #[serde(default)]
+  pub axes: BTreeMap<String, AxisBinding>,
+}
+
+/// Bindings of every context (the content of the bindings file)
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+#[serde(transparent)]
+pub struct InputBindings {
+  pub contexts: BTreeMap<String, ActionContext>,
+}
+impl InputBindings {
+  pub fn load(
+    path: impl AsRef<Path>,
+  ) -> Result<Self, StdError> {
+    Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+  }
+
+  pub fn save(
+    &self,
+    path: impl AsRef<Path>,
+  ) -> Result<(), StdError> {
+    std::fs::write(path, toml::to_string_pretty(self)?)?;
+    Ok(())
+  }
+
+  /// Load the file, or the default bindings if it does not exist.
+  pub fn load_or_default(path: impl AsRef<Path>) -> Self {
+    let path = path.as_ref();
+    if !path.exists() {
+      return Self::default();
+    }
+    Self::load(path).unwrap_or_else(|e| {
+      log::warn!(
+        "Input bindings load error ({}): {e}",
+        path.display()
+      );
+      Self::default()
+    })
+  }
+}
+impl Default for InputBindings {
+  fn default() -> Self {
+    let actions = |list: &[(&str, Vec<Binding>)]| {
+      list
+        .iter()
+        .map(|(name, b)| (name.to_string(), b.clone()))
+        .collect()
+    };
+    let gameplay = ActionContext {
+      actions: actions(&[
+        (
+          "jump",
+          vec![
+            Binding::key("Space"),
+            Binding::Gamepad(GamepadButton::South),
+          ],
+        ),
+        (
+          "attack",
+          vec![
+            Binding::key("KeyJ"),
+            Binding::Mouse(MouseBinding::Left),
+            Binding::Gamepad(GamepadButton::West),
+          ],
+        ),
+        (
+          "pause",
+          vec![
+            Binding::key("Escape"),
+            Binding::Gamepad(GamepadButton::Start),
+          ],
+        ),
+      ]),
+      axes: [
+        (
+          "move_x".to_string(),
+          AxisBinding::digital(
+            &["KeyA", "ArrowLeft"],
+            &["KeyD", "ArrowRight"],
+          )
+          .with_analog(GamepadAxis::LeftStickX),
+        ),
+        (
+          "move_y".to_string(),
+          AxisBinding::digital(
+            &["KeyS", "ArrowDown"],
+            &["KeyW", "ArrowUp"],
+          )
+          .with_analog(GamepadAxis::LeftStickY),
+        ),
+      ]
+      .into(),
+    };
+    Self {
+      contexts: [(GAMEPLAY.to_string(), gameplay)].into(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ActionState {
+  down: bool,
+  pressed: bool,
+  released: bool,
+}
+impl ActionState {
+  /// Advance the states by the actions down in the step.
+  fn advance<'a>(
+    states: &mut HashMap<String, Self>,
+    down: impl IntoIterator<Item = (&'a str, bool)>,
+  ) {
+    for state in states.values_mut() {
+      *state = Self {
+        released: state.down,
+        ..Default::default()
+      };
+    }
+    for (name, now) in down {
+      let state =
+        states.entry(name.to_string()).or_default();
+      let was_down = state.released;
+      *state = Self {
+        down: now,
+        pressed: now && !was_down,
+        released: was_down && !now,
+      };
+    }
+  }
+}
+
+/// Action waiting for the next input to bind
+struct Listening {
+  context: String,
+  action: String,
+  /// Inputs held when the listening started
+  held: HashSet<Binding>,
+}
+
+pub struct InputMap {
+  bindings: InputBindings,
+  /// Active contexts in the activation order
+  active: Vec<String>,
+  actions: HashMap<String, ActionState>,
+  axes: HashMap<String, f32>,
+  listening: Option<Listening>,
+  history: History<InputBindings>,
+  /// Bindings at the latest entry of the history
+  recorded: InputBindings,
+}
+impl InputMap {
+  /// The map with the gameplay context active
+  pub fn new(bindings: InputBindings) -> Self {
+    Self {
+      recorded: bindings.clone(),
+      bindings,
+      active: vec![GAMEPLAY.to_string()],
+      actions: HashMap::new(),
+      axes: HashMap::new(),
+      listening: None,
+      history: History::default(),
+    }
+  }
+
+  pub fn set_bindings(&mut self, bindings: InputBindings) {
+    self.bindings = bindings;
+    self.listening = None;
+  }
+
+  pub fn is_active(&self, context: &str) -> bool {
+    self.active.iter().any(|c| c == context)
+  }
+
+  pub fn activate(&mut self, context: &str) {
+    if !self.is_active(context) {
+      self.active.push(context.to_string());
+    }
+  }
+
+  pub fn deactivate(&mut self, context: &str) {
+    self.active.retain(|c| c != context);
+  }
+
+  /// Read the input state. Called once per simulation step.
+  pub fn update(&mut self, input: &InputState) {
+    self.capture(input);
+    let mut down = HashMap::<&str, (bool, bool)>::new();
+    let mut axes = HashMap::new();
+    for context in self
+      .active
+      .iter()
+      .filter_map(|c| self.bindings.contexts.get(c))
+    {
+      for (name, bindings) in context.actions.iter() {
+        let (held, pressed) =
+          down.entry(name.as_str()).or_default();
+        *held |= bindings.iter().any(|b| b.is_down(input));
+        *pressed |=
+          bindings.iter().any(|b| b.is_pressed(input));
+      }
+      for (name, axis) in context.axes.iter() {
+        let v = axis.value(input);
+        let value =
+          axes.entry(name.clone()).or_insert(0f32);
+        if value.abs() < v.abs() {
+          *value = v;
+        }
+      }
+    }
+    // A tap shorter than a step is down for the step.
+    ActionState::advance(
+      &mut self.actions,
+      down.into_iter().map(|(name, (held, pressed))| {
+        (name, held || pressed)
+      }),
+    );
+    self.axes = axes;
+  }
+
+  /// Take the actions down and the axes of a recorded step instead
+  /// of the input state. Called once per simulation step as
+  /// `update`.
+  pub fn replay<'a>(
+    &mut self,
+    down: impl IntoIterator<Item = &'a str>,
+    axes: impl IntoIterator<Item = (&'a str, f32)>,
+  ) {
+    ActionState::advance(
+      &mut self.actions,
+      down.into_iter().map(|name| (name, true)),
+    );
+    self.axes = axes
+      .into_iter()
+      .map(|(name, v)| (name.to_string(), v))
+      .collect();
+  }
+
+  pub fn is_down(&self, action: &str) -> bool {
+    self.actions.get(action).is_some_and(|s| s.down)
+  }
+
+  /// Actions pressed in the step
+  pub fn pressed_actions(
+    &self,
+  ) -> impl Iterator<Item = &str> {
+    self
+      .actions
+      .iter()
+      .filter(|(_, s)| s.pressed)
+      .map(|(name, _)| name.as_str())
+  }
+
+  /// Actions held in the step
+  pub fn down_actions(&self) -> impl Iterator<Item = &str> {
+    self
+      .actions
+      .iter()
+      .filter(|(_, s)| s.down)
+      .map(|(name, _)| name.as_str())
+  }
+
+  pub fn axis(&self, name: &str) -> f32 {
+    self.axes.get(name).copied().unwrap_or(0.)
+  }
+
+  /// Axes of the active contexts in the step
+  pub fn axes(&self) -> impl Iterator<Item = (&str, f32)> {
+    self.axes.iter().map(|(name, v)| (name.as_str(), *v))
+  }
+
+  /// Add the binding to the action.
+  pub fn bind(
+    &mut self,
+    context: &str,
+    action: &str,
+    binding: Binding,
+  ) {
+    let bindings = self
+      .bindings
+      .contexts
+      .entry(context.to_string())
+      .or_default()
+      .actions
+      .entry(action.to_string())
+      .or_default();
+    if !bindings.contains(&binding) {
+      bindings.push(binding);
+    }
+  }
+
+  pub fn unbind(
+    &mut self,
+    context: &str,
+    action: &str,
+    binding: &Binding,
+  ) {
+    if let Some(bindings) = self
+      .bindings
+      .contexts
+      .get_mut(context)
+      .and_then(|c| c.actions.get_mut(action))
+    {
+      bindings.retain(|b| b != binding);
+    }
+  }
+
+  /// Bind the next input to the action.
+  pub fn listen(
+    &mut self,
+    context: &str,
+    action: &str,
+    input: &InputState,
+  ) {
+    self.listening = Some(Listening {
+      context: context.to_string(),
+      action: action.to_string(),
+      held: input.held_bindings().collect(),
+    });
+  }
+
+  fn capture(&mut self, input: &InputState) {
+    let Some(l) = &self.listening else {
+      return;
+    };
+    let Some(binding) =
+      input.held_bindings().find(|b| !l.held.contains(b))
+    else {
+      return;
+    };
+    let Listening {
+      context, action, ..
+    } = self.listening.take().unwrap();
+    self.bind(&context, &action, binding);
+  }
+
+  pub fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    input: &InputState,
+  ) {
+    // The input may come between the steps.
+    self.capture(input);
+    self.record_change();
+    let undone =
+      self.history.shortcuts(ui, &mut self.bindings);
+    let names = self
+      .bindings
+      .contexts
+      .keys()
+      .cloned()
+      .collect::<Vec<_>>();
+    ui.horizontal(|ui| {
+      ui.label("Active:");
+      for name in names.iter() {
+        let mut active = self.is_active(name);
+        if ui.checkbox(&mut active, name).changed() {
+          if active {
+            self.activate(name);
+          } else {
+            self.deactivate(name);
+          }
+        }
+      }
+    });
+    let mut listen = None;
+    let mut unbind = None;
+    for name in names.iter() {
+      let context = &self.bindings.contexts[name];
+      egui::CollapsingHeader::new(name).show(ui, |ui| {
+        egui::Grid::new(("input actions", name))
+          .striped(true)
+          .show(ui, |ui| {
+            for (action, bindings) in context.actions.iter()
+            {
+              ui.label(action);
+              ui.horizontal_wrapped(|ui| {
+                for b in bindings {
+                  if ui
+                    .small_button(b.to_string())
+                    .on_hover_text("Click to unbind")
+                    .clicked()
+                  {
+                    unbind = Some((
+                      name.clone(),
+                      action.clone(),
+                      b.clone(),
+                    ));
+                  }
+                }
+                let waiting = self
+                  .listening
+                  .as_ref()
+                  .is_some_and(|l| {
+                    &l.context == name
+                      && &l.action == action
+                  });
+                if waiting {
+                  ui.label("press an input...");
+                } else if ui.small_button("+").clicked() {
+                  listen =
+                    Some((name.clone(), action.clone()));
+                }
+              });
+              ui.label(if self.is_down(action) {
+                "down"
+              } else {
+                ""
+              });
+              ui.end_row();
+            }
+            for axis in context.axes.keys() {
+              ui.label(axis);
+              ui.label("axis");
+              ui.label(format!("{:+.2}", self.axis(axis)));
+              ui.end_row();
+            }
+          });
+      });
+    }
+    if let Some((context, action)) = listen {
+      self.listen(&context, &action, input);
+    }
+    if let Some((context, action, b)) = unbind {
+      self.unbind(&context, &action, &b);
+    }
+    ui.horizontal(|ui| {
+      if ui.button("Save").clicked() {
+        if let Err(e) = self.bindings.save(BINDINGS_PATH) {
+          log::warn!("Input bindings save error: {e}");
+        }
+      }
+      if ui.button("Reset").clicked() {
+        self.set_bindings(InputBindings::default());
+      }
+      let clicked = self.history.ui(ui, &mut self.bindings);
+      if undone || clicked {
+        self.recorded = self.bindings.clone();
+        self.listening = None;
+      }
+    });
+    self.record_change();
+  }
+
+  /// Record the change of the bindings since the last entry.
+  fn record_change(&mut self) {
+    if self.bindings != self.recorded {
+      let before = std::mem::replace(
+        &mut self.recorded,
+        self.bindings.clone(),
+      );
+      self.history.record(Replace::new(
+        "bindings",
+        before,
+        self.bindings.clone(),
+      ));
+      self.history.seal();
+    }
+  }
+}
+
+impl InputState {
+  /// Bindings of the held inputs
+  fn held_bindings(
+    &self,
+  ) -> impl Iterator<Item = Binding> + '_ {
+    let keys =
+      self.keys.iter().map(|k| Binding::Key(key_name(*k)));
+    let buttons = self
+      .buttons
+      .iter()
+      .filter_map(|b| MouseBinding::from_button(*b))
+      .map(Binding::Mouse);
+    let pads = self
+      .pad_buttons
+      .iter()
+      .copied()
+      .map(Binding::Gamepad);
+    keys.chain(buttons).chain(pads)
+  }
+}
@@ -0,0 +1,245 @@
+//! Input State
+//! ウィンドウイベントから集計した入力の状態
+
+use hashbrown::{HashMap, HashSet};
+use serde::{Deserialize, Serialize};
+use winit::{
+  event::{ElementState, MouseButton, WindowEvent},
+  keyboard::{KeyCode, PhysicalKey},
+};
+
+pub mod buffer;
+pub mod gamepad;
+pub mod map;
+use gamepad::{GamepadAxis, GamepadButton};
+
+/// Keyboard, mouse and gamepad state of the current frame
+///
+/// `pressed` is kept until `end_frame`, which is called after each
+/// simulation step of the game loop. The actions of `map` tell the
+/// release from the down state of the step before.
+#[derive(Debug, Default)]
+pub struct InputState {
+  keys: HashSet<KeyCode>,
+  keys_pressed: HashSet<KeyCode>,
+  buttons: HashSet<MouseButton>,
+  buttons_pressed: HashSet<MouseButton>,
+  pad_buttons: HashSet<GamepadButton>,
+  pad_pressed: HashSet<GamepadButton>,
+  pad_axes: HashMap<GamepadAxis, f32>,
+  /// Cursor position in the window (pixel, top-left origin)
+  cursor: [f32; 2],
+}
+impl InputState {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn handle_event(&mut self, event: &WindowEvent) {
+    match event {
+      WindowEvent::KeyboardInput { event, .. } => {
+        let PhysicalKey::Code(code) = event.physical_key
+        else {
+          return;
+        };
+        match event.state {
+          ElementState::Pressed => {
+            if self.keys.insert(code) {
+              self.keys_pressed.insert(code);
+            }
+          }
+          ElementState::Released => {
+            self.keys.remove(&code);
+          }
+        }
+      }
+      WindowEvent::MouseInput { state, button, .. } => {
+        match state {
+          ElementState::Pressed => {
+            if self.buttons.insert(*button) {
+              self.buttons_pressed.insert(*button);
+            }
+          }
+          ElementState::Released => {
+            self.buttons.remove(button);
+          }
+        }
+      }
+      WindowEvent::CursorMoved { position, .. } => {
+        self.cursor =
+          [position.x as f32, position.y as f32];
+      }
+      WindowEvent::Focused(false) => {
+        self.keys.clear();
+        self.buttons.clear();
+        self.release_gamepad();
+      }
+      _ => {}
+    }
+  }
+
+  /// Clear the pressed state.
+  pub fn end_frame(&mut self) {
+    self.keys_pressed.clear();
+    self.buttons_pressed.clear();
+    self.pad_pressed.clear();
+  }
+
+  /// Set the gamepad button state (from the gamepad backend).
+  #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+  pub fn set_gamepad_button(
+    &mut self,
+    button: GamepadButton,
+    down: bool,
+  ) {
+    if down {
+      if self.pad_buttons.insert(button) {
+        self.pad_pressed.insert(button);
+      }
+    } else {
+      self.pad_buttons.remove(&button);
+    }
+  }
+
+  /// Release the buttons and center the axes of the gamepad (lost
+  /// focus, disconnected).
+  pub fn release_gamepad(&mut self) {
+    self.pad_buttons.clear();
+    self.pad_axes.clear();
+  }
+
+  /// Set the gamepad axis value (from the gamepad backend).
+  #[cfg_attr(not(feature = "gamepad"), allow(dead_code))]
+  pub fn set_gamepad_axis(
+    &mut self,
+    axis: GamepadAxis,
+    value: f32,
+  ) {
+    self.pad_axes.insert(axis, value.clamp(-1., 1.));
+  }
+
+  /// Key state by the name of `KeyCode` (`"KeyA"`, `"Space"`, ...)
+  pub fn is_down_by_name(&self, name: &str) -> bool {
+    self.keys.iter().any(|k| key_name(*k) == name)
+  }
+
+  pub fn is_pressed_by_name(&self, name: &str) -> bool {
+    self.keys_pressed.iter().any(|k| key_name(*k) == name)
+  }
+
+  pub fn is_button_down(
+    &self,
+    button: MouseButton,
+  ) -> bool {
+    self.buttons.contains(&button)
+  }
+
+  pub fn is_button_pressed(
+    &self,
+    button: MouseButton,
+  ) -> bool {
+    self.buttons_pressed.contains(&button)
+  }
+
+  pub fn is_gamepad_down(
+    &self,
+    button: GamepadButton,
+  ) -> bool {
+    self.pad_buttons.contains(&button)
+  }
+
+  pub fn is_gamepad_pressed(
+    &self,
+    button: GamepadButton,
+  ) -> bool {
+    self.pad_pressed.contains(&button)
+  }
+
+  pub fn gamepad_axis(&self, axis: GamepadAxis) -> f32 {
+    self.pad_axes.get(&axis).copied().unwrap_or(0.)
+  }
+
+  /// State read by the scripts in the step
+  pub fn raw(&self) -> RawInput {
+    let names = |keys: &HashSet<KeyCode>| {
+      let mut names = keys
+        .iter()
+        .map(|k| key_name(*k))
+        .collect::<Vec<_>>();
+      names.sort_unstable();
+      names
+    };
+    let mut buttons = self
+      .buttons
+      .iter()
+      .filter_map(|b| RawInput::button_index(*b))
+      .collect::<Vec<_>>();
+    buttons.sort_unstable();
+    RawInput {
+      keys: names(&self.keys),
+      keys_pressed: names(&self.keys_pressed),
+      buttons,
+      cursor: self.cursor,
+    }
+  }
+}
+
+/// Keys, mouse buttons and cursor read by the scripts and the
+/// plugins
+///
+/// Taken from `InputState` once per simulation step, so that a
+/// recording stores it with the input map, and a replay gives it
+/// back to the scripts.
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+pub struct RawInput {
+  /// Names of the keys down (`key_name`)
+  #[serde(default)]
+  pub keys: Vec<String>,
+  /// Names of the keys pressed in the step
+  #[serde(default)]
+  pub keys_pressed: Vec<String>,
+  /// Mouse buttons down (0: left, 1: right, 2: middle)
+  #[serde(default)]
+  pub buttons: Vec<u8>,
+  /// Cursor position in the window (pixel, top-left origin)
+  #[serde(default)]
+  pub cursor: [f32; 2],
+}
+impl RawInput {
+  fn button_index(button: MouseButton) -> Option<u8> {
+    match button {
+      MouseButton::Left => Some(0),
+      MouseButton::Right => Some(1),
+      MouseButton::Middle => Some(2),
+      _ => None,
+    }
+  }
+
+  /// Key state by the name of `KeyCode` (`"KeyA"`, `"Space"`, ...)
+  pub fn is_down_by_name(&self, name: &str) -> bool {
+    self.keys.iter().any(|k| k == name)
+  }
+
+  pub fn is_pressed_by_name(&self, name: &str) -> bool {
+    self.keys_pressed.iter().any(|k| k == name)
+  }
+
+  pub fn is_button_down(
+    &self,
+    button: MouseButton,
+  ) -> bool {
+    Self::button_index(button)
+      .is_some_and(|i| self.buttons.contains(&i))
+  }
+
+  pub fn cursor(&self) -> [f32; 2] {
+    self.cursor
+  }
+}
+
+/// Name of the key (same as the variant name of `KeyCode`)
+pub fn key_name(key: KeyCode) -> String {
+  format!("{key:?}")
+}
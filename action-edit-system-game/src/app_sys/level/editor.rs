@@ -0,0 +1,2026 @@
+//! Level editor
+//! ステージエディタ
+//!
+//! The editor switches the game between the play mode and the edit
+//! mode. In the edit mode the simulation stops, the entities of the
+//! level are despawned, and the screen is the viewport of the level
+//! through the editor camera:
+//!
+//! - Drag with the right or the middle button to pan, and scroll to
+//!   zoom.
+//! - Select: click an entity to select it (Shift to toggle), drag on
+//!   the empty area to select the entities in the box, and drag the
+//!   selection to move it. Delete removes the selection.
+//! - Brush, Rect, Fill: paint the tile of the palette on the current
+//!   layer. Escape cancels the stroke.
+//! - Place: click to place the prefab of the palette.
+//!
+//! The entities snap to the grid. The inspector edits the fields of
+//! every selected entity at once. The fields overriding the prefab
+//! are shown strong, and are reverted to the prefab from their
+//! context menu or all at once by "Revert to prefab".
+//!
+//! The fields of the prefab of the palette are edited under the
+//! prefabs, and apply to every entity of the level that does not
+//! override them. "Save prefab" writes it to `PREFAB_ROOT`.
+//!
+//! Every change can be undone (Ctrl+Z) and redone (Ctrl+Shift+Z).
+//! The levels are saved to `LEVEL_ROOT` as TOML.
+//!
+//! Play from the edit mode takes a `Snapshot` of the level and the
+//! world, and a `SceneSnapshot` of the sprites and the tilemaps, and
+//! Stop restores them. With "Keep changes", the entities changed by
+//! the play are written back to the level as an edit. The buttons
+//! only request the mode, which the app switches between the steps
+//! with the scripts (see `take_request`).
+
+use super::{
+  fields,
+  prefab::{self, Prefab, MAX_DEPTH, PREFAB_ROOT},
+  registry, set_field, Level, LevelEntity, LevelInstance,
+  Snapshot, TileLayer, LEVEL_ROOT,
+};
+use crate::{
+  app_sys::{
+    ecs::{persist::ComponentRegistry, World},
+    gfx::{
+      rdr_2d::camera::Camera2D,
+      util::{TextureID, TextureStorage},
+    },
+    history::{Edit, History},
+    physics::TileShape,
+    scene::{Scene2D, SceneSnapshot},
+  },
+  StdError,
+};
+use egui::{
+  Color32, PointerButton, Pos2, Rect, Sense, Stroke, Vec2,
+};
+use hashbrown::HashMap;
+use serde_json::Value;
+use std::{
+  any::Any,
+  collections::{BTreeMap, BTreeSet},
+  ops::RangeInclusive,
+  path::Path,
+};
+
+/// Camera of the edit mode in the scene
+pub const EDITOR_CAMERA: &str = "editor";
+/// Prefix of the tilemap names of the level in the scene
+const TILEMAP_PREFIX: &str = "level/";
+const ZOOM_RANGE: RangeInclusive<f32> = 0.125..=8.;
+const GRID_COLOR: Color32 =
+  Color32::from_rgba_premultiplied(24, 24, 24, 24);
+const LAYER_COLOR: Color32 =
+  Color32::from_rgb(90, 160, 230);
+const ENTITY_COLOR: Color32 =
+  Color32::from_rgb(230, 180, 60);
+const SELECT_COLOR: Color32 =
+  Color32::from_rgb(250, 250, 120);
+/// Cells drawn in the tile palette at most
+const PALETTE_LIMIT: u32 = 256;
+const PALETTE_CELL: f32 = 24.;
+/// Smallest cell (point) with the grid lines
+const GRID_MIN: f32 = 6.;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+  Play,
+  Edit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Tool {
+  Select,
+  Brush,
+  Rect,
+  Fill,
+  Place,
+}
+impl Tool {
+  const ALL: [Self; 5] = [
+    Self::Select,
+    Self::Brush,
+    Self::Rect,
+    Self::Fill,
+    Self::Place,
+  ];
+
+  fn name(self) -> &'static str {
+    match self {
+      Self::Select => "Select",
+      Self::Brush => "Brush",
+      Self::Rect => "Rect",
+      Self::Fill => "Fill",
+      Self::Place => "Place",
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+enum Drag {
+  /// Moving the selection
+  Move {
+    /// Pointer at the start (world)
+    grab: [f32; 2],
+    /// Positions at the start
+    from: Vec<(u32, [f32; 2])>,
+  },
+  /// Selecting the entities in the box from the point
+  Select { from: Pos2 },
+  /// Tile rectangle from the cell
+  Rect { from: (u32, u32) },
+  /// Stroke of the brush, with the last painted cell
+  Paint { last: Option<(u32, u32)> },
+}
+
+/// Mapping between the world (y up) and the screen through the
+/// camera
+struct View {
+  center: Pos2,
+  pos: [f32; 2],
+  /// Points per world unit
+  scale: f32,
+  rot: f32,
+}
+impl View {
+  fn new(
+    center: Pos2,
+    camera: &Camera2D,
+    ppp: f32,
+  ) -> Self {
+    Self {
+      center,
+      pos: [camera.pos.x, camera.pos.y],
+      scale: camera.zoom / ppp,
+      rot: camera.rot,
+    }
+  }
+
+  fn to_screen(&self, p: [f32; 2]) -> Pos2 {
+    let (s, c) = self.rot.sin_cos();
+    let d = [p[0] - self.pos[0], p[1] - self.pos[1]];
+    let r = [c * d[0] - s * d[1], s * d[0] + c * d[1]];
+    self.center + Vec2::new(r[0], -r[1]) * self.scale
+  }
+
+  fn to_world(&self, p: Pos2) -> [f32; 2] {
+    let v = (p - self.center) / self.scale;
+    let (s, c) = self.rot.sin_cos();
+    [
+      self.pos[0] + c * v.x - s * v.y,
+      self.pos[1] - s * v.x - c * v.y,
+    ]
+  }
+
+  fn rect(&self, min: [f32; 2], max: [f32; 2]) -> Rect {
+    Rect::from_two_pos(
+      self.to_screen(min),
+      self.to_screen(max),
+    )
+  }
+}
+
+/// Change of the tiles of a layer (index, before and after)
+struct TileChange {
+  layer: usize,
+  tiles: Vec<(usize, u32, u32)>,
+}
+impl Edit<Level> for TileChange {
+  fn label(&self) -> String {
+    "paint".to_string()
+  }
+
+  fn apply(&mut self, level: &mut Level) {
+    if let Some(l) = level.layers.get_mut(self.layer) {
+      for (i, _, after) in self.tiles.iter() {
+        l.tiles[*i] = *after;
+      }
+    }
+  }
+
+  fn revert(&mut self, level: &mut Level) {
+    if let Some(l) = level.layers.get_mut(self.layer) {
+      for (i, before, _) in self.tiles.iter().rev() {
+        l.tiles[*i] = *before;
+      }
+    }
+  }
+
+  fn merge(&mut self, next: &mut dyn Any) -> bool {
+    let Some(next) = next
+      .downcast_mut::<Self>()
+      .filter(|n| n.layer == self.layer)
+    else {
+      return false;
+    };
+    for (i, before, after) in next.tiles.drain(..) {
+      match self.tiles.iter_mut().find(|t| t.0 == i) {
+        Some(t) => t.2 = after,
+        None => self.tiles.push((i, before, after)),
+      }
+    }
+    true
+  }
+}
+
+/// Change of the entities by the id (None for no entity)
+struct EntityChange {
+  label: String,
+  changes:
+    Vec<(u32, Option<LevelEntity>, Option<LevelEntity>)>,
+}
+impl Edit<Level> for EntityChange {
+  fn label(&self) -> String {
+    self.label.clone()
+  }
+
+  fn apply(&mut self, level: &mut Level) {
+    for (id, _, after) in self.changes.iter() {
+      level.set_entity(*id, after.clone());
+    }
+  }
+
+  fn revert(&mut self, level: &mut Level) {
+    for (id, before, _) in self.changes.iter().rev() {
+      level.set_entity(*id, before.clone());
+    }
+  }
+
+  fn merge(&mut self, next: &mut dyn Any) -> bool {
+    match next.downcast_mut::<Self>() {
+      Some(next)
+        if next.label == self.label
+          && next.changes.len() == self.changes.len()
+          && next
+            .changes
+            .iter()
+            .zip(self.changes.iter())
+            .all(|(a, b)| a.0 == b.0) =>
+      {
+        for (c, n) in self
+          .changes
+          .iter_mut()
+          .zip(next.changes.iter_mut())
+        {
+          std::mem::swap(&mut c.2, &mut n.2);
+        }
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Change of a layer (None for no layer)
+struct LayerChange {
+  index: usize,
+  before: Option<TileLayer>,
+  after: Option<TileLayer>,
+}
+impl LayerChange {
+  fn set(
+    level: &mut Level,
+    index: usize,
+    present: bool,
+    layer: &Option<TileLayer>,
+  ) {
+    match layer {
+      Some(l) if present => level.layers[index] = l.clone(),
+      Some(l) => level.layers.insert(index, l.clone()),
+      None => {
+        level.layers.remove(index);
+      }
+    }
+  }
+}
+impl Edit<Level> for LayerChange {
+  fn label(&self) -> String {
+    match (&self.before, &self.after) {
+      (None, Some(l)) => format!("add layer {}", l.name),
+      (Some(l), None) => format!("remove layer {}", l.name),
+      _ => "edit layer".to_string(),
+    }
+  }
+
+  fn apply(&mut self, level: &mut Level) {
+    let present = self.before.is_some();
+    Self::set(level, self.index, present, &self.after);
+  }
+
+  fn revert(&mut self, level: &mut Level) {
+    let present = self.after.is_some();
+    Self::set(level, self.index, present, &self.before);
+  }
+
+  fn merge(&mut self, next: &mut dyn Any) -> bool {
+    match next.downcast_mut::<Self>() {
+      Some(next)
+        if next.index == self.index
+          && self.before.is_some()
+          && self.after.is_some()
+          && next.before.is_some()
+          && next.after.is_some() =>
+      {
+        std::mem::swap(&mut self.after, &mut next.after);
+        true
+      }
+      _ => false,
+    }
+  }
+}
+
+/// Edit of the inspector applied to every selected entity
+enum FieldEdit {
+  X(f32),
+  Y(f32),
+  Field(String, Value),
+  /// Back to the value of the prefab
+  Revert(String),
+  /// Remove every override
+  RevertAll,
+}
+
+pub struct LevelEditor {
+  mode: Mode,
+  level: Level,
+  history: History<Level>,
+  /// Changed since saved
+  modified: bool,
+  /// Changed since the tiles are synced to the scene
+  dirty: bool,
+  /// Tilemaps of the level in the scene
+  tilemaps: Vec<String>,
+  prefabs: BTreeMap<String, Prefab>,
+  /// Prefabs edited since loaded
+  modified_prefabs: BTreeSet<String>,
+  registry: ComponentRegistry,
+  /// Entities spawned in the play mode
+  instance: Option<LevelInstance>,
+  /// Active camera of the play mode
+  play_camera: String,
+  /// World and scene before the play started from the edit mode
+  snapshot: Option<(Vec<u8>, SceneSnapshot)>,
+  /// Mode chosen by the buttons
+  request: Option<Mode>,
+  /// Write the changes by the play back to the level on stop
+  keep_changes: bool,
+  /// Levels in `LEVEL_ROOT`
+  levels: Vec<String>,
+  tool: Tool,
+  layer: usize,
+  /// Tile painted by the tools
+  tile: u32,
+  prefab: Option<String>,
+  snap: bool,
+  grid: f32,
+  selection: BTreeSet<u32>,
+  drag: Option<Drag>,
+  new_name: String,
+  status: Option<String>,
+}
+impl LevelEditor {
+  /// Load the prefabs under `PREFAB_ROOT`. The editor starts in
+  /// the play mode with an empty level.
+  pub fn new() -> Self {
+    let mut editor = Self {
+      mode: Mode::Play,
+      level: Level::new("untitled"),
+      history: History::default(),
+      modified: false,
+      dirty: true,
+      tilemaps: Vec::new(),
+      prefabs: BTreeMap::new(),
+      modified_prefabs: BTreeSet::new(),
+      registry: registry(),
+      instance: None,
+      play_camera: String::new(),
+      snapshot: None,
+      request: None,
+      keep_changes: false,
+      levels: Vec::new(),
+      tool: Tool::Select,
+      layer: 0,
+      tile: 1,
+      prefab: None,
+      snap: true,
+      grid: 16.,
+      selection: BTreeSet::new(),
+      drag: None,
+      new_name: String::new(),
+      status: None,
+    };
+    editor.reload_prefabs();
+    editor.levels = super::list_dir(LEVEL_ROOT);
+    editor
+  }
+
+  /// In the edit mode (the simulation stops)
+  pub fn is_editing(&self) -> bool {
+    self.mode == Mode::Edit
+  }
+
+  /// Mode chosen by the buttons since the last call, to be passed
+  /// to `set_mode`
+  pub fn take_request(&mut self) -> Option<Mode> {
+    self.request.take()
+  }
+
+  pub fn reload_prefabs(&mut self) {
+    let (prefabs, errors) = prefab::load_dir(PREFAB_ROOT);
+    for e in errors.iter() {
+      log::warn!("Prefab load error: {e}");
+    }
+    self.status = errors.into_iter().next();
+    self.prefabs = prefabs;
+    self.modified_prefabs.clear();
+    if self
+      .prefab
+      .as_ref()
+      .is_none_or(|p| !self.prefabs.contains_key(p))
+    {
+      self.prefab = self.prefabs.keys().next().cloned();
+    }
+  }
+
+  /// Switch the mode. The entities of the level are spawned to the
+  /// world in the play mode. The play from the edit mode is
+  /// restored to the snapshot when it goes back to the edit mode.
+  pub fn set_mode(
+    &mut self,
+    mode: Mode,
+    world: &mut World,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+  ) {
+    if mode == self.mode {
+      return;
+    }
+    self.mode = mode;
+    self.finish_drag();
+    match mode {
+      Mode::Edit => {
+        if let Some(instance) = self.instance.take() {
+          let kept = if self.keep_changes {
+            instance.changes(
+              &self.level,
+              &self.prefabs,
+              &self.registry,
+              world,
+            )
+          } else {
+            Ok(Vec::new())
+          };
+          instance.despawn(world, scene);
+          self.stop(world, scene, kept);
+        }
+        self.play_camera =
+          scene.active_camera_name().to_string();
+        scene.camera_or_insert(EDITOR_CAMERA);
+        scene.set_active_camera(EDITOR_CAMERA);
+      }
+      Mode::Play => {
+        match Snapshot::take(
+          &self.level,
+          &self.registry,
+          world,
+        ) {
+          Ok(snapshot) => {
+            self.snapshot =
+              Some((snapshot, scene.snapshot()))
+          }
+          Err(e) => {
+            self.mode = Mode::Edit;
+            self.status = Some(format!("snapshot: {e}"));
+            return;
+          }
+        }
+        scene.set_active_camera(&self.play_camera);
+        let (instance, errors) = LevelInstance::spawn(
+          &self.level,
+          &self.prefabs,
+          &self.registry,
+          world,
+          scene,
+          textures,
+        );
+        for e in errors.iter() {
+          log::warn!("Level spawn error: {e}");
+        }
+        self.status = errors.into_iter().next();
+        self.instance = Some(instance);
+      }
+    }
+  }
+
+  /// Restore the snapshot, and apply the changes kept from the
+  /// play. The tilemaps are synced again, as the play may have
+  /// changed them.
+  fn stop(
+    &mut self,
+    world: &mut World,
+    scene: &mut Scene2D,
+    kept: Result<
+      Vec<(LevelEntity, Option<LevelEntity>)>,
+      StdError,
+    >,
+  ) {
+    let Some((snapshot, scene_snapshot)) =
+      self.snapshot.take()
+    else {
+      return;
+    };
+    scene.restore(&scene_snapshot);
+    self.dirty = true;
+    match Snapshot::restore(
+      &snapshot,
+      &self.registry,
+      world,
+    ) {
+      Ok(level) => self.level = level,
+      Err(e) => {
+        self.status = Some(format!("restore: {e}"));
+        return;
+      }
+    }
+    match kept {
+      Ok(kept) if !kept.is_empty() => {
+        self.status = Some(format!(
+          "kept changes of {} entities",
+          kept.len()
+        ));
+        self.apply(EntityChange {
+          label: "keep play changes".to_string(),
+          changes: kept
+            .into_iter()
+            .map(|(before, after)| {
+              (before.id, Some(before), after)
+            })
+            .collect(),
+        });
+      }
+      Ok(_) => (),
+      Err(e) => {
+        self.status = Some(format!("keep changes: {e}"))
+      }
+    }
+  }
+
+  /// Snapshot of the level and the world, as the start of a
+  /// recording. The level is synced to the scene first.
+  pub fn snapshot(
+    &mut self,
+    world: &World,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+  ) -> Result<Vec<u8>, StdError> {
+    self.sync_scene(scene, textures);
+    Snapshot::take(&self.level, &self.registry, world)
+  }
+
+  /// Open the level of the snapshot, and bring the world back to
+  /// it. The play of the level is dropped.
+  pub fn restore(
+    &mut self,
+    snapshot: &[u8],
+    world: &mut World,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+  ) -> Result<(), StdError> {
+    if let Some(instance) = self.instance.take() {
+      instance.despawn(world, scene);
+    }
+    self.snapshot = None;
+    let level =
+      Snapshot::restore(snapshot, &self.registry, world)?;
+    self.open(level);
+    self.sync_scene(scene, textures);
+    Ok(())
+  }
+
+  /// Advance the level in the play mode. Called once per simulation
+  /// step.
+  pub fn step(
+    &mut self,
+    world: &mut World,
+    scene: &mut Scene2D,
+    dt: f32,
+  ) {
+    if let Some(instance) = &self.instance {
+      instance.step(world, scene, dt);
+    }
+  }
+
+  /// Open the level, and forget the history.
+  fn open(&mut self, level: Level) {
+    self.finish_drag();
+    self.level = level;
+    self.history.clear();
+    self.selection.clear();
+    self.layer = 0;
+    self.modified = false;
+    self.dirty = true;
+  }
+
+  fn load(&mut self, name: &str) {
+    let path = Path::new(LEVEL_ROOT)
+      .join(name)
+      .with_extension("toml");
+    match super::load(&path) {
+      Ok(level) => {
+        self.open(level);
+        self.status =
+          Some(format!("loaded {}", path.display()));
+      }
+      Err(e) => self.status = Some(e.to_string()),
+    }
+  }
+
+  fn save(&mut self) {
+    let path = Path::new(LEVEL_ROOT)
+      .join(&self.level.name)
+      .with_extension("toml");
+    let result = self
+      .level
+      .validate()
+      .map_err(Into::into)
+      .and_then(|_| super::save(&path, &self.level));
+    match result {
+      Ok(_) => {
+        self.status =
+          Some(format!("saved {}", path.display()));
+        self.modified = false;
+        self.levels = super::list_dir(LEVEL_ROOT);
+      }
+      Err(e) => self.status = Some(e.to_string()),
+    }
+  }
+
+  fn apply(&mut self, edit: impl Edit<Level>) {
+    self.history.apply(&mut self.level, edit);
+    self.changed();
+  }
+
+  fn changed(&mut self) {
+    self.modified = true;
+    self.dirty = true;
+    self
+      .selection
+      .retain(|id| self.level.entity(*id).is_some());
+    self.layer = self
+      .layer
+      .min(self.level.layers.len().saturating_sub(1));
+  }
+
+  /// End the drag. A stroke of the brush is kept.
+  fn finish_drag(&mut self) {
+    if let Some(Drag::Paint { .. }) = self.drag.take() {
+      self.history.end();
+    }
+  }
+
+  fn snapped(&self, p: [f32; 2]) -> [f32; 2] {
+    if self.snap && 0. < self.grid {
+      p.map(|v| (v / self.grid).round() * self.grid)
+    } else {
+      p
+    }
+  }
+
+  /// Box of the entity in the world (`[min, max]`)
+  fn entity_box(&self, e: &LevelEntity) -> [[f32; 2]; 2] {
+    self.prefab_box(&e.prefab, e.pos)
+  }
+
+  /// Box of the prefab at the position (`[min, max]`)
+  fn prefab_box(
+    &self,
+    prefab: &str,
+    pos: [f32; 2],
+  ) -> [[f32; 2]; 2] {
+    let prefab = self.prefabs.get(prefab);
+    let size = prefab.map_or([16.; 2], |p| p.size());
+    let offset = prefab
+      .and_then(|p| p.sprite.as_ref())
+      .map_or([0.; 2], |s| s.offset);
+    let c = [pos[0] + offset[0], pos[1] + offset[1]];
+    [
+      [c[0] - size[0] * 0.5, c[1] - size[1] * 0.5],
+      [c[0] + size[0] * 0.5, c[1] + size[1] * 0.5],
+    ]
+  }
+
+  /// Topmost entity at the world position
+  fn entity_at(&self, p: [f32; 2]) -> Option<u32> {
+    self
+      .level
+      .entities
+      .iter()
+      .rev()
+      .find(|e| {
+        let [min, max] = self.entity_box(e);
+        (min[0]..=max[0]).contains(&p[0])
+          && (min[1]..=max[1]).contains(&p[1])
+      })
+      .map(|e| e.id)
+  }
+
+  /// Paint the tiles of the current layer.
+  fn paint(&mut self, cells: &[(u32, u32)]) {
+    let Some(layer) = self.level.layers.get(self.layer)
+    else {
+      return;
+    };
+    let tiles = cells
+      .iter()
+      .filter(|(x, y)| {
+        layer.get(*x, *y).is_some_and(|t| t != self.tile)
+      })
+      .map(|(x, y)| {
+        let i = (y * layer.width + x) as usize;
+        (i, layer.tiles[i], self.tile)
+      })
+      .collect::<Vec<_>>();
+    if !tiles.is_empty() {
+      self.apply(TileChange {
+        layer: self.layer,
+        tiles,
+      });
+    }
+  }
+
+  /// Reflect the tiles to the scene.
+  fn sync_scene(
+    &mut self,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+  ) {
+    if !std::mem::take(&mut self.dirty) {
+      return;
+    }
+    let names = self
+      .level
+      .layers
+      .iter()
+      .map(|l| format!("{TILEMAP_PREFIX}{}", l.name))
+      .collect::<Vec<_>>();
+    for old in self.tilemaps.iter() {
+      if !names.contains(old) {
+        scene.remove_tilemap(old);
+      }
+    }
+    for (name, layer) in
+      names.iter().zip(self.level.layers.iter())
+    {
+      let Some(fresh) = layer.to_tilemap(textures) else {
+        continue;
+      };
+      match scene.tilemap_mut(name) {
+        Some(map)
+          if map.width() == layer.width
+            && map.height() == layer.height =>
+        {
+          if map.texture != fresh.texture
+            || map.origin != fresh.origin
+            || map.tile_size != fresh.tile_size
+            || map.atlas_cell != fresh.atlas_cell
+          {
+            *map = fresh;
+          } else {
+            for (i, t) in layer.tiles.iter().enumerate() {
+              let (x, y) = (
+                i as u32 % layer.width,
+                i as u32 / layer.width,
+              );
+              if map.get(x, y) != Some(*t) {
+                map.set(x, y, *t);
+              }
+            }
+          }
+        }
+        _ => scene.insert_tilemap(name, fresh),
+      }
+    }
+    self.tilemaps = names;
+  }
+
+  /// Draw the editor window, and the viewport in the edit mode.
+  pub fn show(
+    &mut self,
+    ctx: &egui::Context,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    egui::Window::new("Level editor")
+      .default_open(false)
+      .resizable(true)
+      .show(ctx, |ui| self.ui(ui, textures, egui_textures));
+    if self.is_editing() {
+      egui::CentralPanel::default()
+        .frame(egui::Frame::none())
+        .show(ctx, |ui| {
+          self.viewport(ui, scene, textures, egui_textures)
+        });
+    }
+    let busy = ctx.input(|i| i.pointer.any_down())
+      || ctx.wants_keyboard_input();
+    if !busy && !self.history.in_transaction() {
+      self.history.seal();
+    }
+    self.sync_scene(scene, textures);
+  }
+
+  fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    ui.horizontal(|ui| {
+      let (label, mode) = match self.mode {
+        Mode::Play if self.snapshot.is_some() => {
+          ("Stop", Mode::Edit)
+        }
+        Mode::Play => ("Edit", Mode::Edit),
+        Mode::Edit => ("Play", Mode::Play),
+      };
+      if ui.button(label).clicked() {
+        self.request = Some(mode);
+      }
+      if self.snapshot.is_some() {
+        ui.checkbox(&mut self.keep_changes, "Keep changes")
+          .on_hover_text(
+            "Write the entities changed by the play to the level",
+          );
+      }
+      ui.label(format!(
+        "{}{}",
+        self.level.name,
+        if self.modified { " *" } else { "" }
+      ));
+    });
+    if let Some(status) = &self.status {
+      ui.label(status);
+    }
+    if !self.is_editing() {
+      return;
+    }
+    if self.history.shortcuts(ui, &mut self.level) {
+      self.changed();
+    }
+    self.toolbar(ui);
+    ui.separator();
+    ui.horizontal(|ui| {
+      for tool in Tool::ALL {
+        ui.selectable_value(
+          &mut self.tool,
+          tool,
+          tool.name(),
+        );
+      }
+      ui.separator();
+      ui.checkbox(&mut self.snap, "Snap");
+      ui.add(
+        egui::DragValue::new(&mut self.grid)
+          .range(1.0..=256.)
+          .prefix("grid "),
+      );
+    });
+    egui::ScrollArea::vertical().show(ui, |ui| {
+      egui::CollapsingHeader::new("Layers")
+        .default_open(true)
+        .show(ui, |ui| self.layers_ui(ui, textures));
+      egui::CollapsingHeader::new("Tiles")
+        .default_open(true)
+        .show(ui, |ui| {
+          self.palette_ui(ui, textures, egui_textures)
+        });
+      egui::CollapsingHeader::new("Prefabs")
+        .default_open(true)
+        .show(ui, |ui| self.prefabs_ui(ui));
+      egui::CollapsingHeader::new("Inspector")
+        .default_open(true)
+        .show(ui, |ui| self.inspector(ui));
+    });
+  }
+
+  fn toolbar(&mut self, ui: &mut egui::Ui) {
+    ui.horizontal(|ui| {
+      let mut load = None;
+      egui::ComboBox::from_id_salt("level editor levels")
+        .selected_text("Open")
+        .show_ui(ui, |ui| {
+          for name in self.levels.iter() {
+            if ui.selectable_label(false, name).clicked() {
+              load = Some(name.clone());
+            }
+          }
+        });
+      if let Some(name) = load {
+        self.load(&name);
+      }
+      ui.add(
+        egui::TextEdit::singleline(&mut self.new_name)
+          .hint_text("name")
+          .desired_width(100.),
+      );
+      if ui
+        .add_enabled(
+          !self.new_name.is_empty(),
+          egui::Button::new("New"),
+        )
+        .clicked()
+      {
+        let name = std::mem::take(&mut self.new_name);
+        self.open(Level::new(name));
+      }
+      if ui.button("Save").clicked() {
+        self.save();
+      }
+      if ui.button("Reload prefabs").clicked() {
+        self.reload_prefabs();
+      }
+      if self.history.ui(ui, &mut self.level) {
+        self.changed();
+      }
+    });
+  }
+
+  fn layers_ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+  ) {
+    let mut remove = None;
+    for (i, l) in self.level.layers.iter().enumerate() {
+      ui.horizontal(|ui| {
+        ui.selectable_value(&mut self.layer, i, &l.name);
+        if ui.small_button("x").clicked() {
+          remove = Some(i);
+        }
+      });
+    }
+    if let Some(index) = remove {
+      let before = self.level.layers.get(index).cloned();
+      self.apply(LayerChange {
+        index,
+        before,
+        after: None,
+      });
+    }
+    if ui.button("Add layer").clicked() {
+      let index = self.level.layers.len();
+      let mut name = format!("layer{index}");
+      while self.level.layer(&name).is_some() {
+        name.push('_');
+      }
+      let mut texture =
+        textures.iter().map(|(n, _)| n).collect::<Vec<_>>();
+      texture.sort();
+      let layer = TileLayer::new(
+        name,
+        texture.first().copied().unwrap_or_default(),
+        64,
+        32,
+        [16., 16.],
+      );
+      self.apply(LayerChange {
+        index,
+        before: None,
+        after: Some(layer),
+      });
+      self.layer = index;
+    }
+
+    let Some(layer) = self.level.layers.get(self.layer)
+    else {
+      return;
+    };
+    let mut edited = layer.clone();
+    let (mut width, mut height) =
+      (layer.width, layer.height);
+    egui::Grid::new("level editor layer").show(ui, |ui| {
+      ui.label("name");
+      ui.text_edit_singleline(&mut edited.name);
+      ui.end_row();
+      ui.label("texture");
+      let mut names =
+        textures.iter().map(|(n, _)| n).collect::<Vec<_>>();
+      names.sort();
+      egui::ComboBox::from_id_salt("level editor texture")
+        .selected_text(&edited.texture)
+        .show_ui(ui, |ui| {
+          for n in names {
+            ui.selectable_value(
+              &mut edited.texture,
+              n.to_string(),
+              n,
+            );
+          }
+        });
+      ui.end_row();
+      ui.label("size");
+      ui.horizontal(|ui| {
+        ui.add(
+          egui::DragValue::new(&mut width).range(1..=1024),
+        );
+        ui.add(
+          egui::DragValue::new(&mut height).range(1..=1024),
+        );
+      });
+      ui.end_row();
+      ui.label("origin");
+      ui.horizontal(|ui| {
+        for c in edited.origin.iter_mut() {
+          ui.add(egui::DragValue::new(c));
+        }
+      });
+      ui.end_row();
+      ui.label("tile size");
+      ui.horizontal(|ui| {
+        for c in edited.tile_size.iter_mut() {
+          ui.add(
+            egui::DragValue::new(c).range(1.0..=1024.),
+          );
+        }
+      });
+      ui.end_row();
+      ui.label("atlas cell");
+      ui.horizontal(|ui| {
+        for c in edited.atlas_cell.iter_mut() {
+          ui.add(egui::DragValue::new(c).range(1..=1024));
+        }
+      });
+      ui.end_row();
+      ui.label("collision");
+      let shape_name = |s: Option<TileShape>| match s {
+        Some(s) => format!("{s:?}"),
+        None => "none".to_string(),
+      };
+      egui::ComboBox::from_id_salt(
+        "level editor collision",
+      )
+      .selected_text(shape_name(edited.collision))
+      .show_ui(ui, |ui| {
+        for s in [
+          None,
+          Some(TileShape::Solid),
+          Some(TileShape::OneWay),
+          Some(TileShape::SlopeLeft),
+          Some(TileShape::SlopeRight),
+        ] {
+          ui.selectable_value(
+            &mut edited.collision,
+            s,
+            shape_name(s),
+          );
+        }
+      });
+      ui.end_row();
+    });
+    if (width, height) != (edited.width, edited.height) {
+      edited.resize(width, height);
+    }
+    let taken =
+      self.level.layers.iter().enumerate().any(|(i, l)| {
+        i != self.layer && l.name == edited.name
+      });
+    if edited != *layer && !edited.name.is_empty() && !taken
+    {
+      self.apply(LayerChange {
+        index: self.layer,
+        before: Some(layer.clone()),
+        after: Some(edited),
+      });
+    }
+  }
+
+  fn palette_ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    let Some(layer) = self.level.layers.get(self.layer)
+    else {
+      ui.label("no layer");
+      return;
+    };
+    let atlas =
+      textures.get_id(&layer.texture).and_then(|id| {
+        Some((textures.size(id)?, *egui_textures.get(&id)?))
+      });
+    ui.horizontal_wrapped(|ui| {
+      ui.selectable_value(&mut self.tile, 0, "Empty");
+      let Some((size, egui_id)) = atlas else {
+        return;
+      };
+      let cell = layer.atlas_cell.map(|c| c.max(1));
+      let columns = size[0] / cell[0];
+      let count =
+        (columns * (size[1] / cell[1])).min(PALETTE_LIMIT);
+      let extent = Vec2::new(
+        cell[0] as f32 / size[0] as f32,
+        cell[1] as f32 / size[1] as f32,
+      );
+      for i in 0..count {
+        let uv = Rect::from_min_size(
+          Pos2::new(
+            (i % columns) as f32 * extent.x,
+            (i / columns) as f32 * extent.y,
+          ),
+          extent,
+        );
+        let image =
+          egui::Image::new(egui::load::SizedTexture::new(
+            egui_id,
+            Vec2::splat(PALETTE_CELL),
+          ))
+          .uv(uv);
+        let button = egui::ImageButton::new(image)
+          .selected(self.tile == i + 1);
+        if ui.add(button).clicked() {
+          self.tile = i + 1;
+        }
+      }
+    });
+  }
+
+  fn prefabs_ui(&mut self, ui: &mut egui::Ui) {
+    if self.prefabs.is_empty() {
+      ui.label(format!("no prefab in {PREFAB_ROOT}"));
+    }
+    ui.horizontal_wrapped(|ui| {
+      for name in self.prefabs.keys() {
+        if ui
+          .selectable_label(
+            self.prefab.as_ref() == Some(name),
+            name,
+          )
+          .clicked()
+        {
+          self.prefab = Some(name.clone());
+          self.tool = Tool::Place;
+        }
+      }
+    });
+    let Some(name) = self.prefab.clone() else {
+      return;
+    };
+    let Some(prefab) = self.prefabs.get(&name) else {
+      return;
+    };
+    let modified = self.modified_prefabs.contains(&name);
+    let mut save = false;
+    ui.horizontal(|ui| {
+      let count = self
+        .level
+        .entities
+        .iter()
+        .filter(|e| e.prefab == name)
+        .count();
+      ui.label(format!(
+        "{name}{}: {count} in the level",
+        if modified { " *" } else { "" }
+      ));
+      save = ui
+        .add_enabled(
+          modified,
+          egui::Button::new("Save prefab"),
+        )
+        .clicked();
+    });
+    let mut edits = Vec::new();
+    egui::Grid::new("level editor prefab")
+      .striped(true)
+      .show(ui, |ui| {
+        for (path, value) in fields(&prefab.components) {
+          ui.label(&path);
+          if let Some(v) = value_ui(ui, &[value]) {
+            edits.push((path, v));
+          }
+          ui.end_row();
+        }
+        for c in prefab.children.iter() {
+          ui.label("child");
+          ui.label(format!(
+            "{} at ({}, {})",
+            c.prefab, c.offset[0], c.offset[1]
+          ));
+          ui.end_row();
+        }
+      });
+    let Some(prefab) = self.prefabs.get_mut(&name) else {
+      return;
+    };
+    if !edits.is_empty() {
+      for (path, v) in edits {
+        set_field(&mut prefab.components, &path, v);
+      }
+      self.modified_prefabs.insert(name.clone());
+    }
+    if save {
+      let path = Path::new(PREFAB_ROOT)
+        .join(&name)
+        .with_extension("toml");
+      match prefab::save(&path, prefab) {
+        Ok(()) => {
+          self.modified_prefabs.remove(&name);
+          self.status =
+            Some(format!("saved {}", path.display()));
+        }
+        Err(e) => self.status = Some(e.to_string()),
+      }
+    }
+  }
+
+  /// Fields of the selected entities with their prefabs. The
+  /// fields that differ among them are shown with the value of the
+  /// first.
+  fn inspector(&mut self, ui: &mut egui::Ui) {
+    let selected = self
+      .selection
+      .iter()
+      .filter_map(|id| self.level.entity(*id))
+      .collect::<Vec<_>>();
+    let Some(first) = selected.first() else {
+      ui.label("nothing selected");
+      return;
+    };
+    let bases = selected
+      .iter()
+      .map(|e| self.prefab_components(&e.prefab))
+      .collect::<Vec<_>>();
+    let all_fields = selected
+      .iter()
+      .zip(bases.iter())
+      .map(|(e, base)| {
+        fields(&prefab::resolve(base, &e.overrides))
+      })
+      .collect::<Vec<_>>();
+    let mut edits = Vec::new();
+    ui.horizontal(|ui| {
+      ui.label(format!("{} selected", selected.len()));
+      let overridden =
+        selected.iter().any(|e| !e.overrides.is_empty());
+      if ui
+        .add_enabled(
+          overridden,
+          egui::Button::new("Revert to prefab"),
+        )
+        .on_hover_text("Remove every override")
+        .clicked()
+      {
+        edits.push(FieldEdit::RevertAll);
+      }
+    });
+    egui::Grid::new("level editor inspector")
+      .striped(true)
+      .show(ui, |ui| {
+        ui.label("prefab");
+        let prefab = &first.prefab;
+        if selected.iter().all(|e| &e.prefab == prefab) {
+          ui.label(prefab);
+        } else {
+          ui.weak("mixed");
+        }
+        ui.end_row();
+        for axis in 0..2 {
+          ui.label(["x", "y"][axis]);
+          let values = selected
+            .iter()
+            .map(|e| Value::from(e.pos[axis]))
+            .collect::<Vec<_>>();
+          if let Some(v) = value_ui(ui, &values) {
+            let v = v.as_f64().unwrap_or_default() as f32;
+            edits.push(match axis {
+              0 => FieldEdit::X(v),
+              _ => FieldEdit::Y(v),
+            });
+          }
+          ui.end_row();
+        }
+        for (path, value) in all_fields[0].iter() {
+          let mut values = vec![value.clone()];
+          for f in all_fields[1..].iter() {
+            match f.get(path) {
+              Some(v) => values.push(v.clone()),
+              None => break,
+            }
+          }
+          if values.len() < all_fields.len() {
+            continue;
+          }
+          let overridden = selected.iter().any(|e| {
+            prefab::is_overridden(&e.overrides, path)
+          });
+          let text = egui::RichText::new(path);
+          ui.add(
+            egui::Label::new(if overridden {
+              text.strong()
+            } else {
+              text
+            })
+            .sense(Sense::click()),
+          )
+          .context_menu(|ui| {
+            if ui
+              .add_enabled(
+                overridden,
+                egui::Button::new("Revert to prefab"),
+              )
+              .clicked()
+            {
+              edits.push(FieldEdit::Revert(path.clone()));
+              ui.close_menu();
+            }
+          });
+          if let Some(v) = value_ui(ui, &values) {
+            edits.push(FieldEdit::Field(path.clone(), v));
+          }
+          ui.end_row();
+        }
+      });
+    if edits.is_empty() {
+      return;
+    }
+    let changes = selected
+      .iter()
+      .zip(bases.iter())
+      .map(|(e, base)| {
+        let mut after = (*e).clone();
+        let mut components =
+          prefab::resolve(base, &e.overrides);
+        for edit in edits.iter() {
+          match edit {
+            FieldEdit::X(x) => after.pos[0] = *x,
+            FieldEdit::Y(y) => after.pos[1] = *y,
+            FieldEdit::Field(path, v) => {
+              set_field(&mut components, path, v.clone());
+            }
+            FieldEdit::Revert(path) => {
+              let mut overrides =
+                prefab::overrides(base, &components);
+              overrides.retain(|k, _| {
+                !prefab::covers(k, path)
+                  && !prefab::covers(path, k)
+              });
+              components =
+                prefab::resolve(base, &overrides);
+            }
+            FieldEdit::RevertAll => {
+              components = base.clone()
+            }
+          }
+        }
+        after.overrides =
+          prefab::overrides(base, &components);
+        (e.id, Some((*e).clone()), Some(after))
+      })
+      .filter(|(_, before, after)| before != after)
+      .collect::<Vec<_>>();
+    if changes.is_empty() {
+      return;
+    }
+    self.apply(EntityChange {
+      label: "edit".to_string(),
+      changes,
+    });
+  }
+
+  /// Components of the prefab (none if it is missing)
+  fn prefab_components(
+    &self,
+    prefab: &str,
+  ) -> BTreeMap<String, Value> {
+    self
+      .prefabs
+      .get(prefab)
+      .map(|p| p.components.clone())
+      .unwrap_or_default()
+  }
+
+  fn viewport(
+    &mut self,
+    ui: &mut egui::Ui,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    if self.history.shortcuts(ui, &mut self.level) {
+      self.finish_drag();
+      self.changed();
+    }
+    let (response, painter) = ui.allocate_painter(
+      ui.available_size(),
+      Sense::click_and_drag(),
+    );
+    let center = ui.ctx().screen_rect().center();
+    let ppp = ui.ctx().pixels_per_point();
+    let camera = scene.camera_or_insert(EDITOR_CAMERA);
+    let view = View::new(center, camera, ppp);
+    let pan =
+      [PointerButton::Secondary, PointerButton::Middle]
+        .into_iter()
+        .any(|b| response.dragged_by(b));
+    if pan {
+      let a = view.to_world(center);
+      let b = view.to_world(center + response.drag_delta());
+      camera.pos.x -= b[0] - a[0];
+      camera.pos.y -= b[1] - a[1];
+    }
+    let scroll = ui.input(|i| i.raw_scroll_delta.y);
+    if let Some(p) =
+      response.hover_pos().filter(|_| scroll != 0.)
+    {
+      let before = view.to_world(p);
+      camera.zoom = (camera.zoom * (scroll * 0.002).exp())
+        .clamp(*ZOOM_RANGE.start(), *ZOOM_RANGE.end());
+      let after =
+        View::new(center, camera, ppp).to_world(p);
+      camera.pos.x += before[0] - after[0];
+      camera.pos.y += before[1] - after[1];
+    }
+    let view = View::new(center, camera, ppp);
+
+    self.draw(&painter, &view, textures, egui_textures);
+    let keys = response.hovered()
+      && !ui.ctx().wants_keyboard_input();
+    if keys
+      && ui.input(|i| i.key_pressed(egui::Key::Escape))
+    {
+      if let Some(Drag::Paint { .. }) = self.drag {
+        self.history.cancel(&mut self.level);
+        self.changed();
+      }
+      self.drag = None;
+    }
+    if keys
+      && ui.input(|i| i.key_pressed(egui::Key::Delete))
+    {
+      self.delete_selection();
+    }
+    let Some(pointer) = response
+      .interact_pointer_pos()
+      .or(response.hover_pos())
+    else {
+      return;
+    };
+    let p = view.to_world(pointer);
+    let shift = ui.input(|i| i.modifiers.shift);
+    let primary = PointerButton::Primary;
+    match self.tool {
+      Tool::Select => {
+        if response.drag_started_by(primary) {
+          self.drag = Some(match self.entity_at(p) {
+            Some(id) => {
+              if !self.selection.contains(&id) {
+                if !shift {
+                  self.selection.clear();
+                }
+                self.selection.insert(id);
+              }
+              Drag::Move {
+                grab: p,
+                from: self
+                  .selection
+                  .iter()
+                  .filter_map(|id| self.level.entity(*id))
+                  .map(|e| (e.id, e.pos))
+                  .collect(),
+              }
+            }
+            None => Drag::Select { from: pointer },
+          });
+        } else if response.clicked() {
+          match self.entity_at(p) {
+            Some(id) if shift => {
+              if !self.selection.remove(&id) {
+                self.selection.insert(id);
+              }
+            }
+            Some(id) => {
+              self.selection = BTreeSet::from([id]);
+            }
+            None if shift => {}
+            None => self.selection.clear(),
+          }
+        }
+        if let Some(Drag::Move { grab, from }) = &self.drag
+        {
+          if response.dragged_by(primary) {
+            self.move_selection(*grab, from.clone(), p);
+          }
+        }
+        if response.drag_stopped_by(primary) {
+          if let Some(Drag::Select { from }) = self.drag {
+            let area = Rect::from_two_pos(from, pointer);
+            if !shift {
+              self.selection.clear();
+            }
+            for e in self.level.entities.iter() {
+              let [min, max] = self.entity_box(e);
+              if area.intersects(view.rect(min, max)) {
+                self.selection.insert(e.id);
+              }
+            }
+          }
+          self.drag = None;
+        }
+      }
+      Tool::Brush => {
+        if response.drag_started_by(primary) {
+          self.history.begin("paint");
+          self.drag = Some(Drag::Paint { last: None });
+        }
+        let cell = self.cell_at(p);
+        if response.clicked() {
+          if let Some(c) = cell {
+            self.paint(&[c]);
+          }
+        }
+        if let Some(Drag::Paint { last }) = self.drag {
+          if let Some(c) =
+            cell.filter(|_| response.dragged_by(primary))
+          {
+            let cells = match last {
+              Some(l) => line(l, c),
+              None => vec![c],
+            };
+            self.paint(&cells);
+            self.drag = Some(Drag::Paint { last: Some(c) });
+          }
+        }
+        if response.drag_stopped_by(primary) {
+          self.finish_drag();
+        }
+      }
+      Tool::Rect => {
+        if response.drag_started_by(primary) {
+          self.drag =
+            self.cell_at(p).map(|from| Drag::Rect { from });
+        }
+        if response.drag_stopped_by(primary) {
+          if let Some(Drag::Rect { from }) =
+            self.drag.take()
+          {
+            let to = self.clamped_cell(p);
+            let cells = (from.1.min(to.1)
+              ..=from.1.max(to.1))
+              .flat_map(|y| {
+                (from.0.min(to.0)..=from.0.max(to.0))
+                  .map(move |x| (x, y))
+              })
+              .collect::<Vec<_>>();
+            self.paint(&cells);
+          }
+        }
+      }
+      Tool::Fill => {
+        if response.clicked() {
+          let cells = self
+            .cell_at(p)
+            .and_then(|(x, y)| {
+              Some(
+                self
+                  .level
+                  .layers
+                  .get(self.layer)?
+                  .flood(x, y),
+              )
+            })
+            .unwrap_or_default();
+          self.paint(&cells);
+        }
+      }
+      Tool::Place => {
+        if response.clicked() {
+          self.place(p);
+        }
+      }
+    }
+    self.draw_drag(&painter, &view, pointer, p);
+  }
+
+  fn cell_at(&self, p: [f32; 2]) -> Option<(u32, u32)> {
+    self.level.layers.get(self.layer)?.cell_at(p)
+  }
+
+  /// Cell at the position, clamped into the layer
+  fn clamped_cell(&self, p: [f32; 2]) -> (u32, u32) {
+    let Some(l) = self.level.layers.get(self.layer) else {
+      return (0, 0);
+    };
+    let c = |i: usize, size: u32| {
+      ((p[i] - l.origin[i]) / l.tile_size[i])
+        .clamp(0., size.saturating_sub(1) as f32)
+        as u32
+    };
+    (c(0, l.width), c(1, l.height))
+  }
+
+  fn move_selection(
+    &mut self,
+    grab: [f32; 2],
+    from: Vec<(u32, [f32; 2])>,
+    p: [f32; 2],
+  ) {
+    let Some((_, anchor)) = from.first() else {
+      return;
+    };
+    let target = self.snapped([
+      anchor[0] + p[0] - grab[0],
+      anchor[1] + p[1] - grab[1],
+    ]);
+    let d = [target[0] - anchor[0], target[1] - anchor[1]];
+    let changes = from
+      .iter()
+      .filter_map(|(id, pos)| {
+        let before = self.level.entity(*id)?.clone();
+        let mut after = before.clone();
+        after.pos = [pos[0] + d[0], pos[1] + d[1]];
+        Some((*id, Some(before), Some(after)))
+      })
+      .collect::<Vec<_>>();
+    if changes.iter().any(|(_, b, a)| b != a) {
+      self.apply(EntityChange {
+        label: "move".to_string(),
+        changes,
+      });
+    }
+  }
+
+  fn place(&mut self, p: [f32; 2]) {
+    let Some(prefab) = self
+      .prefab
+      .as_ref()
+      .and_then(|p| self.prefabs.get(p))
+    else {
+      self.status = Some("no prefab to place".to_string());
+      return;
+    };
+    let id = self.level.next_id();
+    let entity = LevelEntity {
+      id,
+      prefab: prefab.name.clone(),
+      pos: self.snapped(p),
+      overrides: BTreeMap::new(),
+    };
+    self.apply(EntityChange {
+      label: format!("place {}", prefab.name),
+      changes: vec![(id, None, Some(entity))],
+    });
+    self.selection = BTreeSet::from([id]);
+  }
+
+  fn delete_selection(&mut self) {
+    let changes = self
+      .selection
+      .iter()
+      .filter_map(|id| {
+        Some((
+          *id,
+          Some(self.level.entity(*id)?.clone()),
+          None,
+        ))
+      })
+      .collect::<Vec<_>>();
+    if !changes.is_empty() {
+      self.apply(EntityChange {
+        label: "delete".to_string(),
+        changes,
+      });
+    }
+  }
+
+  fn draw(
+    &self,
+    painter: &egui::Painter,
+    view: &View,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+  ) {
+    for (i, l) in self.level.layers.iter().enumerate() {
+      let min = l.origin;
+      let max = l.cell_origin(l.width, l.height);
+      let current = i == self.layer;
+      let color = if current {
+        LAYER_COLOR
+      } else {
+        LAYER_COLOR.gamma_multiply(0.3)
+      };
+      painter.rect_stroke(
+        view.rect(min, max),
+        0.,
+        Stroke::new(1., color),
+      );
+      if !current || l.tile_size[0] * view.scale < GRID_MIN
+      {
+        continue;
+      }
+      let grid = Stroke::new(1., GRID_COLOR);
+      for x in 1..l.width {
+        let a = view.to_screen(l.cell_origin(x, 0));
+        let b = view.to_screen(l.cell_origin(x, l.height));
+        painter.line_segment([a, b], grid);
+      }
+      for y in 1..l.height {
+        let a = view.to_screen(l.cell_origin(0, y));
+        let b = view.to_screen(l.cell_origin(l.width, y));
+        painter.line_segment([a, b], grid);
+      }
+    }
+    for e in self.level.entities.iter() {
+      self.draw_prefab(
+        painter,
+        view,
+        textures,
+        egui_textures,
+        &e.prefab,
+        e.pos,
+      );
+      let [min, max] = self.entity_box(e);
+      let rect = view.rect(min, max);
+      let selected = self.selection.contains(&e.id);
+      painter.rect_stroke(
+        rect,
+        0.,
+        if selected {
+          Stroke::new(2., SELECT_COLOR)
+        } else {
+          Stroke::new(1., ENTITY_COLOR)
+        },
+      );
+      painter.circle_filled(
+        view.to_screen(e.pos),
+        2.,
+        ENTITY_COLOR,
+      );
+    }
+  }
+
+  /// Sprites of the prefab and its children at the position
+  fn draw_prefab(
+    &self,
+    painter: &egui::Painter,
+    view: &View,
+    textures: &TextureStorage,
+    egui_textures: &HashMap<TextureID, egui::TextureId>,
+    prefab: &str,
+    pos: [f32; 2],
+  ) {
+    let mut stack = vec![(prefab, pos, 0)];
+    while let Some((name, pos, depth)) = stack.pop() {
+      let [min, max] = self.prefab_box(name, pos);
+      let rect = view.rect(min, max);
+      let prefab = self.prefabs.get(name);
+      let image = prefab
+        .and_then(|p| p.sprite.as_ref())
+        .and_then(|s| s.resolve(textures))
+        .and_then(|(id, uv)| {
+          Some((*egui_textures.get(&id)?, uv))
+        });
+      match image {
+        Some((id, [o, x])) => painter.image(
+          id,
+          rect,
+          Rect::from_min_size(
+            Pos2::new(o[0], o[1]),
+            Vec2::new(x[0], x[1]),
+          ),
+          Color32::WHITE,
+        ),
+        None => painter.rect_filled(
+          rect,
+          0.,
+          ENTITY_COLOR.gamma_multiply(0.4),
+        ),
+      };
+      let Some(prefab) =
+        prefab.filter(|_| depth < MAX_DEPTH)
+      else {
+        continue;
+      };
+      for c in prefab.children.iter() {
+        let pos =
+          [pos[0] + c.offset[0], pos[1] + c.offset[1]];
+        stack.push((c.prefab.as_str(), pos, depth + 1));
+      }
+    }
+  }
+
+  /// Pointer feedback of the tool
+  fn draw_drag(
+    &self,
+    painter: &egui::Painter,
+    view: &View,
+    pointer: Pos2,
+    p: [f32; 2],
+  ) {
+    let stroke = Stroke::new(1., SELECT_COLOR);
+    let Some(l) = self.level.layers.get(self.layer) else {
+      return;
+    };
+    match (&self.drag, self.tool) {
+      (Some(Drag::Select { from }), _) => {
+        painter.rect_stroke(
+          Rect::from_two_pos(*from, pointer),
+          0.,
+          stroke,
+        );
+      }
+      (Some(Drag::Rect { from }), _) => {
+        let to = self.clamped_cell(p);
+        let min =
+          l.cell_origin(from.0.min(to.0), from.1.min(to.1));
+        let max = l.cell_origin(
+          from.0.max(to.0) + 1,
+          from.1.max(to.1) + 1,
+        );
+        painter.rect_stroke(
+          view.rect(min, max),
+          0.,
+          stroke,
+        );
+      }
+      (_, Tool::Brush | Tool::Rect | Tool::Fill) => {
+        if let Some((x, y)) = l.cell_at(p) {
+          painter.rect_stroke(
+            view.rect(
+              l.cell_origin(x, y),
+              l.cell_origin(x + 1, y + 1),
+            ),
+            0.,
+            stroke,
+          );
+        }
+      }
+      _ => {}
+    }
+  }
+}
+impl Default for LevelEditor {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+/// Cells on the line between the cells (both included)
+fn line(a: (u32, u32), b: (u32, u32)) -> Vec<(u32, u32)> {
+  let d =
+    [b.0 as f32 - a.0 as f32, b.1 as f32 - a.1 as f32];
+  let steps = d[0].abs().max(d[1].abs()) as u32;
+  (0..=steps)
+    .map(|i| {
+      let t = i as f32 / steps.max(1) as f32;
+      (
+        (a.0 as f32 + d[0] * t).round() as u32,
+        (a.1 as f32 + d[1] * t).round() as u32,
+      )
+    })
+    .collect()
+}
+
+/// Editor of the leaf values of the fields. Returns the new value.
+fn value_ui(
+  ui: &mut egui::Ui,
+  values: &[Value],
+) -> Option<Value> {
+  let first = values.first()?;
+  let mixed = values.iter().any(|v| v != first);
+  ui.horizontal(|ui| {
+    let value = match first {
+      Value::Bool(b) => {
+        let mut b = *b;
+        ui.checkbox(&mut b, "")
+          .changed()
+          .then_some(Value::Bool(b))
+      }
+      Value::Number(n) if n.is_u64() => {
+        let mut x = n.as_u64().unwrap_or_default();
+        ui.add(egui::DragValue::new(&mut x))
+          .changed()
+          .then(|| Value::from(x))
+      }
+      Value::Number(n) if n.is_i64() => {
+        let mut x = n.as_i64().unwrap_or_default();
+        ui.add(egui::DragValue::new(&mut x))
+          .changed()
+          .then(|| Value::from(x))
+      }
+      Value::Number(n) => {
+        let mut x = n.as_f64().unwrap_or_default();
+        ui.add(egui::DragValue::new(&mut x).speed(0.5))
+          .changed()
+          .then(|| Value::from(x))
+      }
+      Value::String(s) => {
+        let mut s = s.clone();
+        ui.text_edit_singleline(&mut s)
+          .changed()
+          .then_some(Value::String(s))
+      }
+      other => {
+        ui.label(other.to_string());
+        None
+      }
+    };
+    if mixed {
+      ui.weak("mixed");
+    }
+    value
+  })
+  .inner
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_sys::{
+    combat::Fighter, gfx::rdr_2d::camera::Camera2D,
+    scene::Sprite,
+  };
+  use nalgebra::Point2;
+
+  #[test]
+  fn stop_brings_the_world_and_the_scene_back() {
+    let mut world = World::new();
+    let mut scene =
+      Scene2D::new(Camera2D::with_view_size(1., 1.));
+    let textures = TextureStorage::new();
+    let mut editor = LevelEditor::new();
+    editor.set_mode(
+      Mode::Edit,
+      &mut world,
+      &mut scene,
+      &textures,
+    );
+    let mut layer =
+      TileLayer::new("ground", "tiles", 4, 2, [16.; 2]);
+    layer.tiles[1] = 3;
+    editor.open(Level {
+      layers: vec![layer],
+      ..Level::new("test")
+    });
+    editor.sync_scene(&mut scene, &textures);
+    let fighter: Fighter = toml::from_str(
+      "action = 'idle'\nfacing_right = true\nhealth = 100",
+    )
+    .unwrap();
+    let e = world.spawn((fighter.clone(),));
+    let sprite = scene.spawn_sprite(Sprite::default());
+    let map = format!("{TILEMAP_PREFIX}ground");
+    let before = Snapshot::take(
+      &editor.level,
+      &editor.registry,
+      &world,
+    )
+    .unwrap();
+
+    editor.set_mode(
+      Mode::Play,
+      &mut world,
+      &mut scene,
+      &textures,
+    );
+    world.get_mut::<Fighter>(e).unwrap().health = 10;
+    world.spawn((fighter,));
+    scene.sprite_mut(sprite).unwrap().pos =
+      Point2::new(5., 5.);
+    let spawned = scene.spawn_sprite(Sprite::default());
+    scene.tilemap_mut(&map).unwrap().set(0, 0, 7);
+    scene.remove_tilemap(&map);
+    editor.set_mode(
+      Mode::Edit,
+      &mut world,
+      &mut scene,
+      &textures,
+    );
+
+    let after = Snapshot::take(
+      &editor.level,
+      &editor.registry,
+      &world,
+    )
+    .unwrap();
+    assert_eq!(after, before);
+    assert_eq!(
+      scene.sprite_ids().collect::<Vec<_>>(),
+      [sprite]
+    );
+    assert_eq!(
+      scene.sprite(sprite).unwrap().pos,
+      Point2::origin()
+    );
+    assert_eq!(
+      scene.spawn_sprite(Sprite::default()),
+      spawned
+    );
+    let tiles = scene.tilemap(&map).unwrap();
+    assert_eq!(tiles.get(0, 0), Some(0));
+    assert_eq!(tiles.get(1, 0), Some(3));
+    assert!(tiles.is_dirty());
+  }
+}
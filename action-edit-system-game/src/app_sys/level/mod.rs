@@ -0,0 +1,796 @@
+//! Levels
+//! タイルとエンティティを配置したステージ
+//!
+//! A level is the tile layers and the entities placed from the
+//! prefabs (`prefab`), which store only the fields overriding the
+//! prefab. It is edited in the level editor (`editor`),
+//! and saved to `LEVEL_ROOT` in the formats of the action assets.
+//!
+//! In the play mode, the entities and the children of their
+//! prefabs are spawned to the world with their components
+//! (`LevelInstance`). The components are the
+//! serde values of the components registered to the
+//! `ComponentRegistry` of `registry`.
+//!
+//! The play from the editor starts with a `Snapshot` of the level
+//! and the world, which is restored when the play stops.
+
+use super::{
+  action::{format::format_of, machine::StateMachine},
+  combat::Fighter,
+  ecs::{
+    persist::{ComponentRegistry, WorldData},
+    Entity, World,
+  },
+  gfx::util::TextureStorage,
+  physics::{body::Body, CollisionWorld, TileShape},
+  scene::{Scene2D, Sprite, SpriteID, Tilemap},
+};
+use crate::StdError;
+use nalgebra::{Point2, Vector2};
+use prefab::{Prefab, MAX_DEPTH};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::Path};
+
+pub mod editor;
+pub mod prefab;
+
+/// Directory of the levels
+pub const LEVEL_ROOT: &str = "./levels";
+/// Drawing order of the entity sprites
+const ENTITY_LAYER: i32 = 100;
+/// Fields changed by the simulation, not kept from the play
+const RUNTIME_FIELDS: [&str; 7] = [
+  "fighter.pos",
+  "fighter.action",
+  "fighter.tick",
+  "body.velocity",
+  "machine.state",
+  "machine.tick",
+  "machine.hit",
+];
+
+/// Grid of tiles from an atlas texture
+///
+/// Tile value 0 is empty, and `n` is the `n - 1`th cell of the
+/// atlas (row-major), as `Tilemap`.
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct TileLayer {
+  pub name: String,
+  /// Atlas texture name in `TextureStorage`
+  pub texture: String,
+  /// Size of an atlas cell in pixel
+  pub atlas_cell: [u32; 2],
+  /// Bottom-left corner in the world
+  #[serde(default)]
+  pub origin: [f32; 2],
+  /// Size of a tile in the world
+  pub tile_size: [f32; 2],
+  pub width: u32,
+  pub height: u32,
+  /// Tiles from the bottom row
+  pub tiles: Vec<u32>,
+  /// Collision shape of the non-empty tiles (None for no collision)
+  #[serde(
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub collision: Option<TileShape>,
+}
+impl TileLayer {
+  pub fn new(
+    name: impl ToString,
+    texture: impl ToString,
+    width: u32,
+    height: u32,
+    tile_size: [f32; 2],
+  ) -> Self {
+    Self {
+      name: name.to_string(),
+      texture: texture.to_string(),
+      atlas_cell: [
+        tile_size[0] as u32,
+        tile_size[1] as u32,
+      ],
+      origin: [0.; 2],
+      tile_size,
+      width,
+      height,
+      tiles: vec![0; (width * height) as usize],
+      collision: None,
+    }
+  }
+
+  pub fn get(&self, x: u32, y: u32) -> Option<u32> {
+    (x < self.width && y < self.height)
+      .then(|| self.tiles[(y * self.width + x) as usize])
+  }
+
+  /// Change the size. The tiles keep the position from the
+  /// bottom-left corner.
+  pub fn resize(&mut self, width: u32, height: u32) {
+    let mut tiles = vec![0; (width * height) as usize];
+    for y in 0..height.min(self.height) {
+      for x in 0..width.min(self.width) {
+        tiles[(y * width + x) as usize] =
+          self.tiles[(y * self.width + x) as usize];
+      }
+    }
+    self.width = width;
+    self.height = height;
+    self.tiles = tiles;
+  }
+
+  /// Tile at the world position
+  pub fn cell_at(&self, p: [f32; 2]) -> Option<(u32, u32)> {
+    let x = (p[0] - self.origin[0]) / self.tile_size[0];
+    let y = (p[1] - self.origin[1]) / self.tile_size[1];
+    (0. <= x && 0. <= y)
+      .then_some((x as u32, y as u32))
+      .filter(|(x, y)| x < &self.width && y < &self.height)
+  }
+
+  /// Bottom-left corner of the tile in the world
+  pub fn cell_origin(&self, x: u32, y: u32) -> [f32; 2] {
+    [
+      self.origin[0] + self.tile_size[0] * x as f32,
+      self.origin[1] + self.tile_size[1] * y as f32,
+    ]
+  }
+
+  /// Tiles connected to the tile by the sides, with the same value
+  pub fn flood(&self, x: u32, y: u32) -> Vec<(u32, u32)> {
+    let Some(tile) = self.get(x, y) else {
+      return Vec::new();
+    };
+    let mut visited =
+      vec![false; (self.width * self.height) as usize];
+    let mut stack = vec![(x, y)];
+    let mut cells = Vec::new();
+    while let Some((x, y)) = stack.pop() {
+      let i = (y * self.width + x) as usize;
+      if visited[i] || self.tiles[i] != tile {
+        continue;
+      }
+      visited[i] = true;
+      cells.push((x, y));
+      if 0 < x {
+        stack.push((x - 1, y));
+      }
+      if 0 < y {
+        stack.push((x, y - 1));
+      }
+      if x + 1 < self.width {
+        stack.push((x + 1, y));
+      }
+      if y + 1 < self.height {
+        stack.push((x, y + 1));
+      }
+    }
+    cells
+  }
+
+  /// None if the layer is too large for a tilemap.
+  pub fn to_tilemap(
+    &self,
+    textures: &TextureStorage,
+  ) -> Option<Tilemap> {
+    let mut map = Tilemap::new(
+      self.width,
+      self.height,
+      Vector2::new(self.tile_size[0], self.tile_size[1]),
+      self.atlas_cell,
+    )?;
+    map.texture = textures.get_id(&self.texture);
+    map.origin =
+      Point2::new(self.origin[0], self.origin[1]);
+    for (i, t) in self.tiles.iter().enumerate() {
+      let i = i as u32;
+      map.set(i % self.width, i / self.width, *t);
+    }
+    Some(map)
+  }
+}
+
+/// Entity placed in a level
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct LevelEntity {
+  /// Unique in the level
+  pub id: u32,
+  pub prefab: String,
+  pub pos: [f32; 2],
+  /// Fields overriding the prefab by the path (as `fields`). A
+  /// path of a component name overrides the whole component.
+  #[serde(
+    default,
+    alias = "components",
+    skip_serializing_if = "BTreeMap::is_empty"
+  )]
+  pub overrides: BTreeMap<String, Value>,
+}
+
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+pub struct Level {
+  pub name: String,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub layers: Vec<TileLayer>,
+  /// In the id order
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub entities: Vec<LevelEntity>,
+}
+impl Level {
+  pub fn new(name: impl ToString) -> Self {
+    Self {
+      name: name.to_string(),
+      ..Default::default()
+    }
+  }
+
+  pub fn entity(&self, id: u32) -> Option<&LevelEntity> {
+    let i = self
+      .entities
+      .binary_search_by_key(&id, |e| e.id)
+      .ok()?;
+    Some(&self.entities[i])
+  }
+
+  /// Replace, add (Some) or remove (None) the entity of the id.
+  /// Returns the previous one.
+  pub fn set_entity(
+    &mut self,
+    id: u32,
+    entity: Option<LevelEntity>,
+  ) -> Option<LevelEntity> {
+    let found =
+      self.entities.binary_search_by_key(&id, |e| e.id);
+    match (found, entity) {
+      (Ok(i), Some(e)) => {
+        Some(std::mem::replace(&mut self.entities[i], e))
+      }
+      (Ok(i), None) => Some(self.entities.remove(i)),
+      (Err(i), Some(e)) => {
+        self.entities.insert(i, e);
+        None
+      }
+      (Err(_), None) => None,
+    }
+  }
+
+  /// Id for a new entity
+  pub fn next_id(&self) -> u32 {
+    self.entities.last().map_or(0, |e| e.id + 1)
+  }
+
+  pub fn layer(&self, name: &str) -> Option<&TileLayer> {
+    self.layers.iter().find(|l| l.name == name)
+  }
+
+  /// Check the data that the runtime does not accept.
+  pub fn validate(&self) -> Result<(), String> {
+    if self.name.is_empty() {
+      return Err("the level has no name".to_string());
+    }
+    for (i, l) in self.layers.iter().enumerate() {
+      if l.width.checked_mul(l.height).map(|n| n as usize)
+        != Some(l.tiles.len())
+      {
+        return Err(format!(
+          "{}: layer {} has {} tiles for {}x{}",
+          self.name,
+          l.name,
+          l.tiles.len(),
+          l.width,
+          l.height
+        ));
+      }
+      if self.layers[..i].iter().any(|o| o.name == l.name) {
+        return Err(format!(
+          "{}: layer {} is duplicated",
+          self.name, l.name
+        ));
+      }
+    }
+    if let Some(w) =
+      self.entities.windows(2).find(|w| w[1].id <= w[0].id)
+    {
+      return Err(format!(
+        "{}: entity {} is out of the id order",
+        self.name, w[1].id
+      ));
+    }
+    Ok(())
+  }
+}
+
+/// Load and validate the level.
+pub fn load(
+  path: impl AsRef<Path>,
+) -> Result<Level, StdError> {
+  let path = path.as_ref();
+  let level: Level =
+    format_of(path)?.decode(&std::fs::read(path)?)?;
+  level.validate()?;
+  Ok(level)
+}
+
+pub fn save(
+  path: impl AsRef<Path>,
+  level: &Level,
+) -> Result<(), StdError> {
+  let path = path.as_ref();
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  std::fs::write(path, format_of(path)?.encode(level)?)?;
+  Ok(())
+}
+
+/// Names of the levels in the directory
+pub fn list_dir(dir: impl AsRef<Path>) -> Vec<String> {
+  let Ok(entries) = std::fs::read_dir(dir) else {
+    return Vec::new();
+  };
+  let mut names = entries
+    .filter_map(|e| e.ok().map(|e| e.path()))
+    .filter(|p| format_of(p).is_ok())
+    .filter_map(|p| {
+      Some(p.file_stem()?.to_str()?.to_string())
+    })
+    .collect::<Vec<_>>();
+  names.sort();
+  names.dedup();
+  names
+}
+
+/// Leaf values of the components by the path (as
+/// `fighter.knockback.0`)
+pub fn fields(
+  components: &BTreeMap<String, Value>,
+) -> BTreeMap<String, Value> {
+  fn walk(
+    path: String,
+    value: &Value,
+    out: &mut BTreeMap<String, Value>,
+  ) {
+    match value {
+      Value::Object(m) => {
+        for (k, v) in m.iter() {
+          walk(format!("{path}.{k}"), v, out);
+        }
+      }
+      Value::Array(a) => {
+        for (i, v) in a.iter().enumerate() {
+          walk(format!("{path}.{i}"), v, out);
+        }
+      }
+      _ => {
+        out.insert(path, value.clone());
+      }
+    }
+  }
+  let mut out = BTreeMap::new();
+  for (name, value) in components.iter() {
+    walk(name.clone(), value, &mut out);
+  }
+  out
+}
+
+/// Set the value of the path, adding the missing keys as objects.
+/// Returns false if the path goes out of an array or into a value
+/// other than an object.
+pub fn set_field(
+  components: &mut BTreeMap<String, Value>,
+  path: &str,
+  value: Value,
+) -> bool {
+  let mut keys = path.split('.');
+  let Some(mut target) = keys.next().map(|k| {
+    components.entry(k.to_string()).or_insert(Value::Null)
+  }) else {
+    return false;
+  };
+  for k in keys {
+    if target.is_null() {
+      *target = Value::Object(Default::default());
+    }
+    let next = match target {
+      Value::Object(m) => {
+        Some(m.entry(k).or_insert(Value::Null))
+      }
+      Value::Array(a) => {
+        k.parse::<usize>().ok().and_then(|i| a.get_mut(i))
+      }
+      _ => None,
+    };
+    let Some(next) = next else {
+      return false;
+    };
+    target = next;
+  }
+  *target = value;
+  true
+}
+
+/// Entity spawned from a level
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Placed {
+  /// Id in the level (of the root for the children of the
+  /// prefabs)
+  pub id: u32,
+  pub prefab: String,
+  pub pos: [f32; 2],
+}
+
+/// Components of the levels
+pub fn registry() -> ComponentRegistry {
+  let mut registry = ComponentRegistry::new();
+  registry
+    .register::<Placed>("placed")
+    .register::<Fighter>("fighter")
+    .register::<Body>("body")
+    .register::<StateMachine>("machine");
+  registry
+}
+
+/// Level and world before the play in the editor
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+  pub level: Level,
+  pub world: WorldData,
+}
+impl Snapshot {
+  /// Save the level and the registered components of the world as
+  /// MessagePack.
+  pub fn take(
+    level: &Level,
+    registry: &ComponentRegistry,
+    world: &World,
+  ) -> Result<Vec<u8>, StdError> {
+    let snapshot = Self {
+      level: level.clone(),
+      world: registry.save(world)?,
+    };
+    Ok(rmp_serde::to_vec_named(&snapshot)?)
+  }
+
+  /// Bring the world back to the snapshot, and return the level.
+  pub fn restore(
+    bytes: &[u8],
+    registry: &ComponentRegistry,
+    world: &mut World,
+  ) -> Result<Level, StdError> {
+    let snapshot: Self = rmp_serde::from_slice(bytes)?;
+    registry.restore(world, &snapshot.world)?;
+    Ok(snapshot.level)
+  }
+}
+
+/// Entities of a level spawned to the world
+#[derive(Debug, Default)]
+pub struct LevelInstance {
+  entities: Vec<Spawned>,
+  /// After their descendants
+  children: Vec<SpawnedChild>,
+  /// Colliders of the tile layers
+  collision: CollisionWorld,
+}
+
+#[derive(Debug)]
+struct Spawned {
+  /// Id in the level
+  id: u32,
+  entity: Entity,
+  sprite: Option<SpriteID>,
+  /// Components on spawn
+  components: BTreeMap<String, Value>,
+}
+
+#[derive(Debug)]
+struct SpawnedChild {
+  parent: Entity,
+  entity: Entity,
+  offset: [f32; 2],
+  sprite: Option<SpriteID>,
+}
+
+/// Spawns the prefabs with their children
+struct Spawner<'a> {
+  prefabs: &'a BTreeMap<String, Prefab>,
+  registry: &'a ComponentRegistry,
+  world: &'a mut World,
+  scene: &'a mut Scene2D,
+  textures: &'a TextureStorage,
+  children: Vec<SpawnedChild>,
+  errors: Vec<String>,
+}
+impl Spawner<'_> {
+  fn spawn(
+    &mut self,
+    placed: Placed,
+    overrides: &BTreeMap<String, Value>,
+    depth: usize,
+  ) -> Result<(Entity, Option<SpriteID>), String> {
+    if MAX_DEPTH < depth {
+      return Err(format!(
+        "children of {} are nested too deep",
+        placed.prefab
+      ));
+    }
+    let prefabs = self.prefabs;
+    let Some(prefab) = prefabs.get(&placed.prefab) else {
+      return Err(format!("no prefab {}", placed.prefab));
+    };
+    let (id, pos) = (placed.id, placed.pos);
+    let e = self.world.spawn((placed,));
+    if let Err(err) = self.registry.insert(
+      self.world,
+      e,
+      &prefab.instantiate(overrides),
+    ) {
+      self.world.despawn(e);
+      return Err(err.to_string());
+    }
+    // The position of the level wins over the one of the
+    // components.
+    if let Some(f) = self.world.get_mut::<Fighter>(e) {
+      f.pos = pos;
+    }
+    let sprite = prefab.sprite.as_ref().and_then(|s| {
+      let (texture, uv) = s.resolve(self.textures)?;
+      Some(self.scene.spawn_sprite(Sprite {
+        pos: Point2::new(
+          pos[0] + s.offset[0],
+          pos[1] + s.offset[1],
+        ),
+        size: Vector2::new(s.size[0], s.size[1]),
+        texture: Some(texture),
+        uv,
+        layer: ENTITY_LAYER,
+        ..Default::default()
+      }))
+    });
+    for child in prefab.children.iter() {
+      let placed = Placed {
+        id,
+        prefab: child.prefab.clone(),
+        pos: [
+          pos[0] + child.offset[0],
+          pos[1] + child.offset[1],
+        ],
+      };
+      match self.spawn(placed, &child.overrides, depth + 1)
+      {
+        Ok((entity, sprite)) => {
+          self.children.push(SpawnedChild {
+            parent: e,
+            entity,
+            offset: child.offset,
+            sprite,
+          })
+        }
+        Err(err) => self.errors.push(format!(
+          "entity {id}: child {}: {err}",
+          child.prefab
+        )),
+      }
+    }
+    Ok((e, sprite))
+  }
+}
+
+/// Move the entity and its sprite.
+fn move_to(
+  world: &mut World,
+  scene: &mut Scene2D,
+  e: Entity,
+  sprite: Option<SpriteID>,
+  pos: [f32; 2],
+) {
+  if let Some(f) = world.get_mut::<Fighter>(e) {
+    f.pos = pos;
+  }
+  let Some(placed) = world.get_mut::<Placed>(e) else {
+    return;
+  };
+  let d = Vector2::new(
+    pos[0] - placed.pos[0],
+    pos[1] - placed.pos[1],
+  );
+  placed.pos = pos;
+  if d == Vector2::zeros() {
+    return;
+  }
+  if let Some(s) =
+    sprite.and_then(|id| scene.sprite_mut(id))
+  {
+    s.pos += d;
+  }
+}
+
+impl LevelInstance {
+  /// Spawn the entities with the components and the sprites of
+  /// their prefabs. The entities that fail are skipped, and
+  /// returned as the errors.
+  pub fn spawn(
+    level: &Level,
+    prefabs: &BTreeMap<String, Prefab>,
+    registry: &ComponentRegistry,
+    world: &mut World,
+    scene: &mut Scene2D,
+    textures: &TextureStorage,
+  ) -> (Self, Vec<String>) {
+    let mut spawner = Spawner {
+      prefabs,
+      registry,
+      world,
+      scene,
+      textures,
+      children: Vec::new(),
+      errors: Vec::new(),
+    };
+    let mut entities = Vec::new();
+    for entity in level.entities.iter() {
+      let placed = Placed {
+        id: entity.id,
+        prefab: entity.prefab.clone(),
+        pos: entity.pos,
+      };
+      match spawner.spawn(placed, &entity.overrides, 0) {
+        Ok((e, sprite)) => {
+          let mut components = registry
+            .save_entity(spawner.world, e)
+            .unwrap_or_default();
+          components.remove("placed");
+          entities.push(Spawned {
+            id: entity.id,
+            entity: e,
+            sprite,
+            components,
+          });
+        }
+        Err(err) => spawner
+          .errors
+          .push(format!("entity {}: {err}", entity.id)),
+      }
+    }
+    let mut collision = CollisionWorld::default();
+    for layer in level.layers.iter() {
+      let (Some(shape), Some(map)) =
+        (layer.collision, layer.to_tilemap(textures))
+      else {
+        continue;
+      };
+      collision
+        .add_tilemap(&map, |t| (t != 0).then_some(shape));
+    }
+    let instance = Self {
+      entities,
+      children: spawner.children,
+      collision,
+    };
+    (instance, spawner.errors)
+  }
+
+  /// Move the bodies, follow the fighters by the placements and the
+  /// sprites, and the parents by the children. Called once per
+  /// simulation step.
+  pub fn step(
+    &self,
+    world: &mut World,
+    scene: &mut Scene2D,
+    dt: f32,
+  ) {
+    world.query::<(&mut Body, &mut Fighter)>(
+      |_, (b, f)| {
+        f.pos = b.step(&self.collision, f.pos, dt);
+      },
+    );
+    for s in self.entities.iter() {
+      if let Some(pos) =
+        world.get::<Fighter>(s.entity).map(|f| f.pos)
+      {
+        move_to(world, scene, s.entity, s.sprite, pos);
+      }
+    }
+    for c in self.children.iter().rev() {
+      let Some(parent) =
+        world.get::<Placed>(c.parent).map(|p| p.pos)
+      else {
+        continue;
+      };
+      let pos =
+        [parent[0] + c.offset[0], parent[1] + c.offset[1]];
+      move_to(world, scene, c.entity, c.sprite, pos);
+    }
+  }
+
+  /// Remove the entities and the sprites.
+  pub fn despawn(
+    self,
+    world: &mut World,
+    scene: &mut Scene2D,
+  ) {
+    let entities = self
+      .entities
+      .into_iter()
+      .map(|s| (s.entity, s.sprite));
+    let children = self
+      .children
+      .into_iter()
+      .map(|c| (c.entity, c.sprite));
+    for (e, sprite) in entities.chain(children) {
+      world.despawn(e);
+      if let Some(id) = sprite {
+        scene.remove_sprite(id);
+      }
+    }
+  }
+
+  /// Level entities changed by the play: the entity in the level,
+  /// and the one in the world (None if despawned). The fields
+  /// changed from the spawn become the overrides.
+  pub fn changes(
+    &self,
+    level: &Level,
+    prefabs: &BTreeMap<String, Prefab>,
+    registry: &ComponentRegistry,
+    world: &World,
+  ) -> Result<
+    Vec<(LevelEntity, Option<LevelEntity>)>,
+    StdError,
+  > {
+    let mut changes = Vec::new();
+    for s in self.entities.iter() {
+      let Some(before) = level.entity(s.id) else {
+        continue;
+      };
+      let after = match world.get::<Placed>(s.entity) {
+        Some(placed) => {
+          let mut now =
+            registry.save_entity(world, s.entity)?;
+          now.remove("placed");
+          let base = prefabs
+            .get(&placed.prefab)
+            .map(|p| p.components.clone())
+            .unwrap_or_default();
+          let mut components =
+            prefab::resolve(&base, &before.overrides);
+          for (path, value) in
+            prefab::overrides(&s.components, &now)
+          {
+            // The position is the one of the placement.
+            if !RUNTIME_FIELDS
+              .iter()
+              .any(|f| prefab::covers(f, &path))
+            {
+              set_field(&mut components, &path, value);
+            }
+          }
+          Some(LevelEntity {
+            id: s.id,
+            prefab: placed.prefab.clone(),
+            pos: placed.pos,
+            overrides: prefab::overrides(
+              &base,
+              &components,
+            ),
+          })
+        }
+        None => None,
+      };
+      if after.as_ref() != Some(before) {
+        changes.push((before.clone(), after));
+      }
+    }
+    Ok(changes)
+  }
+}
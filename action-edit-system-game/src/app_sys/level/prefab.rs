@@ -0,0 +1,204 @@
+//! Prefabs
+//!
+//! A prefab is the template of the level entities: the sprite shown
+//! in the editor and the game, the components given to the
+//! entities placed from it, and the child entities spawned with
+//! them.
+//!
+//! The entities store only the fields overriding their prefab
+//! (`overrides`), so the edits of a prefab apply to every entity
+//! that does not override the field.
+
+use super::set_field;
+use crate::{
+  app_sys::action::{format::format_of, SpriteRef},
+  StdError,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{collections::BTreeMap, path::Path};
+
+/// Directory of the prefabs
+pub const PREFAB_ROOT: &str = "./prefabs";
+/// Limit of the nested children, against the prefabs including
+/// themselves
+pub const MAX_DEPTH: usize = 8;
+
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct Prefab {
+  pub name: String,
+  #[serde(
+    default,
+    skip_serializing_if = "Option::is_none"
+  )]
+  pub sprite: Option<SpriteRef>,
+  /// Components by the registered name
+  #[serde(
+    default,
+    skip_serializing_if = "BTreeMap::is_empty"
+  )]
+  pub components: BTreeMap<String, Value>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub children: Vec<PrefabChild>,
+}
+impl Prefab {
+  /// Size of the entity in the editor
+  pub fn size(&self) -> [f32; 2] {
+    self.sprite.as_ref().map_or([16.; 2], |s| s.size)
+  }
+
+  /// Components of an instance with the overrides
+  pub fn instantiate(
+    &self,
+    overrides: &BTreeMap<String, Value>,
+  ) -> BTreeMap<String, Value> {
+    resolve(&self.components, overrides)
+  }
+}
+
+/// Entity spawned with the prefab, which follows it
+#[derive(
+  Debug, Clone, PartialEq, Serialize, Deserialize,
+)]
+pub struct PrefabChild {
+  pub prefab: String,
+  /// Position from the parent
+  #[serde(default)]
+  pub offset: [f32; 2],
+  /// Fields overriding the prefab of the child
+  #[serde(
+    default,
+    skip_serializing_if = "BTreeMap::is_empty"
+  )]
+  pub overrides: BTreeMap<String, Value>,
+}
+
+/// Components with the values of the overrides by the path (as
+/// `fields`)
+pub fn resolve(
+  base: &BTreeMap<String, Value>,
+  overrides: &BTreeMap<String, Value>,
+) -> BTreeMap<String, Value> {
+  let mut components = base.clone();
+  for (path, value) in overrides.iter() {
+    if !set_field(&mut components, path, value.clone()) {
+      log::warn!("Override {path} does not fit the prefab");
+    }
+  }
+  components
+}
+
+/// Values of the components that differ from the base, by the
+/// shallowest path. The fields missing in the components are not
+/// overridden.
+pub fn overrides(
+  base: &BTreeMap<String, Value>,
+  components: &BTreeMap<String, Value>,
+) -> BTreeMap<String, Value> {
+  fn diff(
+    path: String,
+    base: Option<&Value>,
+    value: &Value,
+    out: &mut BTreeMap<String, Value>,
+  ) {
+    match (base, value) {
+      (Some(Value::Object(b)), Value::Object(m)) => {
+        for (k, v) in m.iter() {
+          diff(format!("{path}.{k}"), b.get(k), v, out);
+        }
+      }
+      (Some(Value::Array(b)), Value::Array(a))
+        if b.len() == a.len() =>
+      {
+        for (i, (b, v)) in b.iter().zip(a).enumerate() {
+          diff(format!("{path}.{i}"), Some(b), v, out);
+        }
+      }
+      _ if base != Some(value) => {
+        out.insert(path, value.clone());
+      }
+      _ => {}
+    }
+  }
+  let mut out = BTreeMap::new();
+  for (name, value) in components.iter() {
+    diff(name.clone(), base.get(name), value, &mut out);
+  }
+  out
+}
+
+/// The path is the field or a parent of it.
+pub fn covers(path: &str, field: &str) -> bool {
+  field.strip_prefix(path).is_some_and(|rest| {
+    rest.is_empty() || rest.starts_with('.')
+  })
+}
+
+/// The field is overridden by itself or a parent.
+pub fn is_overridden(
+  overrides: &BTreeMap<String, Value>,
+  field: &str,
+) -> bool {
+  overrides.keys().any(|k| covers(k, field))
+}
+
+pub fn load(
+  path: impl AsRef<Path>,
+) -> Result<Prefab, StdError> {
+  let path = path.as_ref();
+  let prefab: Prefab =
+    format_of(path)?.decode(&std::fs::read(path)?)?;
+  if prefab.name.is_empty() {
+    return Err("the prefab has no name".into());
+  }
+  Ok(prefab)
+}
+
+pub fn save(
+  path: impl AsRef<Path>,
+  prefab: &Prefab,
+) -> Result<(), StdError> {
+  let path = path.as_ref();
+  if let Some(dir) = path.parent() {
+    std::fs::create_dir_all(dir)?;
+  }
+  std::fs::write(path, format_of(path)?.encode(prefab)?)?;
+  Ok(())
+}
+
+/// Load the prefabs in the directory by the name. Returns the
+/// errors of the files that are not loaded.
+pub fn load_dir(
+  dir: impl AsRef<Path>,
+) -> (BTreeMap<String, Prefab>, Vec<String>) {
+  let dir = dir.as_ref();
+  let mut prefabs = BTreeMap::new();
+  let entries = match std::fs::read_dir(dir) {
+    Ok(entries) => entries,
+    Err(e) => {
+      return (
+        prefabs,
+        vec![format!("{}: {e}", dir.display())],
+      )
+    }
+  };
+  let mut paths = entries
+    .filter_map(|e| e.ok().map(|e| e.path()))
+    .filter(|p| format_of(p).is_ok())
+    .collect::<Vec<_>>();
+  paths.sort();
+  let mut errors = Vec::new();
+  for path in paths {
+    match load(&path) {
+      Ok(prefab) => {
+        prefabs.insert(prefab.name.clone(), prefab);
+      }
+      Err(e) => {
+        errors.push(format!("{}: {e}", path.display()))
+      }
+    }
+  }
+  (prefabs, errors)
+}
@@ -0,0 +1,954 @@
+//! Lua API of the 2D scene
+//!
+//! Globals:
+//! - `scene`: sprites, cameras and tilemaps
+//! - `texture`: texture storage (by name or `TextureID`)
+//! - `log`: application log
+//!
+//! `texture.load` reads only the files under `ASSET_ROOT`.
+
+use crate::app_sys::{
+  gfx::{
+    rdr_2d::camera::Camera2D,
+    util::{TextureID, TextureStorage},
+  },
+  scene::{Scene2D, Sprite, SpriteID, Tilemap},
+};
+use mlua::{
+  AnyUserData, Lua, MetaMethod, Table, UserData,
+  UserDataFields, UserDataMethods, Value, Variadic,
+};
+use parking_lot::RwLock;
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+/// Directory that `texture.load` can read
+pub const ASSET_ROOT: &str = ".";
+
+/// Shared state that is touched by the scripts
+#[derive(Clone)]
+pub struct SceneContext {
+  pub scene: Arc<RwLock<Scene2D>>,
+  pub textures: Arc<RwLock<TextureStorage>>,
+}
+impl SceneContext {
+  fn texture_from_lua(
+    &self,
+    value: Value,
+  ) -> mlua::Result<Option<TextureID>> {
+    let textures = self.textures.read();
+    match value {
+      Value::Nil => Ok(None),
+      Value::Integer(_) | Value::Number(_) => {
+        let raw = match value {
+          Value::Integer(i) => {
+            u32::try_from(i).map_err(|_| i.to_string())
+          }
+          Value::Number(n)
+            if n.fract() == 0.
+              && (0. ..=u32::MAX as f64).contains(&n) =>
+          {
+            Ok(n as u32)
+          }
+          Value::Number(n) => Err(n.to_string()),
+          _ => unreachable!(),
+        }
+        .map_err(|v| {
+          mlua::Error::runtime(format!(
+            "{v} is not a texture id"
+          ))
+        })?;
+        let id = TextureID::from_raw(raw);
+        textures
+          .contains(id)
+          .then_some(Some(id))
+          .ok_or_else(|| {
+            mlua::Error::runtime(format!(
+              "texture id {raw} is not exist"
+            ))
+          })
+      }
+      Value::String(s) => {
+        let name = s.to_str()?;
+        textures.get_id(&name).map(Some).ok_or_else(|| {
+          mlua::Error::runtime(format!(
+            "texture \"{}\" is not exist",
+            &*name
+          ))
+        })
+      }
+      Value::UserData(ud) => {
+        Ok(Some(ud.borrow::<LuaTexture>()?.id))
+      }
+      v => Err(mlua::Error::runtime(format!(
+        "{} cannot be used as a texture",
+        v.type_name()
+      ))),
+    }
+  }
+}
+
+/// Resolve the path of `texture.load` under `ASSET_ROOT`.
+fn asset_path(path: &str) -> mlua::Result<PathBuf> {
+  let root = Path::new(ASSET_ROOT).canonicalize()?;
+  root
+    .join(path)
+    .canonicalize()
+    .ok()
+    .filter(|p| p.is_file() && p.starts_with(&root))
+    .ok_or_else(|| {
+      mlua::Error::runtime(format!(
+        "{path} is not found in {ASSET_ROOT}"
+      ))
+    })
+}
+
+/// Register the scene API to the globals.
+pub fn register(
+  lua: &Lua,
+  ctx: &SceneContext,
+) -> mlua::Result<()> {
+  let globals = lua.globals();
+  globals.set("scene", scene_table(lua, ctx)?)?;
+  globals.set("texture", texture_table(lua, ctx)?)?;
+  globals.set("log", log_table(lua)?)?;
+  Ok(())
+}
+
+/// Field and method names of the API userdata
+///
+/// They are collected from `add_fields` and `add_methods` of the
+/// type, so the console completion follows the registration.
+#[derive(Debug, Default)]
+pub struct UserDataMembers {
+  pub fields: Vec<String>,
+  pub methods: Vec<String>,
+}
+impl UserDataMembers {
+  fn of<T: UserData>() -> Self {
+    let mut members = Self::default();
+    T::add_fields(&mut members);
+    T::add_methods(&mut members);
+    members
+  }
+}
+impl<T> UserDataFields<T> for UserDataMembers {
+  fn add_field<V>(&mut self, name: impl ToString, _: V) {
+    self.fields.push(name.to_string());
+  }
+
+  fn add_field_method_get<M, R>(
+    &mut self,
+    name: impl ToString,
+    _: M,
+  ) {
+    self.fields.push(name.to_string());
+  }
+
+  fn add_field_method_set<M, A>(
+    &mut self,
+    _: impl ToString,
+    _: M,
+  ) {
+  }
+
+  fn add_field_function_get<F, R>(
+    &mut self,
+    name: impl ToString,
+    _: F,
+  ) {
+    self.fields.push(name.to_string());
+  }
+
+  fn add_field_function_set<F, A>(
+    &mut self,
+    _: impl ToString,
+    _: F,
+  ) {
+  }
+
+  fn add_meta_field<V>(&mut self, _: impl ToString, _: V) {}
+
+  fn add_meta_field_with<F, R>(
+    &mut self,
+    _: impl ToString,
+    _: F,
+  ) {
+  }
+}
+impl<T> UserDataMethods<T> for UserDataMembers {
+  fn add_method<M, A, R>(
+    &mut self,
+    name: impl ToString,
+    _: M,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_method_mut<M, A, R>(
+    &mut self,
+    name: impl ToString,
+    _: M,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_async_method<M, A, MR, R>(
+    &mut self,
+    name: impl ToString,
+    _: M,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_async_method_mut<M, A, MR, R>(
+    &mut self,
+    name: impl ToString,
+    _: M,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_function<F, A, R>(
+    &mut self,
+    name: impl ToString,
+    _: F,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_function_mut<F, A, R>(
+    &mut self,
+    name: impl ToString,
+    _: F,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_async_function<F, A, FR, R>(
+    &mut self,
+    name: impl ToString,
+    _: F,
+  ) {
+    self.methods.push(name.to_string());
+  }
+
+  fn add_meta_method<M, A, R>(
+    &mut self,
+    _: impl ToString,
+    _: M,
+  ) {
+  }
+
+  fn add_meta_method_mut<M, A, R>(
+    &mut self,
+    _: impl ToString,
+    _: M,
+  ) {
+  }
+
+  fn add_async_meta_method<M, A, MR, R>(
+    &mut self,
+    _: impl ToString,
+    _: M,
+  ) {
+  }
+
+  fn add_async_meta_method_mut<M, A, MR, R>(
+    &mut self,
+    _: impl ToString,
+    _: M,
+  ) {
+  }
+
+  fn add_meta_function<F, A, R>(
+    &mut self,
+    _: impl ToString,
+    _: F,
+  ) {
+  }
+
+  fn add_meta_function_mut<F, A, R>(
+    &mut self,
+    _: impl ToString,
+    _: F,
+  ) {
+  }
+
+  fn add_async_meta_function<F, A, FR, R>(
+    &mut self,
+    _: impl ToString,
+    _: F,
+  ) {
+  }
+}
+
+/// Members of the userdata if it is a type of this API
+pub fn userdata_members(
+  ud: &AnyUserData,
+) -> UserDataMembers {
+  if ud.is::<LuaSprite>() {
+    UserDataMembers::of::<LuaSprite>()
+  } else if ud.is::<LuaCamera>() {
+    UserDataMembers::of::<LuaCamera>()
+  } else if ud.is::<LuaTexture>() {
+    UserDataMembers::of::<LuaTexture>()
+  } else if ud.is::<LuaTilemap>() {
+    UserDataMembers::of::<LuaTilemap>()
+  } else {
+    UserDataMembers::default()
+  }
+}
+
+fn scene_table(
+  lua: &Lua,
+  ctx: &SceneContext,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let c = ctx.clone();
+  t.set(
+    "spawn_sprite",
+    lua.create_function(
+      move |_, desc: Option<Table>| {
+        let mut sprite = Sprite::default();
+        if let Some(desc) = desc {
+          sprite.pos.x =
+            desc.get::<Option<f32>>("x")?.unwrap_or(0.);
+          sprite.pos.y =
+            desc.get::<Option<f32>>("y")?.unwrap_or(0.);
+          sprite.size.x = desc
+            .get::<Option<f32>>("w")?
+            .unwrap_or(sprite.size.x);
+          sprite.size.y = desc
+            .get::<Option<f32>>("h")?
+            .unwrap_or(sprite.size.y);
+          sprite.rot =
+            desc.get::<Option<f32>>("rot")?.unwrap_or(0.);
+          sprite.layer =
+            desc.get::<Option<i32>>("layer")?.unwrap_or(0);
+          sprite.texture = c.texture_from_lua(
+            desc.get::<Value>("texture")?,
+          )?;
+        }
+        let id = c.scene.write().spawn_sprite(sprite);
+        Ok(LuaSprite { ctx: c.clone(), id })
+      },
+    )?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "sprite",
+    lua.create_function(move |_, id: u32| {
+      let id = SpriteID::from_raw(id);
+      Ok(
+        c.scene
+          .read()
+          .sprite(id)
+          .is_some()
+          .then(|| LuaSprite { ctx: c.clone(), id }),
+      )
+    })?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "sprites",
+    lua.create_function(move |_, ()| {
+      let mut ids =
+        c.scene.read().sprite_ids().collect::<Vec<_>>();
+      ids.sort();
+      Ok(
+        ids
+          .into_iter()
+          .map(|id| LuaSprite { ctx: c.clone(), id })
+          .collect::<Vec<_>>(),
+      )
+    })?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "camera",
+    lua.create_function(
+      move |_, name: Option<String>| {
+        let mut scene = c.scene.write();
+        let name = name.unwrap_or_else(|| {
+          scene.active_camera_name().to_string()
+        });
+        scene.camera_or_insert(&name);
+        Ok(LuaCamera {
+          ctx: c.clone(),
+          name,
+        })
+      },
+    )?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "create_tilemap",
+    lua.create_function(
+      move |_, (name, desc): (String, Table)| {
+        let width = desc.get::<u32>("width")?;
+        let height = desc.get::<u32>("height")?;
+        let tile_w =
+          desc.get::<Option<f32>>("tile_w")?.unwrap_or(32.);
+        let tile_h = desc
+          .get::<Option<f32>>("tile_h")?
+          .unwrap_or(tile_w);
+        let cell_w =
+          desc.get::<Option<u32>>("cell_w")?.unwrap_or(16);
+        let cell_h = desc
+          .get::<Option<u32>>("cell_h")?
+          .unwrap_or(cell_w);
+        let mut map = Tilemap::new(
+          width,
+          height,
+          nalgebra::Vector2::new(tile_w, tile_h),
+          [cell_w, cell_h],
+        )
+        .ok_or_else(|| {
+          mlua::Error::runtime(format!(
+            "tilemap of {width}x{height} tiles is too large"
+          ))
+        })?;
+        map.origin.x =
+          desc.get::<Option<f32>>("x")?.unwrap_or(0.);
+        map.origin.y =
+          desc.get::<Option<f32>>("y")?.unwrap_or(0.);
+        map.texture = c.texture_from_lua(
+          desc.get::<Value>("texture")?,
+        )?;
+        c.scene.write().insert_tilemap(&name, map);
+        Ok(LuaTilemap {
+          ctx: c.clone(),
+          name,
+        })
+      },
+    )?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "tilemap",
+    lua.create_function(move |_, name: String| {
+      Ok(c.scene.read().tilemap(&name).is_some().then(
+        || LuaTilemap {
+          ctx: c.clone(),
+          name,
+        },
+      ))
+    })?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "remove_tilemap",
+    lua.create_function(move |_, name: String| {
+      Ok(c.scene.write().remove_tilemap(&name).is_some())
+    })?,
+  )?;
+  Ok(t)
+}
+
+fn texture_table(
+  lua: &Lua,
+  ctx: &SceneContext,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let c = ctx.clone();
+  t.set(
+    "load",
+    lua.create_function(
+      move |_, (name, path): (String, String)| {
+        let file = asset_path(&path)?;
+        let id =
+          c.textures.write().load(&name, &file).map_err(
+            |e| {
+              mlua::Error::runtime(format!("{path}: {e}"))
+            },
+          )?;
+        Ok(LuaTexture { ctx: c.clone(), id })
+      },
+    )?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "get",
+    lua.create_function(move |_, key: Value| {
+      Ok(
+        c.texture_from_lua(key)
+          .ok()
+          .flatten()
+          .map(|id| LuaTexture { ctx: c.clone(), id }),
+      )
+    })?,
+  )?;
+  let c = ctx.clone();
+  t.set(
+    "list",
+    lua.create_function(move |_, ()| {
+      let textures = c.textures.read();
+      let mut ids = textures
+        .iter()
+        .map(|(_, id)| id)
+        .collect::<Vec<_>>();
+      ids.sort();
+      Ok(
+        ids
+          .into_iter()
+          .map(|id| LuaTexture { ctx: c.clone(), id })
+          .collect::<Vec<_>>(),
+      )
+    })?,
+  )?;
+  Ok(t)
+}
+
+fn log_table(lua: &Lua) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  for (name, level) in [
+    ("debug", log::Level::Debug),
+    ("info", log::Level::Info),
+    ("warn", log::Level::Warn),
+    ("error", log::Level::Error),
+  ] {
+    t.set(
+      name,
+      lua.create_function(
+        move |lua, args: Variadic<Value>| {
+          let tostring = lua
+            .globals()
+            .get::<mlua::Function>("tostring")?;
+          let msg = args
+            .iter()
+            .map(|v| tostring.call::<String>(v.clone()))
+            .collect::<mlua::Result<Vec<_>>>()?
+            .join("\t");
+          log::log!(target: "lua", level, "{msg}");
+          Ok(())
+        },
+      )?,
+    )?;
+  }
+  Ok(t)
+}
+
+/// Handle of the sprite in the scene
+#[derive(Clone)]
+pub struct LuaSprite {
+  ctx: SceneContext,
+  id: SpriteID,
+}
+impl LuaSprite {
+  fn with<R>(
+    &self,
+    f: impl FnOnce(&mut Sprite) -> R,
+  ) -> mlua::Result<R> {
+    let mut scene = self.ctx.scene.write();
+    let sprite =
+      scene.sprite_mut(self.id).ok_or_else(|| {
+        mlua::Error::runtime(format!(
+          "sprite {} is already removed",
+          self.id.raw()
+        ))
+      })?;
+    Ok(f(sprite))
+  }
+}
+impl UserData for LuaSprite {
+  fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+    fields.add_field_method_get("id", |_, this| {
+      Ok(this.id.raw())
+    });
+    fields.add_field_method_get("x", |_, this| {
+      this.with(|s| s.pos.x)
+    });
+    fields.add_field_method_set("x", |_, this, v: f32| {
+      this.with(|s| s.pos.x = v)
+    });
+    fields.add_field_method_get("y", |_, this| {
+      this.with(|s| s.pos.y)
+    });
+    fields.add_field_method_set("y", |_, this, v: f32| {
+      this.with(|s| s.pos.y = v)
+    });
+    fields.add_field_method_get("w", |_, this| {
+      this.with(|s| s.size.x)
+    });
+    fields.add_field_method_set("w", |_, this, v: f32| {
+      this.with(|s| s.size.x = v)
+    });
+    fields.add_field_method_get("h", |_, this| {
+      this.with(|s| s.size.y)
+    });
+    fields.add_field_method_set("h", |_, this, v: f32| {
+      this.with(|s| s.size.y = v)
+    });
+    fields.add_field_method_get("rot", |_, this| {
+      this.with(|s| s.rot)
+    });
+    fields
+      .add_field_method_set("rot", |_, this, v: f32| {
+        this.with(|s| s.rot = v)
+      });
+    fields.add_field_method_get("layer", |_, this| {
+      this.with(|s| s.layer)
+    });
+    fields
+      .add_field_method_set("layer", |_, this, v: i32| {
+        this.with(|s| s.layer = v)
+      });
+    fields.add_field_method_get("visible", |_, this| {
+      this.with(|s| s.visible)
+    });
+    fields.add_field_method_set(
+      "visible",
+      |_, this, v: bool| this.with(|s| s.visible = v),
+    );
+    fields.add_field_method_get("texture", |_, this| {
+      Ok(this.with(|s| s.texture)?.map(|id| LuaTexture {
+        ctx: this.ctx.clone(),
+        id,
+      }))
+    });
+    fields.add_field_method_set(
+      "texture",
+      |_, this, v: Value| {
+        let texture = this.ctx.texture_from_lua(v)?;
+        this.with(|s| {
+          s.texture = texture;
+          s.uv = [[0., 0.], [1., 1.]];
+        })
+      },
+    );
+  }
+
+  fn add_methods<M: UserDataMethods<Self>>(
+    methods: &mut M,
+  ) {
+    methods.add_method(
+      "move_by",
+      |_, this, (dx, dy): (f32, f32)| {
+        this.with(|s| {
+          s.pos.x += dx;
+          s.pos.y += dy;
+        })
+      },
+    );
+    methods.add_method(
+      "set_color",
+      |_,
+       this,
+       (r, g, b, a): (f32, f32, f32, Option<f32>)| {
+        this.with(|s| s.filter = [r, g, b, a.unwrap_or(1.)])
+      },
+    );
+    methods.add_method(
+      "set_section",
+      |_, this, name: String| {
+        let texture =
+          this.with(|s| s.texture)?.ok_or_else(|| {
+            mlua::Error::runtime("sprite has no texture")
+          })?;
+        let uv = {
+          let textures = this.ctx.textures.read();
+          textures
+            .section_id(texture, &name)
+            .and_then(|sid| {
+              textures.section_uv(texture, sid)
+            })
+            .ok_or_else(|| {
+              mlua::Error::runtime(format!(
+                "section \"{name}\" is not exist"
+              ))
+            })?
+        };
+        this.with(|s| s.uv = uv)
+      },
+    );
+    methods.add_method("is_alive", |_, this, ()| {
+      Ok(this.ctx.scene.read().sprite(this.id).is_some())
+    });
+    methods.add_method("remove", |_, this, ()| {
+      Ok(
+        this
+          .ctx
+          .scene
+          .write()
+          .remove_sprite(this.id)
+          .is_some(),
+      )
+    });
+    methods.add_meta_method(
+      MetaMethod::Eq,
+      |_, this, other: Value| {
+        Ok(match other {
+          Value::UserData(ud) => ud
+            .borrow::<LuaSprite>()
+            .map(|o| o.id == this.id)
+            .unwrap_or(false),
+          _ => false,
+        })
+      },
+    );
+    methods.add_meta_method(
+      MetaMethod::ToString,
+      |_, this, ()| {
+        Ok(format!("Sprite({})", this.id.raw()))
+      },
+    );
+  }
+}
+
+/// Handle of the named camera
+#[derive(Clone)]
+pub struct LuaCamera {
+  ctx: SceneContext,
+  name: String,
+}
+impl LuaCamera {
+  fn with<R>(
+    &self,
+    f: impl FnOnce(&mut Camera2D) -> R,
+  ) -> mlua::Result<R> {
+    let mut scene = self.ctx.scene.write();
+    let camera =
+      scene.camera_mut(&self.name).ok_or_else(|| {
+        mlua::Error::runtime(format!(
+          "camera \"{}\" is not exist",
+          self.name
+        ))
+      })?;
+    Ok(f(camera))
+  }
+}
+impl UserData for LuaCamera {
+  fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+    fields.add_field_method_get("name", |_, this| {
+      Ok(this.name.clone())
+    });
+    fields.add_field_method_get("x", |_, this| {
+      this.with(|c| c.pos.x)
+    });
+    fields.add_field_method_set("x", |_, this, v: f32| {
+      this.with(|c| c.pos.x = v)
+    });
+    fields.add_field_method_get("y", |_, this| {
+      this.with(|c| c.pos.y)
+    });
+    fields.add_field_method_set("y", |_, this, v: f32| {
+      this.with(|c| c.pos.y = v)
+    });
+    fields.add_field_method_get("rot", |_, this| {
+      this.with(|c| c.rot)
+    });
+    fields
+      .add_field_method_set("rot", |_, this, v: f32| {
+        this.with(|c| c.rot = v)
+      });
+    fields.add_field_method_get("zoom", |_, this| {
+      this.with(|c| c.zoom)
+    });
+    fields
+      .add_field_method_set("zoom", |_, this, v: f32| {
+        this.with(|c| c.zoom = v)
+      });
+  }
+
+  fn add_methods<M: UserDataMethods<Self>>(
+    methods: &mut M,
+  ) {
+    methods.add_method(
+      "move_by",
+      |_, this, (dx, dy): (f32, f32)| {
+        this.with(|c| {
+          c.pos.x += dx;
+          c.pos.y += dy;
+        })
+      },
+    );
+    methods.add_method("activate", |_, this, ()| {
+      Ok(
+        this
+          .ctx
+          .scene
+          .write()
+          .set_active_camera(&this.name),
+      )
+    });
+    methods.add_method("is_active", |_, this, ()| {
+      Ok(
+        this.ctx.scene.read().active_camera_name()
+          == this.name,
+      )
+    });
+    methods.add_meta_method(
+      MetaMethod::ToString,
+      |_, this, ()| Ok(format!("Camera({})", this.name)),
+    );
+  }
+}
+
+/// Handle of the texture in the storage
+#[derive(Clone)]
+pub struct LuaTexture {
+  ctx: SceneContext,
+  id: TextureID,
+}
+impl UserData for LuaTexture {
+  fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+    fields.add_field_method_get("id", |_, this| {
+      Ok(this.id.raw())
+    });
+    fields.add_field_method_get("name", |_, this| {
+      Ok(
+        this
+          .ctx
+          .textures
+          .read()
+          .name(this.id)
+          .map(str::to_string),
+      )
+    });
+    fields.add_field_method_get("width", |_, this| {
+      Ok(
+        this
+          .ctx
+          .textures
+          .read()
+          .size(this.id)
+          .map(|s| s[0]),
+      )
+    });
+    fields.add_field_method_get("height", |_, this| {
+      Ok(
+        this
+          .ctx
+          .textures
+          .read()
+          .size(this.id)
+          .map(|s| s[1]),
+      )
+    });
+  }
+
+  fn add_methods<M: UserDataMethods<Self>>(
+    methods: &mut M,
+  ) {
+    methods.add_method(
+      "add_section",
+      |_, this, (name, x, y, w, h): (String, u32, u32, u32, u32)| {
+        this
+          .ctx
+          .textures
+          .write()
+          .add_section(this.id, name, [[x, y], [w, h]])
+          .map(|sid| sid.raw())
+          .ok_or_else(|| mlua::Error::runtime("texture is removed"))
+      },
+    );
+    methods.add_meta_method(
+      MetaMethod::ToString,
+      |_, this, ()| {
+        let textures = this.ctx.textures.read();
+        Ok(format!(
+          "Texture({}, {})",
+          this.id.raw(),
+          textures.name(this.id).unwrap_or("<removed>")
+        ))
+      },
+    );
+  }
+}
+
+/// Handle of the named tilemap
+#[derive(Clone)]
+pub struct LuaTilemap {
+  ctx: SceneContext,
+  name: String,
+}
+impl LuaTilemap {
+  fn with<R>(
+    &self,
+    f: impl FnOnce(&mut Tilemap) -> R,
+  ) -> mlua::Result<R> {
+    let mut scene = self.ctx.scene.write();
+    let map =
+      scene.tilemap_mut(&self.name).ok_or_else(|| {
+        mlua::Error::runtime(format!(
+          "tilemap \"{}\" is not exist",
+          self.name
+        ))
+      })?;
+    Ok(f(map))
+  }
+}
+impl UserData for LuaTilemap {
+  fn add_fields<F: UserDataFields<Self>>(fields: &mut F) {
+    fields.add_field_method_get("name", |_, this| {
+      Ok(this.name.clone())
+    });
+    fields.add_field_method_get("width", |_, this| {
+      this.with(|m| m.width())
+    });
+    fields.add_field_method_get("height", |_, this| {
+      this.with(|m| m.height())
+    });
+    fields.add_field_method_get("x", |_, this| {
+      this.with(|m| m.origin.x)
+    });
+    fields.add_field_method_set("x", |_, this, v: f32| {
+      this.with(|m| {
+        m.origin.x = v;
+        m.touch();
+      })
+    });
+    fields.add_field_method_get("y", |_, this| {
+      this.with(|m| m.origin.y)
+    });
+    fields.add_field_method_set("y", |_, this, v: f32| {
+      this.with(|m| {
+        m.origin.y = v;
+        m.touch();
+      })
+    });
+    fields.add_field_method_get("texture", |_, this| {
+      Ok(this.with(|m| m.texture)?.map(|id| LuaTexture {
+        ctx: this.ctx.clone(),
+        id,
+      }))
+    });
+    fields.add_field_method_set(
+      "texture",
+      |_, this, v: Value| {
+        let texture = this.ctx.texture_from_lua(v)?;
+        this.with(|m| {
+          m.texture = texture;
+          m.touch();
+        })
+      },
+    );
+  }
+
+  fn add_methods<M: UserDataMethods<Self>>(
+    methods: &mut M,
+  ) {
+    methods.add_method(
+      "get",
+      |_, this, (x, y): (u32, u32)| {
+        this.with(|m| m.get(x, y))
+      },
+    );
+    methods.add_method(
+      "set",
+      |_, this, (x, y, tile): (u32, u32, u32)| {
+        this.with(|m| m.set(x, y, tile))
+      },
+    );
+    methods.add_method("fill", |_, this, tile: u32| {
+      this.with(|m| m.fill(tile))
+    });
+    methods.add_meta_method(
+      MetaMethod::ToString,
+      |_, this, ()| Ok(format!("Tilemap({})", this.name)),
+    );
+  }
+}
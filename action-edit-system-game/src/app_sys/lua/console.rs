@@ -0,0 +1,556 @@
+//! Lua REPL console
+//!
+//! - `print` is redirected to the scrollback.
+//! - The input is evaluated as an expression first, and the results
+//!   are pretty-printed. (tables are expanded)
+//! - Enter runs the input, Shift+Enter inserts a new line, Up/Down
+//!   walk the history, and Tab completes the globals, the table keys
+//!   and the userdata members.
+//! - The history is saved to `HISTORY_PATH`.
+
+use super::api::userdata_members;
+use egui::{
+  Color32, Key, KeyboardShortcut, Modifiers, RichText,
+};
+use hashbrown::HashSet;
+use mlua::{
+  ChunkMode, Function, Lua, MultiValue, Table, Value,
+};
+use parking_lot::Mutex;
+use std::{path::PathBuf, sync::Arc};
+
+/// Default history file
+pub const HISTORY_PATH: &str = "./lua_history.json";
+const HISTORY_LIMIT: usize = 500;
+const SCROLLBACK_LIMIT: usize = 2000;
+/// Nesting limit of the pretty-printed tables
+const PRETTY_DEPTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineKind {
+  Input,
+  Output,
+  Result,
+  Error,
+  Hint,
+}
+
+#[derive(Debug, Clone)]
+pub struct Line {
+  pub kind: LineKind,
+  pub text: String,
+}
+
+pub struct LuaConsole {
+  input: String,
+  scrollback: Vec<Line>,
+  /// Output of `print` that is not moved to the scrollback yet
+  output: Arc<Mutex<Vec<String>>>,
+  history: Vec<String>,
+  /// Position while walking the history, and the input before it
+  history_pos: Option<(usize, String)>,
+  history_path: PathBuf,
+}
+impl LuaConsole {
+  /// Create the console, and replace the global `print`.
+  pub fn new(
+    lua: &Lua,
+    history_path: impl Into<PathBuf>,
+  ) -> mlua::Result<Self> {
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let o = output.clone();
+    lua.globals().set(
+      "print",
+      lua.create_function(
+        move |lua, args: MultiValue| {
+          let tostring =
+            lua.globals().get::<Function>("tostring")?;
+          let line = args
+            .into_iter()
+            .map(|v| tostring.call::<String>(v))
+            .collect::<mlua::Result<Vec<_>>>()?
+            .join("\t");
+          log::debug!(target: "lua", "{line}");
+          o.lock().push(line);
+          Ok(())
+        },
+      )?,
+    )?;
+    let history_path = history_path.into();
+    let history = std::fs::read_to_string(&history_path)
+      .ok()
+      .and_then(|s| serde_json::from_str(&s).ok())
+      .unwrap_or_default();
+    Ok(Self {
+      input: String::new(),
+      scrollback: Vec::new(),
+      output,
+      history,
+      history_pos: None,
+      history_path,
+    })
+  }
+
+  pub fn clear(&mut self) {
+    self.scrollback.clear();
+  }
+
+  fn push(
+    &mut self,
+    kind: LineKind,
+    text: impl Into<String>,
+  ) {
+    self.scrollback.push(Line {
+      kind,
+      text: text.into(),
+    });
+    if SCROLLBACK_LIMIT < self.scrollback.len() {
+      let over = self.scrollback.len() - SCROLLBACK_LIMIT;
+      self.scrollback.drain(..over);
+    }
+  }
+
+  /// Move the captured `print` output to the scrollback.
+  pub fn flush_output(&mut self) {
+    let output = std::mem::take(&mut *self.output.lock());
+    for line in output {
+      self.push(LineKind::Output, line);
+    }
+  }
+
+  /// Run the code, and write the results to the scrollback.
+  pub fn execute(
+    &mut self,
+    lua: &Lua,
+    code: &str,
+  ) -> mlua::Result<()> {
+    self.flush_output();
+    self.push(LineKind::Input, code);
+    self.push_history(code);
+    let result =
+      super::sandbox::run(lua, "console", || {
+        let load = |src: &str| {
+          lua
+            .load(src)
+            .set_name("=console")
+            .set_mode(ChunkMode::Text)
+            .into_function()
+        };
+        let f = match load(&format!("return {code}")) {
+          Ok(f) => f,
+          Err(_) => load(code)?,
+        };
+        f.call::<MultiValue>(())
+      });
+    self.flush_output();
+    match result {
+      Ok(values) => {
+        for v in values.iter() {
+          let text = pretty(lua, v)
+            .unwrap_or_else(|e| format!("<{e}>"));
+          self.push(LineKind::Result, text);
+        }
+        Ok(())
+      }
+      Err(e) => {
+        self.push(LineKind::Error, e.to_string());
+        Err(e)
+      }
+    }
+  }
+
+  fn push_history(&mut self, code: &str) {
+    self.history_pos = None;
+    if code.trim().is_empty()
+      || self.history.last().is_some_and(|h| h == code)
+    {
+      return;
+    }
+    self.history.push(code.to_string());
+    if HISTORY_LIMIT < self.history.len() {
+      let over = self.history.len() - HISTORY_LIMIT;
+      self.history.drain(..over);
+    }
+    let saved = serde_json::to_string(&self.history)
+      .map_err(|e| e.to_string())
+      .and_then(|s| {
+        std::fs::write(&self.history_path, s)
+          .map_err(|e| e.to_string())
+      });
+    if let Err(e) = saved {
+      log::warn!("Lua console history save error: {e}");
+    }
+  }
+
+  /// Walk the history. (`back` is the older direction)
+  fn walk_history(&mut self, back: bool) {
+    let next = match (&self.history_pos, back) {
+      (None, true) => self.history.len().checked_sub(1),
+      (None, false) => None,
+      (Some((i, _)), true) => Some(i.saturating_sub(1)),
+      (Some((i, _)), false) => {
+        (i + 1 < self.history.len()).then_some(i + 1)
+      }
+    };
+    match next {
+      Some(i) => {
+        let saved = match self.history_pos.take() {
+          Some((_, saved)) => saved,
+          None => self.input.clone(),
+        };
+        self.input = self.history[i].clone();
+        self.history_pos = Some((i, saved));
+      }
+      None => {
+        if let Some((_, saved)) = self.history_pos.take() {
+          self.input = saved;
+        }
+      }
+    }
+  }
+
+  /// Complete the word at the end of the input.
+  fn complete(&mut self, lua: &Lua) {
+    let candidates = completions(lua, &self.input);
+    let start =
+      self.input.len() - word_at_end(&self.input).1.len();
+    match candidates.as_slice() {
+      [] => {}
+      [one] => {
+        self.input.truncate(start);
+        self.input.push_str(one);
+      }
+      many => {
+        let prefix = common_prefix(many);
+        if start + prefix.len() > self.input.len() {
+          self.input.truncate(start);
+          self.input.push_str(&prefix);
+        } else {
+          self.push(LineKind::Hint, many.join("  "));
+        }
+      }
+    }
+  }
+
+  /// Draw the console. Returns the result if an input is executed.
+  pub fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+    lua: &Lua,
+  ) -> Option<mlua::Result<()>> {
+    self.flush_output();
+    egui::ScrollArea::vertical()
+      .max_height(240.)
+      .stick_to_bottom(true)
+      .auto_shrink([false, true])
+      .show(ui, |ui| {
+        for line in self.scrollback.iter() {
+          let text = match line.kind {
+            LineKind::Input => {
+              RichText::new(format!("> {}", line.text))
+                .color(Color32::GRAY)
+            }
+            LineKind::Output => RichText::new(&line.text),
+            LineKind::Result => RichText::new(&line.text)
+              .color(Color32::LIGHT_BLUE),
+            LineKind::Error => {
+              RichText::new(&line.text).color(Color32::RED)
+            }
+            LineKind::Hint => RichText::new(&line.text)
+              .color(Color32::DARK_GRAY),
+          };
+          ui.label(text.monospace());
+        }
+      });
+    ui.separator();
+
+    let id = egui::Id::new("lua console input");
+    let focused = ui.memory(|m| m.has_focus(id));
+    let single_line = !self.input.contains('\n');
+    let (run, tab, up, down) = ui.input_mut(|i| {
+      if !focused {
+        return (false, false, false, false);
+      }
+      (
+        i.consume_key(Modifiers::NONE, Key::Enter),
+        i.consume_key(Modifiers::NONE, Key::Tab),
+        single_line
+          && i.consume_key(Modifiers::NONE, Key::ArrowUp),
+        single_line
+          && i.consume_key(Modifiers::NONE, Key::ArrowDown),
+      )
+    });
+    if tab {
+      self.complete(lua);
+    }
+    if up || down {
+      self.walk_history(up);
+    }
+    if tab || up || down {
+      if let Some(mut state) =
+        egui::text_edit::TextEditState::load(ui.ctx(), id)
+      {
+        let end = egui::text::CCursor::new(
+          self.input.chars().count(),
+        );
+        state.cursor.set_char_range(Some(
+          egui::text::CCursorRange::one(end),
+        ));
+        state.store(ui.ctx(), id);
+      }
+    }
+    egui::TextEdit::multiline(&mut self.input)
+      .id(id)
+      .code_editor()
+      .desired_rows(2)
+      .desired_width(f32::INFINITY)
+      .return_key(KeyboardShortcut::new(
+        Modifiers::SHIFT,
+        Key::Enter,
+      ))
+      .hint_text("Lua (Enter: run, Shift+Enter: new line)")
+      .show(ui);
+    let mut result = None;
+    ui.horizontal(|ui| {
+      let execute = ui.button("Execute").clicked() || run;
+      if execute && !self.input.trim().is_empty() {
+        let code = std::mem::take(&mut self.input);
+        let r = self.execute(lua, &code);
+        if let Err(e) = &r {
+          log::warn!("Lua script execute error: {e}");
+        }
+        result = Some(r);
+      }
+      if ui.button("Clear").clicked() {
+        self.clear();
+      }
+    });
+    result
+  }
+}
+
+/// Identifier path at the end of the input (`(base, word)`)
+///
+/// `scene.spr` is `("scene", "spr")`, and `s:mo` is `("s:", "mo")`.
+fn word_at_end(input: &str) -> (&str, &str) {
+  let start = input
+    .char_indices()
+    .rev()
+    .take_while(|(_, c)| {
+      c.is_ascii_alphanumeric()
+        || matches!(c, '_' | '.' | ':')
+    })
+    .last()
+    .map(|(i, _)| i)
+    .unwrap_or(input.len());
+  let path = &input[start..];
+  match path.rfind(['.', ':']) {
+    Some(i) if path.as_bytes()[i] == b':' => {
+      (&path[..=i], &path[i + 1..])
+    }
+    Some(i) => (&path[..i], &path[i + 1..]),
+    None => ("", path),
+  }
+}
+
+fn completions(lua: &Lua, input: &str) -> Vec<String> {
+  let (base, word) = word_at_end(input);
+  let (base, methods_only) = match base.strip_suffix(':') {
+    Some(base) => (base, true),
+    None => (base, false),
+  };
+  let target = if base.is_empty() {
+    Value::Table(lua.globals())
+  } else {
+    if base.split('.').any(|s| {
+      s.is_empty()
+        || s.starts_with(|c: char| c.is_ascii_digit())
+    }) {
+      return Vec::new();
+    }
+    match super::sandbox::run(lua, "console", || {
+      lua
+        .load(format!("return {base}"))
+        .set_name("=completion")
+        .eval::<Value>()
+    }) {
+      Ok(v) => v,
+      Err(_) => return Vec::new(),
+    }
+  };
+  let mut names = HashSet::new();
+  match &target {
+    Value::Table(t) => {
+      collect_keys(t, methods_only, &mut names);
+      if let Some(Value::Table(index)) = t
+        .metatable()
+        .map(|m| m.raw_get::<Value>("__index"))
+        .transpose()
+        .ok()
+        .flatten()
+      {
+        collect_keys(&index, methods_only, &mut names);
+      }
+    }
+    Value::UserData(ud) => {
+      let members = userdata_members(ud);
+      if !methods_only {
+        names.extend(members.fields);
+      }
+      names.extend(members.methods);
+    }
+    _ => {}
+  }
+  let mut names = names
+    .into_iter()
+    .filter(|n| n.starts_with(word))
+    .collect::<Vec<_>>();
+  names.sort();
+  names
+}
+
+fn collect_keys(
+  t: &Table,
+  functions_only: bool,
+  names: &mut HashSet<String>,
+) {
+  for (k, v) in t.pairs::<Value, Value>().flatten() {
+    if functions_only && !matches!(v, Value::Function(_)) {
+      continue;
+    }
+    if let Value::String(s) = k {
+      if let Ok(s) = s.to_str() {
+        if is_identifier(&s) {
+          names.insert(s.to_string());
+        }
+      }
+    }
+  }
+}
+
+fn is_identifier(s: &str) -> bool {
+  s.starts_with(|c: char| {
+    c.is_ascii_alphabetic() || c == '_'
+  }) && s
+    .chars()
+    .all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn common_prefix(names: &[String]) -> String {
+  let first = &names[0];
+  let len =
+    names[1..].iter().fold(first.len(), |len, n| {
+      first
+        .bytes()
+        .zip(n.bytes())
+        .take(len)
+        .take_while(|(a, b)| a == b)
+        .count()
+    });
+  first[..len].to_string()
+}
+
+/// Format the value for the console. Tables are expanded.
+pub fn pretty(
+  lua: &Lua,
+  value: &Value,
+) -> mlua::Result<String> {
+  let mut out = String::new();
+  let mut visited = HashSet::new();
+  write_pretty(lua, value, 0, &mut visited, &mut out)?;
+  Ok(out)
+}
+
+fn write_pretty(
+  lua: &Lua,
+  value: &Value,
+  depth: usize,
+  visited: &mut HashSet<usize>,
+  out: &mut String,
+) -> mlua::Result<()> {
+  match value {
+    Value::Nil => out.push_str("nil"),
+    Value::Boolean(b) => out.push_str(&b.to_string()),
+    Value::Integer(i) => out.push_str(&i.to_string()),
+    Value::Number(n) => out.push_str(&n.to_string()),
+    Value::String(s) => {
+      out.push_str(&format!("{:?}", s.to_string_lossy()))
+    }
+    Value::Table(t) => {
+      let ptr = t.to_pointer() as usize;
+      if PRETTY_DEPTH <= depth || visited.contains(&ptr) {
+        out.push_str("{...}");
+        return Ok(());
+      }
+      visited.insert(ptr);
+      let len = t.raw_len();
+      let mut entries = Vec::new();
+      for pair in t.pairs::<Value, Value>() {
+        let (k, v) = pair?;
+        let array = match k {
+          Value::Integer(i) => 1 <= i && i as usize <= len,
+          _ => false,
+        };
+        let key = match &k {
+          Value::Integer(i) => *i,
+          _ => 0,
+        };
+        entries.push((!array, key, sort_key(&k), k, v));
+      }
+      entries.sort_by(|a, b| {
+        (a.0, a.1, &a.2).cmp(&(b.0, b.1, &b.2))
+      });
+      if entries.is_empty() {
+        out.push_str("{}");
+      } else {
+        let indent = "  ".repeat(depth + 1);
+        out.push_str("{\n");
+        for (not_array, _, _, k, v) in entries {
+          out.push_str(&indent);
+          if not_array {
+            match &k {
+              Value::String(s)
+                if s
+                  .to_str()
+                  .is_ok_and(|s| is_identifier(&s)) =>
+              {
+                out.push_str(&s.to_string_lossy());
+              }
+              k => {
+                out.push('[');
+                write_pretty(
+                  lua,
+                  k,
+                  depth + 1,
+                  visited,
+                  out,
+                )?;
+                out.push(']');
+              }
+            }
+            out.push_str(" = ");
+          }
+          write_pretty(lua, &v, depth + 1, visited, out)?;
+          out.push_str(",\n");
+        }
+        out.push_str(&"  ".repeat(depth));
+        out.push('}');
+      }
+      visited.remove(&ptr);
+    }
+    v => {
+      let tostring =
+        lua.globals().get::<Function>("tostring")?;
+      out.push_str(&tostring.call::<String>(v.clone())?);
+    }
+  }
+  Ok(())
+}
+
+fn sort_key(v: &Value) -> String {
+  match v {
+    Value::String(s) => s.to_string_lossy().to_string(),
+    Value::Number(n) => n.to_string(),
+    v => v.type_name().to_string(),
+  }
+}
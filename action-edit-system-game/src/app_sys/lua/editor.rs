@@ -0,0 +1,239 @@
+//! Script editor
+//!
+//! Edits a script file under the script root. The saved file is
+//! picked up by the hot reload of the loader.
+
+use egui::{Key, Modifiers, RichText};
+use std::path::{Path, PathBuf};
+
+struct OpenFile {
+  path: PathBuf,
+  text: String,
+  /// Text on the disk
+  saved: String,
+}
+
+pub struct ScriptEditor {
+  root: PathBuf,
+  file: Option<OpenFile>,
+  /// Line to move the cursor at the next draw (1-origin)
+  goto: Option<u32>,
+  status: Option<String>,
+  /// Window is shown
+  pub visible: bool,
+}
+impl ScriptEditor {
+  pub fn new(root: impl Into<PathBuf>) -> Self {
+    Self {
+      root: root.into(),
+      file: None,
+      goto: None,
+      status: None,
+      visible: false,
+    }
+  }
+
+  /// Open the file, and move the cursor to the line.
+  /// Unsaved changes of the same file are kept.
+  pub fn open(&mut self, path: &Path, line: Option<u32>) {
+    let same = self
+      .file
+      .as_ref()
+      .is_some_and(|f| f.path.as_path() == path);
+    if !same {
+      match std::fs::read_to_string(path) {
+        Ok(text) => {
+          self.file = Some(OpenFile {
+            path: path.to_path_buf(),
+            saved: text.clone(),
+            text,
+          });
+          self.status = None;
+        }
+        Err(e) => {
+          self.status =
+            Some(format!("{}: {e}", path.display()));
+          return;
+        }
+      }
+    }
+    self.goto = line;
+    self.visible = true;
+  }
+
+  pub fn is_dirty(&self) -> bool {
+    self.file.as_ref().is_some_and(|f| f.text != f.saved)
+  }
+
+  pub fn save(&mut self) {
+    let Some(file) = self.file.as_mut() else {
+      return;
+    };
+    match std::fs::write(&file.path, &file.text) {
+      Ok(_) => {
+        file.saved = file.text.clone();
+        self.status =
+          Some(format!("Saved {}", file.path.display()));
+      }
+      Err(e) => {
+        self.status =
+          Some(format!("{}: {e}", file.path.display()));
+      }
+    }
+  }
+
+  /// Draw the editor window if it is visible.
+  pub fn show(&mut self, ctx: &egui::Context) {
+    let mut visible = self.visible;
+    egui::Window::new("Script editor")
+      .open(&mut visible)
+      .resizable(true)
+      .show(ctx, |ui| self.ui(ui));
+    self.visible = visible;
+  }
+
+  pub fn ui(&mut self, ui: &mut egui::Ui) {
+    ui.horizontal(|ui| {
+      let current = self
+        .file
+        .as_ref()
+        .map(|f| self.relative(&f.path))
+        .unwrap_or_else(|| "(none)".to_string());
+      let mut selected = None;
+      egui::ComboBox::from_id_salt("script editor file")
+        .selected_text(current)
+        .show_ui(ui, |ui| {
+          for path in script_files(&self.root) {
+            if ui
+              .selectable_label(false, self.relative(&path))
+              .clicked()
+            {
+              selected = Some(path);
+            }
+          }
+        });
+      if let Some(path) = selected {
+        self.open(&path, None);
+      }
+      let dirty = self.is_dirty();
+      if ui
+        .add_enabled(dirty, egui::Button::new("Save"))
+        .clicked()
+      {
+        self.save();
+      }
+      if ui
+        .add_enabled(dirty, egui::Button::new("Revert"))
+        .clicked()
+      {
+        if let Some(f) = self.file.as_mut() {
+          f.text = f.saved.clone();
+        }
+      }
+      if dirty {
+        ui.label(RichText::new("modified").italics());
+      }
+    });
+    if let Some(status) = &self.status {
+      ui.label(status);
+    }
+    let Some(file) = self.file.as_mut() else {
+      return;
+    };
+    let id = egui::Id::new("script editor text");
+    let goto = self.goto.take().map(|line| {
+      let ccursor = egui::text::CCursor::new(
+        file
+          .text
+          .split_inclusive('\n')
+          .take(line.saturating_sub(1) as usize)
+          .map(|l| l.chars().count())
+          .sum(),
+      );
+      let mut state =
+        egui::text_edit::TextEditState::load(ui.ctx(), id)
+          .unwrap_or_default();
+      state.cursor.set_char_range(Some(
+        egui::text::CCursorRange::one(ccursor),
+      ));
+      state.store(ui.ctx(), id);
+      ui.memory_mut(|m| m.request_focus(id));
+      ccursor
+    });
+    let output = egui::ScrollArea::both()
+      .max_height(480.)
+      .show(ui, |ui| {
+        let output =
+          egui::TextEdit::multiline(&mut file.text)
+            .id(id)
+            .code_editor()
+            .desired_rows(24)
+            .desired_width(f32::INFINITY)
+            .show(ui);
+        if let Some(ccursor) = goto {
+          let rect = output
+            .galley
+            .pos_from_ccursor(ccursor)
+            .translate(output.galley_pos.to_vec2());
+          ui.scroll_to_rect(
+            rect,
+            Some(egui::Align::Center),
+          );
+        }
+        output
+      })
+      .inner;
+    if let Some(range) = output.cursor_range {
+      let line = file.text[..char_to_byte(
+        &file.text,
+        range.primary.ccursor.index,
+      )]
+        .matches('\n')
+        .count()
+        + 1;
+      ui.label(format!("line {line}"));
+    }
+    if output.response.has_focus()
+      && ui.input_mut(|i| {
+        i.consume_key(Modifiers::COMMAND, Key::S)
+      })
+    {
+      self.save();
+    }
+  }
+
+  fn relative(&self, path: &Path) -> String {
+    path
+      .strip_prefix(&self.root)
+      .unwrap_or(path)
+      .display()
+      .to_string()
+  }
+}
+
+fn char_to_byte(text: &str, index: usize) -> usize {
+  text
+    .char_indices()
+    .nth(index)
+    .map(|(i, _)| i)
+    .unwrap_or(text.len())
+}
+
+/// `*.lua` files under the directory
+fn script_files(dir: &Path) -> Vec<PathBuf> {
+  let mut files = Vec::new();
+  let Ok(entries) = std::fs::read_dir(dir) else {
+    return files;
+  };
+  for path in
+    entries.filter_map(|e| e.ok()).map(|e| e.path())
+  {
+    if path.is_dir() {
+      files.extend(script_files(&path));
+    } else if path.extension().is_some_and(|e| e == "lua") {
+      files.push(path);
+    }
+  }
+  files.sort();
+  files
+}
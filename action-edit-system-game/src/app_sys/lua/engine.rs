@@ -0,0 +1,377 @@
+//! Lua binding of the script host interface
+//!
+//! The global `engine` has the operations of the WIT world
+//! `aes:engine/plugin`, so a behavior can be ported to a wasm plugin
+//! line by line. Every change goes through the `ScriptEngine`.
+//!
+//! - `engine.log(level, message)` ("error" ... "trace")
+//! - `engine.entities`: `spawn{x, y, w, h, texture, layer}` (nil if
+//!   the texture is unknown), `despawn(e)`, `position(e)` (x, y),
+//!   `set_position(e, x, y)`
+//! - `engine.input`: `key_down(key)`, `key_pressed(key)`,
+//!   `mouse_down(button)` ("left", "right", "middle"), `cursor()`,
+//!   the state of the simulation step (`RawInput`), which the
+//!   recordings replay
+//! - `engine.input` with the input buffers (`register_buffers`,
+//!   player is 0-origin): `buffered(player, action [, window])`,
+//!   `consume(player, action)`, `command(player, name)` (registered
+//!   command), `matches(player, notation [, window])`,
+//!   `matched_command(player)`, `direction(player)` (numpad),
+//!   `set_facing(player, right)`
+//! - `engine.assets`: `texture_names()`, `texture_size(name)` (w, h)
+//! - `engine.tiles`: `get(map, x, y)`, `set(map, x, y, tile)`
+//!   (0-origin)
+//! - `engine.events.emit(name [, payload])`
+
+use crate::app_sys::{
+  input::buffer::{
+    CommandPattern, InputBuffer, PlayerInputs,
+  },
+  scene::SpriteID,
+  script::{
+    ScriptCommand, ScriptEngine, ScriptEvent, SpriteDesc,
+  },
+};
+use mlua::{Lua, Table};
+use parking_lot::{Mutex, RwLock};
+use std::sync::Arc;
+use winit::event::MouseButton;
+
+/// Register `engine` to the globals.
+pub fn register(
+  lua: &Lua,
+  engine: &Arc<Mutex<ScriptEngine>>,
+) -> mlua::Result<()> {
+  let t = lua.create_table()?;
+  let e = engine.clone();
+  t.set(
+    "log",
+    lua.create_function(
+      move |_, (level, message): (String, String)| {
+        let level = match level.as_str() {
+          "error" => log::Level::Error,
+          "warn" => log::Level::Warn,
+          "info" => log::Level::Info,
+          "debug" => log::Level::Debug,
+          "trace" => log::Level::Trace,
+          _ => {
+            return Err(mlua::Error::runtime(format!(
+              "unknown log level \"{level}\""
+            )))
+          }
+        };
+        e.lock()
+          .execute(ScriptCommand::Log { level, message });
+        Ok(())
+      },
+    )?,
+  )?;
+  t.set("entities", entities_table(lua, engine)?)?;
+  t.set("input", input_table(lua, engine)?)?;
+  t.set("assets", assets_table(lua, engine)?)?;
+  t.set("tiles", tiles_table(lua, engine)?)?;
+  t.set("events", events_table(lua, engine)?)?;
+  lua.globals().set("engine", t)
+}
+
+fn entities_table(
+  lua: &Lua,
+  engine: &Arc<Mutex<ScriptEngine>>,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let e = engine.clone();
+  t.set(
+    "spawn",
+    lua.create_function(move |_, desc: Table| {
+      let desc = SpriteDesc {
+        pos: [
+          desc.get::<Option<f32>>("x")?.unwrap_or(0.),
+          desc.get::<Option<f32>>("y")?.unwrap_or(0.),
+        ],
+        size: [
+          desc.get::<f32>("w")?,
+          desc.get::<f32>("h")?,
+        ],
+        texture: desc.get::<String>("texture")?,
+        layer: desc
+          .get::<Option<i32>>("layer")?
+          .unwrap_or(0),
+      };
+      Ok(
+        e.lock()
+          .execute(ScriptCommand::Spawn(desc))
+          .entity()
+          .map(SpriteID::raw),
+      )
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "despawn",
+    lua.create_function(move |_, id: u32| {
+      Ok(
+        e.lock()
+          .execute(ScriptCommand::Despawn(
+            SpriteID::from_raw(id),
+          ))
+          .is_ok(),
+      )
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "position",
+    lua.create_function(move |_, id: u32| {
+      Ok(
+        e.lock()
+          .entity_pos(SpriteID::from_raw(id))
+          .map(|[a, b]| (a, b))
+          .unzip(),
+      )
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "set_position",
+    lua.create_function(
+      move |_, (id, x, y): (u32, f32, f32)| {
+        Ok(
+          e.lock()
+            .execute(ScriptCommand::SetPosition(
+              SpriteID::from_raw(id),
+              [x, y],
+            ))
+            .is_ok(),
+        )
+      },
+    )?,
+  )?;
+  Ok(t)
+}
+
+fn input_table(
+  lua: &Lua,
+  engine: &Arc<Mutex<ScriptEngine>>,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let e = engine.clone();
+  t.set(
+    "key_down",
+    lua.create_function(move |_, key: String| {
+      Ok(e.lock().input().is_down_by_name(&key))
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "key_pressed",
+    lua.create_function(move |_, key: String| {
+      Ok(e.lock().input().is_pressed_by_name(&key))
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "mouse_down",
+    lua.create_function(move |_, button: String| {
+      let button = match button.as_str() {
+        "left" => MouseButton::Left,
+        "right" => MouseButton::Right,
+        "middle" => MouseButton::Middle,
+        _ => {
+          return Err(mlua::Error::runtime(format!(
+            "unknown mouse button \"{button}\""
+          )))
+        }
+      };
+      Ok(e.lock().input().is_button_down(button))
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "cursor",
+    lua.create_function(move |_, ()| {
+      let [x, y] = e.lock().input().cursor();
+      Ok((x, y))
+    })?,
+  )?;
+  Ok(t)
+}
+
+/// Add the input buffer functions to `engine.input`.
+pub fn register_buffers(
+  lua: &Lua,
+  buffers: Arc<RwLock<PlayerInputs>>,
+) -> mlua::Result<()> {
+  let t = lua
+    .globals()
+    .get::<Table>("engine")?
+    .get::<Table>("input")?;
+  fn player<R>(
+    buffers: &RwLock<PlayerInputs>,
+    i: usize,
+    f: impl FnOnce(&mut InputBuffer) -> R,
+  ) -> mlua::Result<R> {
+    let mut buffers = buffers.write();
+    let len = buffers.len();
+    buffers.player_mut(i).map(f).ok_or_else(|| {
+      mlua::Error::runtime(format!(
+        "player {i} is out of range (0..{len})"
+      ))
+    })
+  }
+  let b = buffers.clone();
+  t.set(
+    "buffered",
+    lua.create_function(
+      move |_,
+            (i, action, window): (
+        usize,
+        String,
+        Option<u32>,
+      )| {
+        player(&b, i, |p| p.buffered(&action, window))
+      },
+    )?,
+  )?;
+  let b = buffers.clone();
+  t.set(
+    "consume",
+    lua.create_function(
+      move |_, (i, action): (usize, String)| {
+        player(&b, i, |p| p.consume(&action))
+      },
+    )?,
+  )?;
+  let b = buffers.clone();
+  t.set(
+    "command",
+    lua.create_function(
+      move |_, (i, name): (usize, String)| {
+        player(&b, i, |p| p.command(&name))
+      },
+    )?,
+  )?;
+  let b = buffers.clone();
+  t.set(
+    "matches",
+    lua.create_function(
+      move |_,
+            (i, notation, window): (
+        usize,
+        String,
+        Option<u32>,
+      )| {
+        player(&b, i, |p| {
+          let window =
+            window.unwrap_or(p.config.command_window);
+          CommandPattern::parse("", &notation, window)
+            .map(|c| p.matches(&c))
+        })?
+        .map_err(mlua::Error::runtime)
+      },
+    )?,
+  )?;
+  let b = buffers.clone();
+  t.set(
+    "matched_command",
+    lua.create_function(move |_, i: usize| {
+      player(&b, i, |p| {
+        p.matched_command().map(|c| c.name.clone())
+      })
+    })?,
+  )?;
+  let b = buffers.clone();
+  t.set(
+    "direction",
+    lua.create_function(move |_, i: usize| {
+      player(&b, i, |p| p.direction().numpad())
+    })?,
+  )?;
+  let b = buffers;
+  t.set(
+    "set_facing",
+    lua.create_function(
+      move |_, (i, right): (usize, bool)| {
+        player(&b, i, |p| p.set_facing(right))
+      },
+    )?,
+  )?;
+  Ok(())
+}
+
+fn assets_table(
+  lua: &Lua,
+  engine: &Arc<Mutex<ScriptEngine>>,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let e = engine.clone();
+  t.set(
+    "texture_names",
+    lua.create_function(move |_, ()| {
+      Ok(e.lock().texture_names())
+    })?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "texture_size",
+    lua.create_function(move |_, name: String| {
+      Ok(
+        e.lock()
+          .texture_size(&name)
+          .map(|[a, b]| (a, b))
+          .unzip(),
+      )
+    })?,
+  )?;
+  Ok(t)
+}
+
+fn tiles_table(
+  lua: &Lua,
+  engine: &Arc<Mutex<ScriptEngine>>,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let e = engine.clone();
+  t.set(
+    "get",
+    lua.create_function(
+      move |_, (map, x, y): (String, u32, u32)| {
+        Ok(e.lock().tile(&map, x, y))
+      },
+    )?,
+  )?;
+  let e = engine.clone();
+  t.set(
+    "set",
+    lua.create_function(
+      move |_, (map, x, y, tile): (String, u32, u32, u32)| {
+        Ok(
+          e.lock()
+            .execute(ScriptCommand::SetTile { map, x, y, tile })
+            .is_ok(),
+        )
+      },
+    )?,
+  )?;
+  Ok(t)
+}
+
+fn events_table(
+  lua: &Lua,
+  engine: &Arc<Mutex<ScriptEngine>>,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let e = engine.clone();
+  t.set(
+    "emit",
+    lua.create_function(
+      move |_, (name, payload): (String, Option<String>)| {
+        e.lock().execute(ScriptCommand::Emit(ScriptEvent {
+          name,
+          payload: payload.unwrap_or_default(),
+        }));
+        Ok(())
+      },
+    )?,
+  )?;
+  Ok(t)
+}
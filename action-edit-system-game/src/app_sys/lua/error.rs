@@ -0,0 +1,455 @@
+//! Lua error report
+//!
+//! `mlua::Error` is parsed into the message, the source location and
+//! the traceback frames. Every report is logged as a JSON line with
+//! the target `LOG_TARGET`. The errors of the wasm plugins are
+//! reported in the same panel, without the location.
+
+use egui::{Color32, RichText};
+use serde::Serialize;
+use std::{collections::VecDeque, path::PathBuf};
+
+/// Log target of the JSON reports
+pub const LOG_TARGET: &str = "lua_error";
+const REPORT_LIMIT: usize = 50;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+  Syntax,
+  Runtime,
+  Memory,
+  Sandbox,
+  Callback,
+  /// Error of a wasm plugin, shown in the same panel
+  Plugin,
+  Other,
+}
+
+/// Frame of the stack traceback
+#[derive(Debug, Clone, Serialize)]
+pub struct Frame {
+  /// Chunk name (`main.lua`, `[C]`, ...)
+  pub source: String,
+  pub line: Option<u32>,
+  /// Description of the function (`function 'update'`, `main chunk`)
+  pub function: String,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LuaErrorReport {
+  pub kind: ErrorKind,
+  pub message: String,
+  /// Chunk name where the error is raised
+  pub script: Option<String>,
+  pub line: Option<u32>,
+  pub traceback: Vec<Frame>,
+}
+impl LuaErrorReport {
+  pub fn new(e: &mlua::Error) -> Self {
+    let mut traceback = None;
+    let (kind, text) = describe(e, &mut traceback);
+    // The message handler of mlua appends the traceback to the message
+    let (text, inline_traceback) =
+      match text.split_once("\nstack traceback:") {
+        Some((t, tb)) => {
+          (t.to_string(), Some(tb.to_string()))
+        }
+        None => (text, None),
+      };
+    let traceback = traceback
+      .or(inline_traceback)
+      .map(|tb| parse_traceback(&tb))
+      .unwrap_or_default();
+    let (script, line, message) =
+      match split_location(&text) {
+        Some((script, line, message)) => {
+          (Some(script), Some(line), message)
+        }
+        None => (None, None, text.clone()),
+      };
+    // Use the first Lua frame if the message has no location
+    let (script, line) = match script {
+      Some(s) => (Some(s), line),
+      None => traceback
+        .iter()
+        .find(|f| f.line.is_some())
+        .map(|f| (Some(f.source.clone()), f.line))
+        .unwrap_or((None, None)),
+    };
+    Self {
+      kind,
+      message,
+      script,
+      line,
+      traceback,
+    }
+  }
+
+  /// Path of the script file in the script root
+  pub fn script_path(
+    &self,
+    root: &std::path::Path,
+  ) -> Option<PathBuf> {
+    let script = self.script.as_ref()?;
+    if script.starts_with('[') || script.starts_with('=') {
+      return None;
+    }
+    let path = root.join(script);
+    path.is_file().then_some(path)
+  }
+
+  /// Write the report to the log as a JSON line.
+  pub fn log(&self) {
+    match serde_json::to_string(self) {
+      Ok(json) => log::warn!(target: LOG_TARGET, "{json}"),
+      Err(e) => {
+        log::error!("Lua error report serialize error: {e}")
+      }
+    }
+  }
+}
+
+fn describe(
+  e: &mlua::Error,
+  traceback: &mut Option<String>,
+) -> (ErrorKind, String) {
+  match e {
+    mlua::Error::SyntaxError { message, .. } => {
+      (ErrorKind::Syntax, message.clone())
+    }
+    mlua::Error::RuntimeError(m) => {
+      (ErrorKind::Runtime, m.clone())
+    }
+    mlua::Error::MemoryError(m) => {
+      (ErrorKind::Memory, m.clone())
+    }
+    mlua::Error::CallbackError {
+      traceback: tb,
+      cause,
+    } => {
+      traceback.get_or_insert_with(|| tb.clone());
+      match describe(cause, traceback) {
+        (ErrorKind::Other, m) => (ErrorKind::Callback, m),
+        r => r,
+      }
+    }
+    mlua::Error::WithContext { context, cause } => {
+      let (kind, m) = describe(cause, traceback);
+      (kind, format!("{context}: {m}"))
+    }
+    mlua::Error::ExternalError(_) => {
+      match super::sandbox::find_violation(e) {
+        Some(v) => (ErrorKind::Sandbox, v.to_string()),
+        None => (ErrorKind::Other, e.to_string()),
+      }
+    }
+    e => (ErrorKind::Other, e.to_string()),
+  }
+}
+
+/// Split `source:line: message`.
+fn split_location(
+  text: &str,
+) -> Option<(String, u32, String)> {
+  let first = text.lines().next()?;
+  // Chunk name may contain ':' (`[string "a:1:b"]`), so find the
+  // first `:<digits>:` after the closing `"]` of a string chunk.
+  let mut search = first
+    .strip_prefix("[string \"")
+    .and_then(|_| first.find("\"]"))
+    .map_or(0, |i| i + 2);
+  while let Some(i) = first[search..].find(':') {
+    let i = search + i;
+    let rest = &first[i + 1..];
+    let digits =
+      rest.bytes().take_while(u8::is_ascii_digit).count();
+    if 0 < digits && rest[digits..].starts_with(':') {
+      let line = rest[..digits].parse().ok()?;
+      let message = text[i + 1 + digits + 1..].trim_start();
+      return Some((
+        first[..i].to_string(),
+        line,
+        message.to_string(),
+      ));
+    }
+    search = i + 1;
+  }
+  None
+}
+
+fn parse_traceback(text: &str) -> Vec<Frame> {
+  text
+    .lines()
+    .map(str::trim)
+    .filter(|l| !l.is_empty() && *l != "stack traceback:")
+    .map(|l| {
+      let (location, function) = match l.split_once(": in ")
+      {
+        Some((loc, f)) => (loc, f.to_string()),
+        None => (l, String::new()),
+      };
+      match location.rsplit_once(':') {
+        Some((source, line))
+          if line.parse::<u32>().is_ok() =>
+        {
+          Frame {
+            source: source.to_string(),
+            line: line.parse().ok(),
+            function,
+          }
+        }
+        _ => Frame {
+          source: location
+            .trim_end_matches(':')
+            .to_string(),
+          line: None,
+          function,
+        },
+      }
+    })
+    .collect()
+}
+
+/// Location that the user wants to open (`(path, line)`)
+pub type JumpRequest = (PathBuf, u32);
+
+/// Report with the locations resolved when it is recorded
+struct Entry {
+  report: LuaErrorReport,
+  jump: Option<JumpRequest>,
+  /// Location of each frame of the traceback
+  frames: Vec<Option<JumpRequest>>,
+}
+
+/// Collapsible panel of the recent errors
+pub struct ErrorPanel {
+  root: PathBuf,
+  reports: VecDeque<Entry>,
+}
+impl ErrorPanel {
+  /// `root` is the script root to resolve the chunk names.
+  pub fn new(root: impl Into<PathBuf>) -> Self {
+    Self {
+      root: root.into(),
+      reports: VecDeque::new(),
+    }
+  }
+
+  /// Parse and log the error, and add it to the panel.
+  pub fn push(
+    &mut self,
+    e: &mlua::Error,
+  ) -> &LuaErrorReport {
+    let report = LuaErrorReport::new(e);
+    report.log();
+    let jump =
+      report.script_path(&self.root).zip(report.line);
+    let frames = report
+      .traceback
+      .iter()
+      .map(|frame| {
+        let line = frame.line?;
+        let path = self.root.join(&frame.source);
+        (!frame.source.starts_with('[') && path.is_file())
+          .then_some((path, line))
+      })
+      .collect();
+    self.reports.push_front(Entry {
+      report,
+      jump,
+      frames,
+    });
+    self.reports.truncate(REPORT_LIMIT);
+    &self.reports[0].report
+  }
+
+  /// Log the error of a wasm plugin, and add it to the panel.
+  pub fn push_plugin(&mut self, message: &str) {
+    let report = LuaErrorReport {
+      kind: ErrorKind::Plugin,
+      message: message.to_string(),
+      script: None,
+      line: None,
+      traceback: Vec::new(),
+    };
+    report.log();
+    self.reports.push_front(Entry {
+      report,
+      jump: None,
+      frames: Vec::new(),
+    });
+    self.reports.truncate(REPORT_LIMIT);
+  }
+
+  pub fn clear(&mut self) {
+    self.reports.clear();
+  }
+
+  /// Draw the panel. Returns the location if a link is clicked.
+  pub fn ui(
+    &mut self,
+    ui: &mut egui::Ui,
+  ) -> Option<JumpRequest> {
+    let mut jump = None;
+    egui::CollapsingHeader::new(
+      RichText::new(format!(
+        "Errors ({})",
+        self.reports.len()
+      ))
+      .color(if self.reports.is_empty() {
+        Color32::GRAY
+      } else {
+        Color32::RED
+      }),
+    )
+    .id_salt("lua errors")
+    .default_open(true)
+    .show(ui, |ui| {
+      if ui.button("Clear").clicked() {
+        self.clear();
+      }
+      for (i, entry) in self.reports.iter().enumerate() {
+        let report = &entry.report;
+        let location = match (&report.script, report.line) {
+          (Some(s), Some(l)) => format!("{s}:{l}"),
+          (Some(s), None) => s.clone(),
+          _ => "?".to_string(),
+        };
+        egui::CollapsingHeader::new(
+          RichText::new(format!(
+            "[{:?}] {location}: {}",
+            report.kind,
+            report
+              .message
+              .lines()
+              .next()
+              .unwrap_or_default()
+          ))
+          .color(Color32::RED),
+        )
+        .id_salt(("lua error", i))
+        .default_open(i == 0)
+        .show(ui, |ui| {
+          ui.label(
+            RichText::new(&report.message).monospace(),
+          );
+          if let Some(to) = &entry.jump {
+            if ui.link(format!("Open {location}")).clicked()
+            {
+              jump = Some(to.clone());
+            }
+          }
+          for (frame, to) in
+            report.traceback.iter().zip(&entry.frames)
+          {
+            let text = match frame.line {
+              Some(l) => format!(
+                "{}:{l}: in {}",
+                frame.source, frame.function
+              ),
+              None => {
+                format!(
+                  "{}: in {}",
+                  frame.source, frame.function
+                )
+              }
+            };
+            match to {
+              Some(to) => {
+                if ui
+                  .link(RichText::new(text).monospace())
+                  .clicked()
+                {
+                  jump = Some(to.clone());
+                }
+              }
+              None => {
+                ui.label(RichText::new(text).monospace());
+              }
+            }
+          }
+        });
+      }
+    });
+    jump
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn splits_the_location() {
+    assert_eq!(
+      split_location("main.lua:12: attempt to call nil"),
+      Some((
+        "main.lua".to_string(),
+        12,
+        "attempt to call nil".to_string()
+      ))
+    );
+    assert_eq!(
+      split_location(r#"[string "a:1:b"]:3: boom: x"#),
+      Some((
+        r#"[string "a:1:b"]"#.to_string(),
+        3,
+        "boom: x".to_string()
+      ))
+    );
+    assert_eq!(
+      split_location("C:/game/main.lua:7: oops"),
+      Some((
+        "C:/game/main.lua".to_string(),
+        7,
+        "oops".to_string()
+      ))
+    );
+    assert_eq!(split_location("main.lua: no line"), None);
+    assert_eq!(split_location("not enough memory"), None);
+  }
+
+  #[test]
+  fn parses_the_traceback() {
+    let frames = parse_traceback(
+      "stack traceback:
+	[C]: in function 'error'
+	[string \"a:b\"]:3: in function 'f'
+	scripts/main.lua:10: in main chunk
+	(...tail calls...)
+	[C]: in ?",
+    );
+    let got = frames
+      .iter()
+      .map(|f| {
+        (f.source.as_str(), f.line, f.function.as_str())
+      })
+      .collect::<Vec<_>>();
+    assert_eq!(
+      got,
+      [
+        ("[C]", None, "function 'error'"),
+        (r#"[string "a:b"]"#, Some(3), "function 'f'"),
+        ("scripts/main.lua", Some(10), "main chunk"),
+        ("(...tail calls...)", None, ""),
+        ("[C]", None, "?"),
+      ]
+    );
+  }
+
+  #[test]
+  fn falls_back_to_the_first_lua_frame() {
+    let e = mlua::Error::RuntimeError(
+      "boom\nstack traceback:\n\t[C]: in function \
+       'error'\n\tmain.lua:4: in main chunk"
+        .to_string(),
+    );
+    let report = LuaErrorReport::new(&e);
+    assert_eq!(report.kind, ErrorKind::Runtime);
+    assert_eq!(report.message, "boom");
+    assert_eq!(report.script.as_deref(), Some("main.lua"));
+    assert_eq!(report.line, Some(4));
+    assert_eq!(report.traceback.len(), 2);
+  }
+}
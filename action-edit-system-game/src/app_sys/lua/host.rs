@@ -0,0 +1,265 @@
+//! Lua script host
+//!
+//! Owns the Lua state with the scene API, the sandbox, the task
+//! scheduler and the script loader, and drives the scripts by
+//! `ScriptHost`. The entry points are the globals of the script
+//! environment:
+//! - `init()`: after the first run (see `loader`)
+//! - `tick(dt)`: after the tasks are resumed
+//! - `on_event(name, payload)`: the event is also passed to the task
+//!   scheduler, so `wait_event(name)` returns the payload.
+//! - `shutdown()`
+
+use super::{
+  api::{self, SceneContext},
+  engine,
+  loader::{ScriptLoader, SCRIPT_ROOT},
+  sandbox::{self, Sandbox, SandboxProfile},
+  task::TaskScheduler,
+};
+use crate::app_sys::{
+  input::{buffer::PlayerInputs, RawInput},
+  script::{
+    ScriptCommand, ScriptEngine, ScriptEvent, ScriptHost,
+  },
+};
+use mlua::{
+  Function, IntoLuaMulti, Lua, LuaOptions, MultiValue,
+  Value,
+};
+use parking_lot::{Mutex, RwLock};
+use std::{path::Path, sync::Arc};
+
+pub struct LuaHost {
+  lua: Lua,
+  scripts: ScriptLoader,
+  tasks: TaskScheduler,
+  engine: Arc<Mutex<ScriptEngine>>,
+}
+impl LuaHost {
+  /// Create the Lua state. The scripts are loaded by `load_dir`.
+  pub fn new(
+    ctx: &SceneContext,
+    input: Arc<RwLock<RawInput>>,
+    profile: SandboxProfile,
+  ) -> mlua::Result<Self> {
+    let lua = Lua::new_with(
+      sandbox::std_libs(),
+      LuaOptions::new(),
+    )?;
+    let engine = Arc::new(Mutex::new(ScriptEngine::new(
+      "lua",
+      ctx.clone(),
+      input,
+    )));
+    api::register(&lua, ctx)?;
+    engine::register(&lua, &engine)?;
+    Sandbox::install(&lua, profile)?;
+    let tasks = TaskScheduler::new(&lua)?;
+    let scripts = ScriptLoader::new(&lua, SCRIPT_ROOT)?;
+    Ok(Self {
+      lua,
+      scripts,
+      tasks,
+      engine,
+    })
+  }
+
+  pub fn lua(&self) -> &Lua {
+    &self.lua
+  }
+
+  /// Expose the input buffers to the scripts.
+  pub fn register_input_buffers(
+    &self,
+    buffers: Arc<RwLock<PlayerInputs>>,
+  ) -> mlua::Result<()> {
+    engine::register_buffers(&self.lua, buffers)
+  }
+
+  /// Seed `math.random` of the scripts, for the sessions to be
+  /// replayed.
+  pub fn seed_random(&self, seed: u64) -> mlua::Result<()> {
+    let math: mlua::Table =
+      self.lua.globals().get("math")?;
+    math.get::<Function>("randomseed")?.call(seed as f64)
+  }
+
+  pub fn tasks(&self) -> &TaskScheduler {
+    &self.tasks
+  }
+
+  /// Data of the script environments, for the state hash of the
+  /// replays. The scripts are in the name order, and the pairs of
+  /// a table in the order of their bytes, so the bytes do not
+  /// depend on the table layout.
+  pub fn state_bytes(&self) -> mlua::Result<Vec<u8>> {
+    let mut scripts =
+      self.scripts.script_names().collect::<Vec<_>>();
+    scripts.sort();
+    let mut out = Vec::new();
+    for script in scripts {
+      let Some(env) = self.scripts.env(script) else {
+        continue;
+      };
+      out.extend_from_slice(script.as_bytes());
+      out.push(0);
+      encode_value(
+        &Value::Table(env.clone()),
+        STATE_DEPTH,
+        &mut out,
+      )?;
+    }
+    Ok(out)
+  }
+
+  /// Call the global of every script that defines it.
+  fn call_scripts(
+    &self,
+    name: &str,
+    args: impl IntoLuaMulti + Clone,
+  ) -> Vec<mlua::Error> {
+    let mut scripts =
+      self.scripts.script_names().collect::<Vec<_>>();
+    scripts.sort();
+    scripts
+      .into_iter()
+      .filter_map(|script| {
+        let env = self.scripts.env(script)?;
+        let label = format!("{script}.{name}");
+        sandbox::run(&self.lua, &label, || {
+          match env.raw_get::<Option<Function>>(name)? {
+            Some(f) => f.call::<()>(args.clone()),
+            None => Ok(()),
+          }
+        })
+        .err()
+      })
+      .collect()
+  }
+}
+/// Tables nested deeper are skipped (for the cycles).
+const STATE_DEPTH: u32 = 8;
+
+/// Append the value with its type. Returns false for the values
+/// that are not data (functions, userdata, threads), which are
+/// skipped.
+fn encode_value(
+  value: &Value,
+  depth: u32,
+  out: &mut Vec<u8>,
+) -> mlua::Result<bool> {
+  match value {
+    Value::Nil => out.push(0),
+    Value::Boolean(b) => out.extend([1, *b as u8]),
+    Value::Integer(i) => {
+      out.push(2);
+      out.extend(i.to_le_bytes());
+    }
+    Value::Number(n) => {
+      out.push(3);
+      out.extend(n.to_le_bytes());
+    }
+    Value::String(s) => {
+      let bytes = s.as_bytes();
+      out.push(4);
+      out.extend((bytes.len() as u64).to_le_bytes());
+      out.extend_from_slice(&bytes);
+    }
+    Value::Table(t) if 0 < depth => {
+      let mut pairs = Vec::new();
+      for pair in t.pairs::<Value, Value>() {
+        let (k, v) = pair?;
+        let (mut kb, mut vb) = (Vec::new(), Vec::new());
+        if encode_value(&k, depth - 1, &mut kb)?
+          && encode_value(&v, depth - 1, &mut vb)?
+        {
+          pairs.push((kb, vb));
+        }
+      }
+      pairs.sort();
+      out.push(5);
+      out.extend((pairs.len() as u64).to_le_bytes());
+      for (k, v) in pairs {
+        out.extend(k);
+        out.extend(v);
+      }
+    }
+    _ => return Ok(false),
+  }
+  Ok(true)
+}
+
+impl ScriptHost for LuaHost {
+  type Error = mlua::Error;
+
+  fn backend(&self) -> &'static str {
+    "lua"
+  }
+
+  fn load_dir(&mut self, dir: &Path) -> Vec<mlua::Error> {
+    if self.scripts.root() != dir {
+      match ScriptLoader::new(&self.lua, dir) {
+        Ok(scripts) => self.scripts = scripts,
+        Err(e) => return vec![e],
+      }
+    }
+    self.scripts.load_all(&self.lua)
+  }
+
+  /// Reload the changed scripts, resume the tasks, and call `tick`.
+  fn tick(&mut self, dt: f32) -> Vec<mlua::Error> {
+    let mut errors = self.scripts.poll(&self.lua);
+    errors.extend(self.tasks.tick(&self.lua, dt as f64));
+    errors.extend(self.call_scripts("tick", dt));
+    errors
+  }
+
+  fn dispatch(
+    &mut self,
+    event: &ScriptEvent,
+  ) -> Vec<mlua::Error> {
+    match self.lua.create_string(&event.payload) {
+      Ok(payload) => self.tasks.emit(
+        &event.name,
+        MultiValue::from_iter([mlua::Value::String(
+          payload,
+        )]),
+      ),
+      Err(e) => return vec![e],
+    }
+    self.call_scripts(
+      "on_event",
+      (event.name.clone(), event.payload.clone()),
+    )
+  }
+
+  fn take_events(&mut self) -> Vec<ScriptEvent> {
+    self.engine.lock().take_events()
+  }
+
+  fn shutdown(&mut self) -> Vec<mlua::Error> {
+    self.call_scripts("shutdown", ())
+  }
+
+  /// Drop the tasks and the environments, and run the scripts
+  /// again.
+  fn restart(&mut self) -> Vec<mlua::Error> {
+    self.tasks.clear();
+    self.engine.lock().take_events();
+    match ScriptLoader::new(&self.lua, self.scripts.root())
+    {
+      Ok(scripts) => self.scripts = scripts,
+      Err(e) => return vec![e],
+    }
+    self.scripts.load_all(&self.lua)
+  }
+
+  fn set_trace(&mut self, enable: bool) {
+    self.engine.lock().set_trace(enable);
+  }
+
+  fn take_trace(&mut self) -> Vec<ScriptCommand> {
+    self.engine.lock().take_trace()
+  }
+}
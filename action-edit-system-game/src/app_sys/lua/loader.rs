@@ -0,0 +1,386 @@
+//! Script loader
+//!
+//! Every `*.lua` file directly under the script root is run as a
+//! script with its own environment. Modules are loaded by `require`
+//! only from the script root. (`a.b` is `a/b.lua` or `a/b/init.lua`)
+//!
+//! Changed files are reloaded in the same environment, so state
+//! tables written as `state = state or {}` survive the reload.
+//! After the first run, `init()` of the script is called, and after
+//! the reload, `on_reload()` is called if they are defined.
+
+use hashbrown::{HashMap, HashSet};
+use mlua::{ChunkMode, Function, Lua, Table, Value};
+use parking_lot::Mutex;
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+  time::{Duration, Instant, SystemTime},
+};
+
+/// Default script root
+pub const SCRIPT_ROOT: &str = "./scripts";
+/// Registry key of the loaded module table
+const LOADED_KEY: &str =
+  "action_edit_system.script_loader.loaded";
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Code that calls `require`
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Requirer {
+  Script(String),
+  Module(String),
+  Global,
+}
+
+struct ModuleMeta {
+  path: PathBuf,
+  modified: Option<SystemTime>,
+  dependents: HashSet<Requirer>,
+}
+
+/// Module bookkeeping shared with the `require` functions
+struct ModuleTable {
+  root: PathBuf,
+  modules: HashMap<String, ModuleMeta>,
+  loading: HashSet<String>,
+}
+impl ModuleTable {
+  fn resolve(&self, name: &str) -> mlua::Result<PathBuf> {
+    let valid = !name.is_empty()
+      && name.split('.').all(|s| {
+        !s.is_empty()
+          && s
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_')
+      });
+    if !valid {
+      return Err(mlua::Error::runtime(format!(
+        "invalid module name \"{name}\""
+      )));
+    }
+    let base = name
+      .split('.')
+      .fold(self.root.clone(), |p, s| p.join(s));
+    let root = self.root.canonicalize()?;
+    [base.with_extension("lua"), base.join("init.lua")]
+      .into_iter()
+      .filter_map(|p| p.canonicalize().ok())
+      .find(|p| p.is_file() && p.starts_with(&root))
+      .ok_or_else(|| {
+        mlua::Error::runtime(format!(
+          "module \"{name}\" is not found in {}",
+          self.root.display()
+        ))
+      })
+  }
+
+  /// Forget the module and the modules that depend on it, and
+  /// returns the scripts that should be reloaded.
+  fn invalidate(
+    &mut self,
+    loaded: &Table,
+    name: &str,
+    scripts: &mut HashSet<String>,
+  ) -> mlua::Result<()> {
+    let Some(meta) = self.modules.remove(name) else {
+      return Ok(());
+    };
+    loaded.raw_set(name, Value::Nil)?;
+    for dependent in meta.dependents {
+      match dependent {
+        Requirer::Script(s) => {
+          scripts.insert(s);
+        }
+        Requirer::Module(m) => {
+          self.invalidate(loaded, &m, scripts)?
+        }
+        Requirer::Global => {}
+      }
+    }
+    Ok(())
+  }
+}
+
+struct Script {
+  path: PathBuf,
+  modified: Option<SystemTime>,
+  env: Table,
+}
+
+pub struct ScriptLoader {
+  modules: Arc<Mutex<ModuleTable>>,
+  scripts: HashMap<String, Script>,
+  last_poll: Instant,
+}
+impl ScriptLoader {
+  /// Create the loader, and replace the global `require`.
+  pub fn new(
+    lua: &Lua,
+    root: impl AsRef<Path>,
+  ) -> mlua::Result<Self> {
+    let modules = Arc::new(Mutex::new(ModuleTable {
+      root: root.as_ref().to_path_buf(),
+      modules: HashMap::new(),
+      loading: HashSet::new(),
+    }));
+    lua.set_named_registry_value(
+      LOADED_KEY,
+      lua.create_table()?,
+    )?;
+    lua.globals().set(
+      "require",
+      create_require(lua, &modules, Requirer::Global)?,
+    )?;
+    Ok(Self {
+      modules,
+      scripts: HashMap::new(),
+      last_poll: Instant::now(),
+    })
+  }
+
+  pub fn root(&self) -> PathBuf {
+    self.modules.lock().root.clone()
+  }
+
+  pub fn script_names(&self) -> impl Iterator<Item = &str> {
+    self.scripts.keys().map(String::as_str)
+  }
+
+  /// Environment of the script
+  pub fn env(&self, name: &str) -> Option<&Table> {
+    self.scripts.get(name).map(|s| &s.env)
+  }
+
+  /// Run every script under the script root.
+  pub fn load_all(
+    &mut self,
+    lua: &Lua,
+  ) -> Vec<mlua::Error> {
+    let mut errors = Vec::new();
+    for (name, path) in self.scan() {
+      if !self.scripts.contains_key(&name) {
+        if let Err(e) = self.load(lua, &name, path) {
+          errors.push(e);
+        }
+      }
+    }
+    errors
+  }
+
+  /// Check the files, and reload the changed scripts and modules.
+  /// The check runs at most once per `POLL_INTERVAL`.
+  pub fn poll(&mut self, lua: &Lua) -> Vec<mlua::Error> {
+    if self.last_poll.elapsed() < POLL_INTERVAL {
+      return Vec::new();
+    }
+    self.last_poll = Instant::now();
+    let mut errors = Vec::new();
+    let mut reload = HashSet::new();
+    {
+      let mut modules = self.modules.lock();
+      let changed = modules
+        .modules
+        .iter()
+        .filter(|(_, m)| modified(&m.path) != m.modified)
+        .map(|(n, _)| n.clone())
+        .collect::<Vec<_>>();
+      match lua.named_registry_value::<Table>(LOADED_KEY) {
+        Ok(loaded) => {
+          for name in changed {
+            log::info!("Lua module \"{name}\" is changed.");
+            if let Err(e) = modules.invalidate(
+              &loaded,
+              &name,
+              &mut reload,
+            ) {
+              errors.push(e);
+            }
+          }
+        }
+        Err(e) => errors.push(e),
+      }
+    }
+    let files = self.scan();
+    self.scripts.retain(|name, _| {
+      let exists = files.iter().any(|(n, _)| n == name);
+      if !exists {
+        log::info!("Lua script \"{name}\" is removed.");
+      }
+      exists
+    });
+    for (name, path) in files {
+      let changed = match self.scripts.get(&name) {
+        Some(s) => modified(&s.path) != s.modified,
+        None => true,
+      };
+      if changed || reload.contains(&name) {
+        if let Err(e) = self.load(lua, &name, path) {
+          errors.push(e);
+        }
+      }
+    }
+    errors
+  }
+
+  /// Run the script file, and call its `init`. A loaded script is
+  /// run again in the same environment, and its `on_reload` is
+  /// called instead.
+  pub fn load(
+    &mut self,
+    lua: &Lua,
+    name: &str,
+    path: PathBuf,
+  ) -> mlua::Result<()> {
+    let source = std::fs::read_to_string(&path)?;
+    let reload = self.scripts.contains_key(name);
+    let env = match self.scripts.get(name) {
+      Some(s) => s.env.clone(),
+      None => create_env(
+        lua,
+        create_require(
+          lua,
+          &self.modules,
+          Requirer::Script(name.to_string()),
+        )?,
+      )?,
+    };
+    self.scripts.insert(
+      name.to_string(),
+      Script {
+        modified: modified(&path),
+        path,
+        env: env.clone(),
+      },
+    );
+    log::info!(
+      "{} Lua script \"{name}\".",
+      if reload { "Reload" } else { "Load" }
+    );
+    super::sandbox::run(lua, name, || {
+      lua
+        .load(&source)
+        .set_name(format!("@{name}.lua"))
+        .set_mode(ChunkMode::Text)
+        .set_environment(env.clone())
+        .exec()?;
+      let hook = if reload { "on_reload" } else { "init" };
+      if let Some(f) = env.raw_get::<Option<Function>>(hook)? {
+        f.call::<()>(())?;
+      }
+      Ok(())
+    })
+  }
+
+  /// Script files directly under the root (`(name, path)`)
+  fn scan(&self) -> Vec<(String, PathBuf)> {
+    let root = self.root();
+    let Ok(dir) = std::fs::read_dir(&root) else {
+      return Vec::new();
+    };
+    let mut files = dir
+      .filter_map(|e| e.ok())
+      .map(|e| e.path())
+      .filter(|p| {
+        p.is_file()
+          && p.extension().is_some_and(|e| e == "lua")
+      })
+      .filter_map(|p| {
+        Some((
+          p.file_stem()?.to_str()?.to_string(),
+          p.clone(),
+        ))
+      })
+      .collect::<Vec<_>>();
+    files.sort();
+    files
+  }
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+  std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+/// Table that reads the missing names from the globals
+pub fn create_env(
+  lua: &Lua,
+  require: Function,
+) -> mlua::Result<Table> {
+  let env = lua.create_table()?;
+  env.set("require", require)?;
+  let meta = lua.create_table()?;
+  meta.set("__index", lua.globals())?;
+  lua
+    .globals()
+    .get::<Function>("setmetatable")?
+    .call::<Table>((env, meta))
+}
+
+fn create_require(
+  lua: &Lua,
+  modules: &Arc<Mutex<ModuleTable>>,
+  requirer: Requirer,
+) -> mlua::Result<Function> {
+  let modules = modules.clone();
+  lua.create_function(move |lua, name: String| {
+    let loaded =
+      lua.named_registry_value::<Table>(LOADED_KEY)?;
+    let path = {
+      let mut table = modules.lock();
+      if let Some(meta) = table.modules.get_mut(&name) {
+        meta.dependents.insert(requirer.clone());
+        None
+      } else if table.loading.contains(&name) {
+        return Err(mlua::Error::runtime(format!(
+          "loop detected while loading module \"{name}\""
+        )));
+      } else {
+        let path = table.resolve(&name)?;
+        table.loading.insert(name.clone());
+        Some(path)
+      }
+    };
+    let Some(path) = path else {
+      return loaded.raw_get::<Value>(name.as_str());
+    };
+    let result = (|| {
+      let source = std::fs::read_to_string(&path)?;
+      let env = create_env(
+        lua,
+        create_require(
+          lua,
+          &modules,
+          Requirer::Module(name.clone()),
+        )?,
+      )?;
+      let value = lua
+        .load(&source)
+        .set_name(format!(
+          "@{}.lua",
+          name.replace('.', "/")
+        ))
+        .set_mode(ChunkMode::Text)
+        .set_environment(env)
+        .call::<Value>(())?;
+      Ok::<_, mlua::Error>(match value {
+        Value::Nil => Value::Boolean(true),
+        v => v,
+      })
+    })();
+    let mut table = modules.lock();
+    table.loading.remove(&name);
+    let value = result?;
+    loaded.raw_set(name.as_str(), value.clone())?;
+    table.modules.insert(
+      name,
+      ModuleMeta {
+        modified: modified(&path),
+        path,
+        dependents: [requirer.clone()]
+          .into_iter()
+          .collect(),
+      },
+    );
+    Ok(value)
+  })
+}
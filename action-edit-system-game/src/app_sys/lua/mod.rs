@@ -0,0 +1,13 @@
+//! Lua scripting
+//! Luaスクリプトとアプリケーションの結合部分
+
+pub mod api;
+pub mod console;
+pub mod editor;
+pub mod engine;
+pub mod error;
+pub mod host;
+pub mod loader;
+pub mod sandbox;
+pub mod task;
+pub use api::SceneContext;
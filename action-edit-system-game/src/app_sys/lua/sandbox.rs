@@ -0,0 +1,490 @@
+//! Sandboxed Lua profile
+//!
+//! - `os`, `io`, `debug`, `ffi` libraries are not loaded (see
+//!   `std_libs`), and removed from `package.loaded` if loaded.
+//! - The JIT compiler is turned off and `jit` is removed, since the
+//!   budget hook is not called in the compiled traces.
+//! - `dofile`, `loadfile`, `getfenv`, `setfenv`, `collectgarbage`,
+//!   `package.loadlib`, `string.dump` raise a violation.
+//! - `load` and `loadstring` accept only the text chunks.
+//! - Every entry point (script, console, callback) has its own
+//!   instruction budget and memory budget.
+//! - The memory of the whole state is limited by the allocator, or
+//!   by the budget hook where it has no limit (some LuaJIT builds).
+
+use mlua::{
+  ChunkMode, Function, HookTriggers, Lua, StdLib, Table,
+  Value, VmState,
+};
+use parking_lot::Mutex;
+use std::{fmt::Display, sync::Arc};
+
+/// Instruction count between the budget checks
+const HOOK_INTERVAL: u32 = 1000;
+/// Libraries removed from the globals and `package.loaded`
+const UNSAFE_LIBS: [&str; 5] =
+  ["os", "io", "debug", "ffi", "jit"];
+/// Globals that raise a violation
+const FORBIDDEN_GLOBALS: [&str; 5] = [
+  "dofile",
+  "loadfile",
+  "getfenv",
+  "setfenv",
+  "collectgarbage",
+];
+
+/// Standard libraries of the sandboxed state
+pub fn std_libs() -> StdLib {
+  StdLib::TABLE
+    | StdLib::STRING
+    | StdLib::MATH
+    | StdLib::PACKAGE
+    | StdLib::BIT
+    | StdLib::JIT
+}
+
+#[derive(Debug, Clone)]
+pub struct SandboxProfile {
+  /// Instruction limit per entry point
+  pub instruction_limit: u64,
+  /// Memory that an entry point can allocate (byte)
+  pub memory_limit: usize,
+  /// Memory limit of the whole Lua state (byte)
+  pub total_memory_limit: usize,
+}
+impl Default for SandboxProfile {
+  fn default() -> Self {
+    Self {
+      instruction_limit: 50_000_000,
+      memory_limit: 64 << 20,
+      total_memory_limit: 512 << 20,
+    }
+  }
+}
+
+#[derive(Debug, Clone)]
+pub enum ViolationKind {
+  InstructionLimit { limit: u64 },
+  MemoryLimit { limit: usize, used: usize },
+  Forbidden { name: String },
+}
+impl Display for ViolationKind {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::InstructionLimit { limit } => {
+        write!(f, "instruction limit ({limit}) exceeded")
+      }
+      Self::MemoryLimit { limit, used } => {
+        write!(
+          f,
+          "memory limit exceeded ({used} / {limit} bytes)"
+        )
+      }
+      Self::Forbidden { name } => {
+        write!(f, "`{name}` is forbidden")
+      }
+    }
+  }
+}
+
+/// Error raised when a script breaks the sandbox rule
+#[derive(Debug, Clone)]
+pub struct SandboxViolation {
+  /// Entry point label (script name, "console", ...)
+  pub script: String,
+  pub kind: ViolationKind,
+}
+impl Display for SandboxViolation {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    write!(
+      f,
+      "sandbox violation in {}: {}",
+      self.script, self.kind
+    )
+  }
+}
+impl std::error::Error for SandboxViolation {}
+
+/// Find the sandbox violation in the error chain.
+pub fn find_violation(
+  e: &mlua::Error,
+) -> Option<&SandboxViolation> {
+  match e {
+    mlua::Error::ExternalError(e) => e.downcast_ref(),
+    mlua::Error::CallbackError { cause, .. } => {
+      find_violation(cause)
+    }
+    mlua::Error::WithContext { cause, .. } => {
+      find_violation(cause)
+    }
+    _ => None,
+  }
+}
+
+struct Budget {
+  profile: SandboxProfile,
+  /// Label of the running entry point
+  active: Option<String>,
+  remaining: u64,
+  memory_base: usize,
+  /// The hook checks the total memory limit
+  check_total: bool,
+}
+impl Budget {
+  fn violation(&self, kind: ViolationKind) -> mlua::Error {
+    mlua::Error::external(SandboxViolation {
+      script: self
+        .active
+        .clone()
+        .unwrap_or_else(|| "<unknown>".to_string()),
+      kind,
+    })
+  }
+}
+
+/// Sandbox installed in the Lua state
+///
+/// It is stored in the app data of the state, and `run` looks it up.
+#[derive(Clone)]
+pub struct Sandbox {
+  budget: Arc<Mutex<Budget>>,
+}
+impl Sandbox {
+  /// Strip the unsafe functions, and install the budget hook.
+  pub fn install(
+    lua: &Lua,
+    profile: SandboxProfile,
+  ) -> mlua::Result<Self> {
+    let check_total = match lua
+      .set_memory_limit(profile.total_memory_limit)
+    {
+      Ok(_) => false,
+      Err(e) => {
+        log::info!(
+          "Lua allocator limit is not available ({e}), the \
+           total memory is checked by the hook."
+        );
+        true
+      }
+    };
+    let sandbox = Self {
+      budget: Arc::new(Mutex::new(Budget {
+        remaining: profile.instruction_limit,
+        profile,
+        active: None,
+        memory_base: 0,
+        check_total,
+      })),
+    };
+    sandbox.strip(lua)?;
+    let budget = sandbox.budget.clone();
+    lua.set_hook(
+      HookTriggers::new()
+        .every_nth_instruction(HOOK_INTERVAL),
+      move |lua, _debug| {
+        let mut budget = budget.lock();
+        if budget.check_total {
+          let used = lua.used_memory();
+          let limit = budget.profile.total_memory_limit;
+          if limit < used {
+            return Err(budget.violation(
+              ViolationKind::MemoryLimit { limit, used },
+            ));
+          }
+        }
+        if budget.active.is_none() {
+          return Ok(VmState::Continue);
+        }
+        budget.remaining = budget
+          .remaining
+          .saturating_sub(HOOK_INTERVAL as u64);
+        if budget.remaining == 0 {
+          let limit = budget.profile.instruction_limit;
+          return Err(budget.violation(
+            ViolationKind::InstructionLimit { limit },
+          ));
+        }
+        let used = lua
+          .used_memory()
+          .saturating_sub(budget.memory_base);
+        if budget.profile.memory_limit < used {
+          let limit = budget.profile.memory_limit;
+          return Err(budget.violation(
+            ViolationKind::MemoryLimit { limit, used },
+          ));
+        }
+        Ok(VmState::Continue)
+      },
+    );
+    lua.set_app_data(sandbox.clone());
+    Ok(sandbox)
+  }
+
+  fn strip(&self, lua: &Lua) -> mlua::Result<()> {
+    let globals = lua.globals();
+    if let Some(jit) =
+      globals.get::<Option<Table>>("jit")?
+    {
+      jit.get::<Function>("off")?.call::<()>(())?;
+    }
+    for lib in UNSAFE_LIBS {
+      globals.set(lib, Value::Nil)?;
+    }
+    for name in FORBIDDEN_GLOBALS {
+      globals.set(name, self.forbidden(lua, name)?)?;
+    }
+    if let Some(package) =
+      globals.get::<Option<Table>>("package")?
+    {
+      package.set(
+        "loadlib",
+        self.forbidden(lua, "package.loadlib")?,
+      )?;
+      package.set("cpath", "")?;
+      package.set("path", "")?;
+      for key in ["loaded", "preload"] {
+        if let Some(t) =
+          package.get::<Option<Table>>(key)?
+        {
+          strip_libs(&t)?;
+        }
+      }
+    }
+    if let Some(string) =
+      globals.get::<Option<Table>>("string")?
+    {
+      string
+        .set("dump", self.forbidden(lua, "string.dump")?)?;
+    }
+    let load = self.text_load(lua)?;
+    globals.set("load", load.clone())?;
+    globals.set("loadstring", load)?;
+    Ok(())
+  }
+
+  fn forbidden(
+    &self,
+    lua: &Lua,
+    name: &str,
+  ) -> mlua::Result<Function> {
+    let budget = self.budget.clone();
+    let name = name.to_string();
+    lua.create_function(move |_, _: mlua::MultiValue| {
+      Err::<(), _>(budget.lock().violation(
+        ViolationKind::Forbidden { name: name.clone() },
+      ))
+    })
+  }
+
+  /// `load(chunk [, chunkname [, mode [, env]]])` for the text chunks
+  fn text_load(&self, lua: &Lua) -> mlua::Result<Function> {
+    let budget = self.budget.clone();
+    lua.create_function(
+      move |lua,
+            (chunk, name, mode, env): (
+        Value,
+        Option<String>,
+        Option<String>,
+        Option<Table>,
+      )| {
+        let source = match chunk {
+          Value::String(s) => s.as_bytes().to_vec(),
+          Value::Function(f) => {
+            let mut source = Vec::new();
+            while let Some(piece) = f.call::<Option<mlua::String>>(())? {
+              if piece.as_bytes().is_empty() {
+                break;
+              }
+              source.extend_from_slice(&piece.as_bytes());
+            }
+            source
+          }
+          v => {
+            return Err(mlua::Error::runtime(format!(
+              "bad argument #1 to 'load' (string expected, got {})",
+              v.type_name()
+            )))
+          }
+        };
+        if source.first() == Some(&0x1b)
+          || mode.is_some_and(|m| m.contains('b'))
+        {
+          return Err(budget.lock().violation(ViolationKind::Forbidden {
+            name: "load (binary chunk)".to_string(),
+          }));
+        }
+        let mut chunk = lua
+          .load(source)
+          .set_mode(ChunkMode::Text)
+          .set_name(name.unwrap_or_else(|| "=(load)".to_string()));
+        if let Some(env) = env {
+          chunk = chunk.set_environment(env);
+        }
+        Ok(match chunk.into_function() {
+          Ok(f) => (Some(f), None),
+          Err(e) => (None, Some(e.to_string())),
+        })
+      },
+    )
+  }
+
+  /// Run the entry point with a fresh budget.
+  /// A nested call shares the budget of the outer entry point.
+  pub fn run<R>(
+    &self,
+    lua: &Lua,
+    label: &str,
+    f: impl FnOnce() -> mlua::Result<R>,
+  ) -> mlua::Result<R> {
+    {
+      let mut budget = self.budget.lock();
+      if budget.active.is_some() {
+        drop(budget);
+        return f();
+      }
+      budget.active = Some(label.to_string());
+      budget.remaining = budget.profile.instruction_limit;
+      budget.memory_base = lua.used_memory();
+    }
+    let r = f();
+    self.budget.lock().active = None;
+    r
+  }
+}
+
+/// Remove the unsafe libraries and the `jit.*` modules.
+fn strip_libs(t: &Table) -> mlua::Result<()> {
+  let names = t
+    .pairs::<String, Value>()
+    .filter_map(|pair| pair.ok().map(|(name, _)| name))
+    .filter(|name| {
+      UNSAFE_LIBS.contains(&name.as_str())
+        || name.starts_with("jit.")
+    })
+    .collect::<Vec<_>>();
+  for name in names {
+    t.set(name, Value::Nil)?;
+  }
+  Ok(())
+}
+
+/// Run the entry point in the sandbox of the state if it exists.
+pub fn run<R>(
+  lua: &Lua,
+  label: &str,
+  f: impl FnOnce() -> mlua::Result<R>,
+) -> mlua::Result<R> {
+  let sandbox =
+    lua.app_data_ref::<Sandbox>().map(|s| (*s).clone());
+  match sandbox {
+    Some(sandbox) => sandbox.run(lua, label, f),
+    None => f(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn sandboxed(instruction_limit: u64) -> (Lua, Sandbox) {
+    with_profile(SandboxProfile {
+      instruction_limit,
+      ..Default::default()
+    })
+  }
+
+  fn with_profile(
+    profile: SandboxProfile,
+  ) -> (Lua, Sandbox) {
+    let lua =
+      Lua::new_with(std_libs(), mlua::LuaOptions::new())
+        .unwrap();
+    let sandbox = Sandbox::install(&lua, profile).unwrap();
+    (lua, sandbox)
+  }
+
+  #[test]
+  fn busy_loop_exceeds_instruction_limit() {
+    let (lua, sandbox) = sandboxed(1_000_000);
+    let e = sandbox
+      .run(&lua, "busy", || {
+        lua.load("while true do end").exec()
+      })
+      .unwrap_err();
+    let violation = find_violation(&e).unwrap();
+    assert_eq!(violation.script, "busy");
+    assert!(matches!(
+      violation.kind,
+      ViolationKind::InstructionLimit { limit: 1_000_000 }
+    ));
+  }
+
+  #[test]
+  fn unsafe_libs_are_unreachable() {
+    let (lua, sandbox) = sandboxed(1_000_000);
+    for lib in UNSAFE_LIBS {
+      let found = sandbox
+        .run(&lua, "libs", || {
+          lua
+            .load(format!(
+              "return {lib} ~= nil or package.loaded.{lib} ~= nil"
+            ))
+            .eval::<bool>()
+        })
+        .unwrap();
+      assert!(!found, "{lib} is reachable");
+    }
+  }
+
+  #[test]
+  fn forbidden_functions_raise_violation() {
+    let (lua, sandbox) = sandboxed(1_000_000);
+    for name in FORBIDDEN_GLOBALS {
+      let e = sandbox
+        .run(&lua, name, || {
+          lua.load(format!("{name}('x.lua')")).exec()
+        })
+        .unwrap_err();
+      assert!(
+        matches!(
+          find_violation(&e).unwrap().kind,
+          ViolationKind::Forbidden { .. }
+        ),
+        "{name} is callable"
+      );
+    }
+  }
+
+  #[test]
+  fn hook_checks_total_memory_without_allocator_limit() {
+    let limit = 8 << 20;
+    let (lua, sandbox) = with_profile(SandboxProfile {
+      memory_limit: usize::MAX,
+      total_memory_limit: limit,
+      ..Default::default()
+    });
+    // As the LuaJIT builds that cannot limit the allocator
+    lua.set_memory_limit(0).unwrap();
+    sandbox.budget.lock().check_total = true;
+    let e = sandbox
+      .run(&lua, "grow", || {
+        lua
+          .load(
+            "local t = {} \
+             for i = 1, 1e6 do t[i] = string.rep('x', 100) .. i end",
+          )
+          .exec()
+      })
+      .unwrap_err();
+    assert!(matches!(
+      find_violation(&e).unwrap().kind,
+      ViolationKind::MemoryLimit { limit: l, .. } if l == limit
+    ));
+  }
+}
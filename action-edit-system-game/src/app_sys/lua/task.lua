@@ -0,0 +1,21 @@
+-- Yieldable helpers of the task scheduler
+-- The constructors of the wait requests are passed by the scheduler.
+
+local request = ...
+local yield = coroutine.yield
+
+function wait(frames)
+  return yield(request.frames(frames or 1))
+end
+
+function wait_seconds(seconds)
+  return yield(request.seconds(seconds))
+end
+
+function wait_until(predicate)
+  return yield(request.until_true(predicate))
+end
+
+function wait_event(name)
+  return yield(request.event(name))
+end
@@ -0,0 +1,390 @@
+//! Lua task scheduler
+//!
+//! A task is a coroutine that is resumed by the game tick. It is
+//! suspended by the helpers:
+//! - `wait(frames)`: resume after the ticks (default 1)
+//! - `wait_seconds(seconds)`: resume after the game time
+//! - `wait_until(fn)`: resume at the tick when `fn()` is truthy
+//! - `wait_event(name)`: resume at the tick after the event is
+//!   emitted, and return the event arguments
+//!
+//! A bare `coroutine.yield()` waits one tick.
+//!
+//! Globals:
+//! - `task.spawn(fn [, name])`: start the task from the next tick
+//! - `task.cancel(id)`, `task.list()`, `task.emit(name, ...)`
+
+use hashbrown::HashSet;
+use mlua::{
+  Function, Lua, MultiValue, Table, Thread, ThreadStatus,
+  UserData, Value,
+};
+use parking_lot::Mutex;
+use std::sync::Arc;
+
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct TaskID(u32);
+impl TaskID {
+  pub fn raw(self) -> u32 {
+    self.0
+  }
+}
+
+/// Value yielded by the helpers
+#[derive(Clone)]
+enum WaitRequest {
+  Frames(u64),
+  Seconds(f64),
+  Until(Function),
+  Event(String),
+}
+impl UserData for WaitRequest {}
+
+/// Condition to resume the task
+enum Waiting {
+  /// Resume at the tick
+  Frame(u64),
+  /// Resume when the game time reaches
+  Time(f64),
+  Until(Function),
+  Event(String),
+}
+
+struct Task {
+  id: TaskID,
+  name: String,
+  thread: Thread,
+  waiting: Waiting,
+  /// Tick when the task is spawned
+  spawned: u64,
+}
+
+/// Task state for the debug UI
+#[derive(Debug, Clone)]
+pub struct TaskInfo {
+  pub id: TaskID,
+  pub name: String,
+  /// Description of the wait condition
+  pub waiting: String,
+  /// Tick count since the task is spawned
+  pub age: u64,
+}
+
+struct Shared {
+  next_id: u32,
+  frame: u64,
+  time: f64,
+  tasks: Vec<Task>,
+  /// Tasks cancelled during the tick
+  cancelled: HashSet<TaskID>,
+  /// Events emitted since the last tick
+  events: Vec<(String, MultiValue)>,
+}
+impl Shared {
+  fn spawn(
+    &mut self,
+    lua: &Lua,
+    name: Option<String>,
+    f: Function,
+  ) -> mlua::Result<TaskID> {
+    let id = TaskID(self.next_id);
+    self.next_id += 1;
+    self.tasks.push(Task {
+      id,
+      name: name
+        .unwrap_or_else(|| format!("task#{}", id.0)),
+      thread: lua.create_thread(f)?,
+      waiting: Waiting::Frame(self.frame + 1),
+      spawned: self.frame,
+    });
+    Ok(id)
+  }
+
+  fn cancel(&mut self, id: TaskID) -> bool {
+    let len = self.tasks.len();
+    self.tasks.retain(|t| t.id != id);
+    self.cancelled.insert(id);
+    self.tasks.len() != len
+  }
+
+  fn waiting(&self, values: MultiValue) -> Waiting {
+    let request = match values.into_iter().next() {
+      Some(Value::UserData(ud)) => ud
+        .borrow::<WaitRequest>()
+        .ok()
+        .map(|r| (*r).clone()),
+      _ => None,
+    };
+    match request {
+      Some(WaitRequest::Frames(n)) => {
+        Waiting::Frame(self.frame + n)
+      }
+      Some(WaitRequest::Seconds(s)) => {
+        Waiting::Time(self.time + s)
+      }
+      Some(WaitRequest::Until(f)) => Waiting::Until(f),
+      Some(WaitRequest::Event(name)) => {
+        Waiting::Event(name)
+      }
+      None => Waiting::Frame(self.frame + 1),
+    }
+  }
+
+  fn info(&self, task: &Task) -> TaskInfo {
+    TaskInfo {
+      id: task.id,
+      name: task.name.clone(),
+      waiting: match &task.waiting {
+        Waiting::Frame(f) => format!(
+          "{} frame(s)",
+          f.saturating_sub(self.frame)
+        ),
+        Waiting::Time(t) => {
+          format!("{:.2} sec", (t - self.time).max(0.))
+        }
+        Waiting::Until(_) => "until".to_string(),
+        Waiting::Event(name) => format!("event \"{name}\""),
+      },
+      age: self.frame - task.spawned,
+    }
+  }
+}
+
+/// How the task is resumed at this tick
+enum Resume {
+  Now(MultiValue),
+  Check(Function),
+}
+
+pub struct TaskScheduler {
+  shared: Arc<Mutex<Shared>>,
+}
+impl TaskScheduler {
+  /// Create the scheduler, and register the helpers and `task` to
+  /// the globals.
+  pub fn new(lua: &Lua) -> mlua::Result<Self> {
+    let shared = Arc::new(Mutex::new(Shared {
+      next_id: 0,
+      frame: 0,
+      time: 0.,
+      tasks: Vec::new(),
+      cancelled: HashSet::new(),
+      events: Vec::new(),
+    }));
+    lua
+      .load(include_str!("task.lua"))
+      .set_name("=task.lua")
+      .call::<()>(request_table(lua)?)?;
+    lua.globals().set("task", task_table(lua, &shared)?)?;
+    Ok(Self { shared })
+  }
+
+  /// Cancel the task. Returns false if it is not alive.
+  pub fn cancel(&self, id: TaskID) -> bool {
+    self.shared.lock().cancel(id)
+  }
+
+  /// Drop every task and the events not delivered yet.
+  pub fn clear(&self) {
+    let mut shared = self.shared.lock();
+    shared.tasks.clear();
+    shared.cancelled.clear();
+    shared.events.clear();
+  }
+
+  /// Emit the event. It is delivered at the next tick.
+  pub fn emit(&self, name: &str, args: MultiValue) {
+    self
+      .shared
+      .lock()
+      .events
+      .push((name.to_string(), args));
+  }
+
+  pub fn frame(&self) -> u64 {
+    self.shared.lock().frame
+  }
+
+  /// Game time in second
+  pub fn time(&self) -> f64 {
+    self.shared.lock().time
+  }
+
+  pub fn tasks(&self) -> Vec<TaskInfo> {
+    let shared = self.shared.lock();
+    shared.tasks.iter().map(|t| shared.info(t)).collect()
+  }
+
+  /// Advance the tick, and resume the ready tasks.
+  /// Returns the errors of the failed tasks.
+  pub fn tick(
+    &self,
+    lua: &Lua,
+    dt: f64,
+  ) -> Vec<mlua::Error> {
+    let ready = {
+      let mut shared = self.shared.lock();
+      shared.frame += 1;
+      shared.time += dt;
+      shared.cancelled.clear();
+      let events = std::mem::take(&mut shared.events);
+      shared
+        .tasks
+        .iter()
+        .filter_map(|t| {
+          let resume = match &t.waiting {
+            Waiting::Frame(f) => (*f <= shared.frame)
+              .then(MultiValue::new)
+              .map(Resume::Now),
+            Waiting::Time(time) => (*time <= shared.time)
+              .then(MultiValue::new)
+              .map(Resume::Now),
+            Waiting::Until(f) => {
+              Some(Resume::Check(f.clone()))
+            }
+            Waiting::Event(name) => events
+              .iter()
+              .find(|(n, _)| n == name)
+              .map(|(_, args)| Resume::Now(args.clone())),
+          }?;
+          Some((
+            t.id,
+            t.name.clone(),
+            t.thread.clone(),
+            resume,
+          ))
+        })
+        .collect::<Vec<_>>()
+    };
+    let mut errors = Vec::new();
+    for (id, name, thread, resume) in ready {
+      if self.shared.lock().cancelled.contains(&id) {
+        continue;
+      }
+      let result = super::sandbox::run(lua, &name, || {
+        let args = match resume {
+          Resume::Now(args) => args,
+          Resume::Check(f) => {
+            if !f.call::<bool>(())? {
+              return Ok(None);
+            }
+            MultiValue::new()
+          }
+        };
+        thread.resume::<MultiValue>(args).map(Some)
+      });
+      let mut shared = self.shared.lock();
+      let waiting = match result {
+        Ok(None) => continue,
+        Ok(Some(values)) => (thread.status()
+          == ThreadStatus::Resumable)
+          .then(|| shared.waiting(values)),
+        Err(e) => {
+          log::warn!("Lua task \"{name}\" failed: {e}");
+          errors.push(e);
+          None
+        }
+      };
+      match waiting {
+        Some(waiting) => {
+          if let Some(t) =
+            shared.tasks.iter_mut().find(|t| t.id == id)
+          {
+            t.waiting = waiting;
+          }
+        }
+        None => shared.tasks.retain(|t| t.id != id),
+      }
+    }
+    errors
+  }
+}
+
+/// Constructors of the wait requests passed to `task.lua`
+fn request_table(lua: &Lua) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  t.set(
+    "frames",
+    lua.create_function(|_, frames: u64| {
+      Ok(WaitRequest::Frames(frames.max(1)))
+    })?,
+  )?;
+  t.set(
+    "seconds",
+    lua.create_function(|_, seconds: f64| {
+      if !seconds.is_finite() || seconds < 0. {
+        return Err(mlua::Error::runtime(format!(
+          "invalid wait time {seconds}"
+        )));
+      }
+      Ok(WaitRequest::Seconds(seconds))
+    })?,
+  )?;
+  t.set(
+    "until_true",
+    lua.create_function(|_, f: Function| {
+      Ok(WaitRequest::Until(f))
+    })?,
+  )?;
+  t.set(
+    "event",
+    lua.create_function(|_, name: String| {
+      Ok(WaitRequest::Event(name))
+    })?,
+  )?;
+  Ok(t)
+}
+
+fn task_table(
+  lua: &Lua,
+  shared: &Arc<Mutex<Shared>>,
+) -> mlua::Result<Table> {
+  let t = lua.create_table()?;
+  let s = shared.clone();
+  t.set(
+    "spawn",
+    lua.create_function(
+      move |lua, (f, name): (Function, Option<String>)| {
+        Ok(s.lock().spawn(lua, name, f)?.0)
+      },
+    )?,
+  )?;
+  let s = shared.clone();
+  t.set(
+    "cancel",
+    lua.create_function(move |_, id: u32| {
+      Ok(s.lock().cancel(TaskID(id)))
+    })?,
+  )?;
+  let s = shared.clone();
+  t.set(
+    "list",
+    lua.create_function(move |lua, ()| {
+      let shared = s.lock();
+      let list = lua.create_table()?;
+      for task in shared.tasks.iter() {
+        let info = shared.info(task);
+        let item = lua.create_table()?;
+        item.set("id", info.id.0)?;
+        item.set("name", info.name)?;
+        item.set("waiting", info.waiting)?;
+        item.set("age", info.age)?;
+        list.push(item)?;
+      }
+      Ok(list)
+    })?,
+  )?;
+  let s = shared.clone();
+  t.set(
+    "emit",
+    lua.create_function(
+      move |_, (name, args): (String, MultiValue)| {
+        s.lock().events.push((name, args));
+        Ok(())
+      },
+    )?,
+  )?;
+  Ok(t)
+}
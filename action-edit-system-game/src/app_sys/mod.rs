@@ -2,14 +2,30 @@
 //! アプリケーションのシステムとの結合部分の実装
 
 use crate::StdError;
-use egui::RichText;
+use gfx::rdr_2d::{camera, square, tile};
+use parking_lot::RwLock;
+use script::ScriptHost;
 use std::{
   io::Read,
+  path::{Path, PathBuf},
   sync::{atomic::AtomicBool, Arc},
 };
 use winit::event::WindowEvent;
 
+pub mod action;
+pub mod combat;
+pub mod ecs;
+pub mod game_loop;
 pub mod gfx;
+pub mod history;
+pub mod input;
+pub mod level;
+pub mod lua;
+pub mod physics;
+pub mod replay;
+pub mod scene;
+pub mod script;
+pub mod wasm;
 pub use gfx::render_chain::{RenderChainCommand, Renderer};
 
 pub struct TestRender;
@@ -55,9 +71,18 @@ pub struct AppGuiService {
   window: Arc<winit::window::Window>,
   gfx: Arc<gfx::AppGfxService>,
   egui: gfx::rdr_egui::EguiRenderer,
+  textures: Arc<RwLock<gfx::util::TextureStorage>>,
+  camera: camera::Camera2D,
+  camera_object: Arc<RwLock<camera::Camera2DWGPUObject>>,
+  square: square::SquareRenderer,
+  tile: tile::TileRenderer,
+  tile_chunks: Vec<tile::TileChunk>,
 }
 impl AppGuiService {
-  pub fn new(window: winit::window::Window) -> Result<Self, StdError> {
+  pub fn new(
+    window: winit::window::Window,
+    scene_ctx: &lua::SceneContext,
+  ) -> Result<Self, StdError> {
     let window = Arc::new(window);
     let gfx =
       Arc::new(pollster::block_on(gfx::AppGfxService::new(&window))?);
@@ -71,38 +96,390 @@ impl AppGuiService {
       rdr.read_to_end(&mut buffer)?;
       buffer
     });
+    let textures = scene_ctx.textures.clone();
+    let camera = scene_ctx.scene.read().active_camera().clone();
+    let camera_object =
+      Arc::new(RwLock::new(camera::Camera2DWGPUObject::new(
+        gfx.device(),
+        gfx.upload_belt().clone(),
+      )));
+    let square = square::SquareRenderer::new(
+      gfx.device(),
+      gfx.surface_format(),
+      camera_object.clone(),
+      gfx.upload_belt().clone(),
+      textures.clone(),
+    );
+    let tile = tile::TileRenderer::new(
+      gfx.device(),
+      gfx.surface_format(),
+      camera_object.clone(),
+      gfx.upload_belt().clone(),
+      textures.clone(),
+    );
 
-    Ok(Self { window, gfx, egui })
+    Ok(Self {
+      window,
+      gfx,
+      egui,
+      textures,
+      camera,
+      camera_object,
+      square,
+      tile,
+      tile_chunks: Vec::new(),
+    })
+  }
+
+  /// Reflect the scene to the renderers. `alpha` is the
+  /// interpolation alpha of the game loop.
+  fn sync_scene(
+    &mut self,
+    scene: &mut scene::Scene2D,
+    alpha: f32,
+  ) {
+    let uploaded =
+      self.textures.write().prepare(self.gfx.device(), self.gfx.queue());
+    self.egui.sync_textures(
+      self.gfx.device(),
+      &self.textures.read(),
+      &uploaded,
+    );
+    self.camera = scene.active_camera().clone();
+    let wsize = self.window.inner_size();
+    self
+      .camera
+      .set_view_size(wsize.width as f32, wsize.height as f32);
+    if let Some(instances) = scene.take_sprite_instances(alpha) {
+      self.square.set_instances(instances);
+    }
+    if scene.take_tilemaps_dirty() {
+      self.tile_chunks.clear();
+      scene.tilemaps_mut().for_each(|(_, m)| m.touch());
+    }
+    let textures = self.textures.read();
+    for (i, (_, map)) in scene.tilemaps_mut().enumerate() {
+      if self.tile_chunks.len() <= i {
+        self.tile_chunks.push(tile::TileChunk::new(self.gfx.device()));
+      }
+      if map.is_dirty() {
+        let chunk = &mut self.tile_chunks[i];
+        chunk.texture = map.texture;
+        chunk.set_instances(map.take_instances(&textures));
+      }
+    }
   }
 }
 
 pub struct AppFrontend {
   gui: Option<AppGuiService>,
-  lua: mlua::Lua,
-  lua_script_buffer: String,
-  catch_lua_error: Option<mlua::Error>,
+  scene_ctx: lua::SceneContext,
+  lua: lua::host::LuaHost,
+  game_loop: game_loop::GameLoop,
+  console: lua::console::LuaConsole,
+  lua_errors: lua::error::ErrorPanel,
+  script_editor: lua::editor::ScriptEditor,
+  world: ecs::World,
+  /// World changes queued in the step, applied at the end of it
+  commands: ecs::command::Commands,
+  combat: combat::Combat,
+  action_editor: action::editor::ActionEditor,
+  machine_debugger: action::machine_debugger::MachineDebugger,
+  level_editor: level::editor::LevelEditor,
+  input: Arc<RwLock<input::InputState>>,
+  gamepads: input::gamepad::GamepadPoll,
+  /// Input read by the scripts and the plugins in the step
+  script_input: Arc<RwLock<input::RawInput>>,
+  input_map: input::map::InputMap,
+  input_buffers: Arc<RwLock<input::buffer::PlayerInputs>>,
+  plugins: wasm::PluginHost,
   program_terminate: Arc<AtomicBool>,
+  /// Seed of the random numbers of the session
+  seed: u64,
+  /// Recording of the session and the path to save it on exit
+  recorder: Option<(replay::Recorder, PathBuf)>,
+  /// Recorded session taken as the input instead of the window
+  replay: Option<replay::Replay>,
 }
 impl AppFrontend {
-  pub fn new() -> Result<Self, StdError> {
+  /// Create the session. The scripts take the seed for the random
+  /// numbers.
+  pub fn new(seed: u64) -> Result<Self, StdError> {
     let program_terminate = Arc::new(AtomicBool::new(false));
+    let scene_ctx = lua::SceneContext {
+      scene: Arc::new(RwLock::new(scene::Scene2D::new(
+        camera::Camera2D::with_view_size(1280., 720.),
+      ))),
+      textures: Arc::new(RwLock::new(gfx::util::TextureStorage::new())),
+    };
+    scene_ctx.textures.write().load("ferris", "./ferris.png")?;
+    let action_editor =
+      action::editor::ActionEditor::new(&mut scene_ctx.textures.write());
+    let input = Arc::new(RwLock::new(input::InputState::new()));
+    let script_input = Arc::new(RwLock::new(input::RawInput::default()));
+    let input_buffers = Arc::new(RwLock::new(
+      input::buffer::PlayerInputs::new(
+        1,
+        input::buffer::BufferConfig::default(),
+      ),
+    ));
+    let mut lua = lua::host::LuaHost::new(
+      &scene_ctx,
+      script_input.clone(),
+      lua::sandbox::SandboxProfile::default(),
+    )?;
+    lua.register_input_buffers(input_buffers.clone())?;
+    lua.seed_random(seed)?;
+    let term_flag = program_terminate.clone();
+    let f = lua.lua().create_function(move |_lua, _: ()| {
+      term_flag.store(true, std::sync::atomic::Ordering::Relaxed);
+      Ok(())
+    })?;
+    lua.lua().globals().set("exit", f)?;
+    let console = lua::console::LuaConsole::new(
+      lua.lua(),
+      lua::console::HISTORY_PATH,
+    )?;
+    let mut lua_errors =
+      lua::error::ErrorPanel::new(lua::loader::SCRIPT_ROOT);
+    for e in lua.load_dir(Path::new(lua::loader::SCRIPT_ROOT)) {
+      log::warn!("Lua script load error: {e}");
+      lua_errors.push(&e);
+    }
+    let mut plugins = wasm::PluginHost::new(
+      scene_ctx.clone(),
+      script_input.clone(),
+      wasm::PluginLimits::default(),
+    )?;
+    for e in plugins.load_dir(Path::new(wasm::PLUGIN_ROOT)) {
+      log::warn!("Wasm plugin load error: {e}");
+      lua_errors.push_plugin(&e);
+    }
     Ok(Self {
       gui: None,
-      lua: {
-        let lua = mlua::Lua::new();
-        let term_flag = program_terminate.clone();
-        let f = lua.create_function(move |_lua, _: ()| {
-          term_flag.store(true, std::sync::atomic::Ordering::Relaxed);
-          Ok(())
-        })?;
-        lua.globals().set("exit", f)?;
-        lua
-      },
-      lua_script_buffer: String::new(),
-      catch_lua_error: None,
+      lua,
+      game_loop: game_loop::GameLoop::default(),
+      scene_ctx,
+      console,
+      lua_errors,
+      script_editor: lua::editor::ScriptEditor::new(
+        lua::loader::SCRIPT_ROOT,
+      ),
+      world: ecs::World::new(),
+      commands: ecs::command::Commands::new(),
+      combat: combat::Combat::new(),
+      action_editor,
+      machine_debugger: action::machine_debugger::MachineDebugger::new(),
+      level_editor: level::editor::LevelEditor::new(),
+      input,
+      gamepads: input::gamepad::GamepadPoll::new(),
+      script_input,
+      input_map: input::map::InputMap::new(
+        input::map::InputBindings::load_or_default(
+          input::map::BINDINGS_PATH,
+        ),
+      ),
+      input_buffers,
+      plugins,
       program_terminate,
+      seed,
+      recorder: None,
+      replay: None,
     })
   }
+
+  /// Record the session from the current level and world, which is
+  /// saved to the path on exit.
+  pub fn record(
+    &mut self,
+    path: impl AsRef<Path>,
+  ) -> Result<(), StdError> {
+    let start = self.level_editor.snapshot(
+      &self.world,
+      &mut self.scene_ctx.scene.write(),
+      &self.scene_ctx.textures.read(),
+    )?;
+    let recorder = replay::Recorder::new(
+      self.seed,
+      self.game_loop.tick_rate(),
+      start,
+    );
+    self.recorder = Some((recorder, path.as_ref().to_path_buf()));
+    Ok(())
+  }
+
+  /// Restore the level and the world of the recording, and take the
+  /// input from it until it finishes. The session must be created
+  /// with the seed of the recording.
+  pub fn replay(
+    &mut self,
+    replay: replay::Replay,
+  ) -> Result<(), StdError> {
+    self.level_editor.restore(
+      &replay.recording().start,
+      &mut self.world,
+      &mut self.scene_ctx.scene.write(),
+      &self.scene_ctx.textures.read(),
+    )?;
+    self.game_loop = game_loop::GameLoop::new(
+      replay.recording().tick_rate,
+      game_loop::DEFAULT_MAX_STEPS,
+    );
+    self.replay = Some(replay);
+    Ok(())
+  }
+
+  /// Switch the mode of the level editor. The scripts and the plugins
+  /// are shut down on Stop, and start over in fresh states on Play, so
+  /// every play from the edit mode starts from the same state.
+  fn set_level_mode(&mut self, mode: level::editor::Mode) {
+    if mode == level::editor::Mode::Edit {
+      for e in self.lua.shutdown() {
+        self.lua_errors.push(&e);
+      }
+      for e in self.plugins.shutdown() {
+        self.lua_errors.push_plugin(&e);
+      }
+    }
+    self.level_editor.set_mode(
+      mode,
+      &mut self.world,
+      &mut self.scene_ctx.scene.write(),
+      &self.scene_ctx.textures.read(),
+    );
+    if mode == level::editor::Mode::Play && !self.level_editor.is_editing() {
+      for e in self.lua.restart() {
+        self.lua_errors.push(&e);
+      }
+      for e in self.plugins.restart() {
+        self.lua_errors.push_plugin(&e);
+      }
+    }
+  }
+
+  /// Advance the simulation by a step.
+  fn step(&mut self, dt: f32) {
+    if let Some(mode) = self.level_editor.take_request() {
+      self.set_level_mode(mode);
+    }
+    self.scene_ctx.scene.write().save_previous();
+    match self.replay.as_mut() {
+      Some(replay) => {
+        replay.step(&mut self.input_map, &mut self.script_input.write());
+      }
+      None => {
+        let input = self.input.read();
+        self.input_map.update(&input);
+        *self.script_input.write() = input.raw();
+      }
+    }
+    if let Some((recorder, _)) = self.recorder.as_mut() {
+      recorder.record(&self.input_map, &self.script_input.read());
+    }
+    self.input_buffers.write().record(&self.input_map);
+    // The game stops while editing the level.
+    if !self.level_editor.is_editing() {
+      for e in self.lua.tick(dt) {
+        self.lua_errors.push(&e);
+      }
+      for e in self.plugins.tick(dt) {
+        self.lua_errors.push_plugin(&e);
+      }
+      action::machine::step_fighters(
+        &self.world,
+        self.machine_debugger.machines(),
+        self.action_editor.library(),
+        &mut self.input_buffers.write(),
+      );
+      self
+        .combat
+        .step(&mut self.world, self.action_editor.library(), dt);
+      self
+        .level_editor
+        .step(&mut self.world, &mut self.scene_ctx.scene.write(), dt);
+      // Events emitted while delivering are delivered at the next tick.
+      let mut events = self.lua.take_events();
+      events.extend(self.plugins.take_events());
+      for e in self.combat.take_events() {
+        if let combat::CombatEvent::Hit { attacker, .. } = e {
+          self.commands.add(move |w| {
+            if let Some(m) = w.get_mut::<action::machine::StateMachine>(attacker) {
+              m.notify_hit();
+            }
+          });
+        }
+        events.push(e.to_script_event());
+      }
+      for event in events.iter() {
+        for e in self.lua.dispatch(event) {
+          self.lua_errors.push(&e);
+        }
+        for e in self.plugins.dispatch(event) {
+          self.lua_errors.push_plugin(&e);
+        }
+      }
+    }
+    self.action_editor.preview_step(
+      &mut self.scene_ctx.scene.write(),
+      &self.scene_ctx.textures.read(),
+    );
+    self.commands.apply(&mut self.world);
+    self.input.write().end_frame();
+  }
+
+  /// Check the state hash when the replay is finished, and go back to
+  /// the live input. Returns the hash if it matches.
+  fn finish_replay(&mut self) -> Option<Result<u64, StdError>> {
+    if !self.replay.as_ref()?.is_finished() {
+      return None;
+    }
+    let replay = self.replay.take()?;
+    let recording = replay.recording();
+    Some(self.state_hash().and_then(|hash| {
+      if hash == recording.hash {
+        Ok(hash)
+      } else {
+        Err(
+          format!(
+            "state hash {hash:016x} differs from the recorded {:016x} \
+             after {} ticks",
+            recording.hash,
+            recording.ticks()
+          )
+          .into(),
+        )
+      }
+    }))
+  }
+
+  fn state_hash(&self) -> Result<u64, StdError> {
+    replay::state_hash(
+      &self.world,
+      &self.scene_ctx.scene.read(),
+      &self.lua,
+    )
+  }
+
+  /// Save the recording, and shut the scripts and the plugins down.
+  fn shutdown(&mut self) {
+    if let Some((recorder, path)) = self.recorder.take() {
+      let result = self
+        .state_hash()
+        .and_then(|hash| recorder.finish(hash).save(&path));
+      match result {
+        Ok(()) => log::info!("Saved the recording to {}.", path.display()),
+        Err(e) => {
+          log::error!("Recording save error: {}: {e}", path.display())
+        }
+      }
+    }
+    for e in self.lua.shutdown() {
+      self.lua_errors.push(&e);
+    }
+    for e in self.plugins.shutdown() {
+      self.lua_errors.push_plugin(&e);
+    }
+  }
 }
 impl winit::application::ApplicationHandler for AppFrontend {
   fn resumed(&mut self, event_loop: &winit::event_loop::ActiveEventLoop) {
@@ -125,7 +502,7 @@ impl winit::application::ApplicationHandler for AppFrontend {
             );
             window.set_outer_position(w_pos);
           }
-          let gui = match AppGuiService::new(window) {
+          let gui = match AppGuiService::new(window, &self.scene_ctx) {
             Ok(gui) => gui,
             Err(e) => {
               log::error!("Gui initialize process failure");
@@ -156,51 +533,140 @@ impl winit::application::ApplicationHandler for AppFrontend {
     if let Some(gui) =
       self.gui.as_mut().filter(|g| g.window.id() == window_id)
     {
-      let _ = gui.egui.event_input(&gui.window, &event);
+      if !gui.egui.event_input(&gui.window, &event).consumed {
+        self.input.write().handle_event(&event);
+      }
       match event {
         WindowEvent::CloseRequested => event_loop.exit(),
         WindowEvent::RedrawRequested => {
+          self.gamepads.poll(&mut self.input.write());
+          let frame = self.game_loop.advance();
+          let dt = self.game_loop.dt();
+          for _ in 0..frame.steps {
+            self.step(dt);
+          }
+          match self.finish_replay() {
+            Some(Ok(hash)) => {
+              log::info!("Replay matched the state hash {hash:016x}.")
+            }
+            Some(Err(e)) => log::error!("Replay error: {e}"),
+            None => {}
+          }
+          let Some(gui) = self.gui.as_mut() else {
+            return;
+          };
+          gui.sync_scene(
+            &mut self.scene_ctx.scene.write(),
+            frame.alpha,
+          );
+          let egui_textures = gui.egui.native_textures().clone();
           match gui.gfx.rendering() {
-            Ok(rc) => match rc
-              .rendering(&mut TestRender, ())
-              .rendering(
+            Ok(rc) => match {
+              let rc = rc.rendering(&mut TestRender, ());
+              let rc = rc.rendering(
+                &mut *gui.camera_object.write(),
+                &gui.camera,
+              );
+              rc.rendering(&mut gui.tile, &mut gui.tile_chunks[..])
+                .rendering(&mut gui.square, ())
+                .rendering(
                 &mut gui.egui,
                 (&gui.window, |c| {
-                  egui::Window::new("egui window")
+                  egui::Window::new("Lua console")
                     .resizable(true)
                     .vscroll(true)
                     .hscroll(true)
                     .default_open(false)
                     .show(c, |ui| {
                       ui.vertical(|ui| {
-                        ui.label("Input lua script!");
-                        ui.text_edit_multiline(
-                          &mut self.lua_script_buffer,
-                        );
-                        if ui.button("Execute").clicked() {
-                          match self
-                            .lua
-                            .load(&self.lua_script_buffer)
-                            .exec()
-                          {
-                            Ok(_) => self.catch_lua_error = None,
-                            Err(e) => {
-                              log::warn!("Lua script execute error: {e}");
-                              self.catch_lua_error = Some(e);
-                            }
-                          }
+                        if let Some(Err(e)) = self.console.ui(ui, self.lua.lua()) {
+                          self.lua_errors.push(&e);
                         }
-                        if let Some(e) = self.catch_lua_error.as_ref() {
-                          ui.label(
-                            RichText::new(format!("{e}"))
-                              .color(egui::Rgba::from_rgb(255., 0., 0.)),
-                          );
+                        if let Some((path, line)) = self.lua_errors.ui(ui) {
+                          self.script_editor.open(&path, Some(line));
+                        }
+                        if ui.button("Script editor").clicked() {
+                          self.script_editor.visible = true;
                         }
+                        let tasks = self.lua.tasks().tasks();
+                        egui::CollapsingHeader::new(format!(
+                          "Tasks ({})",
+                          tasks.len()
+                        ))
+                        .show(ui, |ui| {
+                          ui.label(format!(
+                            "Tick {} ({:.2} sec)",
+                            self.lua.tasks().frame(),
+                            self.lua.tasks().time()
+                          ));
+                          egui::Grid::new("lua tasks").striped(true).show(
+                            ui,
+                            |ui| {
+                              for task in tasks {
+                                ui.label(task.id.raw().to_string());
+                                ui.label(&task.name);
+                                ui.label(&task.waiting);
+                                ui.label(format!("{} frames", task.age));
+                                if ui.button("Cancel").clicked() {
+                                  self.lua.tasks().cancel(task.id);
+                                }
+                                ui.end_row();
+                              }
+                            },
+                          );
+                        });
                       })
                     });
+                  self.script_editor.show(c);
+                  self.action_editor.show(
+                    c,
+                    &self.scene_ctx.textures.read(),
+                    &egui_textures,
+                  );
+                  self.machine_debugger.show(
+                    c,
+                    &self.world,
+                    &self.input_buffers.read(),
+                    self.action_editor.library(),
+                  );
+                  egui::Window::new("Plugins")
+                    .default_open(false)
+                    .show(c, |ui| self.plugins.ui(ui));
+                  egui::Window::new("Game loop")
+                    .default_open(false)
+                    .show(c, |ui| {
+                      self.game_loop.ui(ui);
+                      ui.label(format!("seed {:016x}", self.seed));
+                      if let Some((recorder, path)) = &self.recorder {
+                        ui.label(format!(
+                          "recording {} ticks to {}",
+                          recorder.ticks(),
+                          path.display()
+                        ));
+                      }
+                      if let Some(replay) = &self.replay {
+                        ui.label(format!(
+                          "replaying {} / {} ticks",
+                          replay.tick(),
+                          replay.recording().ticks()
+                        ));
+                      }
+                    });
+                  egui::Window::new("Input")
+                    .default_open(false)
+                    .show(c, |ui| {
+                      self.input_map.ui(ui, &self.input.read())
+                    });
+                  self.level_editor.show(
+                    c,
+                    &mut self.scene_ctx.scene.write(),
+                    &self.scene_ctx.textures.read(),
+                    &egui_textures,
+                  );
                 }),
               )
-              .finish()
+            }
+            .finish()
             {
               Ok(_) => {}
               Err(e) => {
@@ -229,4 +695,8 @@ impl winit::application::ApplicationHandler for AppFrontend {
       }
     }
   }
+
+  fn exiting(&mut self, _event_loop: &winit::event_loop::ActiveEventLoop) {
+    self.shutdown();
+  }
 }
@@ -0,0 +1,142 @@
+//! Bodies of the entities
+//!
+//! A `Body` falls by the gravity and stands on the colliders of the
+//! level, moved by a `CharacterController`. The origin of the
+//! entity is the bottom center of the box.
+//!
+//! The controller is made from the origin at the first step, and
+//! again when the origin is moved from outside (the editor, the
+//! scripts, a restored snapshot).
+
+use super::{
+  controller::{CharacterController, ControllerConfig},
+  CollisionWorld,
+};
+use nalgebra::{Point2, Vector2};
+use serde::{Deserialize, Serialize};
+
+/// Default gravity (per second squared)
+pub const GRAVITY: f32 = 980.;
+
+fn default_gravity() -> f32 {
+  GRAVITY
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Body {
+  /// Half size of the box
+  pub half_size: [f32; 2],
+  /// Velocity (per second)
+  #[serde(default)]
+  pub velocity: [f32; 2],
+  /// Acceleration down (per second squared)
+  #[serde(default = "default_gravity")]
+  pub gravity: f32,
+  /// Movement added to the next step
+  #[serde(skip)]
+  motion: [f32; 2],
+  /// Controller and the origin written by the last step
+  #[serde(skip)]
+  state: Option<(CharacterController, [f32; 2])>,
+}
+impl Body {
+  /// Move by the distance in the next step, as the root motion.
+  pub fn add_motion(&mut self, d: [f32; 2]) {
+    self.motion[0] += d[0];
+    self.motion[1] += d[1];
+  }
+
+  /// Standing on a collider after the last step
+  pub fn is_grounded(&self) -> bool {
+    self
+      .state
+      .as_ref()
+      .is_some_and(|(c, _)| c.is_grounded())
+  }
+
+  /// Move the box from the origin by the velocity, and return the
+  /// new origin.
+  pub fn step(
+    &mut self,
+    world: &CollisionWorld,
+    origin: [f32; 2],
+    dt: f32,
+  ) -> [f32; 2] {
+    let half =
+      Vector2::new(self.half_size[0], self.half_size[1]);
+    let mut controller = match self.state.take() {
+      Some((c, last)) if last == origin => c,
+      _ => CharacterController::new(
+        Point2::new(origin[0], origin[1] + half.y),
+        half,
+        ControllerConfig::default(),
+      ),
+    };
+    self.velocity[1] -= self.gravity * dt;
+    let velocity =
+      Vector2::new(self.velocity[0], self.velocity[1]);
+    let motion = std::mem::take(&mut self.motion);
+    let delta =
+      velocity * dt + Vector2::new(motion[0], motion[1]);
+    let result =
+      controller.move_and_slide(world, delta, dt);
+    if result.hit_wall {
+      self.velocity[0] = 0.;
+    }
+    if (result.grounded && self.velocity[1] < 0.)
+      || (result.hit_ceiling && 0. < self.velocity[1])
+    {
+      self.velocity[1] = 0.;
+    }
+    let b = controller.bounds;
+    let origin = [(b.min.x + b.max.x) * 0.5, b.min.y];
+    self.state = Some((controller, origin));
+    origin
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_sys::physics::{
+    shape::Aabb, Collider, Shape,
+  };
+
+  fn floor() -> CollisionWorld {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::solid(Shape::Aabb(Aabb::new(
+      Point2::new(-100., -50.),
+      Point2::new(100., 0.),
+    ))));
+    world
+  }
+
+  fn body() -> Body {
+    toml::from_str("half_size = [8, 16]").unwrap()
+  }
+
+  #[test]
+  fn falls_and_stands_on_the_floor() {
+    let world = floor();
+    let mut body = body();
+    let mut origin = [0., 100.];
+    for _ in 0..120 {
+      origin = body.step(&world, origin, 1. / 60.);
+    }
+    assert_eq!(body.velocity, [0., 0.]);
+    assert!(origin[1].abs() < 0.1);
+  }
+
+  #[test]
+  fn restarts_from_the_moved_origin() {
+    let world = floor();
+    let mut body = body();
+    let origin = body.step(&world, [0., 0.], 1. / 60.);
+    assert_eq!(body.velocity, [0., 0.]);
+    let moved = [origin[0] + 50., origin[1] + 50.];
+    let origin = body.step(&world, moved, 1. / 60.);
+    assert!(body.velocity[1] < 0.);
+    assert_eq!(origin[0], moved[0]);
+    assert!(origin[1] < moved[1]);
+  }
+}
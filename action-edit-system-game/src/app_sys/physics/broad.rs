@@ -0,0 +1,52 @@
+//! Broad phase
+//!
+//! The colliders are registered to the cells of a uniform grid that
+//! their bounds cover. A query collects the colliders of the cells
+//! that the area covers, so only the near colliders are tested.
+
+use super::shape::Aabb;
+use hashbrown::HashMap;
+
+#[derive(Debug)]
+pub struct UniformGrid {
+  cell_size: f32,
+  cells: HashMap<(i32, i32), Vec<u32>>,
+}
+impl UniformGrid {
+  pub fn new(cell_size: f32) -> Self {
+    Self {
+      cell_size: cell_size.max(f32::EPSILON),
+      cells: HashMap::new(),
+    }
+  }
+
+  fn cell_range(
+    &self,
+    aabb: &Aabb,
+  ) -> impl Iterator<Item = (i32, i32)> {
+    let cell = |v: f32| (v / self.cell_size).floor() as i32;
+    let (x0, x1) = (cell(aabb.min.x), cell(aabb.max.x));
+    let (y0, y1) = (cell(aabb.min.y), cell(aabb.max.y));
+    (y0..=y1)
+      .flat_map(move |y| (x0..=x1).map(move |x| (x, y)))
+  }
+
+  pub fn insert(&mut self, id: u32, aabb: &Aabb) {
+    for key in self.cell_range(aabb).collect::<Vec<_>>() {
+      self.cells.entry(key).or_default().push(id);
+    }
+  }
+
+  /// Ids registered to the cells the area covers (sorted, without
+  /// duplicates)
+  pub fn query(&self, aabb: &Aabb, out: &mut Vec<u32>) {
+    out.clear();
+    for key in self.cell_range(aabb) {
+      if let Some(ids) = self.cells.get(&key) {
+        out.extend_from_slice(ids);
+      }
+    }
+    out.sort_unstable();
+    out.dedup();
+  }
+}
@@ -0,0 +1,523 @@
+//! Kinematic character controller
+//!
+//! The box moves by the requested delta, x first and y next, in the
+//! sub steps short enough not to pass through the thin colliders.
+//!
+//! - AABB: blocks from every side.
+//! - One-way: blocks only a box that falls from above it.
+//! - Slope: pushes the box up onto the surface, so walking into it
+//!   climbs it.
+//!
+//! A grounded box is snapped down to the ground within
+//! `snap_distance`, so it keeps on the ground going down a slope.
+//! The time since the ground was left is the coyote time, in which
+//! `can_jump` is still true.
+//!
+//! `Body` does not jump nor drop through yet, so `jump`,
+//! `can_jump` and `drop_through` are only built for the tests.
+
+use super::{
+  shape::{Aabb, Shape},
+  ColliderKind, CollisionWorld,
+};
+use nalgebra::{Point2, Vector2};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ControllerConfig {
+  /// Gap kept between the box and the colliders
+  pub skin: f32,
+  /// Longest move of a sub step. None for the half of the box.
+  pub max_step: Option<f32>,
+  /// Distance to snap down to the ground while grounded
+  pub snap_distance: f32,
+  /// Time the jump is allowed after leaving the ground (second)
+  pub coyote_time: f32,
+}
+impl Default for ControllerConfig {
+  fn default() -> Self {
+    Self {
+      skin: 0.01,
+      max_step: None,
+      snap_distance: 4.,
+      coyote_time: 0.1,
+    }
+  }
+}
+
+/// What happened in a move
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MoveResult {
+  /// Actual delta
+  pub moved: Vector2<f32>,
+  pub grounded: bool,
+  pub hit_wall: bool,
+  pub hit_ceiling: bool,
+  /// Touched the ground in this move
+  pub landed: bool,
+  /// Left the ground in this move (the coyote time starts)
+  pub left_ground: bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct CharacterController {
+  pub bounds: Aabb,
+  pub config: ControllerConfig,
+  grounded: bool,
+  /// Time since grounded (second)
+  air_time: f32,
+  /// Jump was used since grounded
+  jumped: bool,
+  /// Fall through the one-way platforms in the next move
+  drop_through: bool,
+}
+impl CharacterController {
+  pub fn new(
+    center: Point2<f32>,
+    half_size: Vector2<f32>,
+    config: ControllerConfig,
+  ) -> Self {
+    Self {
+      bounds: Aabb::from_center(center, half_size),
+      config,
+      grounded: false,
+      air_time: 0.,
+      jumped: false,
+      drop_through: false,
+    }
+  }
+
+  pub fn is_grounded(&self) -> bool {
+    self.grounded
+  }
+
+  /// Time since the ground was left (0 while grounded)
+  #[cfg(test)]
+  pub fn air_time(&self) -> f32 {
+    self.air_time
+  }
+
+  /// Grounded, or in the coyote time without a jump
+  #[cfg(test)]
+  pub fn can_jump(&self) -> bool {
+    self.grounded
+      || (!self.jumped
+        && self.air_time <= self.config.coyote_time)
+  }
+
+  /// Mark the jump, so the coyote time does not allow another.
+  #[cfg(test)]
+  pub fn jump(&mut self) {
+    self.jumped = true;
+    self.grounded = false;
+  }
+
+  /// Fall through the one-way platforms in the next move.
+  #[cfg(test)]
+  pub fn drop_through(&mut self) {
+    self.drop_through = true;
+  }
+
+  /// Move by the delta, sliding along the colliders. `dt` advances
+  /// the coyote time.
+  pub fn move_and_slide(
+    &mut self,
+    world: &CollisionWorld,
+    delta: Vector2<f32>,
+    dt: f32,
+  ) -> MoveResult {
+    let was_grounded = self.grounded;
+    let start = self.bounds.min;
+    let mut result = MoveResult::default();
+
+    let half = self.bounds.half_size();
+    let max_step = self
+      .config
+      .max_step
+      .unwrap_or(half.x.min(half.y))
+      .max(f32::EPSILON);
+    let steps =
+      (delta.abs().max() / max_step).ceil().max(1.) as u32;
+    let step = delta / steps as f32;
+    for _ in 0..steps {
+      self.move_x(world, step.x, &mut result);
+      self.move_y(world, step.y, &mut result);
+    }
+
+    // Keep on the ground going down, or resting on it.
+    if !result.grounded && delta.y <= 0. && !self.jumped {
+      let reach = if was_grounded {
+        self.config.snap_distance
+      } else {
+        self.config.skin * 2.
+      };
+      if let Some(top) = self.ground_below(world, reach) {
+        let dy = top + self.config.skin - self.bounds.min.y;
+        self.bounds =
+          self.bounds.translated(Vector2::y() * dy);
+        result.grounded = true;
+      }
+    }
+    self.drop_through = false;
+
+    self.grounded = result.grounded;
+    if self.grounded {
+      self.air_time = 0.;
+      self.jumped = false;
+    } else {
+      self.air_time += dt;
+    }
+    result.landed = !was_grounded && self.grounded;
+    result.left_ground = was_grounded && !self.grounded;
+    result.moved = self.bounds.min - start;
+    result
+  }
+
+  fn move_x(
+    &mut self,
+    world: &CollisionWorld,
+    dx: f32,
+    result: &mut MoveResult,
+  ) {
+    if dx == 0. {
+      return;
+    }
+    let skin = self.config.skin;
+    let mut moved =
+      self.bounds.translated(Vector2::x() * dx);
+    for (_, c) in world.query(&moved.union(&self.bounds)) {
+      let (ColliderKind::Solid, Shape::Aabb(a)) =
+        (c.kind, c.shape)
+      else {
+        continue;
+      };
+      if !a.overlaps(&moved) {
+        continue;
+      }
+      let x = if 0. < dx {
+        (a.min.x - skin - moved.max.x).min(0.)
+      } else {
+        (a.max.x + skin - moved.min.x).max(0.)
+      };
+      moved = moved.translated(Vector2::x() * x);
+      result.hit_wall = true;
+    }
+    self.bounds = moved;
+  }
+
+  fn move_y(
+    &mut self,
+    world: &CollisionWorld,
+    dy: f32,
+    result: &mut MoveResult,
+  ) {
+    let skin = self.config.skin;
+    let prev = self.bounds;
+    let mut moved = prev.translated(Vector2::y() * dy);
+    for (_, c) in world.query(&moved.union(&prev)) {
+      match (c.kind, c.shape) {
+        (ColliderKind::Solid, Shape::Aabb(a)) => {
+          if !a.overlaps(&moved) {
+            continue;
+          }
+          if dy <= 0. {
+            let y = (a.max.y + skin - moved.min.y).max(0.);
+            moved = moved.translated(Vector2::y() * y);
+            result.grounded = true;
+          } else {
+            let y = (a.min.y - skin - moved.max.y).min(0.);
+            moved = moved.translated(Vector2::y() * y);
+            result.hit_ceiling = true;
+          }
+        }
+        (ColliderKind::OneWay, shape) => {
+          let top = shape.bounds().max.y;
+          if self.drop_through
+            || 0. < dy
+            || prev.min.y < top - skin
+            || !shape.bounds().overlaps(&moved)
+          {
+            continue;
+          }
+          moved = moved.translated(
+            Vector2::y() * (top + skin - moved.min.y),
+          );
+          result.grounded = true;
+        }
+        (ColliderKind::Solid, Shape::Slope(s)) => {
+          // Jumping up through the slope is not stopped.
+          if 0. < dy {
+            continue;
+          }
+          if let Some(contact) =
+            Shape::Slope(s).contact(&moved)
+          {
+            moved = moved.translated(
+              Vector2::y() * (contact.depth + skin),
+            );
+            result.grounded = true;
+          }
+        }
+      }
+    }
+    self.bounds = moved;
+  }
+
+  /// Highest ground top under the box within the reach
+  fn ground_below(
+    &self,
+    world: &CollisionWorld,
+    reach: f32,
+  ) -> Option<f32> {
+    let bottom = self.bounds.min.y;
+    let probe = Aabb::new(
+      Point2::new(self.bounds.min.x, bottom - reach),
+      Point2::new(self.bounds.max.x, bottom),
+    );
+    world
+      .query(&probe)
+      .into_iter()
+      .filter(|(_, c)| {
+        !(self.drop_through
+          && c.kind == ColliderKind::OneWay)
+      })
+      .filter_map(|(_, c)| {
+        let b = c.shape.bounds();
+        if b.max.x <= probe.min.x || probe.max.x <= b.min.x
+        {
+          return None;
+        }
+        match c.shape {
+          Shape::Aabb(a) => Some(a.max.y),
+          Shape::Slope(s) => {
+            Some(s.surface_under(&self.bounds))
+          }
+        }
+      })
+      .filter(|top| {
+        bottom - reach <= *top
+          && *top <= bottom + f32::EPSILON
+      })
+      .max_by(|a, b| a.total_cmp(b))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_sys::physics::{
+    shape::{Slope, SlopeDir},
+    Collider,
+  };
+
+  const DT: f32 = 1. / 60.;
+
+  fn aabb(min: [f32; 2], max: [f32; 2]) -> Shape {
+    Shape::Aabb(Aabb::new(
+      Point2::new(min[0], min[1]),
+      Point2::new(max[0], max[1]),
+    ))
+  }
+
+  /// Box of 16x32 standing at the bottom center
+  fn character(x: f32, y: f32) -> CharacterController {
+    CharacterController::new(
+      Point2::new(x, y + 16.),
+      Vector2::new(8., 16.),
+      ControllerConfig::default(),
+    )
+  }
+
+  #[test]
+  fn sweep_does_not_pass_through_thin_wall() {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::solid(aabb(
+      [50., 0.],
+      [51., 100.],
+    )));
+    let mut c = character(0., 20.);
+    let r =
+      c.move_and_slide(&world, Vector2::new(500., 0.), DT);
+    assert!(r.hit_wall);
+    assert!(c.bounds.max.x <= 50.);
+    assert!(49.9 < c.bounds.max.x);
+  }
+
+  #[test]
+  fn fast_fall_lands_on_thin_floor() {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::solid(aabb(
+      [-100., 0.],
+      [100., 1.],
+    )));
+    let mut c = character(0., 200.);
+    let r = c.move_and_slide(
+      &world,
+      Vector2::new(0., -1000.),
+      DT,
+    );
+    assert!(r.grounded && r.landed);
+    assert!((c.bounds.min.y - 1.).abs() < 0.1);
+  }
+
+  fn slope_world() -> CollisionWorld {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::solid(aabb(
+      [-200., -50.],
+      [0., 0.],
+    )));
+    world.insert(Collider::solid(Shape::Slope(Slope {
+      bounds: Aabb::new(
+        Point2::new(0., 0.),
+        Point2::new(64., 64.),
+      ),
+      rising: SlopeDir::Right,
+    })));
+    world.insert(Collider::solid(aabb(
+      [64., -50.],
+      [200., 64.],
+    )));
+    world
+      .insert(Collider::solid(aabb([0., -50.], [64., 0.])));
+    world
+  }
+
+  #[test]
+  fn walks_up_and_down_slope() {
+    let world = slope_world();
+    let mut c = character(-20., 0.);
+    assert!(
+      c.move_and_slide(&world, Vector2::zeros(), DT)
+        .grounded
+    );
+    for _ in 0..60 {
+      let r =
+        c.move_and_slide(&world, Vector2::new(2., -1.), DT);
+      assert!(r.grounded);
+    }
+    assert!((c.bounds.min.y - 64.).abs() < 0.5);
+    for _ in 0..60 {
+      let r =
+        c.move_and_slide(&world, Vector2::new(-2., 0.), DT);
+      assert!(
+        r.grounded,
+        "left the slope at {:?}",
+        c.bounds
+      );
+    }
+    assert!(c.bounds.min.y.abs() < 0.5);
+  }
+
+  #[test]
+  fn one_way_blocks_only_from_above() {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::one_way(aabb(
+      [-50., 50.],
+      [50., 60.],
+    )));
+    // Jumping up through it
+    let mut c = character(0., 20.);
+    let r =
+      c.move_and_slide(&world, Vector2::new(0., 60.), DT);
+    assert!(!r.hit_ceiling && !r.grounded);
+    assert!((c.bounds.min.y - 80.).abs() < 1e-3);
+    // Landing on it
+    let r =
+      c.move_and_slide(&world, Vector2::new(0., -40.), DT);
+    assert!(r.grounded);
+    assert!((c.bounds.min.y - 60.).abs() < 0.1);
+  }
+
+  #[test]
+  fn drops_through_one_way() {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::one_way(aabb(
+      [-50., 50.],
+      [50., 60.],
+    )));
+    let mut c = character(0., 60.);
+    assert!(
+      c.move_and_slide(&world, Vector2::zeros(), DT)
+        .grounded
+    );
+    // Standing still does not drop.
+    let r =
+      c.move_and_slide(&world, Vector2::new(0., -2.), DT);
+    assert!(r.grounded);
+    c.drop_through();
+    let r =
+      c.move_and_slide(&world, Vector2::new(0., -2.), DT);
+    assert!(!r.grounded && r.left_ground);
+    // The platform does not catch the box under its top.
+    let r =
+      c.move_and_slide(&world, Vector2::new(0., -2.), DT);
+    assert!(!r.grounded);
+    assert!(c.bounds.min.y < 57.);
+  }
+
+  #[test]
+  fn coyote_time_allows_one_jump() {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::solid(aabb(
+      [-200., -50.],
+      [0., 0.],
+    )));
+    let mut c = character(-10., 0.);
+    assert!(
+      c.move_and_slide(&world, Vector2::zeros(), DT)
+        .grounded
+    );
+    let r =
+      c.move_and_slide(&world, Vector2::new(20., 0.), DT);
+    assert!(r.left_ground && !c.is_grounded());
+    assert!(c.can_jump());
+    let mut air = c.clone();
+    while air.air_time() <= air.config.coyote_time {
+      assert!(air.can_jump());
+      air.move_and_slide(
+        &world,
+        Vector2::new(0., -0.1),
+        DT,
+      );
+    }
+    assert!(!air.can_jump());
+    c.jump();
+    assert!(!c.can_jump());
+  }
+
+  #[test]
+  fn snaps_down_small_steps_only() {
+    let mut world = CollisionWorld::default();
+    world.insert(Collider::solid(aabb(
+      [-200., -50.],
+      [0., 0.],
+    )));
+    world.insert(Collider::solid(aabb(
+      [0., -50.],
+      [100., -3.],
+    )));
+    world.insert(Collider::solid(aabb(
+      [100., -50.],
+      [200., -13.],
+    )));
+    let mut c = character(-20., 0.);
+    assert!(
+      c.move_and_slide(&world, Vector2::zeros(), DT)
+        .grounded
+    );
+    // Down 3, in the snap distance
+    for _ in 0..40 {
+      let r =
+        c.move_and_slide(&world, Vector2::new(2., 0.), DT);
+      assert!(r.grounded);
+    }
+    assert!((c.bounds.min.y + 3.).abs() < 0.1);
+    // Down 10, over the snap distance
+    let mut left = false;
+    for _ in 0..60 {
+      left |= c
+        .move_and_slide(&world, Vector2::new(2., 0.), DT)
+        .left_ground;
+    }
+    assert!(left);
+  }
+}
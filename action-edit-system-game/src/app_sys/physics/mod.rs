@@ -0,0 +1,162 @@
+//! 2D collision
+//! プラットフォーマー用の当たり判定とキャラクターコントローラー
+//!
+//! The static level is a set of colliders (AABB and slope) in
+//! `CollisionWorld`, searched through a uniform grid. The
+//! characters move by `controller::CharacterController`, which
+//! sweeps their boxes against the world. The entities with a
+//! `body::Body` are moved by it in the game step.
+//!
+//! Nothing here touches the GPU or the window, so the module runs
+//! on its own (as the tools and the headless checks).
+
+use crate::app_sys::scene::Tilemap;
+use nalgebra::Vector2;
+use serde::{Deserialize, Serialize};
+
+pub mod body;
+pub mod broad;
+pub mod controller;
+pub mod shape;
+use broad::UniformGrid;
+use shape::{Aabb, Shape, Slope, SlopeDir};
+
+/// Default cell size of the broad phase
+pub const DEFAULT_CELL_SIZE: f32 = 64.;
+
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct ColliderID(u32);
+
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum ColliderKind {
+  Solid,
+  /// Blocks only from above (the top of the bounds)
+  OneWay,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Collider {
+  pub shape: Shape,
+  pub kind: ColliderKind,
+}
+impl Collider {
+  pub fn solid(shape: Shape) -> Self {
+    Self {
+      shape,
+      kind: ColliderKind::Solid,
+    }
+  }
+
+  pub fn one_way(shape: Shape) -> Self {
+    Self {
+      shape,
+      kind: ColliderKind::OneWay,
+    }
+  }
+}
+
+/// Collision shape of a tile
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum TileShape {
+  Solid,
+  OneWay,
+  SlopeLeft,
+  SlopeRight,
+}
+
+/// Static colliders of the level
+#[derive(Debug)]
+pub struct CollisionWorld {
+  colliders: Vec<Collider>,
+  grid: UniformGrid,
+}
+impl CollisionWorld {
+  pub fn new(cell_size: f32) -> Self {
+    Self {
+      colliders: Vec::new(),
+      grid: UniformGrid::new(cell_size),
+    }
+  }
+
+  pub fn insert(
+    &mut self,
+    collider: Collider,
+  ) -> ColliderID {
+    let id = self.colliders.len() as u32;
+    self.colliders.push(collider);
+    self.grid.insert(id, &collider.shape.bounds());
+    ColliderID(id)
+  }
+
+  /// Colliders whose bounds may overlap the area
+  pub fn query(
+    &self,
+    area: &Aabb,
+  ) -> Vec<(ColliderID, Collider)> {
+    let mut ids = Vec::new();
+    self.grid.query(area, &mut ids);
+    ids
+      .into_iter()
+      .filter_map(|id| {
+        let c = self.colliders[id as usize];
+        c.shape
+          .bounds()
+          .expanded(f32::EPSILON)
+          .overlaps(area)
+          .then_some((ColliderID(id), c))
+      })
+      .collect()
+  }
+
+  /// Add the colliders of the tiles. `shape_of` gives the shape of
+  /// a tile number (None for the empty tiles).
+  pub fn add_tilemap(
+    &mut self,
+    map: &Tilemap,
+    shape_of: impl Fn(u32) -> Option<TileShape>,
+  ) -> Vec<ColliderID> {
+    let mut ids = Vec::new();
+    for y in 0..map.height() {
+      for x in 0..map.width() {
+        let Some(shape) = map.get(x, y).and_then(&shape_of)
+        else {
+          continue;
+        };
+        let min = map.origin
+          + map.tile_size.component_mul(&Vector2::new(
+            x as f32, y as f32,
+          ));
+        let bounds = Aabb::new(min, min + map.tile_size);
+        let slope =
+          |rising| Shape::Slope(Slope { bounds, rising });
+        ids.push(self.insert(match shape {
+          TileShape::Solid => {
+            Collider::solid(Shape::Aabb(bounds))
+          }
+          TileShape::OneWay => {
+            Collider::one_way(Shape::Aabb(bounds))
+          }
+          TileShape::SlopeLeft => {
+            Collider::solid(slope(SlopeDir::Left))
+          }
+          TileShape::SlopeRight => {
+            Collider::solid(slope(SlopeDir::Right))
+          }
+        }));
+      }
+    }
+    ids
+  }
+}
+impl Default for CollisionWorld {
+  fn default() -> Self {
+    Self::new(DEFAULT_CELL_SIZE)
+  }
+}
@@ -0,0 +1,172 @@
+//! Collision shapes
+//!
+//! The coordinates are the world coordinates (y up).
+//! A contact is the push to move the AABB out of the shape.
+
+use nalgebra::{Point2, Vector2};
+use serde::{Deserialize, Serialize};
+
+/// Axis aligned bounding box
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Aabb {
+  pub min: Point2<f32>,
+  pub max: Point2<f32>,
+}
+impl Aabb {
+  pub fn new(min: Point2<f32>, max: Point2<f32>) -> Self {
+    Self { min, max }
+  }
+
+  pub fn from_center(
+    center: Point2<f32>,
+    half: Vector2<f32>,
+  ) -> Self {
+    Self {
+      min: center - half,
+      max: center + half,
+    }
+  }
+
+  pub fn center(&self) -> Point2<f32> {
+    nalgebra::center(&self.min, &self.max)
+  }
+
+  pub fn half_size(&self) -> Vector2<f32> {
+    (self.max - self.min) * 0.5
+  }
+
+  pub fn translated(&self, delta: Vector2<f32>) -> Self {
+    Self {
+      min: self.min + delta,
+      max: self.max + delta,
+    }
+  }
+
+  pub fn expanded(&self, margin: f32) -> Self {
+    let margin = Vector2::repeat(margin);
+    Self {
+      min: self.min - margin,
+      max: self.max + margin,
+    }
+  }
+
+  pub fn union(&self, other: &Aabb) -> Self {
+    Self {
+      min: self.min.inf(&other.min),
+      max: self.max.sup(&other.max),
+    }
+  }
+
+  /// Overlap with the positive area (touching is not overlap)
+  pub fn overlaps(&self, other: &Aabb) -> bool {
+    self.min.x < other.max.x
+      && other.min.x < self.max.x
+      && self.min.y < other.max.y
+      && other.min.y < self.max.y
+  }
+}
+
+/// Side the slope rises to
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize,
+)]
+pub enum SlopeDir {
+  Left,
+  Right,
+}
+
+/// Right triangle in the bounds. The surface goes from the bottom
+/// corner to the top corner of the rising side.
+///
+/// The slope only pushes up. The vertical side should be backed by
+/// a solid collider (as the tiles next to it).
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Slope {
+  pub bounds: Aabb,
+  pub rising: SlopeDir,
+}
+impl Slope {
+  /// Surface height at x (clamped in the bounds)
+  pub fn height_at(&self, x: f32) -> f32 {
+    let b = &self.bounds;
+    let width = (b.max.x - b.min.x).max(f32::EPSILON);
+    let t = ((x - b.min.x) / width).clamp(0., 1.);
+    let t = match self.rising {
+      SlopeDir::Right => t,
+      SlopeDir::Left => 1. - t,
+    };
+    b.min.y + (b.max.y - b.min.y) * t
+  }
+
+  /// Highest surface under the box
+  pub fn surface_under(&self, aabb: &Aabb) -> f32 {
+    match self.rising {
+      SlopeDir::Right => self.height_at(aabb.max.x),
+      SlopeDir::Left => self.height_at(aabb.min.x),
+    }
+  }
+}
+
+/// Push out of the shape
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+  /// Unit direction to push
+  pub normal: Vector2<f32>,
+  pub depth: f32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Shape {
+  Aabb(Aabb),
+  Slope(Slope),
+}
+impl Shape {
+  pub fn bounds(&self) -> Aabb {
+    match self {
+      Shape::Aabb(a) => *a,
+      Shape::Slope(s) => s.bounds,
+    }
+  }
+
+  /// Push to move the box out of the shape. None if they do not
+  /// overlap.
+  pub fn contact(&self, aabb: &Aabb) -> Option<Contact> {
+    match self {
+      Shape::Aabb(a) => aabb_contact(a, aabb),
+      Shape::Slope(s) => slope_contact(s, aabb),
+    }
+  }
+}
+
+fn aabb_contact(
+  solid: &Aabb,
+  aabb: &Aabb,
+) -> Option<Contact> {
+  if !solid.overlaps(aabb) {
+    return None;
+  }
+  let candidates = [
+    (Vector2::x(), solid.max.x - aabb.min.x),
+    (-Vector2::x(), aabb.max.x - solid.min.x),
+    (Vector2::y(), solid.max.y - aabb.min.y),
+    (-Vector2::y(), aabb.max.y - solid.min.y),
+  ];
+  candidates
+    .into_iter()
+    .min_by(|a, b| a.1.total_cmp(&b.1))
+    .map(|(normal, depth)| Contact { normal, depth })
+}
+
+fn slope_contact(
+  s: &Slope,
+  aabb: &Aabb,
+) -> Option<Contact> {
+  if !s.bounds.overlaps(aabb) {
+    return None;
+  }
+  let depth = s.surface_under(aabb) - aabb.min.y;
+  (0. < depth).then_some(Contact {
+    normal: Vector2::y(),
+    depth,
+  })
+}
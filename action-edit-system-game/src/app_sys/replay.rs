@@ -0,0 +1,420 @@
+//! Input recording and replay
+//! 入力の記録と再生
+//!
+//! A session is recorded as the seed of the random numbers, the
+//! level and the world at the start, and in every step the actions
+//! and the axes of the input map with the keys, the mouse buttons
+//! and the cursor read by the scripts (`RawInput`), so the
+//! simulation is reproduced by replaying the steps from the same
+//! start. The steps repeated in a row are stored once with the
+//! count.
+//!
+//! The recording ends with the state hash (`state_hash`) of the
+//! world, the scene and the scripts after the last step, which the
+//! replay checks. `run_headless` replays the
+//! recording without the window, for the bug reports and the
+//! regression tests:
+//!
+//! ```text
+//! game --record session.msgpack
+//! game --replay session.msgpack --headless
+//! ```
+//!
+//! The edits from the GUI are not recorded.
+
+use super::{
+  ecs::World,
+  input::{map::InputMap, RawInput},
+  level,
+  lua::host::LuaHost,
+  scene::Scene2D,
+  AppFrontend,
+};
+use crate::StdError;
+use hashbrown::HashMap;
+use serde::{Deserialize, Serialize};
+use std::{hash::Hasher, path::Path};
+
+/// Actions, axes and the script input of a step
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+pub struct RecordedStep {
+  /// Actions down by the index of `Recording::names`
+  pub down: Vec<u32>,
+  /// Non-zero axes by the index of `Recording::names`
+  pub axes: Vec<(u32, f32)>,
+  pub raw: RawInput,
+}
+
+#[derive(
+  Debug, Clone, Default, PartialEq, Serialize, Deserialize,
+)]
+pub struct Recording {
+  /// Seed of the random numbers of the session
+  pub seed: u64,
+  /// Simulation rate (Hz)
+  pub tick_rate: f64,
+  /// Level and world at the start (`level::Snapshot`)
+  pub start: Vec<u8>,
+  /// Names of the actions and the axes
+  pub names: Vec<String>,
+  /// Steps and the number of times they repeat
+  pub steps: Vec<(u32, RecordedStep)>,
+  /// State hash after the last step
+  pub hash: u64,
+}
+impl Recording {
+  pub fn load(
+    path: impl AsRef<Path>,
+  ) -> Result<Self, StdError> {
+    Ok(rmp_serde::from_slice(&std::fs::read(path)?)?)
+  }
+
+  /// Save as MessagePack without the field names.
+  pub fn save(
+    &self,
+    path: impl AsRef<Path>,
+  ) -> Result<(), StdError> {
+    let path = path.as_ref();
+    if let Some(dir) =
+      path.parent().filter(|d| !d.as_os_str().is_empty())
+    {
+      std::fs::create_dir_all(dir)?;
+    }
+    std::fs::write(path, rmp_serde::to_vec(self)?)?;
+    Ok(())
+  }
+
+  /// Number of the steps
+  pub fn ticks(&self) -> u64 {
+    self.steps.iter().map(|(n, _)| *n as u64).sum()
+  }
+}
+
+/// Records the steps of the input map and the script input
+pub struct Recorder {
+  recording: Recording,
+  indices: HashMap<String, u32>,
+}
+impl Recorder {
+  pub fn new(
+    seed: u64,
+    tick_rate: f64,
+    start: Vec<u8>,
+  ) -> Self {
+    Self {
+      recording: Recording {
+        seed,
+        tick_rate,
+        start,
+        ..Default::default()
+      },
+      indices: HashMap::new(),
+    }
+  }
+
+  /// Record the step from the map and the script input. Called
+  /// once per simulation step, after they are updated.
+  pub fn record(&mut self, map: &InputMap, raw: &RawInput) {
+    let mut down = map
+      .down_actions()
+      .map(|name| self.index(name))
+      .collect::<Vec<_>>();
+    down.sort_unstable();
+    let mut axes = map
+      .axes()
+      .filter(|(_, v)| *v != 0.)
+      .map(|(name, v)| (self.index(name), v))
+      .collect::<Vec<_>>();
+    axes.sort_unstable_by_key(|(i, _)| *i);
+    let step = RecordedStep {
+      down,
+      axes,
+      raw: raw.clone(),
+    };
+    match self.recording.steps.last_mut() {
+      Some((n, last)) if *last == step => *n += 1,
+      _ => self.recording.steps.push((1, step)),
+    }
+  }
+
+  fn index(&mut self, name: &str) -> u32 {
+    if let Some(i) = self.indices.get(name) {
+      return *i;
+    }
+    let i = self.recording.names.len() as u32;
+    self.recording.names.push(name.to_string());
+    self.indices.insert(name.to_string(), i);
+    i
+  }
+
+  pub fn ticks(&self) -> u64 {
+    self.recording.ticks()
+  }
+
+  /// The recording with the state hash after the last step
+  pub fn finish(self, hash: u64) -> Recording {
+    Recording {
+      hash,
+      ..self.recording
+    }
+  }
+}
+
+/// Feeds the recorded steps to the input map and the scripts
+pub struct Replay {
+  recording: Recording,
+  /// Index in `Recording::steps`
+  step: usize,
+  /// Times the step is fed
+  repeat: u32,
+  tick: u64,
+}
+impl Replay {
+  pub fn new(recording: Recording) -> Self {
+    Self {
+      recording,
+      step: 0,
+      repeat: 0,
+      tick: 0,
+    }
+  }
+
+  pub fn recording(&self) -> &Recording {
+    &self.recording
+  }
+
+  /// Steps fed
+  pub fn tick(&self) -> u64 {
+    self.tick
+  }
+
+  pub fn is_finished(&self) -> bool {
+    self.recording.steps.len() <= self.step
+  }
+
+  /// Feed the next step to the map instead of `InputMap::update`,
+  /// and to the script input. Returns false if finished.
+  pub fn step(
+    &mut self,
+    map: &mut InputMap,
+    raw: &mut RawInput,
+  ) -> bool {
+    let Some((n, step)) =
+      self.recording.steps.get(self.step)
+    else {
+      return false;
+    };
+    let names = &self.recording.names;
+    let name =
+      |i: u32| names.get(i as usize).map(String::as_str);
+    map.replay(
+      step.down.iter().filter_map(|i| name(*i)),
+      step
+        .axes
+        .iter()
+        .filter_map(|(i, v)| Some((name(*i)?, *v))),
+    );
+    raw.clone_from(&step.raw);
+    self.repeat += 1;
+    if *n <= self.repeat {
+      self.step += 1;
+      self.repeat = 0;
+    }
+    self.tick += 1;
+    true
+  }
+}
+
+/// FNV-1a, which is the same on every platform and build
+pub struct StateHasher(u64);
+impl Default for StateHasher {
+  fn default() -> Self {
+    Self(0xcbf2_9ce4_8422_2325)
+  }
+}
+impl Hasher for StateHasher {
+  fn write(&mut self, bytes: &[u8]) {
+    for b in bytes {
+      self.0 ^= *b as u64;
+      self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+    }
+  }
+
+  fn finish(&self) -> u64 {
+    self.0
+  }
+}
+
+/// Hash of the components of the world registered to
+/// `level::registry`, the sprites and the tilemaps of the scene,
+/// and the data of the scripts
+pub fn state_hash(
+  world: &World,
+  scene: &Scene2D,
+  lua: &LuaHost,
+) -> Result<u64, StdError> {
+  let data = level::registry().save(world)?;
+  let mut hasher = StateHasher::default();
+  hasher.write(&rmp_serde::to_vec(&data)?);
+  hash_scene(scene, &mut hasher);
+  hasher.write(&lua.state_bytes()?);
+  Ok(hasher.finish())
+}
+
+fn hash_scene(scene: &Scene2D, hasher: &mut StateHasher) {
+  let floats = |h: &mut StateHasher, v: &[f32]| {
+    v.iter().for_each(|f| h.write_u32(f.to_bits()))
+  };
+  let mut ids = scene.sprite_ids().collect::<Vec<_>>();
+  ids.sort_unstable();
+  for id in ids {
+    let Some(s) = scene.sprite(id) else {
+      continue;
+    };
+    hasher.write_u32(id.raw());
+    floats(hasher, &[s.pos.x, s.pos.y, s.size.x, s.size.y]);
+    floats(hasher, &[s.rot]);
+    floats(hasher, &s.filter);
+    floats(hasher, s.uv.as_flattened());
+    hasher
+      .write_u32(s.texture.map_or(u32::MAX, |t| t.raw()));
+    hasher.write_i32(s.layer);
+    hasher.write_u8(s.visible as u8);
+  }
+  for (name, map) in scene.tilemaps() {
+    hasher.write(name.as_bytes());
+    floats(hasher, &[map.origin.x, map.origin.y]);
+    for y in 0..map.height() {
+      for x in 0..map.width() {
+        hasher.write_u32(map.get(x, y).unwrap_or(0));
+      }
+    }
+  }
+}
+
+/// Seed for a new session
+pub fn random_seed() -> u64 {
+  let now = std::time::SystemTime::now()
+    .duration_since(std::time::UNIX_EPOCH)
+    .unwrap_or_default();
+  let mut hasher = StateHasher::default();
+  hasher.write_u128(now.as_nanos());
+  hasher.write_u32(std::process::id());
+  hasher.finish()
+}
+
+/// Replay the recording without the window, and check the state
+/// hash after the last step.
+pub fn run_headless(
+  path: impl AsRef<Path>,
+) -> Result<(), StdError> {
+  let path = path.as_ref();
+  let recording = Recording::load(path)?;
+  log::info!(
+    "Replaying {} ({} ticks, seed {:016x}).",
+    path.display(),
+    recording.ticks(),
+    recording.seed
+  );
+  let mut app = AppFrontend::new(recording.seed)?;
+  app.replay(Replay::new(recording))?;
+  let dt = app.game_loop.dt();
+  let result = loop {
+    if let Some(result) = app.finish_replay() {
+      break result;
+    }
+    app.step(dt);
+  };
+  app.shutdown();
+  let hash = result?;
+  log::info!("Replay matched the state hash {hash:016x}.");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_sys::{
+    combat::Fighter,
+    input::{
+      gamepad::{GamepadAxis, GamepadButton},
+      map::InputBindings,
+    },
+  };
+  use winit::event::MouseButton;
+
+  #[test]
+  fn script_input_is_replayed() {
+    let map = InputMap::new(InputBindings::default());
+    let mut raws = (0..4)
+      .map(|i| RawInput {
+        keys: vec!["KeyA".to_string(); i % 2],
+        keys_pressed: vec!["KeyA".to_string(); i % 2],
+        buttons: vec![0; i / 2],
+        cursor: [i as f32, 10.],
+      })
+      .collect::<Vec<_>>();
+    raws.push(raws[3].clone());
+    let mut recorder = Recorder::new(1, 60., Vec::new());
+    for raw in raws.iter() {
+      recorder.record(&map, raw);
+    }
+    let recording = recorder.finish(0);
+    assert_eq!(recording.steps.len(), 4);
+
+    let mut replay = Replay::new(recording);
+    let mut map = InputMap::new(InputBindings::default());
+    let mut raw = RawInput::default();
+    let replayed = std::iter::from_fn(|| {
+      replay.step(&mut map, &mut raw).then(|| raw.clone())
+    })
+    .collect::<Vec<_>>();
+    assert_eq!(replayed, raws);
+    assert!(raw.is_down_by_name("KeyA"));
+    assert!(raw.is_button_down(MouseButton::Left));
+  }
+
+  #[test]
+  fn replay_reproduces_the_recording() {
+    let path = std::env::temp_dir().join(format!(
+      "replay_test_{}.msgpack",
+      std::process::id()
+    ));
+    let mut app = AppFrontend::new(42).unwrap();
+    // Only the replay that restores the start has it.
+    let fighter: Fighter = toml::from_str(
+      "action = 'idle'\nfacing_right = true\nhealth = 100",
+    )
+    .unwrap();
+    app.world.spawn((fighter,));
+    app.record(&path).unwrap();
+    let dt = app.game_loop.dt();
+    for i in 0..120 {
+      let mut input = app.input.write();
+      input.set_gamepad_button(
+        GamepadButton::South,
+        i % 30 < 10,
+      );
+      input.set_gamepad_axis(
+        GamepadAxis::LeftStickX,
+        (i as f32 * 0.1).sin(),
+      );
+      drop(input);
+      app.step(dt);
+    }
+    app.shutdown();
+
+    let mut recording = Recording::load(&path).unwrap();
+    assert_eq!(recording.ticks(), 120);
+    assert!(!recording.names.is_empty());
+    let matched = run_headless(&path);
+    recording.hash ^= 1;
+    recording.save(&path).unwrap();
+    let differed = run_headless(&path);
+    std::fs::remove_file(&path).ok();
+    matched.unwrap();
+    assert!(differed.is_err());
+  }
+}
@@ -0,0 +1,440 @@
+//! 2D Scene
+//! スクリプトやエディタから操作される2Dシーン
+
+use super::gfx::{
+  rdr_2d::{camera::Camera2D, square, tile},
+  util::{TextureID, TextureStorage},
+};
+use hashbrown::HashMap;
+use nalgebra::{Point2, Vector2};
+
+/// Name of the camera that always exists
+pub const MAIN_CAMERA: &str = "main";
+
+#[derive(
+  Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord,
+)]
+pub struct SpriteID(u32);
+impl SpriteID {
+  pub fn from_raw(raw: u32) -> Self {
+    Self(raw)
+  }
+  pub fn raw(self) -> u32 {
+    self.0
+  }
+}
+
+#[derive(Debug, Clone)]
+pub struct Sprite {
+  pub pos: Point2<f32>,
+  /// Full size of the sprite in the world
+  pub size: Vector2<f32>,
+  pub rot: f32,
+  pub filter: [f32; 4],
+  pub texture: Option<TextureID>,
+  /// UV range (`[origin, extent]`)
+  pub uv: [[f32; 2]; 2],
+  /// Drawing order. Larger is drawn later.
+  pub layer: i32,
+  pub visible: bool,
+}
+impl Default for Sprite {
+  fn default() -> Self {
+    Self {
+      pos: Point2::origin(),
+      size: Vector2::new(32., 32.),
+      rot: 0.,
+      filter: [1.; 4],
+      texture: None,
+      uv: [[0., 0.], [1., 1.]],
+      layer: 0,
+      visible: true,
+    }
+  }
+}
+impl Sprite {
+  pub fn instance(&self) -> square::Instance {
+    square::Instance {
+      pos: self.pos.into(),
+      size: (self.size * 0.5).into(),
+      rot: [self.rot.cos(), self.rot.sin()],
+      filter: self.filter,
+      uv: self.uv,
+    }
+  }
+}
+
+/// Grid of tiles drawn from the atlas texture
+///
+/// Tile value 0 is empty, and `n` is the `n - 1`th cell of the atlas
+/// (row-major).
+#[derive(Debug, Clone)]
+pub struct Tilemap {
+  pub texture: Option<TextureID>,
+  /// Bottom-left corner of the map in the world
+  pub origin: Point2<f32>,
+  /// Size of a tile in the world
+  pub tile_size: Vector2<f32>,
+  /// Size of an atlas cell in pixel
+  pub atlas_cell: [u32; 2],
+  width: u32,
+  height: u32,
+  tiles: Vec<u32>,
+  dirty: bool,
+}
+impl Tilemap {
+  /// None if the number of the tiles overflows `u32`.
+  pub fn new(
+    width: u32,
+    height: u32,
+    tile_size: Vector2<f32>,
+    atlas_cell: [u32; 2],
+  ) -> Option<Self> {
+    let len = width.checked_mul(height)?;
+    Some(Self {
+      texture: None,
+      origin: Point2::origin(),
+      tile_size,
+      atlas_cell,
+      width,
+      height,
+      tiles: vec![0; len as usize],
+      dirty: true,
+    })
+  }
+
+  pub fn width(&self) -> u32 {
+    self.width
+  }
+
+  pub fn height(&self) -> u32 {
+    self.height
+  }
+
+  pub fn get(&self, x: u32, y: u32) -> Option<u32> {
+    (x < self.width && y < self.height)
+      .then(|| self.tiles[(y * self.width + x) as usize])
+  }
+
+  /// Set the tile. Returns false if the position is out of the map.
+  pub fn set(&mut self, x: u32, y: u32, tile: u32) -> bool {
+    if x < self.width && y < self.height {
+      self.tiles[(y * self.width + x) as usize] = tile;
+      self.dirty = true;
+      true
+    } else {
+      false
+    }
+  }
+
+  pub fn fill(&mut self, tile: u32) {
+    self.tiles.fill(tile);
+    self.dirty = true;
+  }
+
+  /// Mark the map to rebuild the instances.
+  pub fn touch(&mut self) {
+    self.dirty = true;
+  }
+
+  pub fn is_dirty(&self) -> bool {
+    self.dirty
+  }
+
+  /// Build the tile instances, and clear the dirty flag.
+  pub fn take_instances(
+    &mut self,
+    textures: &TextureStorage,
+  ) -> Vec<tile::Instance> {
+    self.dirty = false;
+    let Some(tex_size) =
+      self.texture.and_then(|t| textures.size(t))
+    else {
+      return Vec::new();
+    };
+    let cell = [
+      self.atlas_cell[0].max(1),
+      self.atlas_cell[1].max(1),
+    ];
+    let columns = (tex_size[0] / cell[0]).max(1);
+    let extent = [
+      cell[0] as f32 / tex_size[0] as f32,
+      cell[1] as f32 / tex_size[1] as f32,
+    ];
+    let half = self.tile_size * 0.5;
+    self
+      .tiles
+      .iter()
+      .enumerate()
+      .filter(|(_, t)| **t != 0)
+      .map(|(i, t)| {
+        let (x, y) =
+          (i as u32 % self.width, i as u32 / self.width);
+        let cell = t - 1;
+        tile::Instance {
+          pos: [
+            self.origin.x
+              + self.tile_size.x * x as f32
+              + half.x,
+            self.origin.y
+              + self.tile_size.y * y as f32
+              + half.y,
+          ],
+          size: half.into(),
+          filter: [1.; 4],
+          uv: [
+            [
+              (cell % columns) as f32 * extent[0],
+              (cell / columns) as f32 * extent[1],
+            ],
+            extent,
+          ],
+        }
+      })
+      .collect()
+  }
+}
+
+/// Sprites and tilemaps of the scene, to bring the scene back after
+/// a play. The cameras are not included.
+#[derive(Debug, Clone)]
+pub struct SceneSnapshot {
+  sprites: HashMap<SpriteID, Sprite>,
+  next_sprite: u32,
+  tilemaps: Vec<(String, Tilemap)>,
+}
+
+pub struct Scene2D {
+  sprites: HashMap<SpriteID, Sprite>,
+  next_sprite: u32,
+  sprites_dirty: bool,
+  /// Sprite positions at the start of the simulation step
+  prev_pos: HashMap<SpriteID, Point2<f32>>,
+  /// Last instances are interpolated
+  interpolating: bool,
+  cameras: HashMap<String, Camera2D>,
+  active_camera: String,
+  tilemaps: Vec<(String, Tilemap)>,
+  /// Tilemap is added or removed
+  tilemaps_dirty: bool,
+}
+impl Scene2D {
+  pub fn new(main_camera: Camera2D) -> Self {
+    let mut cameras = HashMap::new();
+    cameras.insert(MAIN_CAMERA.to_string(), main_camera);
+    Self {
+      sprites: HashMap::new(),
+      next_sprite: 0,
+      sprites_dirty: false,
+      prev_pos: HashMap::new(),
+      interpolating: false,
+      cameras,
+      active_camera: MAIN_CAMERA.to_string(),
+      tilemaps: Vec::new(),
+      tilemaps_dirty: false,
+    }
+  }
+
+  pub fn snapshot(&self) -> SceneSnapshot {
+    SceneSnapshot {
+      sprites: self.sprites.clone(),
+      next_sprite: self.next_sprite,
+      tilemaps: self.tilemaps.clone(),
+    }
+  }
+
+  /// Bring the sprites and the tilemaps back to the snapshot. The
+  /// sprite ids spawned after it are given again.
+  pub fn restore(&mut self, snapshot: &SceneSnapshot) {
+    self.sprites = snapshot.sprites.clone();
+    self.next_sprite = snapshot.next_sprite;
+    self.sprites_dirty = true;
+    self.prev_pos.clear();
+    self.tilemaps = snapshot.tilemaps.clone();
+    for (_, map) in self.tilemaps.iter_mut() {
+      map.touch();
+    }
+    self.tilemaps_dirty = true;
+  }
+
+  pub fn spawn_sprite(
+    &mut self,
+    sprite: Sprite,
+  ) -> SpriteID {
+    let id = SpriteID(self.next_sprite);
+    self.next_sprite += 1;
+    self.sprites.insert(id, sprite);
+    self.sprites_dirty = true;
+    id
+  }
+
+  pub fn remove_sprite(
+    &mut self,
+    id: SpriteID,
+  ) -> Option<Sprite> {
+    let sprite = self.sprites.remove(&id);
+    self.sprites_dirty |= sprite.is_some();
+    sprite
+  }
+
+  pub fn sprite(&self, id: SpriteID) -> Option<&Sprite> {
+    self.sprites.get(&id)
+  }
+
+  pub fn sprite_mut(
+    &mut self,
+    id: SpriteID,
+  ) -> Option<&mut Sprite> {
+    let sprite = self.sprites.get_mut(&id);
+    self.sprites_dirty |= sprite.is_some();
+    sprite
+  }
+
+  pub fn sprite_ids(
+    &self,
+  ) -> impl Iterator<Item = SpriteID> + '_ {
+    self.sprites.keys().copied()
+  }
+
+  /// Save the sprite positions before the simulation step.
+  pub fn save_previous(&mut self) {
+    self.prev_pos.clear();
+    self
+      .prev_pos
+      .extend(self.sprites.iter().map(|(id, s)| (*id, s.pos)));
+  }
+
+  /// Build the visible sprite instances in the drawing order, and
+  /// clear the dirty flag. Returns None if nothing has changed.
+  ///
+  /// The positions are interpolated from `save_previous` by the
+  /// alpha of the game loop.
+  pub fn take_sprite_instances(
+    &mut self,
+    alpha: f32,
+  ) -> Option<Vec<(TextureID, square::Instance)>> {
+    if !self.sprites_dirty && !self.interpolating {
+      return None;
+    }
+    self.sprites_dirty = false;
+    self.interpolating = false;
+    let mut sprites = self
+      .sprites
+      .iter()
+      .filter(|(_, s)| s.visible)
+      .filter_map(|(id, s)| s.texture.map(|t| (id, t, s)))
+      .collect::<Vec<_>>();
+    sprites.sort_by_key(|(id, t, s)| (s.layer, *t, **id));
+    Some(
+      sprites
+        .into_iter()
+        .map(|(id, t, s)| {
+          let mut instance = s.instance();
+          if let Some(prev) =
+            self.prev_pos.get(id).filter(|p| **p != s.pos)
+          {
+            self.interpolating = true;
+            instance.pos =
+              prev.coords.lerp(&s.pos.coords, alpha).into();
+          }
+          (t, instance)
+        })
+        .collect(),
+    )
+  }
+
+  pub fn camera_mut(
+    &mut self,
+    name: &str,
+  ) -> Option<&mut Camera2D> {
+    self.cameras.get_mut(name)
+  }
+
+  /// Get the camera, or create it as a copy of the active camera.
+  pub fn camera_or_insert(
+    &mut self,
+    name: &str,
+  ) -> &mut Camera2D {
+    if !self.cameras.contains_key(name) {
+      let camera = self.active_camera().clone();
+      self.cameras.insert(name.to_string(), camera);
+    }
+    self.cameras.get_mut(name).unwrap()
+  }
+
+  pub fn active_camera(&self) -> &Camera2D {
+    &self.cameras[&self.active_camera]
+  }
+
+  pub fn active_camera_name(&self) -> &str {
+    &self.active_camera
+  }
+
+  pub fn set_active_camera(&mut self, name: &str) -> bool {
+    let exists = self.cameras.contains_key(name);
+    if exists {
+      self.active_camera = name.to_string();
+    }
+    exists
+  }
+
+  pub fn insert_tilemap(
+    &mut self,
+    name: impl ToString,
+    map: Tilemap,
+  ) {
+    let name = name.to_string();
+    match self.tilemaps.iter_mut().find(|(n, _)| *n == name)
+    {
+      Some((_, m)) => *m = map,
+      None => self.tilemaps.push((name, map)),
+    }
+    self.tilemaps_dirty = true;
+  }
+
+  pub fn remove_tilemap(
+    &mut self,
+    name: &str,
+  ) -> Option<Tilemap> {
+    let i =
+      self.tilemaps.iter().position(|(n, _)| n == name)?;
+    self.tilemaps_dirty = true;
+    Some(self.tilemaps.remove(i).1)
+  }
+
+  pub fn tilemap(&self, name: &str) -> Option<&Tilemap> {
+    self
+      .tilemaps
+      .iter()
+      .find(|(n, _)| n == name)
+      .map(|(_, m)| m)
+  }
+
+  pub fn tilemap_mut(
+    &mut self,
+    name: &str,
+  ) -> Option<&mut Tilemap> {
+    self
+      .tilemaps
+      .iter_mut()
+      .find(|(n, _)| n == name)
+      .map(|(_, m)| m)
+  }
+
+  pub fn tilemaps(
+    &self,
+  ) -> impl Iterator<Item = (&str, &Tilemap)> {
+    self.tilemaps.iter().map(|(n, m)| (n.as_str(), m))
+  }
+
+  pub fn tilemaps_mut(
+    &mut self,
+  ) -> impl Iterator<Item = (&str, &mut Tilemap)> {
+    self.tilemaps.iter_mut().map(|(n, m)| (n.as_str(), m))
+  }
+
+  /// Returns true once after a tilemap is added or removed.
+  pub fn take_tilemaps_dirty(&mut self) -> bool {
+    std::mem::take(&mut self.tilemaps_dirty)
+  }
+}
@@ -0,0 +1,236 @@
+//! Conformance suite of the script hosts
+//!
+//! The same behavior is written for each backend in the
+//! `CONFORMANCE_ROOT` (`behavior.lua`, `behavior.wat`). The suite
+//! drives a host by the same calls in a fresh scene, and compares
+//! the recorded commands, the emitted events and the scene.
+//! Run it with `--script-conformance`, or by `cargo test`.
+
+use super::{
+  ScriptCommand, ScriptEvent, ScriptHost, SpriteDesc,
+};
+use crate::{
+  app_sys::{
+    gfx::{rdr_2d::camera::Camera2D, util::TextureStorage},
+    input::RawInput,
+    lua::{
+      host::LuaHost, sandbox::SandboxProfile, SceneContext,
+    },
+    scene::{Scene2D, Tilemap},
+    wasm::{PluginHost, PluginLimits},
+  },
+  StdError,
+};
+use nalgebra::Vector2;
+use parking_lot::RwLock;
+use std::{path::Path, sync::Arc};
+
+/// Directory of the behaviors
+pub const CONFORMANCE_ROOT: &str = "./conformance";
+/// Texture and tilemap name used by the behavior
+pub const RESOURCE: &str = "conformance";
+const DT: f32 = 1. / 60.;
+
+/// Fresh scene with the resources of the behavior
+pub fn context() -> SceneContext {
+  let ctx = SceneContext {
+    scene: Arc::new(RwLock::new(Scene2D::new(
+      Camera2D::with_view_size(1., 1.),
+    ))),
+    textures: Arc::new(RwLock::new(TextureStorage::new())),
+  };
+  ctx
+    .textures
+    .write()
+    .register(RESOURCE, image::RgbaImage::new(1, 1));
+  ctx.scene.write().insert_tilemap(
+    RESOURCE,
+    Tilemap::new(4, 1, Vector2::new(1., 1.), [1, 1])
+      .expect("small tilemap"),
+  );
+  ctx
+}
+
+struct Checker<'a, H: ScriptHost> {
+  host: &'a mut H,
+  failures: Vec<String>,
+}
+impl<H: ScriptHost> Checker<'_, H> {
+  fn errors(&mut self, step: &str, errors: Vec<H::Error>) {
+    self.failures.extend(
+      errors.iter().map(|e| format!("{step}: {e}")),
+    );
+  }
+
+  fn expect<T: PartialEq + std::fmt::Debug>(
+    &mut self,
+    step: &str,
+    what: &str,
+    got: T,
+    expected: T,
+  ) {
+    if got != expected {
+      self.failures.push(format!(
+        "{step}: {what} expected {expected:?}, got {got:?}"
+      ));
+    }
+  }
+
+  /// Compare the commands and the events since the last step.
+  fn expect_output(
+    &mut self,
+    step: &str,
+    commands: Vec<ScriptCommand>,
+    events: Vec<ScriptEvent>,
+  ) {
+    let got = self.host.take_trace();
+    self.expect(step, "commands", got, commands);
+    let got = self.host.take_events();
+    self.expect(step, "events", got, events);
+  }
+}
+
+/// Drive the host with the behavior in the directory, and return
+/// the mismatches.
+pub fn check<H: ScriptHost>(
+  host: &mut H,
+  ctx: &SceneContext,
+  dir: &Path,
+) -> Vec<String> {
+  let mut c = Checker {
+    host,
+    failures: Vec::new(),
+  };
+  let info = |message: &str| ScriptCommand::Log {
+    level: log::Level::Info,
+    message: message.to_string(),
+  };
+
+  c.host.set_trace(true);
+  let errors = c.host.load_dir(dir);
+  c.errors("init", errors);
+  let ids =
+    ctx.scene.read().sprite_ids().collect::<Vec<_>>();
+  c.expect("init", "sprite count", ids.len(), 1);
+  let Some(&id) = ids.first() else {
+    return c.failures;
+  };
+  c.expect_output(
+    "init",
+    vec![
+      info("init"),
+      ScriptCommand::Spawn(SpriteDesc {
+        pos: [0., 2.],
+        size: [8., 8.],
+        texture: RESOURCE.to_string(),
+        layer: 0,
+      }),
+      ScriptCommand::Emit(ScriptEvent::new("ready", "")),
+    ],
+    vec![ScriptEvent::new("ready", "")],
+  );
+
+  for _ in 0..2 {
+    let errors = c.host.tick(DT);
+    c.errors("tick", errors);
+  }
+  c.expect_output(
+    "tick",
+    vec![
+      ScriptCommand::SetPosition(id, [1., 2.]),
+      ScriptCommand::SetPosition(id, [2., 2.]),
+    ],
+    Vec::new(),
+  );
+  let pos =
+    ctx.scene.read().sprite(id).map(|s| s.pos.into());
+  c.expect("tick", "position", pos, Some([2., 2.]));
+
+  let errors =
+    c.host.dispatch(&ScriptEvent::new("ping", "hello"));
+  c.errors("on_event", errors);
+  c.expect_output(
+    "on_event",
+    vec![
+      ScriptCommand::SetTile {
+        map: RESOURCE.to_string(),
+        x: 1,
+        y: 0,
+        tile: 2,
+      },
+      ScriptCommand::Emit(ScriptEvent::new(
+        "pong", "hello",
+      )),
+    ],
+    vec![ScriptEvent::new("pong", "hello")],
+  );
+  let tile = ctx
+    .scene
+    .read()
+    .tilemap(RESOURCE)
+    .and_then(|m| m.get(1, 0));
+  c.expect("on_event", "tile", tile, Some(2));
+  let errors =
+    c.host.dispatch(&ScriptEvent::new("pang", ""));
+  c.errors("on_event", errors);
+  c.expect_output("on_event", Vec::new(), Vec::new());
+
+  let errors = c.host.shutdown();
+  c.errors("shutdown", errors);
+  c.expect_output(
+    "shutdown",
+    vec![ScriptCommand::Despawn(id), info("shutdown")],
+    Vec::new(),
+  );
+  let alive = ctx.scene.read().sprite(id).is_some();
+  c.expect("shutdown", "sprite alive", alive, false);
+  c.failures
+}
+
+/// Log the result of the host. Returns false if it failed.
+fn report<H: ScriptHost>(
+  host: &mut H,
+  ctx: &SceneContext,
+) -> bool {
+  let failures =
+    check(host, ctx, Path::new(CONFORMANCE_ROOT));
+  for f in failures.iter() {
+    log::error!("[{}] {f}", host.backend());
+  }
+  if failures.is_empty() {
+    log::info!("[{}] conformance passed.", host.backend());
+  }
+  failures.is_empty()
+}
+
+/// Check every backend.
+pub fn run_all() -> Result<(), StdError> {
+  let input = Arc::new(RwLock::new(RawInput::default()));
+  let ctx = context();
+  let mut lua = LuaHost::new(
+    &ctx,
+    input.clone(),
+    SandboxProfile::default(),
+  )?;
+  let lua_ok = report(&mut lua, &ctx);
+  let ctx = context();
+  let mut wasm = PluginHost::new(
+    ctx.clone(),
+    input,
+    PluginLimits::default(),
+  )?;
+  let wasm_ok = report(&mut wasm, &ctx);
+  if lua_ok && wasm_ok {
+    Ok(())
+  } else {
+    Err("script conformance failed".into())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  #[test]
+  fn hosts_conform() {
+    super::run_all().unwrap();
+  }
+}
@@ -0,0 +1,284 @@
+//! Script host interface
+//! LuaとWasmで共通のスクリプト実行部分
+//!
+//! Lua scripts and wasm plugins are driven by `ScriptHost`. The
+//! engine delivers `ScriptEvent`s to a host, and the scripts change
+//! the engine only by the `ScriptCommand`s executed by their
+//! `ScriptEngine`. Queries (entity position, tiles, input, assets)
+//! are answered by the same `ScriptEngine`.
+//!
+//! A behavior has the same entry points in both backends (`init`,
+//! `tick(dt)`, `on_event(name, payload)`, `shutdown`), and the same
+//! operations as the WIT world `aes:engine/plugin`. In Lua, they are
+//! in the global `engine`.
+
+use crate::app_sys::{
+  input::RawInput,
+  lua::SceneContext,
+  scene::{Sprite, SpriteID},
+};
+use nalgebra::{Point2, Vector2};
+use parking_lot::{RwLock, RwLockReadGuard};
+use std::{fmt::Display, path::Path, sync::Arc};
+
+pub mod conformance;
+
+/// Event delivered to the `on_event` of the scripts
+#[derive(Debug, Clone, PartialEq)]
+pub struct ScriptEvent {
+  pub name: String,
+  pub payload: String,
+}
+impl ScriptEvent {
+  pub fn new(
+    name: impl ToString,
+    payload: impl ToString,
+  ) -> Self {
+    Self {
+      name: name.to_string(),
+      payload: payload.to_string(),
+    }
+  }
+}
+
+/// Sprite spawned by a script
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpriteDesc {
+  pub pos: [f32; 2],
+  /// Full size in the world
+  pub size: [f32; 2],
+  /// Texture name
+  pub texture: String,
+  pub layer: i32,
+}
+
+/// Change of the engine requested by a script
+#[derive(Debug, Clone, PartialEq)]
+pub enum ScriptCommand {
+  Log {
+    level: log::Level,
+    message: String,
+  },
+  Spawn(SpriteDesc),
+  Despawn(SpriteID),
+  SetPosition(SpriteID, [f32; 2]),
+  SetTile {
+    map: String,
+    x: u32,
+    y: u32,
+    tile: u32,
+  },
+  /// Emit the event to the scripts of every host. It is delivered
+  /// after the tick.
+  Emit(ScriptEvent),
+}
+
+/// Result of `ScriptEngine::execute`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CommandResult {
+  Done,
+  Spawned(SpriteID),
+  /// The target is not exist.
+  Failed,
+}
+impl CommandResult {
+  pub fn is_ok(self) -> bool {
+    self != Self::Failed
+  }
+
+  pub fn entity(self) -> Option<SpriteID> {
+    match self {
+      Self::Spawned(id) => Some(id),
+      _ => None,
+    }
+  }
+}
+
+/// Engine side of a script (a Lua state or a wasm plugin)
+pub struct ScriptEngine {
+  /// Name in the log
+  source: String,
+  ctx: SceneContext,
+  input: Arc<RwLock<RawInput>>,
+  /// Events emitted since the last `take_events`
+  events: Vec<ScriptEvent>,
+  /// Executed commands while recording
+  trace: Option<Vec<ScriptCommand>>,
+}
+impl ScriptEngine {
+  pub fn new(
+    source: impl ToString,
+    ctx: SceneContext,
+    input: Arc<RwLock<RawInput>>,
+  ) -> Self {
+    Self {
+      source: source.to_string(),
+      ctx,
+      input,
+      events: Vec::new(),
+      trace: None,
+    }
+  }
+
+  pub fn input(&self) -> RwLockReadGuard<'_, RawInput> {
+    self.input.read()
+  }
+
+  /// Apply the command to the engine.
+  pub fn execute(
+    &mut self,
+    command: ScriptCommand,
+  ) -> CommandResult {
+    let result = match &command {
+      ScriptCommand::Log { level, message } => {
+        log::log!(
+          target: "script",
+          *level,
+          "[{}] {message}",
+          self.source
+        );
+        CommandResult::Done
+      }
+      ScriptCommand::Spawn(desc) => {
+        match self.ctx.textures.read().get_id(&desc.texture)
+        {
+          Some(texture) => CommandResult::Spawned(
+            self.ctx.scene.write().spawn_sprite(Sprite {
+              pos: Point2::from(desc.pos),
+              size: Vector2::from(desc.size),
+              texture: Some(texture),
+              layer: desc.layer,
+              ..Default::default()
+            }),
+          ),
+          None => CommandResult::Failed,
+        }
+      }
+      ScriptCommand::Despawn(id) => {
+        match self.ctx.scene.write().remove_sprite(*id) {
+          Some(_) => CommandResult::Done,
+          None => CommandResult::Failed,
+        }
+      }
+      ScriptCommand::SetPosition(id, pos) => {
+        match self.ctx.scene.write().sprite_mut(*id) {
+          Some(s) => {
+            s.pos = Point2::from(*pos);
+            CommandResult::Done
+          }
+          None => CommandResult::Failed,
+        }
+      }
+      ScriptCommand::SetTile { map, x, y, tile } => {
+        let ok = self
+          .ctx
+          .scene
+          .write()
+          .tilemap_mut(map)
+          .is_some_and(|m| m.set(*x, *y, *tile));
+        if ok {
+          CommandResult::Done
+        } else {
+          CommandResult::Failed
+        }
+      }
+      ScriptCommand::Emit(event) => {
+        self.events.push(event.clone());
+        CommandResult::Done
+      }
+    };
+    if let Some(trace) = self.trace.as_mut() {
+      trace.push(command);
+    }
+    result
+  }
+
+  pub fn entity_pos(
+    &self,
+    id: SpriteID,
+  ) -> Option<[f32; 2]> {
+    self.ctx.scene.read().sprite(id).map(|s| s.pos.into())
+  }
+
+  pub fn tile(
+    &self,
+    map: &str,
+    x: u32,
+    y: u32,
+  ) -> Option<u32> {
+    self.ctx.scene.read().tilemap(map)?.get(x, y)
+  }
+
+  pub fn texture_names(&self) -> Vec<String> {
+    self
+      .ctx
+      .textures
+      .read()
+      .iter()
+      .map(|(name, _)| name.to_string())
+      .collect()
+  }
+
+  /// Size in pixel
+  pub fn texture_size(
+    &self,
+    name: &str,
+  ) -> Option<[u32; 2]> {
+    let textures = self.ctx.textures.read();
+    textures.size(textures.get_id(name)?)
+  }
+
+  pub fn take_events(&mut self) -> Vec<ScriptEvent> {
+    std::mem::take(&mut self.events)
+  }
+
+  /// Start or stop recording the executed commands.
+  pub fn set_trace(&mut self, enable: bool) {
+    self.trace = enable.then(Vec::new);
+  }
+
+  /// Commands recorded since the last call
+  pub fn take_trace(&mut self) -> Vec<ScriptCommand> {
+    self
+      .trace
+      .as_mut()
+      .map(std::mem::take)
+      .unwrap_or_default()
+  }
+}
+
+/// Backend that runs the scripts
+pub trait ScriptHost {
+  type Error: Display;
+
+  /// Backend name ("lua", "wasm")
+  fn backend(&self) -> &'static str;
+
+  /// Load every script in the directory, and call `init` of them.
+  fn load_dir(&mut self, dir: &Path) -> Vec<Self::Error>;
+
+  /// Call `tick` of the scripts.
+  fn tick(&mut self, dt: f32) -> Vec<Self::Error>;
+
+  /// Call `on_event` of the scripts.
+  fn dispatch(
+    &mut self,
+    event: &ScriptEvent,
+  ) -> Vec<Self::Error>;
+
+  /// Events emitted by the scripts since the last call
+  fn take_events(&mut self) -> Vec<ScriptEvent>;
+
+  /// Call `shutdown` of the scripts.
+  fn shutdown(&mut self) -> Vec<Self::Error>;
+
+  /// Load the scripts of `load_dir` again in fresh states, and
+  /// call `init` of them. `shutdown` is not called.
+  fn restart(&mut self) -> Vec<Self::Error>;
+
+  /// Start or stop recording the commands of the scripts.
+  fn set_trace(&mut self, enable: bool);
+
+  /// Commands recorded since the last call
+  fn take_trace(&mut self) -> Vec<ScriptCommand>;
+}
@@ -0,0 +1,325 @@
+//! Host ABI of the wasm plugins
+//!
+//! Imports are in the module `HOST_MODULE`. Strings are passed as
+//! `(ptr, len)` of UTF-8 bytes in the exported `memory`, and the
+//! output pointers receive little-endian values.
+//!
+//! | function | signature |
+//! |---|---|
+//! | `log` | `(level, ptr, len)` (1: error ... 5: trace) |
+//! | `entity_spawn` | `(x, y, w, h: f32, tex_ptr, tex_len) -> id` (-1: unknown texture) |
+//! | `entity_despawn` | `(id) -> ok` |
+//! | `entity_get_pos` | `(id, out_ptr: [f32; 2]) -> ok` |
+//! | `entity_set_pos` | `(id, x, y: f32) -> ok` |
+//! | `input_key_down` | `(name_ptr, name_len) -> bool` (`KeyCode` name) |
+//! | `input_key_pressed` | `(name_ptr, name_len) -> bool` |
+//! | `input_mouse_down` | `(button) -> bool` (0: left, 1: right, 2: middle) |
+//! | `input_cursor` | `(out_ptr: [f32; 2])` (window pixel) |
+//! | `tile_get` | `(map_ptr, map_len, x, y) -> tile` (-1: out of the map) |
+//! | `tile_set` | `(map_ptr, map_len, x, y, tile) -> ok` |
+//! | `event_emit` | `(name_ptr, name_len, ptr, len)` |
+//!
+//! Plugin exports:
+//! - `memory` (required)
+//! - `aes_abi_version() -> i32` (required, must be `ABI_VERSION`)
+//! - `init() -> i32` (0 is success), `tick(dt: f32)`, `shutdown()`
+//! - `on_event(name_ptr, name_len, ptr, len)` with
+//!   `aes_alloc(len) -> ptr`: the host writes the name and the
+//!   payload to the buffer of `aes_alloc`. The buffer is used only
+//!   during the call.
+
+use crate::app_sys::{
+  scene::SpriteID,
+  script::{
+    ScriptCommand, ScriptEngine, ScriptEvent, SpriteDesc,
+  },
+};
+use wasmtime::{Caller, Linker, StoreLimits};
+use winit::event::MouseButton;
+
+/// Import module name of the host functions
+pub const HOST_MODULE: &str = "aes_v1";
+/// Version that the plugin must return from `aes_abi_version`
+pub const ABI_VERSION: i32 = 1;
+
+/// Store data of a plugin instance
+pub struct HostState {
+  pub engine: ScriptEngine,
+  pub limits: StoreLimits,
+}
+
+fn memory(
+  caller: &mut Caller<'_, HostState>,
+) -> wasmtime::Result<wasmtime::Memory> {
+  caller
+    .get_export("memory")
+    .and_then(|e| e.into_memory())
+    .ok_or_else(|| {
+      wasmtime::Error::msg("plugin does not export memory")
+    })
+}
+
+fn read_str(
+  caller: &mut Caller<'_, HostState>,
+  ptr: i32,
+  len: i32,
+) -> wasmtime::Result<String> {
+  let memory = memory(caller)?;
+  let (ptr, len) =
+    (ptr as u32 as usize, len as u32 as usize);
+  let bytes = memory
+    .data(&caller)
+    .get(ptr..ptr.saturating_add(len))
+    .ok_or_else(|| {
+      wasmtime::Error::msg("string is out of the memory")
+    })?;
+  Ok(String::from_utf8_lossy(bytes).into_owned())
+}
+
+fn write_f32s(
+  caller: &mut Caller<'_, HostState>,
+  ptr: i32,
+  values: &[f32],
+) -> wasmtime::Result<()> {
+  let memory = memory(caller)?;
+  let bytes = values
+    .iter()
+    .flat_map(|v| v.to_le_bytes())
+    .collect::<Vec<_>>();
+  memory.write(caller, ptr as u32 as usize, &bytes)?;
+  Ok(())
+}
+
+/// Register the host functions to the linker.
+pub fn link(
+  linker: &mut Linker<HostState>,
+) -> wasmtime::Result<()> {
+  linker.func_wrap(
+    HOST_MODULE,
+    "log",
+    |mut caller: Caller<'_, HostState>,
+     level: i32,
+     ptr: i32,
+     len: i32|
+     -> wasmtime::Result<()> {
+      let msg = read_str(&mut caller, ptr, len)?;
+      let level = match level {
+        ..=1 => log::Level::Error,
+        2 => log::Level::Warn,
+        3 => log::Level::Info,
+        4 => log::Level::Debug,
+        _ => log::Level::Trace,
+      };
+      caller.data_mut().engine.execute(ScriptCommand::Log {
+        level,
+        message: msg,
+      });
+      Ok(())
+    },
+  )?;
+
+  linker.func_wrap(
+    HOST_MODULE,
+    "entity_spawn",
+    |mut caller: Caller<'_, HostState>,
+     x: f32,
+     y: f32,
+     w: f32,
+     h: f32,
+     tex_ptr: i32,
+     tex_len: i32|
+     -> wasmtime::Result<i32> {
+      let name = read_str(&mut caller, tex_ptr, tex_len)?;
+      Ok(
+        caller
+          .data_mut()
+          .engine
+          .execute(ScriptCommand::Spawn(SpriteDesc {
+            pos: [x, y],
+            size: [w, h],
+            texture: name,
+            layer: 0,
+          }))
+          .entity()
+          .map(|id| id.raw() as i32)
+          .unwrap_or(-1),
+      )
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "entity_despawn",
+    |mut caller: Caller<'_, HostState>, id: i32| {
+      caller
+        .data_mut()
+        .engine
+        .execute(ScriptCommand::Despawn(SpriteID::from_raw(
+          id as u32,
+        )))
+        .is_ok() as i32
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "entity_get_pos",
+    |mut caller: Caller<'_, HostState>,
+     id: i32,
+     out: i32|
+     -> wasmtime::Result<i32> {
+      let pos = caller
+        .data()
+        .engine
+        .entity_pos(SpriteID::from_raw(id as u32));
+      match pos {
+        Some(pos) => {
+          write_f32s(&mut caller, out, &pos)?;
+          Ok(1)
+        }
+        None => Ok(0),
+      }
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "entity_set_pos",
+    |mut caller: Caller<'_, HostState>,
+     id: i32,
+     x: f32,
+     y: f32| {
+      caller
+        .data_mut()
+        .engine
+        .execute(ScriptCommand::SetPosition(
+          SpriteID::from_raw(id as u32),
+          [x, y],
+        ))
+        .is_ok() as i32
+    },
+  )?;
+
+  linker.func_wrap(
+    HOST_MODULE,
+    "input_key_down",
+    |mut caller: Caller<'_, HostState>,
+     ptr: i32,
+     len: i32|
+     -> wasmtime::Result<i32> {
+      let name = read_str(&mut caller, ptr, len)?;
+      Ok(caller.data().engine.input().is_down_by_name(&name)
+        as i32)
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "input_key_pressed",
+    |mut caller: Caller<'_, HostState>,
+     ptr: i32,
+     len: i32|
+     -> wasmtime::Result<i32> {
+      let name = read_str(&mut caller, ptr, len)?;
+      Ok(
+        caller.data().engine.input().is_pressed_by_name(&name)
+          as i32,
+      )
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "input_mouse_down",
+    |caller: Caller<'_, HostState>, button: i32| {
+      let button = match button {
+        0 => MouseButton::Left,
+        1 => MouseButton::Right,
+        2 => MouseButton::Middle,
+        _ => return 0,
+      };
+      caller.data().engine.input().is_button_down(button)
+        as i32
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "input_cursor",
+    |mut caller: Caller<'_, HostState>,
+     out: i32|
+     -> wasmtime::Result<()> {
+      let cursor = caller.data().engine.input().cursor();
+      write_f32s(&mut caller, out, &cursor)
+    },
+  )?;
+
+  linker.func_wrap(
+    HOST_MODULE,
+    "tile_get",
+    |mut caller: Caller<'_, HostState>,
+     ptr: i32,
+     len: i32,
+     x: i32,
+     y: i32|
+     -> wasmtime::Result<i32> {
+      let name = read_str(&mut caller, ptr, len)?;
+      if x < 0 || y < 0 {
+        return Ok(-1);
+      }
+      Ok(
+        caller
+          .data()
+          .engine
+          .tile(&name, x as u32, y as u32)
+          .map(|t| t as i32)
+          .unwrap_or(-1),
+      )
+    },
+  )?;
+  linker.func_wrap(
+    HOST_MODULE,
+    "tile_set",
+    |mut caller: Caller<'_, HostState>,
+     ptr: i32,
+     len: i32,
+     x: i32,
+     y: i32,
+     tile: i32|
+     -> wasmtime::Result<i32> {
+      let name = read_str(&mut caller, ptr, len)?;
+      if x < 0 || y < 0 || tile < 0 {
+        return Ok(0);
+      }
+      Ok(
+        caller
+          .data_mut()
+          .engine
+          .execute(ScriptCommand::SetTile {
+            map: name,
+            x: x as u32,
+            y: y as u32,
+            tile: tile as u32,
+          })
+          .is_ok() as i32,
+      )
+    },
+  )?;
+
+  linker.func_wrap(
+    HOST_MODULE,
+    "event_emit",
+    |mut caller: Caller<'_, HostState>,
+     name_ptr: i32,
+     name_len: i32,
+     ptr: i32,
+     len: i32|
+     -> wasmtime::Result<()> {
+      let name = read_str(&mut caller, name_ptr, name_len)?;
+      let payload = read_str(&mut caller, ptr, len)?;
+      caller
+        .data_mut()
+        .engine
+        .execute(ScriptCommand::Emit(ScriptEvent {
+          name,
+          payload,
+        }));
+      Ok(())
+    },
+  )?;
+  Ok(())
+}
@@ -0,0 +1,296 @@
+//! Component model host
+//!
+//! Host side of the WIT world `aes:engine/plugin` (`wit/engine.wit`).
+//! A component plugin imports the engine interfaces with the typed
+//! bindings instead of the pointer/length ABI of `abi`.
+
+use super::abi::HostState;
+use crate::app_sys::{
+  scene::SpriteID,
+  script::{ScriptCommand, ScriptEvent, SpriteDesc},
+};
+use winit::event::MouseButton;
+
+pub mod bindings {
+  wasmtime::component::bindgen!({
+    path: "wit",
+    world: "plugin",
+  });
+}
+
+use bindings::aes::engine::{
+  assets, entities, events, input, log, tiles, types,
+};
+use types::Vec2;
+
+/// Register the engine interfaces to the component linker.
+pub fn link(
+  linker: &mut wasmtime::component::Linker<HostState>,
+) -> wasmtime::Result<()> {
+  bindings::Plugin::add_to_linker(
+    linker,
+    |s: &mut HostState| s,
+  )
+}
+
+/// Check the header. A binary has the layer in the preamble (core
+/// module: 0, component: 1), and a text (`.wat`) component starts
+/// with `(component` after the comments.
+pub fn is_component(bytes: &[u8]) -> bool {
+  if bytes.starts_with(b"\0asm") {
+    return bytes.get(6..8) == Some(&[1, 0]);
+  }
+  let mut text = std::str::from_utf8(bytes).unwrap_or_default();
+  loop {
+    text = text.trim_start();
+    if let Some(rest) = text.strip_prefix(";;") {
+      text = rest.split_once('\n').map_or("", |(_, r)| r);
+    } else if let Some(rest) = text.strip_prefix("(;") {
+      text = rest.split_once(";)").map_or("", |(_, r)| r);
+    } else {
+      break;
+    }
+  }
+  text.starts_with("(component")
+}
+
+impl types::Host for HostState {}
+
+impl log::Host for HostState {
+  fn log(&mut self, level: log::Level, message: String) {
+    let level = match level {
+      log::Level::Error => ::log::Level::Error,
+      log::Level::Warn => ::log::Level::Warn,
+      log::Level::Info => ::log::Level::Info,
+      log::Level::Debug => ::log::Level::Debug,
+      log::Level::Trace => ::log::Level::Trace,
+    };
+    self.engine.execute(ScriptCommand::Log { level, message });
+  }
+}
+
+impl entities::Host for HostState {
+  fn spawn(
+    &mut self,
+    desc: entities::SpriteDesc,
+  ) -> Option<u32> {
+    self
+      .engine
+      .execute(ScriptCommand::Spawn(SpriteDesc {
+        pos: [desc.position.x, desc.position.y],
+        size: [desc.size.x, desc.size.y],
+        texture: desc.texture,
+        layer: desc.layer,
+      }))
+      .entity()
+      .map(SpriteID::raw)
+  }
+
+  fn despawn(&mut self, e: u32) -> bool {
+    self
+      .engine
+      .execute(ScriptCommand::Despawn(SpriteID::from_raw(e)))
+      .is_ok()
+  }
+
+  fn position(&mut self, e: u32) -> Option<Vec2> {
+    self
+      .engine
+      .entity_pos(SpriteID::from_raw(e))
+      .map(|[x, y]| Vec2 { x, y })
+  }
+
+  fn set_position(
+    &mut self,
+    e: u32,
+    position: Vec2,
+  ) -> bool {
+    self
+      .engine
+      .execute(ScriptCommand::SetPosition(
+        SpriteID::from_raw(e),
+        [position.x, position.y],
+      ))
+      .is_ok()
+  }
+}
+
+impl input::Host for HostState {
+  fn key_down(&mut self, key: String) -> bool {
+    self.engine.input().is_down_by_name(&key)
+  }
+
+  fn key_pressed(&mut self, key: String) -> bool {
+    self.engine.input().is_pressed_by_name(&key)
+  }
+
+  fn mouse_down(
+    &mut self,
+    button: input::MouseButton,
+  ) -> bool {
+    let button = match button {
+      input::MouseButton::Left => MouseButton::Left,
+      input::MouseButton::Right => MouseButton::Right,
+      input::MouseButton::Middle => MouseButton::Middle,
+    };
+    self.engine.input().is_button_down(button)
+  }
+
+  fn cursor(&mut self) -> Vec2 {
+    let [x, y] = self.engine.input().cursor();
+    Vec2 { x, y }
+  }
+}
+
+impl assets::Host for HostState {
+  fn texture_names(&mut self) -> Vec<String> {
+    self.engine.texture_names()
+  }
+
+  fn texture_size(
+    &mut self,
+    name: String,
+  ) -> Option<(u32, u32)> {
+    let [w, h] = self.engine.texture_size(&name)?;
+    Some((w, h))
+  }
+}
+
+impl tiles::Host for HostState {
+  fn get(
+    &mut self,
+    map: String,
+    x: u32,
+    y: u32,
+  ) -> Option<u32> {
+    self.engine.tile(&map, x, y)
+  }
+
+  fn set(
+    &mut self,
+    map: String,
+    x: u32,
+    y: u32,
+    tile: u32,
+  ) -> bool {
+    self
+      .engine
+      .execute(ScriptCommand::SetTile { map, x, y, tile })
+      .is_ok()
+  }
+}
+
+impl events::Host for HostState {
+  fn emit(&mut self, name: String, payload: String) {
+    self
+      .engine
+      .execute(ScriptCommand::Emit(ScriptEvent { name, payload }));
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::app_sys::{
+    input::RawInput,
+    script::{conformance, ScriptHost},
+    wasm::{PluginHost, PluginLimits, PluginStatus},
+  };
+  use parking_lot::RwLock;
+  use std::sync::Arc;
+
+  /// Component of the world `plugin`, which emits an event in `init`
+  const COMPONENT: &str = r#"
+;; Minimal component of wit/engine.wit
+(component
+  (import "aes:engine/events@1.0.0" (instance $events
+    (export "emit"
+      (func (param "name" string) (param "payload" string)))))
+  (alias export $events "emit" (func $emit))
+
+  (core module $mem
+    (memory (export "memory") 1)
+    (global $next (mut i32) (i32.const 1024))
+    (func (export "realloc")
+      (param i32 i32 i32 i32) (result i32)
+      (global.get $next)
+      (global.set $next
+        (i32.add (global.get $next) (local.get 3)))))
+  (core instance $mem (instantiate $mem))
+  (core func $emit_core
+    (canon lower (func $emit) (memory $mem "memory")))
+
+  (core module $plugin
+    (import "host" "emit" (func $emit (param i32 i32 i32 i32)))
+    (func (export "init") (result i32)
+      (call $emit
+        (i32.const 0) (i32.const 5) (i32.const 5) (i32.const 2))
+      ;; Zeros of `ok`
+      i32.const 16)
+    (func (export "tick") (param f32))
+    (func (export "shutdown"))
+    (func (export "on-event") (param i32 i32 i32 i32)))
+  (core instance $host (export "emit" (func $emit_core)))
+  (core instance $plugin
+    (instantiate $plugin (with "host" (instance $host))))
+  (core module $data
+    (import "host" "memory" (memory 1))
+    (data (i32.const 0) "readyok"))
+  (core instance
+    (instantiate $data
+      (with "host" (instance
+        (export "memory" (memory $mem "memory"))))))
+
+  (func (export "init") (result (result (error string)))
+    (canon lift (core func $plugin "init")
+      (memory $mem "memory")))
+  (func (export "tick") (param "dt" f32)
+    (canon lift (core func $plugin "tick")))
+  (func (export "shutdown")
+    (canon lift (core func $plugin "shutdown")))
+  (func (export "on-event")
+    (param "name" string) (param "payload" string)
+    (canon lift (core func $plugin "on-event")
+      (memory $mem "memory")
+      (realloc (func $mem "realloc")))))
+"#;
+
+  #[test]
+  fn detects_text_components() {
+    assert!(is_component(COMPONENT.as_bytes()));
+    assert!(is_component(b"(; block ;) (component)"));
+    assert!(!is_component(b";; (component)\n(module)"));
+    assert!(!is_component(b"\0asm\x01\0\0\0"));
+    assert!(is_component(b"\0asm\x0d\0\x01\0"));
+  }
+
+  #[test]
+  fn runs_a_component_of_the_world() {
+    let dir = std::env::temp_dir()
+      .join(format!("aes-component-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("minimal.wat");
+    std::fs::write(&path, COMPONENT).unwrap();
+
+    let mut host = PluginHost::new(
+      conformance::context(),
+      Arc::new(RwLock::new(RawInput::default())),
+      PluginLimits::default(),
+    )
+    .unwrap();
+    let result = host.load(&path);
+    std::fs::remove_dir_all(&dir).ok();
+    result.unwrap();
+    assert!(host.plugins[0].is_component());
+    assert_eq!(
+      host.take_events(),
+      [ScriptEvent::new("ready", "ok")]
+    );
+    assert!(host.tick(1. / 60.).is_empty());
+    assert!(host
+      .dispatch(&ScriptEvent::new("hit", "{}"))
+      .is_empty());
+    assert!(host.shutdown().is_empty());
+    assert_eq!(host.plugins[0].status, PluginStatus::Stopped);
+  }
+}
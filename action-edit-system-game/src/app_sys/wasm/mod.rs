@@ -0,0 +1,482 @@
+//! WebAssembly plugin host
+//! wasmtimeによるプラグインの実行部分
+//!
+//! Every `*.wasm` (and `*.wat`) file in the plugin root is loaded as a
+//! plugin. A core module uses the host ABI of `abi`, and a component
+//! uses the WIT world of `component`, either in the binary or in the
+//! text format.
+//! Each call of the exports has its own fuel, and the linear memory
+//! of a plugin is limited. A plugin that traps is stopped.
+//!
+//! `PluginHost` is the `ScriptHost` of the wasm backend. Each plugin
+//! has its own `ScriptEngine`.
+
+use crate::{
+  app_sys::{
+    input::RawInput,
+    lua::SceneContext,
+    script::{
+      ScriptCommand, ScriptEngine, ScriptEvent, ScriptHost,
+    },
+  },
+  StdError,
+};
+use parking_lot::RwLock;
+use std::{
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+use wasmtime::{
+  Config, Engine, Instance, Linker, Module, Store,
+  StoreLimitsBuilder, Trap, TypedFunc,
+};
+
+pub mod abi;
+pub mod component;
+
+/// Default plugin root
+pub const PLUGIN_ROOT: &str = "./plugins";
+
+#[derive(Debug, Clone)]
+pub struct PluginLimits {
+  /// Fuel of a call of the exports
+  pub fuel_per_call: u64,
+  /// Size limit of the linear memory (byte)
+  pub memory: usize,
+}
+impl Default for PluginLimits {
+  fn default() -> Self {
+    Self {
+      fuel_per_call: 10_000_000,
+      memory: 16 << 20,
+    }
+  }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PluginStatus {
+  Running,
+  Failed(String),
+  Stopped,
+}
+
+/// Exports of the instance
+enum Runtime {
+  /// Core module with the pointer/length ABI (`abi`)
+  Core {
+    instance: Instance,
+    tick: Option<TypedFunc<f32, ()>>,
+    shutdown: Option<TypedFunc<(), ()>>,
+  },
+  /// Component of the WIT world (`component`)
+  Component(Box<component::bindings::Plugin>),
+}
+
+pub struct Plugin {
+  name: String,
+  path: PathBuf,
+  store: Store<abi::HostState>,
+  runtime: Runtime,
+  status: PluginStatus,
+  /// Fuel consumed by the last call
+  fuel_used: u64,
+  fuel_per_call: u64,
+}
+impl Plugin {
+  pub fn is_component(&self) -> bool {
+    matches!(self.runtime, Runtime::Component(_))
+  }
+
+  /// Call the export with a fresh fuel. The plugin is stopped if the
+  /// call traps.
+  fn call<R>(
+    &mut self,
+    f: impl FnOnce(
+      &mut Store<abi::HostState>,
+      &Runtime,
+    ) -> wasmtime::Result<R>,
+  ) -> Option<R> {
+    if self.status != PluginStatus::Running {
+      return None;
+    }
+    let result = self
+      .store
+      .set_fuel(self.fuel_per_call)
+      .and_then(|_| f(&mut self.store, &self.runtime));
+    self.fuel_used = self.fuel_per_call
+      - self.store.get_fuel().unwrap_or(self.fuel_per_call);
+    match result {
+      Ok(r) => Some(r),
+      Err(e) => {
+        let msg = match e.downcast_ref::<Trap>() {
+          Some(Trap::OutOfFuel) => format!(
+            "fuel limit ({}) exceeded",
+            self.fuel_per_call
+          ),
+          _ => format!("{e:?}"),
+        };
+        log::error!(
+          "Wasm plugin \"{}\" failed: {msg}",
+          self.name
+        );
+        self.status = PluginStatus::Failed(msg);
+        None
+      }
+    }
+  }
+
+  fn tick(&mut self, dt: f32) {
+    self.call(|store, runtime| match runtime {
+      Runtime::Core { tick: Some(f), .. } => {
+        f.call(store, dt)
+      }
+      Runtime::Core { tick: None, .. } => Ok(()),
+      Runtime::Component(p) => p.call_tick(store, dt),
+    });
+  }
+
+  fn shutdown(&mut self) {
+    self.call(|store, runtime| match runtime {
+      Runtime::Core {
+        shutdown: Some(f), ..
+      } => f.call(store, ()),
+      Runtime::Core { shutdown: None, .. } => Ok(()),
+      Runtime::Component(p) => p.call_shutdown(store),
+    });
+  }
+
+  fn on_event(&mut self, event: &ScriptEvent) {
+    self.call(|store, runtime| match runtime {
+      Runtime::Core { instance, .. } => {
+        let (Some(memory), Some(alloc), Some(f)) = (
+          instance.get_memory(&mut *store, "memory"),
+          instance.get_func(&mut *store, "aes_alloc"),
+          instance.get_func(&mut *store, "on_event"),
+        ) else {
+          return Ok(());
+        };
+        let alloc = alloc.typed::<i32, i32>(&*store)?;
+        let f = f.typed::<(i32, i32, i32, i32), ()>(&*store)?;
+        let (name, payload) =
+          (event.name.as_bytes(), event.payload.as_bytes());
+        let len = (name.len() + payload.len()) as i32;
+        let ptr = alloc.call(&mut *store, len)?;
+        memory.write(&mut *store, ptr as u32 as usize, name)?;
+        memory.write(
+          &mut *store,
+          ptr as u32 as usize + name.len(),
+          payload,
+        )?;
+        f.call(
+          store,
+          (
+            ptr,
+            name.len() as i32,
+            ptr + name.len() as i32,
+            payload.len() as i32,
+          ),
+        )
+      }
+      Runtime::Component(p) => {
+        p.call_on_event(store, &event.name, &event.payload)
+      }
+    });
+  }
+}
+
+pub struct PluginHost {
+  engine: Engine,
+  linker: Linker<abi::HostState>,
+  component_linker:
+    wasmtime::component::Linker<abi::HostState>,
+  limits: PluginLimits,
+  ctx: SceneContext,
+  input: Arc<RwLock<RawInput>>,
+  plugins: Vec<Plugin>,
+  /// Record the commands of the plugins
+  trace: bool,
+}
+impl PluginHost {
+  pub fn new(
+    ctx: SceneContext,
+    input: Arc<RwLock<RawInput>>,
+    limits: PluginLimits,
+  ) -> Result<Self, StdError> {
+    let mut config = Config::new();
+    config.consume_fuel(true);
+    config.wasm_component_model(true);
+    let engine = Engine::new(&config)?;
+    let mut linker = Linker::new(&engine);
+    abi::link(&mut linker)?;
+    let mut component_linker =
+      wasmtime::component::Linker::new(&engine);
+    component::link(&mut component_linker)?;
+    Ok(Self {
+      engine,
+      linker,
+      component_linker,
+      limits,
+      ctx,
+      input,
+      plugins: Vec::new(),
+      trace: false,
+    })
+  }
+
+  /// Call the function for the running plugins. Returns the errors
+  /// of the plugins that are stopped by the call.
+  fn each(
+    &mut self,
+    mut f: impl FnMut(&mut Plugin),
+  ) -> Vec<String> {
+    self
+      .plugins
+      .iter_mut()
+      .filter(|p| p.status == PluginStatus::Running)
+      .filter_map(|p| {
+        f(p);
+        match &p.status {
+          PluginStatus::Failed(msg) => {
+            Some(format!("plugin \"{}\": {msg}", p.name))
+          }
+          _ => None,
+        }
+      })
+      .collect()
+  }
+
+  /// Instantiate the plugin, and call `init`.
+  /// A core module must return `ABI_VERSION` from `aes_abi_version`.
+  pub fn load(
+    &mut self,
+    path: &Path,
+  ) -> Result<(), StdError> {
+    let name = path
+      .file_stem()
+      .and_then(|s| s.to_str())
+      .unwrap_or_default()
+      .to_string();
+    let bytes = std::fs::read(path)?;
+    let mut engine = ScriptEngine::new(
+      &name,
+      self.ctx.clone(),
+      self.input.clone(),
+    );
+    engine.set_trace(self.trace);
+    let mut store = Store::new(
+      &self.engine,
+      abi::HostState {
+        engine,
+        limits: StoreLimitsBuilder::new()
+          .memory_size(self.limits.memory)
+          .build(),
+      },
+    );
+    store.limiter(|s| &mut s.limits);
+    store.set_fuel(self.limits.fuel_per_call)?;
+    let (runtime, init) = if component::is_component(&bytes)
+    {
+      let c = wasmtime::component::Component::new(
+        &self.engine,
+        &bytes,
+      )?;
+      let bindings =
+        component::bindings::Plugin::instantiate(
+          &mut store,
+          &c,
+          &self.component_linker,
+        )?;
+      (Runtime::Component(Box::new(bindings)), None)
+    } else {
+      let module = Module::new(&self.engine, &bytes)?;
+      let instance =
+        self.linker.instantiate(&mut store, &module)?;
+      let version = instance
+        .get_typed_func::<(), i32>(
+          &mut store,
+          "aes_abi_version",
+        )?
+        .call(&mut store, ())?;
+      if version != abi::ABI_VERSION {
+        return Err(
+          format!(
+            "plugin \"{name}\" is built for ABI version {version} (host: {})",
+            abi::ABI_VERSION
+          )
+          .into(),
+        );
+      }
+      let init = instance
+        .get_func(&mut store, "init")
+        .map(|f| f.typed::<(), i32>(&store))
+        .transpose()?;
+      let tick = instance
+        .get_func(&mut store, "tick")
+        .map(|f| f.typed::<f32, ()>(&store))
+        .transpose()?;
+      let shutdown = instance
+        .get_func(&mut store, "shutdown")
+        .map(|f| f.typed::<(), ()>(&store))
+        .transpose()?;
+      (
+        Runtime::Core {
+          instance,
+          tick,
+          shutdown,
+        },
+        init,
+      )
+    };
+    let mut plugin = Plugin {
+      name,
+      path: path.to_path_buf(),
+      store,
+      runtime,
+      status: PluginStatus::Running,
+      fuel_used: 0,
+      fuel_per_call: self.limits.fuel_per_call,
+    };
+    log::info!("Load wasm plugin \"{}\".", plugin.name);
+    let initialized =
+      plugin.call(|store, runtime| match runtime {
+        Runtime::Core { .. } => match &init {
+          Some(f) => Ok(match f.call(store, ())? {
+            0 => Ok(()),
+            code => Err(format!("init returned {code}")),
+          }),
+          None => Ok(Ok(())),
+        },
+        Runtime::Component(p) => p.call_init(store),
+      });
+    if let Some(Err(msg)) = initialized {
+      plugin.status = PluginStatus::Failed(msg);
+    }
+    let failed = match &plugin.status {
+      PluginStatus::Failed(msg) => Some(msg.clone()),
+      _ => None,
+    };
+    self.plugins.push(plugin);
+    match failed {
+      Some(msg) => Err(msg.into()),
+      None => Ok(()),
+    }
+  }
+
+  pub fn ui(&mut self, ui: &mut egui::Ui) {
+    if self.plugins.is_empty() {
+      ui.label(format!("No plugin in {PLUGIN_ROOT}"));
+      return;
+    }
+    egui::Grid::new("wasm plugins").striped(true).show(
+      ui,
+      |ui| {
+        for plugin in self.plugins.iter() {
+          ui.label(&plugin.name).on_hover_text(
+            plugin.path.display().to_string(),
+          );
+          ui.label(if plugin.is_component() {
+            "component"
+          } else {
+            "core"
+          });
+          match &plugin.status {
+            PluginStatus::Running => ui.label("running"),
+            PluginStatus::Stopped => ui.label("stopped"),
+            PluginStatus::Failed(msg) => ui.colored_label(
+              egui::Color32::RED,
+              format!("failed: {msg}"),
+            ),
+          };
+          ui.label(format!("fuel {}", plugin.fuel_used));
+          ui.end_row();
+        }
+      },
+    );
+  }
+}
+
+impl ScriptHost for PluginHost {
+  type Error = String;
+
+  fn backend(&self) -> &'static str {
+    "wasm"
+  }
+
+  fn load_dir(&mut self, dir: &Path) -> Vec<String> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+      return Vec::new();
+    };
+    let mut paths = entries
+      .filter_map(|e| e.ok())
+      .map(|e| e.path())
+      .filter(|p| {
+        p.is_file()
+          && p
+            .extension()
+            .is_some_and(|e| e == "wasm" || e == "wat")
+      })
+      .collect::<Vec<_>>();
+    paths.sort();
+    paths
+      .into_iter()
+      .filter_map(|p| self.load(&p).err())
+      .map(|e| e.to_string())
+      .collect()
+  }
+
+  fn tick(&mut self, dt: f32) -> Vec<String> {
+    self.each(|p| p.tick(dt))
+  }
+
+  fn dispatch(&mut self, event: &ScriptEvent) -> Vec<String> {
+    self.each(|p| p.on_event(event))
+  }
+
+  fn take_events(&mut self) -> Vec<ScriptEvent> {
+    self
+      .plugins
+      .iter_mut()
+      .flat_map(|p| p.store.data_mut().engine.take_events())
+      .collect()
+  }
+
+  /// Call `shutdown` of the running plugins, and stop them.
+  fn shutdown(&mut self) -> Vec<String> {
+    let errors = self.each(|p| p.shutdown());
+    for plugin in self.plugins.iter_mut() {
+      if plugin.status == PluginStatus::Running {
+        plugin.status = PluginStatus::Stopped;
+      }
+    }
+    errors
+  }
+
+  /// Instantiate the plugins again from their files.
+  fn restart(&mut self) -> Vec<String> {
+    let paths = self
+      .plugins
+      .drain(..)
+      .map(|p| p.path)
+      .collect::<Vec<_>>();
+    paths
+      .into_iter()
+      .filter_map(|p| self.load(&p).err())
+      .map(|e| e.to_string())
+      .collect()
+  }
+
+  fn set_trace(&mut self, enable: bool) {
+    self.trace = enable;
+    for plugin in self.plugins.iter_mut() {
+      plugin.store.data_mut().engine.set_trace(enable);
+    }
+  }
+
+  fn take_trace(&mut self) -> Vec<ScriptCommand> {
+    self
+      .plugins
+      .iter_mut()
+      .flat_map(|p| p.store.data_mut().engine.take_trace())
+      .collect()
+  }
+}
@@ -12,9 +12,37 @@ fn main() -> Result<(), StdError> {
     })
     .init();
 
+  // Checking the script hosts
+  if std::env::args().any(|a| a == "--script-conformance") {
+    return app_sys::script::conformance::run_all();
+  }
+
+  // Recording or replaying the session
+  let args = std::env::args().collect::<Vec<_>>();
+  let arg = |name: &str| {
+    args.iter().position(|a| a == name).and_then(|i| args.get(i + 1))
+  };
+  let replay = arg("--replay");
+  if args.iter().any(|a| a == "--headless") {
+    let path = replay.ok_or("--headless needs --replay <path>")?;
+    return app_sys::replay::run_headless(path);
+  }
+  let replay = replay
+    .map(app_sys::replay::Recording::load)
+    .transpose()?;
+
   // Preparing application
   log::info!("Preparing application.");
-  let mut app = app_sys::AppFrontend::new()?;
+  let seed = replay
+    .as_ref()
+    .map_or_else(app_sys::replay::random_seed, |r| r.seed);
+  let mut app = app_sys::AppFrontend::new(seed)?;
+  if let Some(recording) = replay {
+    app.replay(app_sys::replay::Replay::new(recording))?;
+  }
+  if let Some(path) = arg("--record") {
+    app.record(path)?;
+  }
   let event_loop =
     winit::event_loop::EventLoopBuilder::default().build().map(|evl| {
       evl.set_control_flow(winit::event_loop::ControlFlow::Poll);